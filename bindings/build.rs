@@ -1,36 +1,148 @@
 fn main() {
     windows::build!(
         Windows::Win32::FileSystem::{
+            CancelIoEx,
             SetFileCompletionNotificationModes,
+            SetFileInformationByHandle,
             CreateIoCompletionPort,
-            GetQueuedCompletionStatus
+            FILE_ALLOCATION_INFO,
+            FILE_INFO_BY_HANDLE_CLASS,
+            FILE_STORAGE_INFO,
+            GetFileInformationByHandleEx,
+            GetQueuedCompletionStatus,
+            LockFileEx,
+            ReadDirectoryChangesW,
+            ReadFile,
+            UnlockFileEx,
+            WriteFile,
+        },
+        Windows::Win32::IpHelper::{
+            CancelMibChangeNotify2,
+            Icmp6CreateFile,
+            Icmp6ParseReplies,
+            Icmp6SendEcho2,
+            IcmpCloseHandle,
+            IcmpCreateFile,
+            IcmpParseReplies,
+            IcmpSendEcho2,
+            NotifyRouteChange2,
+            NotifyUnicastIpAddressChange,
+        },
+        Windows::Win32::Perf::{
+            PerfCreateInstance,
+            PerfDeleteInstance,
+            PerfSetCounterRefValue,
+            PerfSetCounterSetInfo,
+            PerfStartProvider,
+            PerfStopProvider,
+            PERF_COUNTERSET_INFO,
+            PERF_COUNTERSET_INSTANCE,
+            PERF_COUNTER_INFO,
+            PerfProviderHandle,
+        },
+        Windows::Win32::Security::{
+            AcceptSecurityContext,
+            AcquireCredentialsHandleW,
+            ApplyControlToken,
+            CertCloseStore,
+            CertFindCertificateInStore,
+            CertFreeCertificateContext,
+            CertOpenStore,
+            DecryptMessage,
+            DeleteSecurityContext,
+            EncryptMessage,
+            FreeContextBuffer,
+            FreeCredentialsHandle,
+            InitializeSecurityContextW,
+            QueryContextAttributesW,
+            SCHANNEL_CRED,
+            SecBuffer,
+            SecBufferDesc,
+            SecHandle,
+            SecPkgContext_StreamSizes,
         },
         Windows::Win32::SystemServices::{
             CancelThreadpoolIo,
+            CloseThreadpool,
             CloseThreadpoolIo,
+            CloseThreadpoolTimer,
+            CloseThreadpoolWait,
+            ConnectNamedPipe,
+            CreateMailslotW,
+            CreateNamedPipeW,
+            CreateEventW,
+            CreateThreadpool,
             CreateThreadpoolIo,
+            CreateThreadpoolTimer,
+            CreateThreadpoolWait,
+            COMMTIMEOUTS,
+            DCB,
+            DeviceIoControl,
+            DisassociateCurrentThreadFromCallback,
+            DisconnectNamedPipe,
+            GetCommState,
             INVALID_HANDLE_VALUE,
             OVERLAPPED,
+            SetCommMask,
+            SetCommState,
+            SetCommTimeouts,
+            SetConsoleCtrlHandler,
+            SetNamedPipeHandleState,
+            SetThreadpoolTimer,
+            SetThreadpoolWait,
             StartThreadpoolIo,
+            TP_CALLBACK_ENVIRON_V3,
             TP_CALLBACK_INSTANCE,
+            TP_CALLBACK_PRIORITY,
             TP_IO,
+            TP_POOL,
+            TP_TIMER,
+            TP_WAIT,
+            WaitForThreadpoolIoCallbacks,
+            WaitForThreadpoolTimerCallbacks,
+            WaitForThreadpoolWaitCallbacks,
+            TrySubmitThreadpoolCallback,
+            WaitCommEvent,
+            WaitNamedPipeW,
         },
         Windows::Win32::WinSock::{
+            bind,
+            connect,
+            GetNameInfoW,
+            getpeername,
+            getsockname,
             LPFN_ACCEPTEX,
+            LPFN_CONNECTEX,
+            LPFN_DISCONNECTEX,
             LPFN_GETACCEPTEXSOCKADDRS,
+            LPFN_TRANSMITFILE,
+            LPFN_WSARECVMSG,
+            LPFN_WSASENDMSG,
+            IN_PKTINFO,
+            IN6_PKTINFO,
+            WSAMSG,
+            getsockopt,
+            listen,
             setsockopt,
+            shutdown,
+            SOCKADDR_IN,
+            SOCKADDR_IN6,
+            SOCKADDR_STORAGE,
             WSA_ERROR,
             WSABUF,
             WSAGetLastError,
+            WSAGetOverlappedResult,
             WSAIoctl,
             WSARecv,
+            WSARecvFrom,
             WSASend,
+            WSASendTo,
             WSASocketW,
         },
         Windows::Win32::Debug::{
             GetLastError,
             WIN32_ERROR,
         },
-        Windows::Win32::WindowsProgramming::CloseHandle,
+        Windows::Win32::WindowsProgramming::{CloseHandle, FILETIME},
     );
 }
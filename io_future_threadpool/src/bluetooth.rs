@@ -0,0 +1,202 @@
+//! Bluetooth RFCOMM sockets (`AF_BTH`), the serial-port-like stream protocol most Bluetooth
+//! devices expose their services over. A device is addressed by its 48-bit Bluetooth address, and
+//! a service on it either by its RFCOMM channel or by its service class ID, which is looked up
+//! with SDP when connecting.
+
+use bindings::Windows::Win32::WinSock::{getpeername, getsockname, SOCKADDR};
+
+use windows::Guid;
+
+use std::io;
+use std::mem;
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, OwnedSocket, RawSocket};
+
+use crate::runtime::Handle;
+use crate::socket_stream::{self, SocketListener, SocketStream};
+
+const AF_BTH: u16 = 32;
+const BTHPROTO_RFCOMM: i32 = 3;
+
+/// Binds to any free RFCOMM channel, or when connecting, looks up the channel of the service
+/// class ID with SDP. `BT_PORT_ANY`.
+pub const BT_PORT_ANY: u32 = u32::MAX;
+
+/// The address of an RFCOMM socket: a device and a channel on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BluetoothAddr {
+    /// The device's Bluetooth address in the low 48 bits, or 0 for the local radio when binding.
+    pub device: u64,
+    /// The service to connect to when `port` is [BT_PORT_ANY]. Ignored otherwise.
+    pub service_class_id: Guid,
+    /// The RFCOMM channel, from 1 to 30, or [BT_PORT_ANY].
+    pub port: u32,
+}
+
+impl BluetoothAddr {
+    /// The RFCOMM channel `port` on `device`.
+    pub fn new(device: u64, port: u32) -> BluetoothAddr {
+        BluetoothAddr {
+            device,
+            service_class_id: Guid::zeroed(),
+            port,
+        }
+    }
+
+    /// The service `service_class_id` on `device`, whose channel is looked up when connecting.
+    pub fn with_service(device: u64, service_class_id: Guid) -> BluetoothAddr {
+        BluetoothAddr {
+            device,
+            service_class_id,
+            port: BT_PORT_ANY,
+        }
+    }
+
+    fn to_raw(self) -> SockaddrBth {
+        SockaddrBth {
+            family: AF_BTH,
+            bt_addr: self.device,
+            service_class_id: self.service_class_id,
+            port: self.port,
+        }
+    }
+}
+
+/// `SOCKADDR_BTH` from ws2bth.h, which declares it with 1-byte packing.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SockaddrBth {
+    family: u16,
+    bt_addr: u64,
+    service_class_id: Guid,
+    port: u32,
+}
+
+impl SockaddrBth {
+    fn addr(&self) -> BluetoothAddr {
+        BluetoothAddr {
+            device: self.bt_addr,
+            service_class_id: self.service_class_id,
+            port: self.port,
+        }
+    }
+}
+
+const SOCKADDR_BTH_LEN: i32 = mem::size_of::<SockaddrBth>() as i32;
+
+// getsockname or getpeername.
+fn socket_name(
+    socket: &OwnedSocket,
+    name_fn: unsafe fn(usize, *mut SOCKADDR, *mut i32) -> i32,
+) -> io::Result<BluetoothAddr> {
+    let empty = BluetoothAddr::new(0, 0).to_raw();
+    let (addr, _) = socket_stream::socket_name(socket, empty, name_fn)?;
+    Ok(addr.addr())
+}
+
+/// An RFCOMM socket listening for connections, which it accepts with an overlapped `AcceptEx`
+/// completed on the runtime's threadpool.
+///
+/// Remote devices find the listener by its channel, so a server that is looked up by service class
+/// ID also has to advertise the channel from [AsyncBluetoothListener::local_addr] in an SDP record
+/// with `WSASetService`.
+pub struct AsyncBluetoothListener {
+    inner: SocketListener,
+}
+
+impl AsyncBluetoothListener {
+    /// Creates a socket bound to `port` on the local radio and starts listening on it. Pass
+    /// [BT_PORT_ANY] to have a free channel picked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind(port: u32) -> io::Result<AsyncBluetoothListener> {
+        let socket = socket_stream::new_socket(AF_BTH, BTHPROTO_RFCOMM)?;
+        socket_stream::bind(
+            &socket,
+            &BluetoothAddr::new(0, port).to_raw(),
+            SOCKADDR_BTH_LEN,
+        )?;
+        Ok(AsyncBluetoothListener {
+            inner: SocketListener::listen(socket, AF_BTH)?,
+        })
+    }
+
+    /// The address of the local radio and the channel the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<BluetoothAddr> {
+        socket_name(self.inner.socket(), getsockname)
+    }
+
+    /// Accepts a connection, and returns it along with the address of the device that made it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn accept(&self) -> io::Result<(AsyncBluetoothStream, BluetoothAddr)> {
+        let socket = socket_stream::new_socket(AF_BTH, BTHPROTO_RFCOMM)?;
+        let inner = self
+            .inner
+            .accept(socket, mem::size_of::<SockaddrBth>())
+            .await?;
+        let stream = AsyncBluetoothStream { inner };
+        let peer_addr = stream.peer_addr()?;
+        Ok((stream, peer_addr))
+    }
+}
+
+impl AsRawSocket for AsyncBluetoothListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.socket().as_raw_socket()
+    }
+}
+
+impl AsSocket for AsyncBluetoothListener {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.socket().as_socket()
+    }
+}
+
+/// An RFCOMM connection whose I/O completes on the runtime's threadpool.
+///
+/// Like [crate::stream::AsyncTcpStream], reads and writes only need `&self`, and the
+/// [AsyncRead](futures::io::AsyncRead) and [AsyncWrite](futures::io::AsyncWrite) impls are also
+/// provided for `&AsyncBluetoothStream`, with at most one task reading and one task writing
+/// through them at a time.
+pub struct AsyncBluetoothStream {
+    inner: SocketStream,
+}
+
+impl AsyncBluetoothStream {
+    /// Connects to `addr`, a channel on a device, or a service class ID with [BT_PORT_ANY].
+    ///
+    /// `ConnectEx` does not do the SDP lookup of a service class ID, so the connect is a blocking
+    /// `connect` on the runtime's blocking pool. Paging a device that is out of range can take
+    /// several seconds to fail.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect(addr: BluetoothAddr) -> io::Result<AsyncBluetoothStream> {
+        let remote = addr.to_raw();
+        let socket = socket_stream::new_socket(AF_BTH, BTHPROTO_RFCOMM)?;
+        let socket = Handle::current_for_io()?
+            .spawn_blocking(move || {
+                socket_stream::connect(&socket, &remote, SOCKADDR_BTH_LEN).map(|_| socket)
+            })
+            .await?;
+        Ok(AsyncBluetoothStream {
+            inner: SocketStream::new(socket)?,
+        })
+    }
+
+    /// The address of the other end of the connection.
+    pub fn peer_addr(&self) -> io::Result<BluetoothAddr> {
+        socket_name(self.inner.socket(), getpeername)
+    }
+
+    pub fn local_addr(&self) -> io::Result<BluetoothAddr> {
+        socket_name(self.inner.socket(), getsockname)
+    }
+}
+
+socket_stream::socket_stream_wrapper!(AsyncBluetoothStream);
@@ -0,0 +1,239 @@
+//! Buffers that are owned by an I/O operation for as long as it is in flight.
+//!
+//! Completion based I/O hands Windows a pointer to the buffer when the operation starts, and
+//! Windows reads or writes through it until the operation completes. Methods that borrow the
+//! caller's slice, such as [crate::stream::AsyncTcpStream::poll_read], tie the future to the
+//! caller's stack frame. The `_owned` methods instead take the buffer by value and return it with
+//! the result, so their futures are `'static` and can be stored or spawned freely. Dropping a
+//! borrowing future cancels the operation and waits for it before the slice can be reused, while
+//! dropping an owning one cancels it and leaves the buffer to be freed when it completes; see
+//! [crate::iocp_threadpool::IocpFuture].
+
+use std::alloc::{self, Layout};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::slice;
+use std::task::{Context, Poll};
+
+use crate::iocp_threadpool::{IocpFuture, IocpResult};
+
+/// A buffer that data can be written from.
+///
+/// # Safety
+///
+/// `stable_ptr` must point to at least `bytes_total` bytes, the first `bytes_init` of which are
+/// initialized, and must not change when the buffer is moved.
+pub unsafe trait IoBuf: Unpin + Send + 'static {
+    fn stable_ptr(&self) -> *const u8;
+
+    /// The number of initialized bytes, which is how many a write sends.
+    fn bytes_init(&self) -> usize;
+
+    /// The number of bytes that can be read into the buffer.
+    fn bytes_total(&self) -> usize;
+}
+
+/// A buffer that data can be read into.
+///
+/// # Safety
+///
+/// The same requirements as [IoBuf] apply to `stable_mut_ptr`.
+pub unsafe trait IoBufMut: IoBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8;
+
+    /// Records that the first `pos` bytes have been initialized by a read.
+    ///
+    /// # Safety
+    ///
+    /// The first `pos` bytes must actually be initialized, and `pos` must be at most
+    /// `bytes_total`.
+    unsafe fn set_init(&mut self, pos: usize);
+}
+
+unsafe impl IoBuf for Vec<u8> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+}
+
+unsafe impl IoBufMut for Vec<u8> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        if self.len() < pos {
+            self.set_len(pos);
+        }
+    }
+}
+
+unsafe impl IoBuf for Box<[u8]> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.len()
+    }
+}
+
+unsafe impl IoBufMut for Box<[u8]> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {}
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBuf for bytes::BytesMut {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBufMut for bytes::BytesMut {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        if self.len() < pos {
+            self.set_len(pos);
+        }
+    }
+}
+
+/// A zeroed buffer whose address is a multiple of its alignment, as unbuffered file I/O requires
+/// of the buffers it reads into and writes from. See [crate::fs::AsyncFile::aligned_buf].
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned, like a Box<[u8]>.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Allocates `len` zeroed bytes aligned to `align`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is 0, or if `align` is not a power of two.
+    pub fn new(len: usize, align: usize) -> AlignedBuf {
+        assert!(len > 0, "aligned buffers cannot be empty");
+        let layout = Layout::from_size_align(len, align).expect("invalid buffer alignment");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        match NonNull::new(ptr) {
+            Some(ptr) => AlignedBuf { ptr, layout },
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+unsafe impl IoBuf for AlignedBuf {
+    fn stable_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+unsafe impl IoBufMut for AlignedBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {}
+}
+
+/// An overlapped operation together with the buffer it uses, returned by
+/// [crate::iocp_threadpool::start_async_io_owned]. Resolves to the operation's result and the
+/// buffer.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct OwnedBufFuture<B: IoBuf> {
+    op: IocpFuture,
+    buf: Option<B>,
+}
+
+impl<B: IoBuf> Drop for OwnedBufFuture<B> {
+    fn drop(&mut self) {
+        // An unfinished operation keeps the buffer until it completes, so dropping the future
+        // does not wait for it.
+        if let Some(buf) = self.buf.take() {
+            self.op.abandon(Box::new(buf));
+        }
+    }
+}
+
+impl<B: IoBuf> OwnedBufFuture<B> {
+    /// `op` must have been started on `buf`'s memory.
+    pub(crate) fn new(op: IocpFuture, buf: B) -> Self {
+        OwnedBufFuture { op, buf: Some(buf) }
+    }
+}
+
+impl<B: IoBuf> Future for OwnedBufFuture<B> {
+    type Output = (IocpResult, B);
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.op).poll(cx) {
+            Poll::Ready(result) => Poll::Ready((result, this.buf.take().unwrap())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
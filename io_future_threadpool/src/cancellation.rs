@@ -0,0 +1,108 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+/// A token that can be used to signal cancellation to any number of tasks. Tokens form a tree:
+/// cancelling a token also cancels every token created from it with
+/// [CancellationToken::child_token], but cancelling a child does not affect its parent.
+///
+/// Each [crate::runtime::Runtime] has a root token, see [crate::runtime::Handle::shutdown_token].
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Mutex<TokenState>>,
+}
+
+struct TokenState {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+    children: Vec<Weak<Mutex<TokenState>>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            inner: Arc::new(Mutex::new(TokenState {
+                cancelled: false,
+                wakers: Vec::new(),
+                children: Vec::new(),
+            })),
+        }
+    }
+
+    /// Creates a token that is cancelled when this token is cancelled.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut state = self.inner.lock().unwrap();
+        if state.cancelled {
+            child.inner.lock().unwrap().cancelled = true;
+        } else {
+            state.children.retain(|c| c.strong_count() != 0);
+            state.children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    /// Cancels this token and all of its children.
+    pub fn cancel(&self) {
+        cancel_state(&self.inner);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.lock().unwrap().cancelled
+    }
+
+    /// Returns a future that completes when this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
+
+fn cancel_state(inner: &Mutex<TokenState>) {
+    let (wakers, children) = {
+        let mut state = inner.lock().unwrap();
+        if state.cancelled {
+            return;
+        }
+        state.cancelled = true;
+        (
+            std::mem::take(&mut state.wakers),
+            std::mem::take(&mut state.children),
+        )
+    };
+    for waker in wakers {
+        waker.wake();
+    }
+    for child in children {
+        if let Some(child) = child.upgrade() {
+            cancel_state(&child);
+        }
+    }
+}
+
+/// Future returned by [CancellationToken::cancelled].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.token.inner.lock().unwrap();
+        if state.cancelled {
+            Poll::Ready(())
+        } else {
+            if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Splits a byte stream into length-prefixed frames, and datagrams into typed messages.
+//!
+//! Each frame is sent as its length followed by that many bytes. [Framed] turns any
+//! `futures::io` stream, such as an [crate::stream::AsyncTcpStream], into a [Stream] of received
+//! frames and a [Sink] of frames to send, so protocols built this way don't each have to buffer
+//! partial reads and split them up.
+//!
+//! [UdpFramed] does the same for packet-based protocols such as DNS and STUN: it pairs an
+//! [AsyncUdpSocket] with a [Decoder] and [Encoder] that turn each datagram into a message and
+//! back.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::udp::AsyncUdpSocket;
+
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+// The largest UDP payload, which is what UdpFramed receives into.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Parses messages out of received bytes.
+pub trait Decoder {
+    type Item;
+
+    /// Removes a complete message from the start of `buf`, or returns `None` if `buf` does not
+    /// hold a whole one yet.
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>>;
+}
+
+/// Turns messages of type `Item` into bytes to send.
+pub trait Encoder<Item> {
+    /// Appends the encoding of `item` to `buf`.
+    fn encode(&mut self, item: Item, buf: &mut BytesMut) -> io::Result<()>;
+}
+
+/// How the length of each frame is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthField {
+    /// A big-endian 16 bit length.
+    U16,
+    /// A big-endian 32 bit length.
+    U32,
+    /// An unsigned LEB128 varint, as used by protobuf.
+    Varint,
+}
+
+/// The framing rules, and a builder for [Framed].
+///
+/// Defaults to a [LengthField::U32] header and a maximum frame length of 8 MiB.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthDelimited {
+    length_field: LengthField,
+    max_frame_length: usize,
+}
+
+impl Default for LengthDelimited {
+    fn default() -> LengthDelimited {
+        LengthDelimited {
+            length_field: LengthField::U32,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl LengthDelimited {
+    pub fn new() -> LengthDelimited {
+        LengthDelimited::default()
+    }
+
+    pub fn length_field(mut self, length_field: LengthField) -> LengthDelimited {
+        self.length_field = length_field;
+        self
+    }
+
+    /// Frames longer than this are rejected, in both directions. A received length over the
+    /// limit fails the stream with `InvalidData` rather than allocating a buffer for it.
+    pub fn max_frame_length(mut self, max: usize) -> LengthDelimited {
+        self.max_frame_length = max;
+        self
+    }
+
+    pub fn framed<T>(self, inner: T) -> Framed<T> {
+        Framed {
+            inner,
+            codec: self,
+            read_buf: BytesMut::with_capacity(DEFAULT_BUF_SIZE),
+            write_buf: BytesMut::with_capacity(DEFAULT_BUF_SIZE),
+            eof: false,
+        }
+    }
+
+    /// Removes a complete frame from the start of `buf`, or returns `None` and reserves room for
+    /// the rest of it.
+    fn decode_frame(&self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        let (header_len, frame_len) = match self.decode_header(buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if frame_len > self.max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame is longer than the maximum frame length",
+            ));
+        }
+        let total = header_len + frame_len;
+        if buf.len() < total {
+            buf.reserve(total - buf.len());
+            return Ok(None);
+        }
+        buf.advance(header_len);
+        Ok(Some(buf.split_to(frame_len).freeze()))
+    }
+
+    /// Returns the length of the header and the length of the frame it describes, if the whole
+    /// header is in `buf`.
+    fn decode_header(&self, buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        match self.length_field {
+            LengthField::U16 if buf.len() >= 2 => {
+                Ok(Some((2, u16::from_be_bytes([buf[0], buf[1]]) as usize)))
+            }
+            LengthField::U32 if buf.len() >= 4 => {
+                let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                Ok(Some((4, len as usize)))
+            }
+            LengthField::Varint => {
+                let mut len: u64 = 0;
+                for (i, &b) in buf.iter().enumerate().take(10) {
+                    len |= ((b & 0x7f) as u64) << (7 * i);
+                    if b & 0x80 == 0 {
+                        let len = usize::try_from(len).unwrap_or(usize::MAX);
+                        return Ok(Some((i + 1, len)));
+                    }
+                }
+                if buf.len() >= 10 {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "frame length varint is too long",
+                    ))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn encode_frame(&self, frame: &[u8], buf: &mut BytesMut) -> io::Result<()> {
+        let len = frame.len();
+        let fits = match self.length_field {
+            LengthField::U16 => len <= u16::MAX as usize,
+            LengthField::U32 => len <= u32::MAX as usize,
+            LengthField::Varint => true,
+        };
+        if len > self.max_frame_length || !fits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame is longer than the maximum frame length",
+            ));
+        }
+        match self.length_field {
+            LengthField::U16 => buf.put_u16(len as u16),
+            LengthField::U32 => buf.put_u32(len as u32),
+            LengthField::Varint => {
+                let mut len = len as u64;
+                while len >= 0x80 {
+                    buf.put_u8((len as u8) | 0x80);
+                    len >>= 7;
+                }
+                buf.put_u8(len as u8);
+            }
+        }
+        buf.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+impl Decoder for LengthDelimited {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        self.decode_frame(buf)
+    }
+}
+
+impl Encoder<Bytes> for LengthDelimited {
+    fn encode(&mut self, frame: Bytes, buf: &mut BytesMut) -> io::Result<()> {
+        self.encode_frame(&frame, buf)
+    }
+}
+
+/// A byte stream read and written as length-prefixed frames. Create one with
+/// [Framed::new] or [LengthDelimited::framed].
+///
+/// Sent frames are buffered until the sink is flushed, or until enough have built up that
+/// [Sink::poll_ready] flushes them.
+pub struct Framed<T> {
+    inner: T,
+    codec: LengthDelimited,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    eof: bool,
+}
+
+impl<T> Framed<T> {
+    /// Wraps `inner` with the default [LengthDelimited] rules.
+    pub fn new(inner: T) -> Framed<T> {
+        LengthDelimited::new().framed(inner)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the inner stream. Buffered data in either direction is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for Framed<T> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(frame) = this.codec.decode_frame(&mut this.read_buf)? {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if this.eof {
+                return if this.read_buf.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended in the middle of a frame",
+                    ))))
+                };
+            }
+
+            // Read into a window of at most DEFAULT_BUF_SIZE bytes past the buffered data, zeroed
+            // first since AsyncRead takes an initialized slice. The window is fixed rather than all
+            // the spare capacity, which can grow up to max_frame_length, so each poll only zeroes a
+            // bounded amount.
+            let window = DEFAULT_BUF_SIZE.min(this.codec.max_frame_length).max(1);
+            let start = this.read_buf.len();
+            this.read_buf.resize(start + window, 0);
+            let read = Pin::new(&mut this.inner).poll_read(cx, &mut this.read_buf[start..]);
+            let n = match read {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => {
+                    this.read_buf.truncate(start);
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Pending => {
+                    this.read_buf.truncate(start);
+                    return Poll::Pending;
+                }
+            };
+            this.read_buf.truncate(start + n);
+            if n == 0 {
+                this.eof = true;
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Sink<Bytes> for Framed<T> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.len() >= DEFAULT_BUF_SIZE {
+            self.poll_flush(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Bytes) -> io::Result<()> {
+        let this = self.get_mut();
+        this.codec.encode_frame(&frame, &mut this.write_buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.write_buf.advance(n);
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+type RecvFuture = BoxFuture<'static, io::Result<(BytesMut, SocketAddr)>>;
+type SendFuture = BoxFuture<'static, io::Result<usize>>;
+
+/// An [AsyncUdpSocket] read and written as messages, one per datagram. The [Stream] decodes each
+/// datagram received into a message and yields it with the address it came from, and the [Sink]
+/// encodes each message into a datagram and sends it to the address it is paired with.
+///
+/// A datagram the codec cannot decode a message from yields an `InvalidData` error, and bytes left
+/// over after the message are dropped. Errors do not end the stream: the next poll receives the
+/// next datagram.
+pub struct UdpFramed<C> {
+    socket: Arc<AsyncUdpSocket>,
+    codec: C,
+    recv: Option<RecvFuture>,
+    // The send in progress, which the sink accepts one of at a time.
+    send: Option<SendFuture>,
+}
+
+impl<C> UdpFramed<C> {
+    pub fn new(socket: AsyncUdpSocket, codec: C) -> UdpFramed<C> {
+        UdpFramed {
+            socket: Arc::new(socket),
+            codec,
+            recv: None,
+            send: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &AsyncUdpSocket {
+        &self.socket
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+}
+
+impl<C: Decoder + Unpin> Stream for UdpFramed<C> {
+    type Item = io::Result<(C::Item, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let socket = &this.socket;
+        let recv = this.recv.get_or_insert_with(|| {
+            let socket = socket.clone();
+            Box::pin(async move {
+                let mut buf = BytesMut::zeroed(MAX_DATAGRAM_SIZE);
+                let (n, from) = socket.recv_from(&mut buf).await?;
+                buf.truncate(n);
+                Ok((buf, from))
+            })
+        });
+        let received = ready!(recv.as_mut().poll(cx));
+        this.recv = None;
+        let (mut datagram, from) = received?;
+        match this.codec.decode(&mut datagram)? {
+            Some(item) => Poll::Ready(Some(Ok((item, from)))),
+            None => Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "datagram does not hold a whole message",
+            )))),
+        }
+    }
+}
+
+impl<C, I> Sink<(I, SocketAddr)> for UdpFramed<C>
+where
+    C: Encoder<I> + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, (item, target): (I, SocketAddr)) -> io::Result<()> {
+        let this = self.get_mut();
+        let mut datagram = BytesMut::new();
+        this.codec.encode(item, &mut datagram)?;
+        let socket = this.socket.clone();
+        this.send = Some(Box::pin(
+            async move { socket.send_to(&datagram, target).await },
+        ));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(send) = &mut this.send {
+            let sent = ready!(send.as_mut().poll(cx));
+            this.send = None;
+            sent?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
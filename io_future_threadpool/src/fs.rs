@@ -0,0 +1,727 @@
+//! Files opened for overlapped I/O, which the runtime's threadpool completes without blocking a
+//! thread per operation.
+
+use bindings::{
+    Windows::Win32::FileSystem::{
+        GetFileInformationByHandleEx, LockFileEx, ReadDirectoryChangesW,
+        SetFileInformationByHandle, UnlockFileEx, FILE_ALLOCATION_INFO, FILE_INFO_BY_HANDLE_CLASS,
+        FILE_NOTIFY_CHANGE, FILE_STORAGE_INFO, LOCK_FILE_FLAGS,
+    },
+    Windows::Win32::SystemServices::HANDLE,
+};
+
+use futures::ready;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::ffi::{c_void, OsString};
+use std::fs::{File, Metadata, OpenOptions};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::mem;
+use std::ops::BitOr;
+use std::os::windows::ffi::OsStringExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::buf::AlignedBuf;
+use crate::handle::{AsyncOverlappedHandle, IoPriority};
+use crate::iocp_threadpool::IocpFuture;
+use crate::runtime::Handle;
+
+const FILE_FLAG_OVERLAPPED: u32 = 0x40000000;
+
+/// Flags that change how Windows caches a file, passed to [AsyncFile::open_with_flags].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileFlags(u32);
+
+impl FileFlags {
+    pub const NONE: FileFlags = FileFlags(0);
+    /// `FILE_FLAG_NO_BUFFERING`: reads and writes go straight to the disk, bypassing the system
+    /// cache. Their buffers, lengths and offsets all have to be multiples of
+    /// [AsyncFile::sector_size]; [AsyncFile::aligned_buf] allocates suitable buffers.
+    pub const NO_BUFFERING: FileFlags = FileFlags(0x20000000);
+    /// `FILE_FLAG_WRITE_THROUGH`: writes complete only once the data is on the disk rather than
+    /// in a cache.
+    pub const WRITE_THROUGH: FileFlags = FileFlags(0x80000000);
+    /// `FILE_FLAG_SEQUENTIAL_SCAN`: the file is mostly read from start to end, so the cache reads
+    /// ahead more.
+    pub const SEQUENTIAL_SCAN: FileFlags = FileFlags(0x08000000);
+    /// `FILE_FLAG_RANDOM_ACCESS`: the file is mostly read at random, so the cache reads ahead
+    /// less.
+    pub const RANDOM_ACCESS: FileFlags = FileFlags(0x10000000);
+
+    pub fn contains(self, other: FileFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FileFlags {
+    type Output = FileFlags;
+    fn bitor(self, rhs: FileFlags) -> FileFlags {
+        FileFlags(self.0 | rhs.0)
+    }
+}
+
+/// A file whose reads and writes complete on the threadpool of the current
+/// [crate::runtime::Runtime].
+///
+/// Overlapped I/O has no file pointer, so the file keeps its own cursor, which reads and writes
+/// start at and advance.
+pub struct AsyncFile {
+    inner: AsyncOverlappedHandle<File>,
+    pos: u64,
+}
+
+impl AsyncFile {
+    /// Opens an existing file for reading.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AsyncFile> {
+        AsyncFile::open_with(OpenOptions::new().read(true), path)
+    }
+
+    /// Opens a file for writing, creating it if it does not exist and truncating it if it does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<AsyncFile> {
+        AsyncFile::open_with(
+            OpenOptions::new().write(true).create(true).truncate(true),
+            path,
+        )
+    }
+
+    /// Opens a file with `options`, whose custom flags are replaced by `FILE_FLAG_OVERLAPPED`.
+    /// Append mode is not supported, since writes go to the file's own cursor.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn open_with<P: AsRef<Path>>(options: &mut OpenOptions, path: P) -> io::Result<AsyncFile> {
+        AsyncFile::open_with_flags(options, path, FileFlags::NONE)
+    }
+
+    /// Like [AsyncFile::open_with], with `flags` added to the custom flags.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn open_with_flags<P: AsRef<Path>>(
+        options: &mut OpenOptions,
+        path: P,
+        flags: FileFlags,
+    ) -> io::Result<AsyncFile> {
+        let file = options
+            .custom_flags(FILE_FLAG_OVERLAPPED | flags.0)
+            .open(path)?;
+        Ok(AsyncFile {
+            inner: AsyncOverlappedHandle::new(file)?,
+            pos: 0,
+        })
+    }
+
+    /// Reads the file's metadata on the runtime's blocking pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        self.blocking(|file| file.metadata()).await
+    }
+
+    /// Flushes the file's data and metadata to the disk with `FlushFileBuffers`, on the runtime's
+    /// blocking pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn sync_all(&self) -> io::Result<()> {
+        self.blocking(|file| file.sync_all()).await
+    }
+
+    /// Like [AsyncFile::sync_all]. Windows has no way to flush only the data, so this flushes the
+    /// metadata too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn sync_data(&self) -> io::Result<()> {
+        self.blocking(|file| file.sync_data()).await
+    }
+
+    /// Truncates or extends the file to `len` bytes, on the runtime's blocking pool. Extending it
+    /// fills the new part with zeros. The cursor is not moved.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn set_len(&self, len: u64) -> io::Result<()> {
+        self.blocking(move |file| file.set_len(len)).await
+    }
+
+    /// Reserves disk space for the first `len` bytes of the file without changing its length, on
+    /// the runtime's blocking pool. Writes that extend the file into the reserved space then do
+    /// not need to allocate, and cannot fail for lack of space. Reserving less than the file
+    /// already has allocated does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn preallocate(&self, len: u64) -> io::Result<()> {
+        let mut info = FILE_ALLOCATION_INFO {
+            AllocationSize: len.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "allocation size too large")
+            })?,
+        };
+        self.blocking(move |file| {
+            let ok = unsafe {
+                SetFileInformationByHandle(
+                    HANDLE(file.as_raw_handle() as isize),
+                    FILE_INFO_BY_HANDLE_CLASS::FileAllocationInfo,
+                    &mut info as *mut FILE_ALLOCATION_INFO as *mut c_void,
+                    mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+                )
+            };
+            if ok.as_bool() {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
+        .await
+    }
+
+    /// Sets the priority of this file's reads and writes relative to other I/O to the same disk, so
+    /// background work such as scanning or compaction can use [IoPriority::Low] without starving
+    /// foreground requests. Other handles to the file keep their own priority.
+    pub fn set_io_priority(&self, priority: IoPriority) -> io::Result<()> {
+        self.inner.set_io_priority(priority)
+    }
+
+    // Runs `f` on the blocking pool with a duplicate of the file's handle, which refers to the
+    // same open file.
+    async fn blocking<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&File) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let file = self.inner.get_ref().try_clone()?;
+        Handle::current_for_io()?
+            .spawn_blocking(move || f(&file))
+            .await
+    }
+
+    /// The sector size of the file's disk. With [FileFlags::NO_BUFFERING], the buffers, lengths
+    /// and offsets of reads and writes have to be multiples of it.
+    pub fn sector_size(&self) -> io::Result<u32> {
+        let mut info = FILE_STORAGE_INFO::default();
+        let ok = unsafe {
+            GetFileInformationByHandleEx(
+                self.inner.handle(),
+                FILE_INFO_BY_HANDLE_CLASS::FileStorageInfo,
+                &mut info as *mut FILE_STORAGE_INFO as *mut c_void,
+                mem::size_of::<FILE_STORAGE_INFO>() as u32,
+            )
+        };
+        if !ok.as_bool() {
+            return Err(io::Error::last_os_error());
+        }
+        // Unbuffered I/O only needs the logical sector size, but I/O that is not aligned to the
+        // physical sector size is split up or read-modify-written by the disk.
+        Ok(info
+            .LogicalBytesPerSector
+            .max(info.PhysicalBytesPerSectorForPerformance))
+    }
+
+    /// Allocates a buffer for unbuffered I/O on this file: at least `len` bytes, rounded up to a
+    /// multiple of [AsyncFile::sector_size] and aligned to it.
+    pub fn aligned_buf(&self, len: usize) -> io::Result<AlignedBuf> {
+        let sector = self.sector_size()? as usize;
+        let len = len.max(1).div_ceil(sector) * sector;
+        Ok(AlignedBuf::new(len, sector))
+    }
+
+    /// Moves the cursor, and returns its new position from the start of the file. Seeking past the
+    /// end is allowed; a write there extends the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime] with
+    /// [SeekFrom::End], which reads the file's length with [AsyncFile::metadata].
+    pub async fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.metadata().await?.len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    /// The cursor's position from the start of the file.
+    pub fn stream_position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Reads into `buf` at the cursor, and returns the number of bytes read, which is 0 at the end
+    /// of the file.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read_at(buf, self.pos).await?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+
+    /// Writes some of `buf` at the cursor, and returns the number of bytes written.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write_at(buf, self.pos).await?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut ndx = 0;
+        while ndx < buf.len() {
+            let written = self.write(&buf[ndx..]).await?;
+            if written == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write the whole buffer",
+                ));
+            }
+            ndx += written;
+        }
+        Ok(())
+    }
+
+    /// Reads into `buf` at `offset` from the start of the file, without using or moving the
+    /// cursor. Returns the number of bytes read, which is 0 at the end of the file.
+    ///
+    /// Only `&self` is needed, so reads of different parts of the file can be in flight at the
+    /// same time.
+    pub async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset).await
+    }
+
+    /// Writes some of `buf` at `offset` from the start of the file, without using or moving the
+    /// cursor, and returns the number of bytes written.
+    ///
+    /// Like [AsyncFile::read_at], several writes can be in flight at the same time. Windows does
+    /// not order overlapping writes that are in flight together.
+    pub async fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.inner.write_at(buf, offset).await
+    }
+
+    /// Writes all of `buf` at `offset`, issuing further writes if one is only partially completed.
+    pub async fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.write_all_at(buf, offset).await
+    }
+
+    /// Waits until no other handle has an exclusive lock on the file, and takes a shared lock on
+    /// it. Dropping the returned future stops waiting.
+    ///
+    /// Locks on Windows are mandatory: while the file is locked, reads and writes through other
+    /// handles fail, and for a shared lock, so do writes through this one. The lock is released by
+    /// [AsyncFile::unlock] or by closing the file.
+    pub async fn lock_shared(&self) -> io::Result<()> {
+        self.lock(LOCK_FILE_FLAGS(0)).await
+    }
+
+    /// Like [AsyncFile::lock_shared], but takes an exclusive lock, which needs every other lock on
+    /// the file to be released first.
+    pub async fn lock_exclusive(&self) -> io::Result<()> {
+        self.lock(LOCK_FILE_FLAGS::LOCKFILE_EXCLUSIVE_LOCK).await
+    }
+
+    /// Takes an exclusive lock on the file if that can be done without waiting. Returns whether
+    /// the lock was taken.
+    pub async fn try_lock(&self) -> io::Result<bool> {
+        self.try_lock_with(LOCK_FILE_FLAGS::LOCKFILE_EXCLUSIVE_LOCK)
+            .await
+    }
+
+    /// Takes a shared lock on the file if that can be done without waiting. Returns whether the
+    /// lock was taken.
+    pub async fn try_lock_shared(&self) -> io::Result<bool> {
+        self.try_lock_with(LOCK_FILE_FLAGS(0)).await
+    }
+
+    /// Releases the lock taken by one of the `lock` methods.
+    pub async fn unlock(&self) -> io::Result<()> {
+        let handle = self.inner.handle();
+        let ret = self
+            .inner
+            .submit(|overlapped| unsafe {
+                if UnlockFileEx(handle, 0, u32::MAX, u32::MAX, overlapped).as_bool() {
+                    Some(0)
+                } else {
+                    None
+                }
+            })
+            .await;
+        ret.get_number_of_bytes_transferred().map(|_| ())
+    }
+
+    async fn try_lock_with(&self, flags: LOCK_FILE_FLAGS) -> io::Result<bool> {
+        const ERROR_LOCK_VIOLATION: i32 = 33;
+
+        let flags = LOCK_FILE_FLAGS(flags.0 | LOCK_FILE_FLAGS::LOCKFILE_FAIL_IMMEDIATELY.0);
+        match self.lock(flags).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Locks the whole file. The range starts at the OVERLAPPED's offset, which is 0.
+    async fn lock(&self, flags: LOCK_FILE_FLAGS) -> io::Result<()> {
+        let handle = self.inner.handle();
+        let ret = self
+            .inner
+            .submit(|overlapped| unsafe {
+                if LockFileEx(handle, flags, 0, u32::MAX, u32::MAX, overlapped).as_bool() {
+                    Some(0)
+                } else {
+                    None
+                }
+            })
+            .await;
+        ret.get_number_of_bytes_transferred().map(|_| ())
+    }
+}
+
+impl AsRawHandle for AsyncFile {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// How [copy_file_with_options] splits up a copy.
+///
+/// Defaults to 1 MiB chunks with 4 in flight at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct CopyOptions {
+    chunk_size: usize,
+    queue_depth: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            chunk_size: 1024 * 1024,
+            queue_depth: 4,
+        }
+    }
+}
+
+impl CopyOptions {
+    pub fn new() -> CopyOptions {
+        CopyOptions::default()
+    }
+
+    /// The size of each read and write.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunk_size(mut self, chunk_size: usize) -> CopyOptions {
+        assert!(chunk_size > 0, "the chunk size cannot be 0");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// How many chunks are read or written at the same time. Fast SSDs need several operations
+    /// queued to reach their full speed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue_depth` is 0.
+    pub fn queue_depth(mut self, queue_depth: usize) -> CopyOptions {
+        assert!(queue_depth > 0, "the queue depth cannot be 0");
+        self.queue_depth = queue_depth;
+        self
+    }
+}
+
+/// Copies the contents of `src` to `dst`, which is created or truncated, with the default
+/// [CopyOptions]. Returns the number of bytes copied.
+///
+/// # Errors
+///
+/// Returns an error if called outside the context of a [crate::runtime::Runtime].
+pub async fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<u64> {
+    copy_file_with_options(src, dst, CopyOptions::default()).await
+}
+
+/// Copies the contents of `src` to `dst`, which is created or truncated. Returns the number of
+/// bytes copied.
+///
+/// The file is copied in chunks, each of which is read and then written with positional I/O.
+/// Several chunks are in flight at once, so reads of later chunks overlap writes of earlier ones
+/// instead of alternating with them. Only the contents are copied, not attributes or streams.
+///
+/// # Errors
+///
+/// Returns an error if called outside the context of a [crate::runtime::Runtime].
+pub async fn copy_file_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+    options: CopyOptions,
+) -> io::Result<u64> {
+    let src = AsyncFile::open_with_flags(
+        OpenOptions::new().read(true),
+        src,
+        FileFlags::SEQUENTIAL_SCAN,
+    )?;
+    let dst = AsyncFile::create(dst)?;
+    let len = src.metadata().await?.len();
+    // Lets the file system lay out the copy in one go rather than a chunk at a time.
+    dst.preallocate(len).await?;
+    let chunk_size = options.chunk_size as u64;
+
+    // Copies the chunk at `offset` using `buf`, and returns the buffer for the next chunk along
+    // with the number of bytes copied, which is less than the chunk only if the file ends first.
+    let copy_chunk = |mut buf: Vec<u8>, offset: u64| {
+        let (src, dst) = (&src, &dst);
+        async move {
+            let want = (len - offset).min(chunk_size) as usize;
+            buf.resize(want, 0);
+            let mut read = 0;
+            while read < want {
+                let n = src.read_at(&mut buf[read..], offset + read as u64).await?;
+                if n == 0 {
+                    break;
+                }
+                read += n;
+            }
+            dst.write_all_at(&buf[..read], offset).await?;
+            Ok::<_, io::Error>((buf, read as u64))
+        }
+    };
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut next_offset = 0;
+    let mut copied = 0;
+    let mut free_bufs = Vec::new();
+    loop {
+        while in_flight.len() < options.queue_depth && next_offset < len {
+            let buf = free_bufs.pop().unwrap_or_default();
+            in_flight.push(copy_chunk(buf, next_offset));
+            next_offset += chunk_size;
+        }
+        match in_flight.next().await {
+            Some(result) => {
+                let (buf, n) = result?;
+                copied += n;
+                free_bufs.push(buf);
+            }
+            None => return Ok(copied),
+        }
+    }
+}
+
+/// A change to a directory reported by [DirectoryWatcher]. Paths are relative to the watched
+/// directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DirectoryChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+    /// The contents, size, timestamps or attributes changed.
+    Modified(PathBuf),
+    /// A file was renamed from this name. Usually followed by [DirectoryChange::RenamedTo].
+    RenamedFrom(PathBuf),
+    /// A file was renamed to this name.
+    RenamedTo(PathBuf),
+    /// More changes happened than could be reported, and some were lost. Scan the directory again
+    /// to find out what it looks like now.
+    Rescan,
+}
+
+/// Watches a directory for changes with overlapped `ReadDirectoryChangesW`, as a [Stream] of
+/// [DirectoryChange]s. The stream ends after returning an error, such as when the directory is
+/// deleted.
+///
+/// Windows collects the changes that happen while no read is pending, as long as they fit in its
+/// buffer, so changes between polls are not lost unless there are too many of them.
+#[must_use = "streams do nothing unless polled"]
+pub struct DirectoryWatcher {
+    // Declared before `buf` so that the pending read, which writes to `buf`, is cancelled and
+    // waited for before the buffer is freed.
+    op: Option<IocpFuture>,
+    // FILE_NOTIFY_INFORMATION records have to be DWORD aligned.
+    buf: Vec<u32>,
+    changes: VecDeque<DirectoryChange>,
+    done: bool,
+    recursive: bool,
+    dir: AsyncOverlappedHandle<File>,
+}
+
+impl DirectoryWatcher {
+    /// Watches the directory at `path`, and with `recursive`, all the directories below it, for
+    /// files and directories being added, removed, renamed or modified.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn new<P: AsRef<Path>>(path: P, recursive: bool) -> io::Result<DirectoryWatcher> {
+        const FILE_LIST_DIRECTORY: u32 = 0x0001;
+        const FILE_SHARE_ALL: u32 = 0x00000001 | 0x00000002 | 0x00000004;
+        // Required to open a directory.
+        const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+        // The most ReadDirectoryChangesW returns for a directory on a network share.
+        const BUFFER_SIZE: usize = 64 * 1024;
+
+        // Share everything, so that watching the directory does not stop others from changing it.
+        let dir = OpenOptions::new()
+            .access_mode(FILE_LIST_DIRECTORY)
+            .share_mode(FILE_SHARE_ALL)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED)
+            .open(path)?;
+        Ok(DirectoryWatcher {
+            op: None,
+            buf: vec![0; BUFFER_SIZE / mem::size_of::<u32>()],
+            changes: VecDeque::new(),
+            done: false,
+            recursive,
+            dir: AsyncOverlappedHandle::new(dir)?,
+        })
+    }
+
+    fn start_read(&mut self) -> IocpFuture {
+        let filter = FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_FILE_NAME.0
+            | FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_DIR_NAME.0
+            | FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_ATTRIBUTES.0
+            | FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_SIZE.0
+            | FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_LAST_WRITE.0
+            | FILE_NOTIFY_CHANGE::FILE_NOTIFY_CHANGE_CREATION.0;
+        let handle = self.dir.handle();
+        let buf = self.buf.as_mut_ptr() as *mut c_void;
+        let len: u32 = (self.buf.len() * mem::size_of::<u32>()).try_into().unwrap();
+        let recursive = self.recursive;
+        self.dir.submit(|overlapped| unsafe {
+            let mut returned: u32 = 0;
+            if ReadDirectoryChangesW(
+                handle,
+                buf,
+                len,
+                recursive,
+                FILE_NOTIFY_CHANGE(filter),
+                &mut returned,
+                overlapped,
+                None,
+            )
+            .as_bool()
+            {
+                Some(returned as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    // Queues the changes in the first `len` bytes of the buffer, a chain of
+    // FILE_NOTIFY_INFORMATION records.
+    fn parse_changes(&mut self, len: usize) {
+        const FILE_ACTION_ADDED: u32 = 1;
+        const FILE_ACTION_REMOVED: u32 = 2;
+        const FILE_ACTION_MODIFIED: u32 = 3;
+        const FILE_ACTION_RENAMED_OLD_NAME: u32 = 4;
+        const FILE_ACTION_RENAMED_NEW_NAME: u32 = 5;
+        // NextEntryOffset, Action and FileNameLength come before FileName.
+        const HEADER_WORDS: usize = 3;
+
+        let words = &self.buf[..len / mem::size_of::<u32>()];
+        let mut ndx = 0;
+        while ndx + HEADER_WORDS <= words.len() {
+            let next = words[ndx] as usize / mem::size_of::<u32>();
+            let action = words[ndx + 1];
+            let name_len = words[ndx + 2] as usize / mem::size_of::<u16>();
+            let name = unsafe {
+                std::slice::from_raw_parts(
+                    words[ndx + HEADER_WORDS..].as_ptr() as *const u16,
+                    name_len.min((words.len() - ndx - HEADER_WORDS) * 2),
+                )
+            };
+            let path = PathBuf::from(OsString::from_wide(name));
+            let change = match action {
+                FILE_ACTION_ADDED => Some(DirectoryChange::Added(path)),
+                FILE_ACTION_REMOVED => Some(DirectoryChange::Removed(path)),
+                FILE_ACTION_MODIFIED => Some(DirectoryChange::Modified(path)),
+                FILE_ACTION_RENAMED_OLD_NAME => Some(DirectoryChange::RenamedFrom(path)),
+                FILE_ACTION_RENAMED_NEW_NAME => Some(DirectoryChange::RenamedTo(path)),
+                _ => None,
+            };
+            self.changes.extend(change);
+            if next == 0 {
+                break;
+            }
+            ndx += next;
+        }
+    }
+}
+
+impl Stream for DirectoryWatcher {
+    type Item = io::Result<DirectoryChange>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Windows reports a buffer overflow this way rather than as an error.
+        const ERROR_NOTIFY_ENUM_DIR: i32 = 1022;
+
+        let this = self.get_mut();
+        loop {
+            if let Some(change) = this.changes.pop_front() {
+                return Poll::Ready(Some(Ok(change)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match &mut this.op {
+                Some(op) => {
+                    let result = ready!(Pin::new(op).poll(cx));
+                    this.op = None;
+                    match result.get_number_of_bytes_transferred() {
+                        // Also an overflow: the changes did not fit in the buffer.
+                        Ok(0) => this.changes.push_back(DirectoryChange::Rescan),
+                        Ok(len) => this.parse_changes(len),
+                        Err(e) if e.raw_os_error() == Some(ERROR_NOTIFY_ENUM_DIR) => {
+                            this.changes.push_back(DirectoryChange::Rescan)
+                        }
+                        Err(e) => {
+                            this.done = true;
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                }
+                None => this.op = Some(this.start_read()),
+            }
+        }
+    }
+}
+
+impl AsRawHandle for DirectoryWatcher {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.dir.as_raw_handle()
+    }
+}
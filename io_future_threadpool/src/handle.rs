@@ -0,0 +1,391 @@
+use bindings::{
+    Windows::Win32::FileSystem::{
+        ReadFile, SetFileInformationByHandle, WriteFile, FILE_INFO_BY_HANDLE_CLASS,
+    },
+    Windows::Win32::SystemServices::{DeviceIoControl, HANDLE, OVERLAPPED},
+};
+
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+
+use crate::buf::{IoBuf, IoBufMut, OwnedBufFuture};
+use crate::iocp_threadpool::{
+    start_async_io, start_async_io_owned, start_op, IocpFuture, IocpResult, OpFuture, OverlappedOp,
+    Tpio,
+};
+
+const ERROR_HANDLE_EOF: i32 = 38;
+const ERROR_BROKEN_PIPE: i32 = 109;
+
+/// The priority the I/O manager gives the operations issued through a handle, relative to other
+/// I/O to the same disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// For background work such as indexing or defragmenting.
+    VeryLow,
+    /// For background work that should still make progress, such as scanning or compaction.
+    Low,
+    /// The default.
+    Normal,
+}
+
+// FILE_IO_PRIORITY_HINT_INFO, which the bindings do not include.
+#[repr(C)]
+struct FileIoPriorityHintInfo {
+    priority_hint: i32,
+}
+
+/// A file, pipe or device handle opened with `FILE_FLAG_OVERLAPPED`, whose I/O completes on the
+/// threadpool of the current [crate::runtime::Runtime].
+///
+/// [AsyncOverlappedHandle::read] and [AsyncOverlappedHandle::write] cover the common case. Other
+/// overlapped operations are started with [AsyncOverlappedHandle::submit].
+///
+/// Seekable and non-seekable handles behave differently. Pipes, mailslots and most devices ignore
+/// the offset an operation is given and transfer the next data in the stream. Files and disks have
+/// no cursor for overlapped I/O, so every operation on them happens at the offset it is given:
+/// the `_at` methods take one, and the others use offset 0. [crate::fs::AsyncFile] keeps a cursor
+/// for files.
+pub struct AsyncOverlappedHandle<T: AsRawHandle> {
+    tp_io: Tpio<T>,
+}
+
+impl<T: AsRawHandle> AsyncOverlappedHandle<T> {
+    /// Associates `inner` with the runtime's completion port. Operations on it that complete
+    /// synchronously do not queue a completion if the handle supports that.
+    ///
+    /// The handle must have been opened with `FILE_FLAG_OVERLAPPED`, and can't be associated with
+    /// another completion port afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn new(inner: T) -> io::Result<AsyncOverlappedHandle<T>> {
+        Ok(AsyncOverlappedHandle {
+            tp_io: Tpio::new_for_handle(inner)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.tp_io.get_ref()
+    }
+
+    pub(crate) fn handle(&self) -> HANDLE {
+        HANDLE(self.get_ref().as_raw_handle() as isize)
+    }
+
+    /// Starts an overlapped operation on the handle, like [start_async_io]. `op` is given the
+    /// `OVERLAPPED` to pass to the operation, and returns the number of bytes transferred if it
+    /// completed synchronously, or `None` with the thread's last error set otherwise.
+    ///
+    /// Dropping the returned future cancels the operation and waits for it to finish, so buffers
+    /// borrowed by the operation have to outlive the future.
+    pub fn submit<F>(&self, op: F) -> IocpFuture
+    where
+        F: FnOnce(*mut OVERLAPPED) -> Option<usize>,
+    {
+        start_async_io(&self.tp_io, op)
+    }
+
+    /// Like [AsyncOverlappedHandle::submit], but the operation owns `buf`, which is handed back
+    /// with the result; see [start_async_io_owned].
+    pub fn submit_owned<B, F>(&self, buf: B, op: F) -> OwnedBufFuture<B>
+    where
+        B: IoBuf,
+        F: FnOnce(&mut B, *mut OVERLAPPED) -> Option<usize>,
+    {
+        start_async_io_owned(&self.tp_io, buf, op)
+    }
+
+    /// Starts `op` on this handle, like [start_op].
+    pub fn submit_op<O: OverlappedOp>(&self, op: O) -> OpFuture<O> {
+        start_op(&self.tp_io, op)
+    }
+
+    /// Reads into `buf`, and returns the number of bytes read, which is 0 at the end of a file or
+    /// once the other end of a pipe has been closed. A seekable handle is read at offset 0; use
+    /// [AsyncOverlappedHandle::read_at] for those.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_at(buf, 0).await
+    }
+
+    /// Like [AsyncOverlappedHandle::read], but at `offset` from the start of a seekable handle.
+    /// Non-seekable handles ignore the offset.
+    pub async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        eof_as_zero(
+            self.read_result(buf, offset)
+                .await
+                .get_number_of_bytes_transferred(),
+        )
+    }
+
+    pub(crate) async fn read_result(&self, buf: &mut [u8], offset: u64) -> IocpResult {
+        let handle = self.handle();
+        self.submit(|overlapped| unsafe {
+            set_offset(overlapped, offset);
+            let mut read: u32 = 0;
+            if ReadFile(
+                handle,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len().try_into().unwrap(),
+                &mut read,
+                overlapped,
+            )
+            .as_bool()
+            {
+                Some(read as usize)
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Like [AsyncOverlappedHandle::read], but reads into `buf`, which is handed back with the
+    /// result, so the future does not borrow anything; see [crate::buf]. Data is written from the
+    /// start of the buffer, up to its total capacity. A seekable handle is read at offset 0.
+    pub async fn read_owned<B: IoBufMut>(&self, buf: B) -> (io::Result<usize>, B) {
+        self.submit_op(ReadFileOp::new(buf, 0)).await
+    }
+
+    /// Like [AsyncOverlappedHandle::write], but writes the initialized part of `buf`, which is
+    /// handed back with the result. A seekable handle is written at offset 0.
+    pub async fn write_owned<B: IoBuf>(&self, buf: B) -> (io::Result<usize>, B) {
+        self.submit_op(WriteFileOp::new(buf, 0)).await
+    }
+
+    /// Writes some of `buf`, and returns the number of bytes written. A seekable handle is written
+    /// at offset 0; use [AsyncOverlappedHandle::write_at] for those.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, 0).await
+    }
+
+    /// Like [AsyncOverlappedHandle::write], but at `offset` from the start of a seekable handle.
+    /// Non-seekable handles ignore the offset.
+    pub async fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let handle = self.handle();
+        let ret = self
+            .submit(|overlapped| unsafe {
+                set_offset(overlapped, offset);
+                let mut written: u32 = 0;
+                if WriteFile(
+                    handle,
+                    buf.as_ptr() as *const c_void,
+                    buf.len().try_into().unwrap(),
+                    &mut written,
+                    overlapped,
+                )
+                .as_bool()
+                {
+                    Some(written as usize)
+                } else {
+                    None
+                }
+            })
+            .await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    /// Writes all of `buf`, issuing further writes if one is only partially completed. A seekable
+    /// handle is written from offset 0, like [AsyncOverlappedHandle::write_all_at] with 0.
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.write_all_at(buf, 0).await
+    }
+
+    /// Writes all of `buf` at `offset` from the start of a seekable handle, issuing further writes
+    /// after the bytes already written if one is only partially completed. Non-seekable handles
+    /// ignore the offset.
+    pub async fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut ndx = 0;
+        while ndx < buf.len() {
+            let sent = self.write_at(&buf[ndx..], offset + ndx as u64).await?;
+            if sent == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write the whole buffer",
+                ));
+            }
+            ndx += sent;
+        }
+        Ok(())
+    }
+
+    /// Sets the priority of the I/O issued through this handle from now on, with
+    /// `SetFileInformationByHandle(FileIoPriorityHintInfo)`. Other handles to the same file keep
+    /// their own priority.
+    pub fn set_io_priority(&self, priority: IoPriority) -> io::Result<()> {
+        // The values of PRIORITY_HINT.
+        let mut info = FileIoPriorityHintInfo {
+            priority_hint: match priority {
+                IoPriority::VeryLow => 0,
+                IoPriority::Low => 1,
+                IoPriority::Normal => 2,
+            },
+        };
+        let ok = unsafe {
+            SetFileInformationByHandle(
+                self.handle(),
+                FILE_INFO_BY_HANDLE_CLASS::FileIoPriorityHintInfo,
+                &mut info as *mut FileIoPriorityHintInfo as *mut c_void,
+                mem::size_of::<FileIoPriorityHintInfo>() as u32,
+            )
+        };
+        if ok.as_bool() {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Sends the control code `code` to the device's driver with `input`, and returns the number
+    /// of bytes the driver wrote to `output`.
+    ///
+    /// The buffers are borrowed until the returned future completes or is dropped. Dropping it
+    /// cancels the request and waits for the driver to finish with them.
+    pub async fn device_io_control(
+        &self,
+        code: u32,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> io::Result<usize> {
+        let handle = self.handle();
+        let ret = self
+            .submit(|overlapped| unsafe {
+                let mut returned: u32 = 0;
+                // The input buffer is declared mutable, but it is only read from.
+                if DeviceIoControl(
+                    handle,
+                    code,
+                    input.as_ptr() as *mut c_void,
+                    input.len().try_into().unwrap(),
+                    output.as_mut_ptr() as *mut c_void,
+                    output.len().try_into().unwrap(),
+                    &mut returned,
+                    overlapped,
+                )
+                .as_bool()
+                {
+                    Some(returned as usize)
+                } else {
+                    None
+                }
+            })
+            .await;
+        ret.get_number_of_bytes_transferred()
+    }
+}
+
+// Reads past the end of a file or from a pipe whose other end is closed fail, rather than
+// returning 0 like they do synchronously.
+fn eof_as_zero(ret: io::Result<usize>) -> io::Result<usize> {
+    match ret {
+        Err(e)
+            if e.raw_os_error() == Some(ERROR_HANDLE_EOF)
+                || e.raw_os_error() == Some(ERROR_BROKEN_PIPE) =>
+        {
+            Ok(0)
+        }
+        ret => ret,
+    }
+}
+
+// The position in the file an operation starts at.
+unsafe fn set_offset(overlapped: *mut OVERLAPPED, offset: u64) {
+    (*overlapped).Anonymous.Anonymous.Offset = offset as u32;
+    (*overlapped).Anonymous.Anonymous.OffsetHigh = (offset >> 32) as u32;
+}
+
+/// A `ReadFile` into an owned buffer, at an offset for files. Resolves to the number of bytes read,
+/// which is 0 at the end of a file or once the other end of a pipe has been closed, and the
+/// buffer.
+pub struct ReadFileOp<B> {
+    buf: B,
+    offset: u64,
+}
+
+impl<B: IoBufMut> ReadFileOp<B> {
+    /// A read into `buf`, filling it up to its total capacity. Handles other than files ignore
+    /// the offset.
+    pub fn new(buf: B, offset: u64) -> ReadFileOp<B> {
+        ReadFileOp { buf, offset }
+    }
+}
+
+unsafe impl<B: IoBufMut> OverlappedOp for ReadFileOp<B> {
+    type Output = (io::Result<usize>, B);
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        set_offset(overlapped, self.offset);
+        let mut read: u32 = 0;
+        if ReadFile(
+            HANDLE(handle as isize),
+            self.buf.stable_mut_ptr() as *mut c_void,
+            self.buf.bytes_total().try_into().unwrap(),
+            &mut read,
+            overlapped,
+        )
+        .as_bool()
+        {
+            Some(read as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(mut self, result: IocpResult) -> Self::Output {
+        let ret = eof_as_zero(result.get_number_of_bytes_transferred());
+        if let Ok(n) = ret {
+            unsafe { self.buf.set_init(n) };
+        }
+        (ret, self.buf)
+    }
+}
+
+/// A `WriteFile` of the initialized part of an owned buffer, at an offset for files. Resolves to
+/// the number of bytes written and the buffer.
+pub struct WriteFileOp<B> {
+    buf: B,
+    offset: u64,
+}
+
+impl<B: IoBuf> WriteFileOp<B> {
+    /// A write of `buf`. Handles other than files ignore the offset.
+    pub fn new(buf: B, offset: u64) -> WriteFileOp<B> {
+        WriteFileOp { buf, offset }
+    }
+}
+
+unsafe impl<B: IoBuf> OverlappedOp for WriteFileOp<B> {
+    type Output = (io::Result<usize>, B);
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        set_offset(overlapped, self.offset);
+        let mut written: u32 = 0;
+        if WriteFile(
+            HANDLE(handle as isize),
+            self.buf.stable_ptr() as *const c_void,
+            self.buf.bytes_init().try_into().unwrap(),
+            &mut written,
+            overlapped,
+        )
+        .as_bool()
+        {
+            Some(written as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(self, result: IocpResult) -> Self::Output {
+        (result.get_number_of_bytes_transferred(), self.buf)
+    }
+}
+
+impl<T: AsRawHandle> AsRawHandle for AsyncOverlappedHandle<T> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.get_ref().as_raw_handle()
+    }
+}
@@ -0,0 +1,111 @@
+//! Lets hyper's HTTP client run on this crate's runtime.
+//!
+//! [TcpConnector] opens the client's connections as [AsyncTcpStream]s, and [Handle] implements
+//! hyper's executor trait so the client can spawn its connection tasks on the runtime:
+//!
+//! `hyper::Client::builder().executor(Handle::current()).build(TcpConnector::new())`
+//!
+//! Requests must be made from within the runtime, since connecting needs [Handle::current].
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::runtime::Handle;
+use crate::stream::{AsyncTcpStream, ConnectOptions};
+
+/// Connects to `http` URIs with [AsyncTcpStream::connect_with].
+///
+/// Other schemes are rejected rather than sent in plain text; a TLS connector should wrap this one
+/// and only call it for the underlying TCP connection.
+#[derive(Clone, Debug, Default)]
+pub struct TcpConnector {
+    options: ConnectOptions,
+    enforce_http: bool,
+}
+
+impl TcpConnector {
+    pub fn new() -> TcpConnector {
+        TcpConnector {
+            options: ConnectOptions::new(),
+            enforce_http: true,
+        }
+    }
+
+    /// Connects with `options`, for a timeout, source address or interface.
+    pub fn with_options(options: ConnectOptions) -> TcpConnector {
+        TcpConnector {
+            options,
+            enforce_http: true,
+        }
+    }
+
+    /// Whether URIs with a scheme other than `http` are refused. Defaults to true. Turn it off
+    /// when wrapping this connector in one that adds TLS.
+    pub fn enforce_http(mut self, enforce: bool) -> TcpConnector {
+        self.enforce_http = enforce;
+        self
+    }
+}
+
+impl Service<Uri> for TcpConnector {
+    type Response = AsyncTcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<AsyncTcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let options = self.options.clone();
+        let enforce_http = self.enforce_http;
+        Box::pin(async move {
+            let (host, port) = host_and_port(&uri, enforce_http)?;
+            AsyncTcpStream::connect_with((host.as_str(), port), &options).await
+        })
+    }
+}
+
+fn host_and_port(uri: &Uri, enforce_http: bool) -> io::Result<(String, u16)> {
+    let scheme = uri.scheme_str();
+    if enforce_http && scheme != Some("http") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "URI scheme is not http",
+        ));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?;
+    // IPv6 literals keep their brackets in the URI, but not as a socket address.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = match (uri.port_u16(), scheme) {
+        (Some(port), _) => port,
+        (None, Some("https")) => 443,
+        (None, _) => 80,
+    };
+    Ok((host.to_string(), port))
+}
+
+impl Connection for AsyncTcpStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl<F> hyper::rt::Executor<F> for Handle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        // Dropping the JoinHandle leaves the task running.
+        drop(self.spawn(future));
+    }
+}
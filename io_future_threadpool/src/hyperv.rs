@@ -0,0 +1,197 @@
+//! Hyper-V sockets (`AF_HYPERV`), for communication between a virtual machine and its host without
+//! a network between them. An address is the GUID of a VM, or one of the wildcard GUIDs below,
+//! and the GUID of a service on it. A service has to be registered under
+//! `HKLM\SOFTWARE\Microsoft\Windows NT\CurrentVersion\Virtualization\GuestCommunicationServices`
+//! on the host before guests can connect to it.
+
+use bindings::Windows::Win32::WinSock::{getpeername, getsockname, SOCKADDR};
+
+use windows::Guid;
+
+use std::io;
+use std::mem;
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, OwnedSocket, RawSocket};
+
+use crate::socket_stream::{self, SocketListener, SocketStream};
+
+const AF_HYPERV: u16 = 34;
+const HV_PROTOCOL_RAW: i32 = 1;
+
+/// Listens on, or connects to, every partition. `HV_GUID_WILDCARD`.
+pub const HV_GUID_WILDCARD: Guid = Guid::from_values(0, 0, 0, [0; 8]);
+/// Listens for connections from the partitions that are children of this one, such as the VMs on
+/// a host. `HV_GUID_CHILDREN`.
+pub const HV_GUID_CHILDREN: Guid = Guid::from_values(
+    0x90db8b89,
+    0x0d35,
+    0x4f79,
+    [0x8c, 0xe9, 0x49, 0xea, 0x0a, 0xc8, 0xb7, 0xcd],
+);
+/// Connects to, or listens for connections from, the same partition. `HV_GUID_LOOPBACK`.
+pub const HV_GUID_LOOPBACK: Guid = Guid::from_values(
+    0xe0e16197,
+    0xdd56,
+    0x4a10,
+    [0x91, 0x95, 0x5e, 0xe7, 0xa1, 0x55, 0xa8, 0x38],
+);
+/// Connects to the parent partition, which for a VM is its host. `HV_GUID_PARENT`.
+pub const HV_GUID_PARENT: Guid = Guid::from_values(
+    0xa42e7cda,
+    0xd03f,
+    0x480c,
+    [0x9c, 0xc2, 0xa4, 0xde, 0x20, 0xab, 0xb8, 0x78],
+);
+/// Connects to the host of a silo container. `HV_GUID_SILOHOST`.
+pub const HV_GUID_SILOHOST: Guid = Guid::from_values(
+    0x36bd0c5c,
+    0x7276,
+    0x4223,
+    [0x88, 0xba, 0x7d, 0x03, 0xb6, 0x54, 0xc5, 0x68],
+);
+
+/// The address of a Hyper-V socket: a partition and a service on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HvSocketAddr {
+    /// The ID of the VM, or one of the `HV_GUID_*` constants.
+    pub vm_id: Guid,
+    /// The ID of the service, which is registered with the host.
+    pub service_id: Guid,
+}
+
+impl HvSocketAddr {
+    pub fn new(vm_id: Guid, service_id: Guid) -> HvSocketAddr {
+        HvSocketAddr { vm_id, service_id }
+    }
+
+    fn to_raw(self) -> SockaddrHv {
+        SockaddrHv {
+            family: AF_HYPERV,
+            reserved: 0,
+            vm_id: self.vm_id,
+            service_id: self.service_id,
+        }
+    }
+}
+
+/// `SOCKADDR_HV` from hvsocket.h.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrHv {
+    family: u16,
+    reserved: u16,
+    vm_id: Guid,
+    service_id: Guid,
+}
+
+impl SockaddrHv {
+    fn addr(&self) -> HvSocketAddr {
+        HvSocketAddr::new(self.vm_id, self.service_id)
+    }
+}
+
+const SOCKADDR_HV_LEN: i32 = mem::size_of::<SockaddrHv>() as i32;
+
+// getsockname or getpeername.
+fn socket_name(
+    socket: &OwnedSocket,
+    name_fn: unsafe fn(usize, *mut SOCKADDR, *mut i32) -> i32,
+) -> io::Result<HvSocketAddr> {
+    let empty = HvSocketAddr::new(HV_GUID_WILDCARD, HV_GUID_WILDCARD).to_raw();
+    let (addr, _) = socket_stream::socket_name(socket, empty, name_fn)?;
+    Ok(addr.addr())
+}
+
+/// A Hyper-V socket listening for connections, which it accepts with an overlapped `AcceptEx`
+/// completed on the runtime's threadpool.
+pub struct AsyncHvListener {
+    inner: SocketListener,
+}
+
+impl AsyncHvListener {
+    /// Creates a socket bound to `addr` and starts listening on it. A host service usually binds
+    /// to [HV_GUID_WILDCARD] or [HV_GUID_CHILDREN] and its service ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind(addr: HvSocketAddr) -> io::Result<AsyncHvListener> {
+        let socket = socket_stream::new_socket(AF_HYPERV, HV_PROTOCOL_RAW)?;
+        socket_stream::bind(&socket, &addr.to_raw(), SOCKADDR_HV_LEN)?;
+        Ok(AsyncHvListener {
+            inner: SocketListener::listen(socket, AF_HYPERV)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<HvSocketAddr> {
+        socket_name(self.inner.socket(), getsockname)
+    }
+
+    /// Accepts a connection, and returns it along with the address of the partition that made it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn accept(&self) -> io::Result<(AsyncHvStream, HvSocketAddr)> {
+        let socket = socket_stream::new_socket(AF_HYPERV, HV_PROTOCOL_RAW)?;
+        let inner = self
+            .inner
+            .accept(socket, mem::size_of::<SockaddrHv>())
+            .await?;
+        let stream = AsyncHvStream { inner };
+        let peer_addr = stream.peer_addr()?;
+        Ok((stream, peer_addr))
+    }
+}
+
+impl AsRawSocket for AsyncHvListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.socket().as_raw_socket()
+    }
+}
+
+impl AsSocket for AsyncHvListener {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.socket().as_socket()
+    }
+}
+
+/// A Hyper-V socket connection whose I/O completes on the runtime's threadpool.
+///
+/// Like [crate::stream::AsyncTcpStream], reads and writes only need `&self`, and the
+/// [AsyncRead](futures::io::AsyncRead) and [AsyncWrite](futures::io::AsyncWrite) impls are also
+/// provided for `&AsyncHvStream`, with at most one task reading and one task writing through them
+/// at a time.
+pub struct AsyncHvStream {
+    inner: SocketStream,
+}
+
+impl AsyncHvStream {
+    /// Connects to the service at `addr` with an overlapped `ConnectEx`. A guest agent connects
+    /// to [HV_GUID_PARENT] and the service ID its host registered.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect(addr: HvSocketAddr) -> io::Result<AsyncHvStream> {
+        let socket = socket_stream::new_socket(AF_HYPERV, HV_PROTOCOL_RAW)?;
+        // ConnectEx only works on a bound socket.
+        let local = HvSocketAddr::new(HV_GUID_WILDCARD, HV_GUID_WILDCARD).to_raw();
+        socket_stream::bind(&socket, &local, SOCKADDR_HV_LEN)?;
+        let remote = addr.to_raw();
+        let inner = SocketStream::new(socket)?
+            .connect_ex(AF_HYPERV, &remote, SOCKADDR_HV_LEN)
+            .await?;
+        Ok(AsyncHvStream { inner })
+    }
+
+    /// The address of the other end of the connection.
+    pub fn peer_addr(&self) -> io::Result<HvSocketAddr> {
+        socket_name(self.inner.socket(), getpeername)
+    }
+
+    pub fn local_addr(&self) -> io::Result<HvSocketAddr> {
+        socket_name(self.inner.socket(), getsockname)
+    }
+}
+
+socket_stream::socket_stream_wrapper!(AsyncHvStream);
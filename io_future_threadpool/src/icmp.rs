@@ -0,0 +1,399 @@
+//! ICMP echo (ping) through the ICMP helper API, which does not need the administrator rights a
+//! raw socket does. Each request signals an event when its reply arrives or it times out, and a
+//! threadpool wait on that event completes the future.
+
+use bindings::Windows::Win32::{
+    IpHelper::{
+        Icmp6CreateFile, Icmp6ParseReplies, Icmp6SendEcho2, IcmpCloseHandle, IcmpCreateFile,
+        IcmpParseReplies, IcmpSendEcho2,
+    },
+    SystemServices::{
+        CloseThreadpoolWait, CreateEventW, CreateThreadpoolWait, SetThreadpoolWait,
+        WaitForThreadpoolWaitCallbacks, BOOL, HANDLE, INVALID_HANDLE_VALUE, PWSTR,
+        TP_CALLBACK_INSTANCE, TP_WAIT,
+    },
+    WinSock::SOCKADDR_IN6,
+    WindowsProgramming::CloseHandle,
+};
+
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::runtime::Handle;
+
+const AF_INET6: u16 = 23;
+const ERROR_IO_PENDING: i32 = 997;
+// The data sent by `ping`, the same amount ping.exe sends.
+const DEFAULT_DATA: [u8; 32] = *b"abcdefghijklmnopqrstuvwabcdefghi";
+
+// ICMP_ECHO_REPLY, which the bindings do not include.
+#[repr(C)]
+struct IcmpEchoReply {
+    address: u32,
+    status: u32,
+    round_trip_time: u32,
+    data_size: u16,
+    reserved: u16,
+    data: *mut c_void,
+    ttl: u8,
+    tos: u8,
+    flags: u8,
+    options_size: u8,
+    options_data: *mut u8,
+}
+
+// IPV6_ADDRESS_EX, which is declared with 1-byte packing.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Ipv6AddressEx {
+    port: u16,
+    flow_info: u32,
+    address: [u16; 8],
+    scope_id: u32,
+}
+
+// ICMPV6_ECHO_REPLY. Only the address is packed, so the status is aligned to 28 bytes in, after
+// 2 bytes of padding.
+#[repr(C)]
+struct Icmpv6EchoReply {
+    address: Ipv6AddressEx,
+    status: u32,
+    round_trip_time: u32,
+}
+
+const _: () = assert!(mem::size_of::<Ipv6AddressEx>() == 26);
+const _: () = assert!(mem::size_of::<Icmpv6EchoReply>() == 36);
+
+/// A reply to an echo request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchoReply {
+    /// The host that replied, which is normally the one the request was sent to.
+    pub address: IpAddr,
+    /// The round trip time, which has a resolution of a millisecond.
+    pub round_trip_time: Duration,
+    /// The time to live of the reply. Not reported for IPv6.
+    pub ttl: Option<u8>,
+}
+
+// The ICMP handles, which stay open until the socket and every pending request have let go of
+// them.
+struct IcmpHandles {
+    v4: HANDLE,
+    v6: HANDLE,
+}
+
+impl Drop for IcmpHandles {
+    fn drop(&mut self) {
+        unsafe {
+            IcmpCloseHandle(self.v4);
+            IcmpCloseHandle(self.v6);
+        }
+    }
+}
+
+/// Sends ICMP echo requests to IPv4 and IPv6 hosts.
+pub struct AsyncIcmpSocket {
+    handles: Arc<IcmpHandles>,
+}
+
+impl AsyncIcmpSocket {
+    pub fn new() -> io::Result<AsyncIcmpSocket> {
+        let v4 = unsafe { IcmpCreateFile() };
+        if v4.0 == INVALID_HANDLE_VALUE.0 {
+            return Err(io::Error::last_os_error());
+        }
+        let v6 = unsafe { Icmp6CreateFile() };
+        if v6.0 == INVALID_HANDLE_VALUE.0 {
+            let e = io::Error::last_os_error();
+            unsafe { IcmpCloseHandle(HANDLE(v4.0)) };
+            return Err(e);
+        }
+        Ok(AsyncIcmpSocket {
+            handles: Arc::new(IcmpHandles {
+                v4: HANDLE(v4.0),
+                v6: HANDLE(v6.0),
+            }),
+        })
+    }
+
+    /// Sends an echo request with 32 bytes of data to `addr`, and waits up to `timeout` for the
+    /// reply. Fails with `ErrorKind::TimedOut` if none arrives in time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn ping(&self, addr: IpAddr, timeout: Duration) -> io::Result<EchoReply> {
+        self.ping_with_data(addr, &DEFAULT_DATA, timeout).await
+    }
+
+    /// Like [AsyncIcmpSocket::ping], but sends `data` in the request.
+    ///
+    /// The request is not cancelled if the returned future is dropped. It keeps the socket's
+    /// handles and its buffers until the reply arrives or `timeout` passes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn ping_with_data(
+        &self,
+        addr: IpAddr,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<EchoReply> {
+        let shared = Arc::new(Mutex::new(EchoState {
+            result: None,
+            waker: None,
+        }));
+        PendingEcho::start(
+            &Handle::current_for_io()?,
+            self.handles.clone(),
+            addr,
+            data,
+            timeout,
+            shared.clone(),
+        )?;
+        EchoFuture { shared }.await
+    }
+}
+
+struct EchoState {
+    result: Option<io::Result<EchoReply>>,
+    waker: Option<Waker>,
+}
+
+// Everything a request needs until it completes. Owned by the wait callback once the request has
+// been sent, since the reply buffer is written to after the future may have been dropped.
+struct PendingEcho {
+    // Keeps the thread pool alive until the callback has run.
+    _runtime: Handle,
+    handles: Arc<IcmpHandles>,
+    v6: bool,
+    event: HANDLE,
+    wait: *mut TP_WAIT,
+    request: Vec<u8>,
+    // u64s so the reply structs at the start of it are aligned.
+    reply: Vec<u64>,
+    source: SOCKADDR_IN6,
+    destination: SOCKADDR_IN6,
+    shared: Arc<Mutex<EchoState>>,
+}
+
+extern "system" fn echo_callback(
+    _instance: *mut TP_CALLBACK_INSTANCE,
+    context: *mut c_void,
+    _wait: *mut TP_WAIT,
+    _wait_result: u32,
+) {
+    // The wait is set once, so this is the only time the callback runs, and nothing else refers
+    // to the request any more.
+    let mut pending = unsafe { Box::from_raw(context as *mut PendingEcho) };
+    let result = pending.parse_reply();
+    let waker = {
+        let mut state = pending.shared.lock().unwrap();
+        state.result = Some(result);
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+    // Closing a wait from its own callback is allowed. It is freed once the callback returns.
+    unsafe { CloseThreadpoolWait(pending.wait) };
+}
+
+impl PendingEcho {
+    fn start(
+        runtime: &Handle,
+        handles: Arc<IcmpHandles>,
+        addr: IpAddr,
+        data: &[u8],
+        timeout: Duration,
+        shared: Arc<Mutex<EchoState>>,
+    ) -> io::Result<()> {
+        let request_size: u16 = data.len().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "echo request data too long")
+        })?;
+        // Room for the reply struct, the echoed data and an ICMP error message, as documented.
+        let reply_bytes = mem::size_of::<IcmpEchoReply>() + data.len() + 8 + 16;
+        let event = unsafe { CreateEventW(ptr::null_mut(), BOOL(1), BOOL(0), PWSTR::default()) };
+        if event.0 == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The bindings do not give SOCKADDR_IN6 a Default, but all zeroes is the unspecified
+        // address, which lets the stack pick the source.
+        let mut source: SOCKADDR_IN6 = unsafe { mem::zeroed() };
+        source.sin6_family = AF_INET6;
+        let mut destination = source;
+        if let IpAddr::V6(v6) = addr {
+            destination.sin6_addr.u.Byte = v6.octets();
+        }
+
+        let pending = Box::into_raw(Box::new(PendingEcho {
+            _runtime: runtime.clone(),
+            handles,
+            v6: addr.is_ipv6(),
+            event,
+            wait: ptr::null_mut(),
+            request: data.to_vec(),
+            reply: vec![0; reply_bytes.div_ceil(8)],
+            source,
+            destination,
+            shared,
+        }));
+        unsafe {
+            let wait = CreateThreadpoolWait(
+                Some(echo_callback),
+                pending as *mut c_void,
+                runtime.callback_environment(),
+            );
+            if wait.is_null() {
+                let e = io::Error::last_os_error();
+                drop(Box::from_raw(pending));
+                return Err(e);
+            }
+            (*pending).wait = wait;
+            SetThreadpoolWait(wait, event, ptr::null_mut());
+
+            let p = &mut *pending;
+            let timeout = timeout.as_millis().try_into().unwrap_or(u32::MAX);
+            let reply_size = (p.reply.len() * 8) as u32;
+            let ret = match addr {
+                IpAddr::V4(v4) => IcmpSendEcho2(
+                    p.handles.v4,
+                    event,
+                    None,
+                    ptr::null_mut(),
+                    u32::from_ne_bytes(v4.octets()),
+                    p.request.as_mut_ptr() as *mut c_void,
+                    request_size,
+                    ptr::null_mut(),
+                    p.reply.as_mut_ptr() as *mut c_void,
+                    reply_size,
+                    timeout,
+                ),
+                IpAddr::V6(_) => Icmp6SendEcho2(
+                    p.handles.v6,
+                    event,
+                    None,
+                    ptr::null_mut(),
+                    &mut p.source,
+                    &mut p.destination,
+                    p.request.as_mut_ptr() as *mut c_void,
+                    request_size,
+                    ptr::null_mut(),
+                    p.reply.as_mut_ptr() as *mut c_void,
+                    reply_size,
+                    timeout,
+                ),
+            };
+            // With an event, the request is always completed by signaling it, so a reply that
+            // is already there is picked up by the callback too.
+            if ret == 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(ERROR_IO_PENDING) {
+                    // Nothing will signal the event. Stop waiting and free the request here.
+                    SetThreadpoolWait(wait, HANDLE(0), ptr::null_mut());
+                    WaitForThreadpoolWaitCallbacks(wait, true);
+                    CloseThreadpoolWait(wait);
+                    drop(Box::from_raw(pending));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_reply(&mut self) -> io::Result<EchoReply> {
+        let buf = self.reply.as_mut_ptr() as *mut c_void;
+        let size = (self.reply.len() * 8) as u32;
+        unsafe {
+            if self.v6 {
+                let count = Icmp6ParseReplies(buf, size);
+                let reply = &*(buf as *const Icmpv6EchoReply);
+                if count == 0 || reply.status != 0 {
+                    return Err(status_error(reply.status));
+                }
+                let address = reply.address.address;
+                let mut octets = [0; 16];
+                for (i, word) in address.iter().enumerate() {
+                    // The words are in network byte order.
+                    octets[i * 2..i * 2 + 2].copy_from_slice(&word.to_ne_bytes());
+                }
+                Ok(EchoReply {
+                    address: IpAddr::V6(Ipv6Addr::from(octets)),
+                    round_trip_time: Duration::from_millis(reply.round_trip_time.into()),
+                    ttl: None,
+                })
+            } else {
+                let count = IcmpParseReplies(buf, size);
+                let reply = &*(buf as *const IcmpEchoReply);
+                if count == 0 || reply.status != 0 {
+                    return Err(status_error(reply.status));
+                }
+                Ok(EchoReply {
+                    address: IpAddr::V4(Ipv4Addr::from(reply.address.to_ne_bytes())),
+                    round_trip_time: Duration::from_millis(reply.round_trip_time.into()),
+                    ttl: Some(reply.ttl),
+                })
+            }
+        }
+    }
+}
+
+impl Drop for PendingEcho {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.event) };
+    }
+}
+
+// An IP_STATUS as an error. These are not Win32 error codes, some of them clash with WinSock's, so
+// they get their own messages.
+fn status_error(status: u32) -> io::Error {
+    const IP_DEST_NET_UNREACHABLE: u32 = 11002;
+    const IP_DEST_HOST_UNREACHABLE: u32 = 11003;
+    const IP_DEST_PROT_UNREACHABLE: u32 = 11004;
+    const IP_DEST_PORT_UNREACHABLE: u32 = 11005;
+    const IP_REQ_TIMED_OUT: u32 = 11010;
+    const IP_TTL_EXPIRED_TRANSIT: u32 = 11013;
+
+    match status {
+        IP_REQ_TIMED_OUT => io::Error::new(io::ErrorKind::TimedOut, "echo request timed out"),
+        IP_DEST_NET_UNREACHABLE => io::Error::from(io::ErrorKind::NetworkUnreachable),
+        IP_DEST_HOST_UNREACHABLE => io::Error::from(io::ErrorKind::HostUnreachable),
+        IP_DEST_PROT_UNREACHABLE | IP_DEST_PORT_UNREACHABLE => {
+            io::Error::other("destination unreachable")
+        }
+        IP_TTL_EXPIRED_TRANSIT => io::Error::other("TTL expired in transit"),
+        // Parsing the replies failed without a status, which leaves the reason in the last error.
+        0 => io::Error::last_os_error(),
+        status => io::Error::other(format!("echo request failed with IP status {}", status)),
+    }
+}
+
+// Completes with the result the wait callback stores.
+struct EchoFuture {
+    shared: Arc<Mutex<EchoState>>,
+}
+
+impl Future for EchoFuture {
+    type Output = io::Result<EchoReply>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Utilities for working with the crate's streams through the `futures::io` traits.
+
+use futures::future;
+use futures::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::ready;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Adds buffering to a reader, so protocols that look at a line or a byte at a time do not issue
+/// an overlapped receive for each one.
+///
+/// This implements [AsyncBufRead], so `read_line`, `read_until`, `lines` and `fill_buf` are
+/// available through [futures::io::AsyncBufReadExt].
+pub struct AsyncBufReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R: AsyncRead> AsyncBufReader<R> {
+    /// Wraps `inner` with an 8 KiB buffer.
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        AsyncBufReader {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+        }
+    }
+}
+
+impl<R> AsyncBufReader<R> {
+    /// The data that has been read from the inner reader but not yet consumed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.cap]
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the inner reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncBufReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Skip the copy when the buffer is empty and the caller asked for at least as much as it
+        // holds.
+        if this.pos == this.cap && buf.len() >= this.buf.len() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let available = ready!(Pin::new(&mut *this).poll_fill_buf(cx))?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        Pin::new(this).consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for AsyncBufReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.cap {
+            this.cap = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut this.buf))?;
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.cap]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.cap);
+    }
+}
+
+/// Copies everything from `reader` to `writer` until `reader` reaches end of stream, then flushes
+/// `writer`. Returns the number of bytes copied.
+///
+/// `writer` is not closed, so the caller decides whether to shut it down; see [copy_bidirectional]
+/// for a proxy that does.
+pub async fn copy<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; DEFAULT_BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.flush().await?;
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+}
+
+/// Copies data in both directions between `a` and `b` until both have reached end of stream,
+/// and returns the number of bytes copied from `a` to `b` and from `b` to `a`.
+///
+/// When one side finishes sending, the other side's write half is closed, which for an
+/// [crate::stream::AsyncTcpStream] shuts down its write half. The half-close is passed through, so
+/// the other direction keeps flowing until it finishes too.
+///
+/// The streams are used through shared references so both directions can be in flight at once,
+/// which [crate::stream::AsyncTcpStream] supports. A TCP proxy is an accept, a connect to the
+/// upstream, and `copy_bidirectional(&client, &upstream)`.
+pub async fn copy_bidirectional<'a, A, B>(a: &'a A, b: &'a B) -> io::Result<(u64, u64)>
+where
+    &'a A: AsyncRead + AsyncWrite + Unpin,
+    &'a B: AsyncRead + AsyncWrite + Unpin,
+{
+    let a_to_b = async {
+        let (mut reader, mut writer) = (a, b);
+        let copied = copy(&mut reader, &mut writer).await?;
+        writer.close().await?;
+        Ok::<_, io::Error>(copied)
+    };
+    let b_to_a = async {
+        let (mut reader, mut writer) = (b, a);
+        let copied = copy(&mut reader, &mut writer).await?;
+        writer.close().await?;
+        Ok::<_, io::Error>(copied)
+    };
+    future::try_join(a_to_b, b_to_a).await
+}
@@ -1,22 +1,36 @@
 use bindings::{
     Windows::Win32::Debug::GetLastError,
     Windows::Win32::Debug::WIN32_ERROR,
-    Windows::Win32::FileSystem::SetFileCompletionNotificationModes,
+    Windows::Win32::FileSystem::{CancelIoEx, SetFileCompletionNotificationModes},
     Windows::Win32::SystemServices::{
-        CancelThreadpoolIo, CloseThreadpoolIo, CreateThreadpoolIo, StartThreadpoolIo, OVERLAPPED,
-        TP_CALLBACK_INSTANCE, TP_IO,
+        CancelThreadpoolIo, CloseThreadpoolIo, CreateThreadpoolIo,
+        DisassociateCurrentThreadFromCallback, StartThreadpoolIo, WaitForThreadpoolIoCallbacks,
+        HANDLE, OVERLAPPED, TP_CALLBACK_INSTANCE, TP_IO,
     },
+    Windows::Win32::WinSock::{WSAGetOverlappedResult, WSAIoctl},
 };
 
+use futures::task::{waker_ref, ArcWake, AtomicWaker};
+
+use std::any::Any;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::ffi::c_void;
 use std::future::Future;
 use std::io;
 use std::marker::PhantomPinned;
-use std::os::windows::io::AsRawSocket;
-use std::panic::catch_unwind;
+use std::mem::ManuallyDrop;
+use std::os::windows::io::{AsRawHandle, AsRawSocket, RawHandle, RawSocket};
+use std::panic::{self, catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::ptr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+
+use crate::buf::{IoBuf, OwnedBufFuture};
+use crate::metrics::{IoDirection, StreamCounters};
+use crate::runtime::Handle;
 
 /// Represents the result of an IO operation. Maps to the two interesting parameters of
 /// PTP_WIN32_IO_CALLBACK and GetQueuedCompletionStatus.
@@ -24,6 +38,7 @@ use std::task::{Context, Poll, Waker};
 pub struct IocpResult {
     io_result: WIN32_ERROR,
     number_of_bytes_transferred: usize,
+    flags: Option<u32>,
 }
 
 impl IocpResult {
@@ -35,132 +50,656 @@ impl IocpResult {
             Err(io::Error::from_raw_os_error(self.io_result.0 as i32))
         }
     }
+
+    /// Like [IocpResult::get_number_of_bytes_transferred], but a receive that filled the buffer
+    /// before the end of a message (`ERROR_MORE_DATA`) counts as success. Returns whether that
+    /// happened along with the byte count.
+    pub(crate) fn get_number_of_bytes_transferred_allow_partial(
+        &self,
+    ) -> io::Result<(usize, bool)> {
+        if self.io_result == WIN32_ERROR::ERROR_MORE_DATA {
+            Ok((self.number_of_bytes_transferred, true))
+        } else {
+            self.get_number_of_bytes_transferred().map(|n| (n, false))
+        }
+    }
+
+    /// The `MSG_*` flags `WSAGetOverlappedResult` reported for the operation, such as `MSG_PARTIAL`
+    /// for a message that was truncated or `MSG_OOB` for urgent data.
+    ///
+    /// Only set for operations started with [start_async_io_with_flags] on sockets that completed,
+    /// or completed with a truncated message, since fetching them costs a syscall. Operations on
+    /// file and device handles have no flags: `GetOverlappedResult` only reports the error and byte
+    /// count, and the offset fields of their `OVERLAPPED` are an input.
+    pub fn flags(&self) -> Option<u32> {
+        self.flags
+    }
+}
+
+// Whether an operation completed with a message or datagram that did not fit the buffer, which
+// is reported as ERROR_MORE_DATA or WSAEMSGSIZE.
+fn is_truncated(io_result: WIN32_ERROR) -> bool {
+    const WSAEMSGSIZE: u32 = 10040;
+    io_result == WIN32_ERROR::ERROR_MORE_DATA || io_result.0 == WSAEMSGSIZE
+}
+
+// The flags of an operation on `socket` that completed with `io_result`, from
+// WSAGetOverlappedResult, or None if it failed. A truncated message fails the call too, but its
+// flags, which include MSG_PARTIAL, are still written.
+unsafe fn completion_flags(
+    socket: usize,
+    overlapped: *mut OVERLAPPED,
+    io_result: WIN32_ERROR,
+) -> Option<u32> {
+    let truncated = is_truncated(io_result);
+    if io_result != WIN32_ERROR::NO_ERROR && !truncated {
+        return None;
+    }
+    let mut transferred = 0;
+    let mut flags = 0;
+    // The operation is complete, so this does not wait.
+    let ok = WSAGetOverlappedResult(socket, overlapped, &mut transferred, false, &mut flags);
+    if ok.as_bool() || truncated {
+        Some(flags)
+    } else {
+        None
+    }
 }
 
+/// The result of an operation started with [start_async_io].
+///
+/// Dropping the future before the operation completes cancels the operation with `CancelIoEx` and
+/// blocks until its completion has been delivered, so buffers the operation was using can be
+/// freed or reused as soon as the drop returns. Cancelled operations complete promptly, but
+/// operations that cannot be cancelled are waited out in full. Futures that own the memory their
+/// operation uses, like [OpFuture] and [OwnedBufFuture], do not wait: they hand that memory to the
+/// completion callback, which frees it along with the operation's state.
+///
+/// If the completion callback panics, the panic is resumed by the poll that would have returned
+/// the result, so it fails the task awaiting the operation rather than the process.
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct IocpFuture {
-    state: Arc<Mutex<IocpFutureState>>,
+    // Only taken by IocpFuture::drop, to recycle it.
+    shared: ManuallyDrop<Arc<IocpFutureShared>>,
+    // The handle the operation was issued on, which together with the OVERLAPPED in `shared`
+    // identifies the operation to CancelIoEx.
+    handle: HANDLE,
+    // Whether the operation went pending, see IocpFuture::is_pending.
+    pending: bool,
+    // Where to count the bytes transferred once the operation completes, if anywhere.
+    stats: Option<(Arc<StreamCounters>, IoDirection)>,
+    // Set by IocpFuture::abandon once the completion callback has taken over the operation, so
+    // the drop does not wait for it.
+    abandoned: bool,
 }
 
-struct IocpFutureState {
-    result: Option<IocpResult>,
-    waker: Option<Waker>,
-}
+// The values of IocpFutureShared::state. The result is written by whichever of start_async_io and
+// the completion callback gets to move the state from PENDING to SETTING, and can be read once the
+// state is COMPLETE. A future that is dropped without waiting moves it from PENDING to ABANDONED
+// instead, and the completion callback then frees what the future left in keep_alive.
+const PENDING: u8 = 0;
+const SETTING: u8 = 1;
+const COMPLETE: u8 = 2;
+const ABANDONED: u8 = 3;
+
+// The value of IocpFutureShared::flags when the result has none.
+const NO_FLAGS: u64 = u64::MAX;
 
+// The state of one operation, shared by its future and the completion callback without a lock.
+// The callback holds a reference, made with Arc::into_raw, from when the operation is started until
+// its completion is delivered.
 #[repr(C)]
-struct OverlappedAndIocpStateReference {
-    overlapped: OVERLAPPED,
-    state: Arc<Mutex<IocpFutureState>>,
-    //overlapped must not move during the async IO
+struct IocpFutureShared {
+    // First, so that the pointer to the OVERLAPPED Windows hands to the callback is a pointer to
+    // the struct. It must not move during the async IO.
+    overlapped: UnsafeCell<OVERLAPPED>,
+    state: AtomicU8,
+    io_result: AtomicU32,
+    number_of_bytes_transferred: AtomicUsize,
+    // IocpResult::flags, or NO_FLAGS.
+    flags: AtomicU64,
+    // Whether the operation was started with start_async_io_with_flags, so its flags are fetched
+    // when it completes.
+    want_flags: bool,
+    // A panic caught in the completion callback, written with the result and resumed by the poll
+    // that returns it.
+    panic: UnsafeCell<Option<Box<dyn Any + Send>>>,
+    // The memory an abandoned operation uses, written by IocpFuture::abandon before the state
+    // becomes ABANDONED and dropped by the completion callback.
+    keep_alive: UnsafeCell<Option<Box<dyn Any + Send>>>,
+    waker: AtomicWaker,
+    // Whether CancelIoEx has been called for the operation, so it is only called once.
+    cancel_requested: AtomicBool,
+    // Set while the state is in use, and cleared in the pool so it does not keep a runtime alive.
+    runtime: Option<Handle>,
     _pin: PhantomPinned,
 }
 
-impl OverlappedAndIocpStateReference {
-    fn process_iocp_completion(
-        &mut self,
-        io_result: WIN32_ERROR,
-        number_of_bytes_transferred: usize,
-    ) {
-        let mut mutable_state = self.state.lock().unwrap();
-        mutable_state.result = Some(IocpResult {
-            io_result,
-            number_of_bytes_transferred,
-        });
-        //TODO: do we have to worry about calling the waker while holding the mutex?
-        if let Some(waker) = &mutable_state.waker {
-            waker.wake_by_ref();
-        };
+// The most operation states each thread keeps for reuse.
+const STATE_POOL_SIZE: usize = 64;
+
+thread_local! {
+    // States of completed operations, reused by start_async_io instead of allocating a new one.
+    // Per thread, so taking and returning a state needs no synchronization.
+    static STATE_POOL: RefCell<Vec<Arc<IocpFutureShared>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Windows writes to the OVERLAPPED while the operation is in flight, and we only pass its address
+// to CancelIoEx. The panic is only written while the state is SETTING and only taken by the future
+// once it is COMPLETE. keep_alive is only written by the future while the state is PENDING and only
+// taken by the callback once it is ABANDONED, or by the future if it failed to abandon the
+// operation. Everything else is atomic.
+unsafe impl Send for IocpFutureShared {}
+unsafe impl Sync for IocpFutureShared {}
+
+impl IocpFutureShared {
+    // A state for a new operation, from this thread's pool if it has one.
+    fn take(runtime: &Handle, want_flags: bool) -> Arc<IocpFutureShared> {
+        let pooled = STATE_POOL
+            .try_with(|pool| pool.borrow_mut().pop())
+            .ok()
+            .flatten();
+        match pooled {
+            Some(mut shared) => {
+                // Only states nothing else refers to are pooled.
+                *Arc::get_mut(&mut shared).unwrap() =
+                    IocpFutureShared::new(runtime.clone(), want_flags);
+                shared
+            }
+            None => Arc::new(IocpFutureShared::new(runtime.clone(), want_flags)),
+        }
+    }
+
+    // Returns a state to this thread's pool, if its operation has completed and the completion
+    // callback has let go of it. Otherwise it is freed by whichever reference is released last.
+    fn recycle(mut shared: Arc<IocpFutureShared>) {
+        if let Some(state) = Arc::get_mut(&mut shared) {
+            if state.result().is_none() {
+                return;
+            }
+            state.runtime = None;
+            let _ = STATE_POOL.try_with(|pool| {
+                let mut pool = pool.borrow_mut();
+                if pool.len() < STATE_POOL_SIZE {
+                    pool.push(shared);
+                }
+            });
+        }
+    }
+
+    fn new(runtime: Handle, want_flags: bool) -> IocpFutureShared {
+        IocpFutureShared {
+            overlapped: UnsafeCell::new(Default::default()),
+            state: AtomicU8::new(PENDING),
+            io_result: AtomicU32::new(0),
+            number_of_bytes_transferred: AtomicUsize::new(0),
+            flags: AtomicU64::new(NO_FLAGS),
+            want_flags,
+            panic: UnsafeCell::new(None),
+            keep_alive: UnsafeCell::new(None),
+            waker: AtomicWaker::new(),
+            cancel_requested: AtomicBool::new(false),
+            runtime: Some(runtime),
+            _pin: PhantomPinned,
+        }
+    }
+
+    fn result(&self) -> Option<IocpResult> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            Some(IocpResult {
+                io_result: WIN32_ERROR(self.io_result.load(Ordering::Relaxed)),
+                number_of_bytes_transferred: self
+                    .number_of_bytes_transferred
+                    .load(Ordering::Relaxed),
+                flags: match self.flags.load(Ordering::Relaxed) {
+                    NO_FLAGS => None,
+                    flags => Some(flags as u32),
+                },
+            })
+        } else {
+            None
+        }
+    }
+
+    // Sets the result, and the panic to resume when it is polled if the callback caught one, and
+    // returns the waker of the task waiting for it, unless the result has already been set. That
+    // happens for the duplicate completion of an operation that completed synchronously on a
+    // handle without FILE_SKIP_COMPLETION_PORT_ON_SUCCESS, where start_async_io sets the result.
+    //
+    // If the future abandoned the operation, nothing wants the result, and this frees the memory
+    // the operation was using instead.
+    fn complete(&self, result: IocpResult, panic: Option<Box<dyn Any + Send>>) -> Option<Waker> {
+        match self
+            .state
+            .compare_exchange(PENDING, SETTING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {}
+            Err(ABANDONED) => {
+                // The future is gone and wrote keep_alive before the state became ABANDONED.
+                drop(unsafe { (*self.keep_alive.get()).take() });
+                return None;
+            }
+            Err(_) => return None,
+        }
+        self.io_result.store(result.io_result.0, Ordering::Relaxed);
+        self.number_of_bytes_transferred
+            .store(result.number_of_bytes_transferred, Ordering::Relaxed);
+        self.flags
+            .store(result.flags.map_or(NO_FLAGS, u64::from), Ordering::Relaxed);
+        unsafe {
+            *self.panic.get() = panic;
+        }
+        self.state.store(COMPLETE, Ordering::Release);
+        self.waker.take()
+    }
+
+    // Cancels the operation, unless it has completed or already been cancelled. The OVERLAPPED
+    // lives as long as this struct, so its address cannot have been reused by a later operation.
+    // CancelIoEx fails with ERROR_NOT_FOUND if the operation is already completing, which is fine.
+    fn request_cancel(&self, handle: HANDLE) {
+        if self.result().is_none() && !self.cancel_requested.swap(true, Ordering::Relaxed) {
+            unsafe {
+                CancelIoEx(handle, self.overlapped.get());
+            }
+        }
+    }
+}
+
+// Wakes a thread blocked in IocpFuture::drop.
+struct ThreadWaker(Thread);
+
+impl ArcWake for ThreadWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+impl Drop for IocpFuture {
+    fn drop(&mut self) {
+        if self.abandoned {
+            // The completion callback holds the other reference, and frees the state with it.
+            unsafe { ManuallyDrop::drop(&mut self.shared) };
+            return;
+        }
+        if self.shared.result().is_none() {
+            // Nothing is awaiting the result any more, so the completion wakes this thread instead
+            // of the task that last polled the future.
+            let thread = Arc::new(ThreadWaker(thread::current()));
+            self.shared.waker.register(&waker_ref(&thread));
+            self.shared.request_cancel(self.handle);
+            while self.shared.result().is_none() {
+                thread::park();
+            }
+        }
+        // Not used again, since this is the drop.
+        let shared = unsafe { ManuallyDrop::take(&mut self.shared) };
+        IocpFutureShared::recycle(shared);
     }
 }
 
-impl IocpFutureState {
-    fn new() -> IocpFutureState {
-        IocpFutureState {
-            result: None,
-            waker: None,
+impl IocpFuture {
+    /// Hands `keep_alive`, which owns the memory the operation uses, to the completion callback if
+    /// the operation has not completed, and cancels the operation, so that dropping the future
+    /// does not wait for it. Otherwise `keep_alive` is dropped once the operation has completed.
+    pub(crate) fn abandon(&mut self, keep_alive: Box<dyn Any + Send>) {
+        if self.abandoned || self.shared.result().is_some() {
+            return;
         }
+        self.shared.request_cancel(self.handle);
+        // Only read by the callback once the state is ABANDONED.
+        unsafe {
+            *self.shared.keep_alive.get() = Some(keep_alive);
+        }
+        if self
+            .shared
+            .state
+            .compare_exchange(PENDING, ABANDONED, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.abandoned = true;
+        } else {
+            // The completion got there first. Wait for it as usual before letting go of the
+            // memory.
+            let keep_alive = unsafe { (*self.shared.keep_alive.get()).take() };
+            let thread = Arc::new(ThreadWaker(thread::current()));
+            self.shared.waker.register(&waker_ref(&thread));
+            while self.shared.result().is_none() {
+                thread::park();
+            }
+            drop(keep_alive);
+        }
+    }
+
+    /// Asks for the operation to be cancelled with `CancelIoEx`, without waiting for it. Awaiting
+    /// the future still returns the operation's result, which is `ERROR_OPERATION_ABORTED` if it
+    /// was cancelled before it completed.
+    pub(crate) fn cancel(&self) {
+        self.shared.request_cancel(self.handle);
+    }
+
+    /// Whether the operation went pending and completes through the port, as opposed to having
+    /// completed (or failed) when it was started.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Counts this operation in `counters`, and the bytes it transfers when it completes.
+    pub(crate) fn record_stats(
+        mut self,
+        counters: &Arc<StreamCounters>,
+        direction: IoDirection,
+    ) -> IocpFuture {
+        counters.op_started(direction, self.is_pending());
+        self.stats = Some((counters.clone(), direction));
+        self
     }
 }
 
 impl Future for IocpFuture {
     type Output = IocpResult;
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut shared_state = self.state.lock().unwrap();
-        if let Some(result) = &shared_state.result {
-            Poll::Ready(*result)
-        } else {
-            shared_state.waker = Some(cx.waker().clone());
-            Poll::Pending
+        let this = self.get_mut();
+        let result = match this.shared.result() {
+            Some(result) => result,
+            None => {
+                // Check again after registering, in case the completion came in between.
+                this.shared.waker.register(cx.waker());
+                match this.shared.result() {
+                    Some(result) => result,
+                    None => return Poll::Pending,
+                }
+            }
+        };
+        // Only this future takes the panic, and the callback is done with it once the result is
+        // set.
+        if let Some(payload) = unsafe { (*this.shared.panic.get()).take() } {
+            panic::resume_unwind(payload);
+        }
+        if let Some((counters, direction)) = this.stats.take() {
+            if let Ok(bytes) = result.get_number_of_bytes_transferred() {
+                counters.op_completed(direction, bytes);
+            }
         }
+        Poll::Ready(result)
     }
 }
 
+// The completion callback running on this thread.
+#[derive(Clone, Copy)]
+struct CurrentCallback {
+    context: *const CallbackContext,
+    instance: *mut TP_CALLBACK_INSTANCE,
+    // Set by ThreadpoolIo::drop when the task the callback wakes drops the Tpio, which frees the
+    // context.
+    closed: bool,
+}
+
+thread_local! {
+    // Lets ThreadpoolIo::drop tell when it runs inside the completion callback of its own TP_IO,
+    // because a task polled inline by the callback's wake dropped the Tpio.
+    static CURRENT_CALLBACK: Cell<Option<CurrentCallback>> = const { Cell::new(None) };
+}
+
 extern "system" fn io_completion_function(
-    _instance: *mut TP_CALLBACK_INSTANCE,
-    _context: *mut ::std::ffi::c_void,
+    instance: *mut TP_CALLBACK_INSTANCE,
+    context: *mut ::std::ffi::c_void,
     overlapped: *mut ::std::ffi::c_void,
     io_result: u32,
     number_of_bytes_transferred: usize,
     _io: *mut TP_IO,
 ) {
-    let unwound = catch_unwind(|| unsafe {
-        let mut overlapped = Box::from_raw(overlapped as *mut OverlappedAndIocpStateReference);
-        overlapped.process_iocp_completion(WIN32_ERROR(io_result), number_of_bytes_transferred);
+    // Only dereferenced while the Tpio cannot have been dropped, see the end of this function.
+    let context = context as *const CallbackContext;
+    let io_result = WIN32_ERROR(io_result);
+    // Takes back the reference start_async_io gave the operation.
+    let shared = unsafe { Arc::from_raw(overlapped as *const IocpFutureShared) };
+    let flags = match unsafe { (*context).socket } {
+        Some(socket) if shared.want_flags => unsafe {
+            completion_flags(socket, overlapped as _, io_result)
+        },
+        _ => None,
+    };
+    // A panic here would otherwise unwind into the threadpool and abort the process, so it is
+    // handed to the task awaiting the operation instead.
+    let panic = catch_unwind(AssertUnwindSafe(|| {
+        if let Some(runtime) = &shared.runtime {
+            runtime.metrics_counters().io_completed();
+        }
+    }))
+    .err();
+    let waker = shared.complete(
+        IocpResult {
+            io_result,
+            number_of_bytes_transferred,
+            flags,
+        },
+        panic,
+    );
+    // Released before waking the task, so the future usually holds the only reference when it is
+    // dropped and can recycle the state.
+    drop(shared);
+    let previous = CURRENT_CALLBACK.with(|current| {
+        current.replace(Some(CurrentCallback {
+            context,
+            instance,
+            closed: false,
+        }))
     });
-    if unwound.is_err() {
-        //TODO: is this the right thing to do when a panic happens?
-        std::process::abort();
+    if let Some(waker) = waker {
+        // A waker that panics is a bug in whatever executor made it, and the operation it belonged
+        // to has already completed. The panic hook has reported it, so it is only dropped here.
+        let _ = catch_unwind(AssertUnwindSafe(|| waker.wake()));
+    }
+    let current = CURRENT_CALLBACK.with(|current| current.replace(previous));
+    // ThreadpoolIo::drop waits for the callback to return before freeing the context, unless the
+    // wake dropped the Tpio on this thread, in which case it is already gone.
+    if !current.is_some_and(|current| current.closed) {
+        unsafe { (*context).outstanding.finished() };
     }
 }
 
-/// Enables receiving asynchronous I/O completion notifications.
-pub struct Tpio {
+/// Enables receiving asynchronous I/O completion notifications for the socket or handle it owns.
+///
+/// Owning the socket or handle lets the drop happen in the order `CloseThreadpoolIo` requires: the
+/// socket or handle is closed first, which cancels the operations still in flight, then those are
+/// waited for, and then the TP_IO is closed.
+pub struct Tpio<T> {
+    // Declared first so it is closed before the TP_IO.
+    inner: T,
+    io: ThreadpoolIo,
+}
+
+// The TP_IO of a Tpio, apart from the socket or handle.
+struct ThreadpoolIo {
     tp_io: *mut TP_IO,
+    // The handle registered with the threadpool. For sockets this is the base provider socket.
+    handle: HANDLE,
+    // Whether FILE_SKIP_COMPLETION_PORT_ON_SUCCESS could be set on the handle. If not, operations
+    // that complete synchronously still queue a completion, which start_async_io has to expect.
+    skip_on_success: bool,
+    runtime: Handle,
+    // The context of the completion callback. Boxed so its address is stable, and freed after the
+    // TP_IO is closed.
+    context: Box<CallbackContext>,
+}
+
+// What the completion callback needs to know about the Tpio it runs for.
+struct CallbackContext {
+    outstanding: OutstandingOps,
+    // The handle, if it is a socket, whose completions also carry the flags from
+    // WSAGetOverlappedResult.
+    socket: Option<usize>,
+}
+
+// The operations started on a Tpio whose completion callback has not finished yet.
+struct OutstandingOps {
+    count: AtomicUsize,
+    // Set by ThreadpoolIo::drop while it waits for the count to drop, so the callback only takes
+    // the lock to wake it in that case.
+    draining: AtomicBool,
+    lock: Mutex<()>,
+    idle: Condvar,
+}
+
+impl OutstandingOps {
+    fn started(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finished(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        if self.draining.load(Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+
+    // Waits until only `remaining` operations are outstanding: the callback ThreadpoolIo::drop is
+    // running in, if any.
+    fn wait_idle(&self, remaining: usize) {
+        let mut guard = self.lock.lock().unwrap();
+        self.draining.store(true, Ordering::SeqCst);
+        while self.count.load(Ordering::SeqCst) != remaining {
+            guard = self.idle.wait(guard).unwrap();
+        }
+    }
 }
 
-impl Drop for Tpio {
+impl Drop for ThreadpoolIo {
     fn drop(&mut self) {
         // MSDN says:
         //     You should close the associated file handle and wait for all outstanding overlapped
         //     I/O operations to complete before calling this function. You must not cause any more
         //     overlapped I/O operations to occur after calling this function.
-        // Maybe we can do something with lifetimes to make sure this is dropped after the socket
-        // is closed?
-        // TODO: make sure this is dropped after the socket is closed.
-        unsafe {
-            CloseThreadpoolIo(self.tp_io);
+        // Tpio closes the handle before this is dropped, which cancels what is still in flight.
+        // Then the remaining completions are waited for here, and so are callbacks that are still
+        // returning after counting themselves out.
+        let context: *const CallbackContext = &*self.context;
+        let current = CURRENT_CALLBACK
+            .try_with(Cell::get)
+            .ok()
+            .flatten()
+            .filter(|current| current.context == context);
+        match current {
+            None => {
+                self.context.outstanding.wait_idle(0);
+                unsafe { WaitForThreadpoolIoCallbacks(self.tp_io, false) };
+            }
+            Some(mut current) => {
+                // A task polled inline by one of this TP_IO's completion callbacks dropped the
+                // Tpio. Waiting for that callback would never finish, so it is left out of the
+                // count and no longer counts as a callback of the TP_IO, and it skips counting
+                // itself out once it returns, since the context is freed by then.
+                self.context.outstanding.wait_idle(1);
+                unsafe {
+                    DisassociateCurrentThreadFromCallback(current.instance);
+                    WaitForThreadpoolIoCallbacks(self.tp_io, false);
+                }
+                current.closed = true;
+                CURRENT_CALLBACK.with(|cell| cell.set(Some(current)));
+            }
         }
+        unsafe { CloseThreadpoolIo(self.tp_io) };
+    }
+}
+
+impl<T: AsRawSocket> Tpio<T> {
+    /// Creates a new [Tpio] that owns `sock`. This can be used with [start_async_io] for as long
+    /// as the socket is open. Completions run on the threadpool of the current
+    /// [crate::runtime::Runtime].
+    ///
+    /// The socket is registered by its base provider handle, see [base_socket]. Overlapped
+    /// operations must be issued on [Tpio::socket] rather than on `sock` itself.
+    ///
+    /// This also calls [disable_callbacks_on_synchronous_completion]. If that fails, as it can for
+    /// sockets from some layered service providers, [start_async_io] deduplicates the completions
+    /// of operations that complete synchronously instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn new(sock: T) -> io::Result<Tpio<T>> {
+        let io = ThreadpoolIo::create(HANDLE(base_socket(sock.as_raw_socket()) as isize), true)?;
+        Ok(Tpio { inner: sock, io })
+    }
+
+    /// The socket to issue overlapped operations on, which is the base provider socket of the
+    /// socket the [Tpio] owns.
+    pub fn socket(&self) -> RawSocket {
+        self.io.handle.0 as RawSocket
+    }
+}
+
+impl<T: AsRawHandle> Tpio<T> {
+    /// Like [Tpio::new], but for a file or device handle opened with `FILE_FLAG_OVERLAPPED`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn new_for_handle(handle: T) -> io::Result<Tpio<T>> {
+        let io = ThreadpoolIo::create(HANDLE(handle.as_raw_handle() as isize), false)?;
+        Ok(Tpio { inner: handle, io })
+    }
+}
+
+impl<T> Tpio<T> {
+    /// The socket or handle.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Closes the TP_IO once the operations still in flight have completed, and returns the socket
+    /// or handle, which stays associated with the completion port. Only for when nothing is in
+    /// flight, since nothing cancels it.
+    pub(crate) fn into_inner(self) -> T {
+        let Tpio { inner, io } = self;
+        drop(io);
+        inner
     }
 }
 
-impl Tpio {
-    /// Creates a new [Tpio] for the given handle. This can be used with [start_async_io] for the
-    /// lifetime of the handle.
-    pub fn new<T>(sock: &T) -> io::Result<Tpio>
-    where
-        T: AsRawSocket,
-    {
+impl ThreadpoolIo {
+    fn create(handle: HANDLE, is_socket: bool) -> io::Result<ThreadpoolIo> {
+        let runtime = Handle::current_for_io()?;
+        let skip_on_success = skip_completion_port_on_success(handle).is_ok();
+        let context = Box::new(CallbackContext {
+            outstanding: OutstandingOps {
+                count: AtomicUsize::new(0),
+                draining: AtomicBool::new(false),
+                lock: Mutex::new(()),
+                idle: Condvar::new(),
+            },
+            socket: if is_socket {
+                Some(handle.0 as usize)
+            } else {
+                None
+            },
+        });
         let tp_io = unsafe {
             CreateThreadpoolIo(
-                sock.as_raw_socket(),
+                handle,
                 Some(io_completion_function),
-                ptr::null_mut(),
-                ptr::null_mut(),
+                &*context as *const CallbackContext as *mut c_void,
+                runtime.callback_environment(),
             )
         };
         if tp_io.is_null() {
             Err(io::Error::last_os_error())
         } else {
-            Ok(Tpio { tp_io })
+            Ok(ThreadpoolIo {
+                tp_io,
+                handle,
+                skip_on_success,
+                runtime,
+                context,
+            })
         }
     }
 }
 
-// The lifetime of the TP_IO must be at least as long as the handle it is tied to. It's free to move
-// between threads during that time. I'm not sure if there is a better way to model that.
-// TODO: is this ok?
-unsafe impl Send for Tpio {}
-unsafe impl Sync for Tpio {}
+// The TP_IO is free threaded, and Tpio keeps it alive for as long as the handle it is tied to.
+unsafe impl Send for ThreadpoolIo {}
+unsafe impl Sync for ThreadpoolIo {}
 
 /// Used to start an async I/O operation. Returns a future the completes when the operation
 /// completes.
@@ -170,9 +709,6 @@ unsafe impl Sync for Tpio {}
 /// This is a wrapper around the Win32 [`StartThreadpoolIo`](https://docs.microsoft.com/windows/win32/api/threadpoolapiset/nf-threadpoolapiset-startthreadpoolio)
 /// API.
 ///
-/// The caller of this function must have first used [disable_callbacks_on_synchronous_completion]
-/// on the handle.
-///
 /// The caller must have previously created one and only one [Tpio] for their handle.
 ///
 /// # Callback
@@ -183,68 +719,263 @@ unsafe impl Sync for Tpio {}
 /// If the operation completes synchronously, the call back should return the number of bytes transferred.
 /// Otherwise return [None]. `start_async_io` will handle calling `GetLastError` to determine if the
 /// I/O is pending or failed.
-pub fn start_async_io<F>(tp_io: &Tpio, op: F) -> IocpFuture
+///
+/// Buffers the operation uses are borrowed by the closure, so they have to outlive the returned
+/// future. Dropping the future waits for the operation, but leaking it with `mem::forget` does not,
+/// and the buffers must not be freed in that case. [start_async_io_owned] avoids this by moving
+/// the buffer into the future.
+pub fn start_async_io<T, F>(tp_io: &Tpio<T>, op: F) -> IocpFuture
+where
+    F: FnOnce(*mut OVERLAPPED) -> Option<usize>,
+{
+    start_io(tp_io, false, op)
+}
+
+/// Like [start_async_io], but for a socket operation whose `MSG_*` flags are wanted, such as a
+/// `WSARecv` with `MSG_OOB` or one that may report `MSG_PARTIAL`. Once the operation completes,
+/// the flags are fetched with `WSAGetOverlappedResult` and returned by [IocpResult::flags].
+pub fn start_async_io_with_flags<T, F>(tp_io: &Tpio<T>, op: F) -> IocpFuture
+where
+    F: FnOnce(*mut OVERLAPPED) -> Option<usize>,
+{
+    start_io(tp_io, true, op)
+}
+
+fn start_io<T, F>(tp_io: &Tpio<T>, want_flags: bool, op: F) -> IocpFuture
 where
     F: FnOnce(*mut OVERLAPPED) -> Option<usize>,
 {
-    let state = Arc::new(Mutex::new(IocpFutureState::new()));
+    let tp_io = &tp_io.io;
+    let shared = IocpFutureShared::take(&tp_io.runtime, want_flags);
+    let mut pending = false;
     unsafe {
-        let overlapped = Box::new(OverlappedAndIocpStateReference {
-            overlapped: Default::default(),
-            state: state.clone(),
-            _pin: PhantomPinned,
-        });
-        let overlapped = Box::into_raw(overlapped);
+        // The completion callback's reference, which it releases once the operation completes.
+        let overlapped = Arc::into_raw(shared.clone()) as *mut OVERLAPPED;
+        tp_io.runtime.metrics_counters().io_started();
+        tp_io.context.outstanding.started();
         StartThreadpoolIo(tp_io.tp_io);
-        let maybe_sync_completion = op(overlapped as *mut OVERLAPPED);
+        let maybe_sync_completion = op(overlapped);
 
         let rc = match maybe_sync_completion {
             Some(number_of_bytes_transferred) => IocpResult {
                 io_result: WIN32_ERROR::NO_ERROR,
                 number_of_bytes_transferred,
+                flags: match tp_io.context.socket {
+                    Some(socket) if want_flags => {
+                        completion_flags(socket, overlapped, WIN32_ERROR::NO_ERROR)
+                    }
+                    _ => None,
+                },
             },
             None => IocpResult {
                 io_result: GetLastError(),
                 number_of_bytes_transferred: 0,
+                flags: None,
             },
         };
 
-        if rc.io_result == WIN32_ERROR::ERROR_IO_PENDING {
-            //io_completion_function will take have of cleaning up the Box
+        // A datagram or message that does not fit the buffer fails with ERROR_MORE_DATA or
+        // WSAEMSGSIZE. Those come from a warning status rather than an error, so the I/O manager
+        // queues a completion for them even though the call did not return ERROR_IO_PENDING, and
+        // even with FILE_SKIP_COMPLETION_PORT_ON_SUCCESS. Their result is taken from that
+        // completion like any other pending operation.
+        if rc.io_result == WIN32_ERROR::ERROR_IO_PENDING || is_truncated(rc.io_result) {
+            //io_completion_function will take care of releasing its reference
+            pending = true;
+        } else if rc.io_result == WIN32_ERROR::NO_ERROR && !tp_io.skip_on_success {
+            // The operation is complete, but a completion is still queued for it. The result is
+            // set now, and the completion only releases its reference.
+            shared.complete(rc, None);
         } else {
+            tp_io.runtime.metrics_counters().io_completed();
             //cleanup resources from async IO that never happened
             CancelThreadpoolIo(tp_io.tp_io);
-            drop(Box::from_raw(overlapped));
+            tp_io.context.outstanding.finished();
+            drop(Arc::from_raw(overlapped as *const IocpFutureShared));
 
             //propagate results
-            let mut mutable_state = state.lock().unwrap();
-            mutable_state.result = Some(rc);
+            shared.complete(rc, None);
+        }
+    }
+
+    IocpFuture {
+        shared: ManuallyDrop::new(shared),
+        handle: tp_io.handle,
+        pending,
+        stats: None,
+        abandoned: false,
+    }
+}
+
+/// Like [start_async_io], but the operation takes ownership of `buf`, and the future resolves to the
+/// result along with the buffer. `op` is given the buffer to start the operation on, through
+/// [IoBuf::stable_ptr] or [crate::buf::IoBufMut::stable_mut_ptr].
+///
+/// The buffer lives in the future, so it cannot be freed while the operation is in flight: dropping
+/// the future hands the buffer to the completion callback, which drops it once the operation has
+/// completed, and leaking the future leaks the buffer with it.
+pub fn start_async_io_owned<T, B, F>(tp_io: &Tpio<T>, mut buf: B, op: F) -> OwnedBufFuture<B>
+where
+    B: IoBuf,
+    F: FnOnce(&mut B, *mut OVERLAPPED) -> Option<usize>,
+{
+    let future = start_async_io(tp_io, |overlapped| op(&mut buf, overlapped));
+    // Moving the buffer into the future does not move its memory, see IoBuf.
+    OwnedBufFuture::new(future, buf)
+}
+
+/// An overlapped operation as a type: how to start it, and how to turn its result into the
+/// operation's output. Started with [start_op], which owns the operation until it completes.
+///
+/// # Safety
+///
+/// Memory that [OverlappedOp::submit] hands to Windows must be owned by the operation and must not
+/// move when the operation is moved, like the buffers in [crate::buf]. `submit` must start at most
+/// one overlapped operation with the `OVERLAPPED` it is given, and only on the handle it is given.
+/// Any other handle or socket the operation uses, such as the socket `AcceptEx` accepts onto, must
+/// be owned by the operation too, since dropping an [OpFuture] does not wait for the operation.
+pub unsafe trait OverlappedOp: Unpin + Send + 'static {
+    type Output;
+
+    /// Starts the operation on `handle`, like the closure passed to [start_async_io]: returns the
+    /// number of bytes transferred if it completed synchronously, or `None` with the thread's last
+    /// error set.
+    ///
+    /// `handle` is the one the [Tpio] is registered for, which for a socket is [Tpio::socket].
+    ///
+    /// # Safety
+    ///
+    /// `overlapped` must be passed to the Win32 function as is, without a completion routine.
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize>;
+
+    /// Decodes the result once the operation has completed, handing back anything it owns.
+    fn complete(self, result: IocpResult) -> Self::Output;
+}
+
+/// The future returned by [start_op]. Dropping it before the operation completes cancels the
+/// operation without waiting for it: the operation and its buffers are handed to the completion
+/// callback, which drops them once the operation has completed.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct OpFuture<O: OverlappedOp> {
+    future: IocpFuture,
+    op: Option<O>,
+}
+
+impl<O: OverlappedOp> Drop for OpFuture<O> {
+    fn drop(&mut self) {
+        if let Some(op) = self.op.take() {
+            self.future.abandon(Box::new(op));
+        }
+    }
+}
+
+impl<O: OverlappedOp> OpFuture<O> {
+    /// Counts this operation in `counters`, like [IocpFuture::record_stats].
+    pub(crate) fn record_stats(
+        mut self,
+        counters: &Arc<StreamCounters>,
+        direction: IoDirection,
+    ) -> OpFuture<O> {
+        counters.op_started(direction, self.future.is_pending());
+        self.future.stats = Some((counters.clone(), direction));
+        self
+    }
+}
+
+impl<O: OverlappedOp> Future for OpFuture<O> {
+    type Output = O::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.future).poll(cx) {
+            Poll::Ready(result) => Poll::Ready(this.op.take().unwrap().complete(result)),
+            Poll::Pending => Poll::Pending,
         }
     }
+}
 
-    IocpFuture { state }
+/// Starts `op` on the handle of `tp_io`. The future resolves to the operation's output.
+pub fn start_op<T, O: OverlappedOp>(tp_io: &Tpio<T>, mut op: O) -> OpFuture<O> {
+    let handle = tp_io.io.handle.0 as RawHandle;
+    let future = start_async_io(tp_io, |overlapped| unsafe { op.submit(handle, overlapped) });
+    // Moving the operation does not move the memory it handed to Windows, see OverlappedOp.
+    OpFuture {
+        future,
+        op: Some(op),
+    }
 }
 
-/// Disables IOCP notifications when a operation completes synchronously. This MUST be called and
-/// MUST return Ok before [start_async_io] is called. Failure to do so may result in memory
-/// corruption.
+/// Disables IOCP notifications when a operation completes synchronously. [Tpio::new] already does
+/// this, and falls back to expecting the notifications if it fails.
 pub fn disable_callbacks_on_synchronous_completion<T>(sock: &T) -> io::Result<()>
 where
     T: AsRawSocket,
 {
+    skip_completion_port_on_success(HANDLE(base_socket(sock.as_raw_socket()) as isize))
+}
+
+/// Like [disable_callbacks_on_synchronous_completion], but for a file or device handle.
+pub fn disable_callbacks_on_synchronous_completion_for_handle<T>(handle: &T) -> io::Result<()>
+where
+    T: AsRawHandle,
+{
+    skip_completion_port_on_success(HANDLE(handle.as_raw_handle() as isize))
+}
+
+fn skip_completion_port_on_success(handle: HANDLE) -> io::Result<()> {
     // 3 = FILE_SKIP_COMPLETION_PORT_ON_SUCCESS | FILE_SKIP_SET_EVENT_ON_HANDLE
     // It prevents a completion from being queued to the IOCP if the operation
     // completes synchronously.
     //
-    // NOTE: some other runtimes (.NET) handle this call failing and deal with the async notification
-    // on synchronous competition. They say:
+    // If this fails, Tpio falls back to deduplicating the notifications, like .NET, which says:
     //     There is a known bug that exists through Windows 7 with UDP and SetFileCompletionNotificationModes.
     //     So, don't try to enable skipping the completion port on success in this case.
     unsafe {
-        if SetFileCompletionNotificationModes(sock.as_raw_socket(), 3).as_bool() {
+        if SetFileCompletionNotificationModes(handle, 3).as_bool() {
             Ok(())
         } else {
             Err(std::io::Error::last_os_error())
         }
     }
 }
+
+/// Returns the base service provider socket for `sock`.
+///
+/// Layered service providers installed by some VPN and antivirus software hand out socket handles
+/// that are not real kernel handles, which cannot be associated with a completion port. Asking for
+/// the base handle bypasses the layers. Some broken providers intercept `SIO_BASE_HANDLE` itself,
+/// so the `SIO_BSP_HANDLE_*` ioctls are tried as fallbacks. If everything fails the socket is
+/// returned unchanged, which is right when no providers are layered on top.
+pub fn base_socket(sock: RawSocket) -> RawSocket {
+    const SIO_BSP_HANDLE: u32 = 0x4800001B;
+    const SIO_BSP_HANDLE_SELECT: u32 = 0x4800001C;
+    const SIO_BSP_HANDLE_POLL: u32 = 0x4800001D;
+    const SIO_BASE_HANDLE: u32 = 0x48000022;
+    const INVALID_SOCKET: usize = !0;
+
+    for &ioctl in &[
+        SIO_BASE_HANDLE,
+        SIO_BSP_HANDLE_SELECT,
+        SIO_BSP_HANDLE_POLL,
+        SIO_BSP_HANDLE,
+    ] {
+        let mut base: usize = INVALID_SOCKET;
+        let mut bytes_returned: u32 = 0;
+        let rc = unsafe {
+            WSAIoctl(
+                sock as usize,
+                ioctl,
+                ptr::null_mut(),
+                0,
+                &mut base as *mut usize as *mut c_void,
+                std::mem::size_of::<usize>() as u32,
+                &mut bytes_returned,
+                ptr::null_mut(),
+                None,
+            )
+        };
+        if rc == 0 && base != INVALID_SOCKET {
+            return base as RawSocket;
+        }
+    }
+    sock
+}
@@ -0,0 +1,41 @@
+pub mod bluetooth;
+pub mod buf;
+pub mod cancellation;
+#[cfg(feature = "bytes")]
+pub mod framed;
+pub mod fs;
+pub mod handle;
+#[cfg(feature = "hyper")]
+pub mod hyper_connector;
+pub mod hyperv;
+pub mod icmp;
+pub mod io;
+pub mod iocp_threadpool;
+pub mod listener;
+pub mod mailslot;
+pub mod metrics;
+pub mod net;
+#[cfg(feature = "perf-counters")]
+pub mod perf_counters;
+pub mod pipe;
+pub mod pool;
+pub mod process;
+pub mod recording;
+pub mod runtime;
+pub mod serial;
+pub mod signal;
+mod sockaddr;
+pub mod socket;
+mod socket_stream;
+mod sockopt;
+pub mod stream;
+pub mod task;
+pub mod testing;
+pub mod throttle;
+pub mod time;
+pub mod tls;
+#[cfg(feature = "tokio-compat")]
+mod tokio_compat;
+pub mod udp;
+pub mod unix;
+mod wsa_functions;
@@ -1,205 +1,571 @@
 use bindings::{
+    Windows::Win32::FileSystem::CancelIoEx,
     Windows::Win32::SystemServices::PSTR,
     Windows::Win32::WinSock::{
-        setsockopt, WSAIoctl, WSASocketW, LPFN_ACCEPTEX, LPFN_GETACCEPTEXSOCKADDRS,
+        setsockopt, LPFN_ACCEPTEX, LPFN_GETACCEPTEXSOCKADDRS, SOCKADDR, SOCKADDR_IN, SOCKADDR_IN6,
     },
 };
 
-use windows::Guid;
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::pin_mut;
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
 
 use std::convert::TryInto;
 use std::ffi::c_void;
+use std::future::Future;
 use std::io;
 use std::mem;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::net::{TcpListener, TcpStream};
-use std::os::windows::io::{AsRawSocket, FromRawSocket, RawSocket};
+use std::os::windows::io::{AsRawSocket, FromRawSocket};
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::Duration;
 
+use crate::cancellation::CancellationToken;
 use crate::iocp_threadpool;
-use crate::stream::AsyncTcpStream;
+use crate::runtime::Handle;
+use crate::sockaddr;
+use crate::socket;
+use crate::socket_stream::AcceptExOp;
+use crate::sockopt;
+use crate::stream::{AsyncTcpStream, TcpKeepalive};
 
-struct WsaFunctionCache {
-    guid: Guid,
-    //We don't need any ordering guarantees when loading or storing to these.
-    //It's ok if we do the IOCTL multiple times; it should gives us the same pointer each time.
-    ipv4_ptr: AtomicPtr<c_void>,
-    ipv6_ptr: AtomicPtr<c_void>,
+pub struct AsyncTcpListener {
+    inner: Arc<ListenerInner>,
+    keepalive: Option<TcpKeepalive>,
+    linger: Option<Option<Duration>>,
+    // Options that are re-applied to each accepted connection, in case it did not inherit them.
+    nodelay: Option<bool>,
+    ttl: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    send_buffer_size: Option<u32>,
+    pending_accepts: usize,
+    spare_sockets: usize,
+    // AcceptEx operations that have been posted but whose connections have not been returned by
+    // accept() yet. They keep accepting connections between calls to accept().
+    pending: AsyncMutex<FuturesUnordered<PendingAccept>>,
 }
 
-unsafe impl Sync for WsaFunctionCache {}
-
-impl WsaFunctionCache {
-    fn get_ptr(&self, listener: &TcpListener) -> io::Result<*mut c_void> {
-        let atomic_ptr = match listener.local_addr()? {
-            SocketAddr::V4(..) => &self.ipv4_ptr,
-            SocketAddr::V6(..) => &self.ipv6_ptr,
-        };
-        {
-            let ret = atomic_ptr.load(Ordering::Relaxed);
-            if ret.is_null() {
-                return Ok(ret);
-            }
-        }
+type PendingAccept = BoxFuture<'static, io::Result<(AsyncTcpStream, SocketAddr)>>;
 
-        const SIO_GET_EXTENSION_FUNCTION_POINTER: u32 = 0xC8000006;
-        let mut guid = self.guid.clone();
-        let mut fnptr: *mut c_void = ptr::null_mut();
-        let mut bytes_returned: u32 = 0;
-        let rc: i32;
-        unsafe {
-            rc = WSAIoctl(
-                listener.as_raw_socket() as usize,
-                SIO_GET_EXTENSION_FUNCTION_POINTER,
-                &mut guid as *mut Guid as *mut c_void,
-                std::mem::size_of::<Guid>() as u32,
-                &mut fnptr as *mut *mut c_void as *mut c_void,
-                std::mem::size_of::<*mut c_void>() as u32,
-                &mut bytes_returned,
-                ptr::null_mut(),
-                None,
-            );
-        }
-        if rc == 0 {
-            atomic_ptr.store(fnptr, Ordering::Relaxed);
-            Ok(fnptr)
-        } else {
-            Err(io::Error::last_os_error())
-        }
-    }
-    fn get_acceptex(listener: &TcpListener) -> io::Result<LPFN_ACCEPTEX> {
-        static CACHE: WsaFunctionCache = WsaFunctionCache {
-            // WSAID_ACCEPTEX
-            guid: Guid::from_values(
-                0xb5367df1,
-                0xcbac,
-                0x11cf,
-                [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
-            ),
-            ipv4_ptr: AtomicPtr::new(ptr::null_mut()),
-            ipv6_ptr: AtomicPtr::new(ptr::null_mut()),
-        };
-        unsafe { Ok(mem::transmute(CACHE.get_ptr(listener)?)) }
-    }
-
-    #[allow(unused)]
-    fn get_get_acceptex_sockaddrs(listener: &TcpListener) -> io::Result<LPFN_GETACCEPTEXSOCKADDRS> {
-        static CACHE: WsaFunctionCache = WsaFunctionCache {
-            // WSAID_GETACCEPTEXSOCKADDRS
-            guid: Guid::from_values(
-                0xb5367df2,
-                0xcbac,
-                0x11cf,
-                [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
-            ),
-            ipv4_ptr: AtomicPtr::new(ptr::null_mut()),
-            ipv6_ptr: AtomicPtr::new(ptr::null_mut()),
-        };
-        unsafe { Ok(mem::transmute(CACHE.get_ptr(listener)?)) }
-    }
+// The parts of the listener that posted accepts need, shared so that they can outlive a call to
+// accept().
+struct ListenerInner {
+    tp_io: iocp_threadpool::Tpio<TcpListener>,
+    // None if AcceptEx could not be loaded, in which case connections are accepted with a
+    // blocking accept on the threadpool.
+    acceptex: Option<AcceptExFunctions>,
+    // Cancelled by AsyncTcpListener::shutdown, or when the runtime shuts down.
+    shutdown: CancellationToken,
+    runtime: Handle,
+    // Sockets created ahead of time for AcceptEx to accept onto, refilled on the threadpool.
+    spare_sockets: Mutex<Vec<TcpStream>>,
+    // Set while a refill of spare_sockets is queued or running, so only one runs at a time.
+    refilling: AtomicBool,
 }
 
-pub struct AsyncTcpListener {
-    listener: TcpListener,
-    tp_io: iocp_threadpool::Tpio,
-    accept_fnptr: LPFN_ACCEPTEX,
+struct AcceptExFunctions {
+    accept: LPFN_ACCEPTEX,
+    get_sockaddrs: LPFN_GETACCEPTEXSOCKADDRS,
 }
 
 impl AsyncTcpListener {
+    /// Creates a listener bound to the given address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<AsyncTcpListener> {
-        let listener = TcpListener::bind(addr)?;
-        iocp_threadpool::disable_callbacks_on_synchronous_completion(&listener)?;
-        let accept_fnptr = WsaFunctionCache::get_acceptex(&listener)?;
-        let tp_io = iocp_threadpool::Tpio::new(&listener)?;
+        Self::from_listener(TcpListener::bind(addr)?)
+    }
+
+    /// Creates a dual-stack listener on `port` that accepts both IPv4 and IPv6 connections.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind_dual_stack(port: u16) -> io::Result<AsyncTcpListener> {
+        Self::builder()
+            .only_v6(false)
+            .bind((Ipv6Addr::UNSPECIFIED, port))
+    }
+
+    /// Returns a builder for a listener with options that must be set before it starts listening.
+    pub fn builder() -> ListenerBuilder {
+        ListenerBuilder::default()
+    }
+
+    /// Wraps a [TcpListener] that is already bound and listening, for example one inherited from a
+    /// process manager or configured with socket2. The socket must have been created for
+    /// overlapped I/O, which sockets created by std and by `WSASocketW` with
+    /// `WSA_FLAG_OVERLAPPED` are. It is associated with the runtime's completion port, so it
+    /// cannot already be associated with another one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn from_std(listener: TcpListener) -> io::Result<AsyncTcpListener> {
+        const SO_ACCEPTCONN: i32 = 0x0002;
+        let listening: u32 = sockopt::get(&listener, sockopt::SOL_SOCKET, SO_ACCEPTCONN)?;
+        if listening == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket is not listening",
+            ));
+        }
+        Self::from_listener(listener)
+    }
+
+    pub(crate) fn from_listener(listener: TcpListener) -> io::Result<AsyncTcpListener> {
+        let runtime = Handle::current_for_io()?;
+        // Loading the extension functions fails on some layered service providers and emulated
+        // environments.
+        let acceptex = match (
+            runtime.wsa_functions().acceptex(&listener),
+            runtime.wsa_functions().get_acceptex_sockaddrs(&listener),
+        ) {
+            (Ok(accept), Ok(get_sockaddrs)) => Some(AcceptExFunctions {
+                accept,
+                get_sockaddrs,
+            }),
+            _ => None,
+        };
+        let tp_io = iocp_threadpool::Tpio::new(listener)?;
         Ok(AsyncTcpListener {
-            listener,
-            tp_io,
-            accept_fnptr,
+            inner: Arc::new(ListenerInner {
+                tp_io,
+                acceptex,
+                shutdown: runtime.shutdown_token().child_token(),
+                runtime,
+                spare_sockets: Mutex::new(Vec::new()),
+                refilling: AtomicBool::new(false),
+            }),
+            keepalive: None,
+            linger: None,
+            nodelay: None,
+            ttl: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            pending_accepts: 1,
+            spare_sockets: 0,
+            pending: AsyncMutex::new(FuturesUnordered::new()),
         })
     }
 
-    //TODO: this is roughly based on the Socket code from std. Use that directly somehow?
-    fn _create_accept_socket(&self) -> io::Result<RawSocket> {
-        const AF_INET: i32 = 2;
-        const AF_INET6: i32 = 23;
-        const SOCK_STREAM: i32 = 1;
-        const IPPROTO_TCP: i32 = 6;
-        const WSA_FLAG_OVERLAPPED: u32 = 1;
-        const WSA_FLAG_NO_HANDLE_INHERIT: u32 = 0x80;
+    /// Applies `keepalive` to every connection accepted from now on, or stops doing so if `None`.
+    pub fn set_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.keepalive = keepalive;
+    }
 
-        let fam = match self.listener.local_addr()? {
-            SocketAddr::V4(..) => AF_INET,
-            SocketAddr::V6(..) => AF_INET6,
-        };
+    /// Sets `SO_LINGER` on every connection accepted from now on. See
+    /// [AsyncTcpStream::set_linger] for what the value means.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = Some(linger);
+    }
 
-        unsafe {
-            let sock = WSASocketW(
-                fam,
-                SOCK_STREAM,
-                IPPROTO_TCP,
-                ptr::null_mut(),
-                0,
-                WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT,
-            );
-            if sock == !0 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(sock as RawSocket)
+    /// Sets `TCP_NODELAY` on every connection accepted from now on. See
+    /// [AsyncTcpStream::set_nodelay].
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = Some(nodelay);
+    }
+
+    /// Sets the time-to-live of IPv4 packets sent by the listener, such as the SYN-ACK, and by
+    /// every connection accepted from now on.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        sockopt::set(
+            self.inner.tp_io.get_ref(),
+            sockopt::IPPROTO_IP,
+            sockopt::IP_TTL,
+            ttl,
+        )?;
+        self.ttl = Some(ttl);
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::get(
+            self.inner.tp_io.get_ref(),
+            sockopt::IPPROTO_IP,
+            sockopt::IP_TTL,
+        )
+    }
+
+    /// Sets the receive buffer size (`SO_RCVBUF`) of the listener and of every connection accepted
+    /// from now on. The TCP window scale is negotiated during the handshake, so a connection only
+    /// gets a window scale based on a larger buffer if the listener had it first; see
+    /// [ListenerBuilder::recv_buffer_size] to set it before listening starts.
+    pub fn set_recv_buffer_size(&mut self, size: u32) -> io::Result<()> {
+        sockopt::set(
+            self.inner.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_RCVBUF,
+            size,
+        )?;
+        self.recv_buffer_size = Some(size);
+        Ok(())
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(
+            self.inner.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_RCVBUF,
+        )
+    }
+
+    /// Sets the send buffer size (`SO_SNDBUF`) of every connection accepted from now on. See
+    /// [AsyncTcpStream::set_send_buffer_size].
+    pub fn set_send_buffer_size(&mut self, size: u32) -> io::Result<()> {
+        sockopt::set(
+            self.inner.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_SNDBUF,
+            size,
+        )?;
+        self.send_buffer_size = Some(size);
+        Ok(())
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(
+            self.inner.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_SNDBUF,
+        )
+    }
+
+    /// Whether an IPv6 listener only accepts IPv6 connections. This can only be changed before the
+    /// listener is bound, with [ListenerBuilder::only_v6]; accepted connections always match the
+    /// listener.
+    pub fn only_v6(&self) -> io::Result<bool> {
+        let value: u32 = sockopt::get(
+            self.inner.tp_io.get_ref(),
+            sockopt::IPPROTO_IPV6,
+            sockopt::IPV6_V6ONLY,
+        )?;
+        Ok(value != 0)
+    }
+
+    /// Sets how many AcceptEx operations [AsyncTcpListener::accept] keeps posted, so that
+    /// connections keep being accepted while the application is busy with the previous one. The
+    /// default is 1; servers that see bursts of new connections benefit from more. Values less
+    /// than 1 are treated as 1. If AcceptEx is not available, only one blocking accept is run at a
+    /// time regardless.
+    pub fn set_pending_accepts(&mut self, count: usize) {
+        self.pending_accepts = count.max(1);
+    }
+
+    /// Keeps up to `count` sockets created ahead of time for accepting connections onto, so
+    /// [AsyncTcpListener::accept] does not have to create one while a client is waiting. The
+    /// sockets are created in the background on the threadpool as they are used up. The default
+    /// is 0, which creates each socket when it is needed.
+    pub fn set_spare_sockets(&mut self, count: usize) {
+        self.spare_sockets = count;
+        self.inner.spare_sockets.lock().unwrap().truncate(count);
+        self.inner.refill_spare_sockets(count);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.tp_io.get_ref().local_addr()
+    }
+
+    /// Accepts a new connection, returning it along with the address of the peer. IPv4 peers of a
+    /// dual-stack listener are returned as IPv4 addresses rather than IPv4-mapped IPv6 addresses.
+    ///
+    /// Once the listener is shut down with [AsyncTcpListener::shutdown] or the runtime starts
+    /// shutting down (see [Handle::shutdown_token]) this fails, including any accept that is
+    /// already pending.
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        // Only one caller at a time waits on the pending accepts, since FuturesUnordered only
+        // remembers the most recent waker.
+        let mut pending = self.pending.lock().await;
+        if self.inner.shutdown.is_cancelled() {
+            return Err(shut_down_error());
+        }
+        while pending.len() < self.pending_accepts {
+            if self.inner.acceptex.is_none() {
+                // Each blocking accept ties up a threadpool thread, so only one is started.
+                if pending.is_empty() {
+                    pending.push(self.inner.clone().accept_blocking().boxed());
+                }
+                break;
             }
+            // The socket is associated with the completion port before AcceptEx, so the
+            // connection can use the same TP_IO.
+            let stream = match self
+                .inner
+                .take_accept_socket(self.spare_sockets)
+                .and_then(iocp_threadpool::Tpio::new)
+            {
+                Ok(stream) => stream,
+                // Make do with the accepts that are already posted, if there are any.
+                Err(_) if !pending.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            pending.push(self.inner.clone().accept_on(stream).boxed());
         }
+        let (stream, peer_addr) = pending
+            .next()
+            .await
+            .expect("at least one accept is pending")?;
+        drop(pending);
+        self.apply_options(&stream)?;
+        Ok((stream, peer_addr))
     }
 
-    pub async fn accept(&self) -> io::Result<AsyncTcpStream> {
-        let stream: TcpStream;
-        unsafe {
-            stream = FromRawSocket::from_raw_socket(self._create_accept_socket()?);
+    /// Returns a stream of accepted connections, for use with stream combinators such as
+    /// `for_each_concurrent`. Accept errors are yielded and do not end the stream. The stream
+    /// ends when the listener is shut down or the runtime starts shutting down.
+    pub fn incoming(&self) -> impl Stream<Item = io::Result<AsyncTcpStream>> + '_ {
+        stream::unfold(self, |listener| async move {
+            if listener.inner.shutdown.is_cancelled() {
+                return None;
+            }
+            match listener.accept().await {
+                Err(_) if listener.inner.shutdown.is_cancelled() => None,
+                ret => Some((ret.map(|(stream, _)| stream), listener)),
+            }
+        })
+    }
+
+    /// Accepts connections and runs `handler` on each of them as a separate task, with at most
+    /// `limit` of those tasks running at once. While `limit` connections are being handled, no more
+    /// are accepted, so new clients wait in the listen backlog.
+    ///
+    /// Returns `Ok` once the listener is shut down or the runtime starts shutting down; connections
+    /// that are still being handled keep running. Connections that are reset before they can be
+    /// accepted are skipped, and any other accept error is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub async fn serve<F, Fut>(&self, limit: usize, mut handler: F) -> io::Result<()>
+    where
+        F: FnMut(AsyncTcpStream, SocketAddr) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        assert!(limit > 0, "serve needs room for at least one connection");
+        let handle = Handle::current_for_io()?;
+        let limit = Arc::new(ConnectionLimit::new(limit));
+        loop {
+            let permit = {
+                let acquire = limit.acquire();
+                let cancelled = self.inner.shutdown.cancelled();
+                pin_mut!(acquire, cancelled);
+                match future::select(acquire, cancelled).await {
+                    Either::Left((permit, _)) => permit,
+                    Either::Right(_) => return Ok(()),
+                }
+            };
+            let (stream, peer_addr) = match self.accept().await {
+                Ok(connection) => connection,
+                Err(_) if self.inner.shutdown.is_cancelled() => return Ok(()),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            let connection = handler(stream, peer_addr);
+            handle.spawn(async move {
+                connection.await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// Stops accepting connections. Pending accepts are cancelled with `CancelIoEx`, [incoming]
+    /// streams end, and later calls to [AsyncTcpListener::accept] fail. Resolves once every
+    /// pending accept has completed and its socket has been closed, including any connection that
+    /// was accepted but not returned yet.
+    ///
+    /// When `AcceptEx` is not available, accepts fall back to a blocking `accept` on the
+    /// threadpool, which cannot be cancelled. Shutting down still fails the pending accept and
+    /// resolves, but the threadpool thread stays blocked, and keeps the listening socket open,
+    /// until the next connection arrives, which is then closed.
+    ///
+    /// [incoming]: AsyncTcpListener::incoming
+    pub async fn shutdown(&self) {
+        self.inner.shutdown.cancel();
+        let mut pending = self.pending.lock().await;
+        while pending.next().await.is_some() {}
+        self.inner.spare_sockets.lock().unwrap().clear();
+    }
+
+    /// Accepts a connection onto `stream`, which is either a new socket or one recycled with
+    /// `DisconnectEx`, and is already associated with the completion port.
+    pub(crate) async fn accept_on(
+        &self,
+        stream: iocp_threadpool::Tpio<TcpStream>,
+    ) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        if self.inner.acceptex.is_none() {
+            // Without AcceptEx there is no way to accept onto an existing socket, so it is closed.
+            return self.accept().await;
+        }
+        let (stream, peer_addr) = self.inner.clone().accept_on(stream).await?;
+        self.apply_options(&stream)?;
+        Ok((stream, peer_addr))
+    }
+
+    // Another listener on the same socket, with the same options but its own pending accepts.
+    fn new_shard(&self) -> AsyncTcpListener {
+        AsyncTcpListener {
+            inner: self.inner.clone(),
+            keepalive: self.keepalive,
+            linger: self.linger,
+            nodelay: self.nodelay,
+            ttl: self.ttl,
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            pending_accepts: self.pending_accepts,
+            spare_sockets: self.spare_sockets,
+            pending: AsyncMutex::new(FuturesUnordered::new()),
+        }
+    }
+
+    fn apply_options(&self, stream: &AsyncTcpStream) -> io::Result<()> {
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.apply(stream)?;
+        }
+        if let Some(linger) = self.linger {
+            sockopt::set_linger(stream, linger)?;
+        }
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(ttl) = self.ttl {
+            stream.set_ttl(ttl)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+impl ListenerInner {
+    fn create_accept_socket(&self) -> io::Result<TcpStream> {
+        let socket = socket::new_tcp_socket(&self.tp_io.get_ref().local_addr()?)?;
+        Ok(unsafe { TcpStream::from_raw_socket(socket) })
+    }
+
+    // Takes a spare socket if there is one, and starts refilling the spares up to `spares`.
+    fn take_accept_socket(self: &Arc<Self>, spares: usize) -> io::Result<TcpStream> {
+        let spare = self.spare_sockets.lock().unwrap().pop();
+        self.refill_spare_sockets(spares);
+        match spare {
+            Some(stream) => Ok(stream),
+            None => self.create_accept_socket(),
+        }
+    }
+
+    fn refill_spare_sockets(self: &Arc<Self>, target: usize) {
+        if target == 0 || self.acceptex.is_none() || self.refilling.swap(true, Ordering::AcqRel) {
+            return;
         }
-        iocp_threadpool::disable_callbacks_on_synchronous_completion(&stream)?;
+        let inner = self.clone();
+        self.runtime.spawn_blocking(move || {
+            while !inner.shutdown.is_cancelled()
+                && inner.spare_sockets.lock().unwrap().len() < target
+            {
+                match inner.create_accept_socket() {
+                    Ok(stream) => inner.spare_sockets.lock().unwrap().push(stream),
+                    // accept() creates its own socket, and reports the error if that fails too.
+                    Err(_) => break,
+                }
+            }
+            inner.refilling.store(false, Ordering::Release);
+        });
+    }
 
+    async fn accept_on(
+        self: Arc<Self>,
+        stream: iocp_threadpool::Tpio<TcpStream>,
+    ) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        if self.shutdown.is_cancelled() {
+            return Err(shut_down_error());
+        }
+        let acceptex = self
+            .acceptex
+            .as_ref()
+            .expect("accept_on is only used when AcceptEx is available");
+        // AcceptEx needs 16 bytes more than the size of each address. A dual-stack listener is an
+        // IPv6 socket, and reports IPv4 peers as IPv4-mapped IPv6 addresses.
         let socket_addr_size = 16
-            + match self.listener.local_addr()? {
-                SocketAddr::V4(..) => 16,
-                SocketAddr::V6(..) => 28,
+            + match self.tp_io.get_ref().local_addr()? {
+                SocketAddr::V4(..) => mem::size_of::<SOCKADDR_IN>(),
+                SocketAddr::V6(..) => mem::size_of::<SOCKADDR_IN6>(),
             };
 
-        // Hypothetically if we made this bigger we could receive the incoming connection's initial
-        // data. Right now it is only the size of the socket addresses.
-        let mut receive_buff: Vec<u8> = vec![0; 2 * socket_addr_size];
-        let mut listener_handle: usize = self.listener.as_raw_socket().try_into().unwrap();
-        let accept_handle: usize = stream.as_raw_socket().try_into().unwrap();
-
-        let ret = iocp_threadpool::start_async_io(&self.tp_io, |overlapped| {
-            let mut bytes_transferred: u32 = 0;
-            let fnptr = self.accept_fnptr;
-            unsafe {
-                let rc = fnptr(
-                    listener_handle,
-                    accept_handle,
-                    receive_buff.as_mut_ptr() as *mut c_void,
-                    0,
-                    socket_addr_size as u32,
-                    socket_addr_size as u32,
-                    &mut bytes_transferred,
-                    overlapped,
-                );
-
-                if rc.as_bool() {
-                    Some(bytes_transferred as usize)
-                } else {
-                    None
+        // AcceptEx goes through the base provider sockets, the same as every other overlapped
+        // operation. SO_UPDATE_ACCEPT_CONTEXT below uses the sockets the application sees.
+        let mut listener_handle: usize = self.tp_io.get_ref().as_raw_socket().try_into().unwrap();
+        let accept_handle: usize = stream.get_ref().as_raw_socket().try_into().unwrap();
+        let base_accept = stream.socket();
+
+        // Hypothetically if we gave AcceptEx a bigger buffer we could receive the incoming
+        // connection's initial data. Right now it only has space for the socket addresses.
+        let accept = iocp_threadpool::start_op(
+            &self.tp_io,
+            AcceptExOp::new(acceptex.accept, stream, base_accept, socket_addr_size),
+        );
+        // The operation owns the accept socket and receive_buff, so dropping it part way, as
+        // dropping pending_accepts does, leaves them to the completion callback.
+        let (ret, mut receive_buff, stream) =
+            match future::select(accept, self.shutdown.cancelled()).await {
+                Either::Left((ret, _)) => ret,
+                Either::Right((_, accept)) => {
+                    // The connection may have been accepted before it could be cancelled, so the
+                    // result is still taken from the operation.
+                    unsafe {
+                        CancelIoEx(self.tp_io.socket(), ptr::null_mut());
+                    }
+                    accept.await
                 }
-            }
-        })
-        .await;
+            };
 
-        if 0 != ret.get_number_of_bytes_transferred()? {
+        if 0 != ret? {
             // We did not specify that we wanted data, nor did we make the buffer big enough for any
             // extra data.
             panic!("Received socket data!?");
         }
 
-        //TODO: GetAcceptExSockaddrs to cache it local and remote addresses?
+        // AcceptEx wrote both addresses into receive_buff, so there is no need to call
+        // getpeername.
+        let peer_addr = unsafe {
+            let mut local: *mut SOCKADDR = ptr::null_mut();
+            let mut local_len: i32 = 0;
+            let mut remote: *mut SOCKADDR = ptr::null_mut();
+            let mut remote_len: i32 = 0;
+            let fnptr = acceptex.get_sockaddrs;
+            fnptr(
+                receive_buff.as_mut_ptr() as *mut c_void,
+                0,
+                socket_addr_size as u32,
+                socket_addr_size as u32,
+                &mut local,
+                &mut local_len,
+                &mut remote,
+                &mut remote_len,
+            );
+            sockaddr::to_socket_addr(remote, remote_len)?
+        };
+        let peer_addr = unmap_peer_addr(peer_addr);
 
         unsafe {
             const SO_UPDATE_ACCEPT_CONTEXT: i32 = 0x700B;
@@ -209,13 +575,306 @@ impl AsyncTcpListener {
                 SOL_SOCKET,
                 SO_UPDATE_ACCEPT_CONTEXT,
                 PSTR(&mut listener_handle as *mut usize as *mut u8),
-                std::mem::size_of::<usize>() as i32,
+                mem::size_of::<usize>() as i32,
             );
             if ret != 0 {
                 return Err(io::Error::from_raw_os_error(ret));
             }
         }
 
-        Ok(AsyncTcpStream::new(stream)?)
+        Ok((AsyncTcpStream::from_parts(stream)?, peer_addr))
+    }
+
+    // Accepts with std's blocking accept on the threadpool, for when AcceptEx is not available.
+    async fn accept_blocking(self: Arc<Self>) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        if self.shutdown.is_cancelled() {
+            return Err(shut_down_error());
+        }
+        let inner = self.clone();
+        let accept = self
+            .runtime
+            .spawn_blocking(move || inner.tp_io.get_ref().accept());
+        let (stream, peer_addr) = match future::select(accept, self.shutdown.cancelled()).await {
+            Either::Left((ret, _)) => ret?,
+            // A blocking accept cannot be cancelled. It keeps running until the next client
+            // connects, and that connection is then closed.
+            Either::Right(_) => return Err(shut_down_error()),
+        };
+        Ok((AsyncTcpStream::new(stream)?, unmap_peer_addr(peer_addr)))
+    }
+}
+
+/// A listening socket split into several [AsyncTcpListener]s, so that the workers of a multi-core
+/// server can each accept on their own shard instead of taking turns on a single listener.
+///
+/// Each shard keeps its own AcceptEx operations posted and handles its own completions. The shards
+/// share the listening socket: on Windows, binding several sockets to the same port with
+/// `SO_REUSEADDR` does not spread connections between them, so sharing one socket is how accepts
+/// are distributed. Shutting down any shard shuts down all of them.
+pub struct ShardedListener {
+    shards: Vec<AsyncTcpListener>,
+}
+
+impl ShardedListener {
+    /// Creates a listener bound to `addr` and splits it into `shards` shards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn bind<A: ToSocketAddrs>(addr: A, shards: usize) -> io::Result<ShardedListener> {
+        Ok(Self::new(AsyncTcpListener::bind(addr)?, shards))
+    }
+
+    /// Splits `listener` into `shards` shards, each with the options `listener` has.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn new(listener: AsyncTcpListener, shards: usize) -> ShardedListener {
+        assert!(shards > 0, "a sharded listener needs at least one shard");
+        let mut all = Vec::with_capacity(shards);
+        for _ in 1..shards {
+            all.push(listener.new_shard());
+        }
+        all.push(listener);
+        ShardedListener { shards: all }
+    }
+
+    pub fn shards(&self) -> &[AsyncTcpListener] {
+        &self.shards
+    }
+
+    /// Returns the shards, for example to move each one into the task of a different worker.
+    pub fn into_shards(self) -> Vec<AsyncTcpListener> {
+        self.shards
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shards[0].local_addr()
+    }
+
+    /// Shuts down every shard; see [AsyncTcpListener::shutdown].
+    pub async fn shutdown(&self) {
+        future::join_all(self.shards.iter().map(|shard| shard.shutdown())).await;
+    }
+}
+
+/// Configures a listener before it starts listening. Created with [AsyncTcpListener::builder].
+#[derive(Clone, Debug, Default)]
+pub struct ListenerBuilder {
+    loopback_fast_path: bool,
+    backlog: Option<u32>,
+    reuse_address: bool,
+    exclusive_addr_use: bool,
+    only_v6: Option<bool>,
+    recv_buffer_size: Option<u32>,
+    pending_accepts: Option<usize>,
+    spare_sockets: Option<usize>,
+}
+
+impl ListenerBuilder {
+    /// Sets how many connections that have not been accepted yet can be queued. By default the
+    /// system picks the maximum it considers reasonable (`SOMAXCONN`).
+    pub fn backlog(mut self, backlog: u32) -> ListenerBuilder {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Sets `SO_REUSEADDR`. Unlike on Unix, on Windows this lets the listener bind to a port that
+    /// another socket is already using, including one that is still listening, so leave it off
+    /// unless that is wanted. It is not needed to restart a server while its old connections are
+    /// in `TIME_WAIT`. It cannot be combined with [ListenerBuilder::exclusive_addr_use].
+    pub fn reuse_address(mut self, enabled: bool) -> ListenerBuilder {
+        self.reuse_address = enabled;
+        self
+    }
+
+    /// Sets `SO_EXCLUSIVEADDRUSE`, which stops any other socket from binding to the listener's
+    /// port, even one that sets `SO_REUSEADDR`. Servers should normally turn this on so their port
+    /// cannot be hijacked. The port stays unavailable to a new listener until the listener's
+    /// connections have fully closed, which can fail a quick restart.
+    pub fn exclusive_addr_use(mut self, enabled: bool) -> ListenerBuilder {
+        self.exclusive_addr_use = enabled;
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY` when binding to an IPv6 address. Windows defaults it to on; turning it
+    /// off makes a dual-stack listener that also accepts IPv4 clients. It has no effect on IPv4
+    /// addresses.
+    pub fn only_v6(mut self, only_v6: bool) -> ListenerBuilder {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Sets the listener's receive buffer size (`SO_RCVBUF`). Accepted connections inherit it, and
+    /// since the TCP window scale is negotiated during the handshake, this is the only way to give
+    /// them a window scale based on a larger buffer.
+    pub fn recv_buffer_size(mut self, size: u32) -> ListenerBuilder {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Turns on `SIO_LOOPBACK_FAST_PATH`, which speeds up connections from localhost clients that
+    /// turn it on too; see [crate::stream::ConnectOptions::loopback_fast_path].
+    pub fn loopback_fast_path(mut self, enabled: bool) -> ListenerBuilder {
+        self.loopback_fast_path = enabled;
+        self
+    }
+
+    /// Sets how many AcceptEx operations to keep posted; see
+    /// [AsyncTcpListener::set_pending_accepts].
+    pub fn pending_accepts(mut self, count: usize) -> ListenerBuilder {
+        self.pending_accepts = Some(count);
+        self
+    }
+
+    /// Sets how many sockets to create ahead of time for accepting connections onto; see
+    /// [AsyncTcpListener::set_spare_sockets].
+    pub fn spare_sockets(mut self, count: usize) -> ListenerBuilder {
+        self.spare_sockets = Some(count);
+        self
+    }
+
+    /// Creates the listener, binds it to the first address `addr` resolves to that can be bound,
+    /// and starts listening.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<AsyncTcpListener> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_one(&addr) {
+                Ok(listener) => {
+                    let mut listener = AsyncTcpListener::from_listener(listener)?;
+                    if let Some(count) = self.pending_accepts {
+                        listener.set_pending_accepts(count);
+                    }
+                    if let Some(count) = self.spare_sockets {
+                        listener.set_spare_sockets(count);
+                    }
+                    return Ok(listener);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    fn bind_one(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        const SOMAXCONN: i32 = 0x7fffffff;
+
+        let listener = unsafe { TcpListener::from_raw_socket(socket::new_tcp_socket(addr)?) };
+        if self.loopback_fast_path {
+            socket::enable_loopback_fast_path(&listener)?;
+        }
+        if self.reuse_address {
+            sockopt::set(&listener, sockopt::SOL_SOCKET, sockopt::SO_REUSEADDR, 1u32)?;
+        }
+        if self.exclusive_addr_use {
+            sockopt::set(
+                &listener,
+                sockopt::SOL_SOCKET,
+                sockopt::SO_EXCLUSIVEADDRUSE,
+                1u32,
+            )?;
+        }
+        if let (Some(only_v6), SocketAddr::V6(..)) = (self.only_v6, addr) {
+            sockopt::set(
+                &listener,
+                sockopt::IPPROTO_IPV6,
+                sockopt::IPV6_V6ONLY,
+                only_v6 as u32,
+            )?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sockopt::set(&listener, sockopt::SOL_SOCKET, sockopt::SO_RCVBUF, size)?;
+        }
+        socket::bind(&listener, addr)?;
+        let backlog = match self.backlog {
+            Some(backlog) => backlog.min(i32::MAX as u32) as i32,
+            None => SOMAXCONN,
+        };
+        socket::listen(&listener, backlog)?;
+        Ok(listener)
+    }
+}
+
+// A dual-stack listener reports IPv4 peers as IPv4-mapped IPv6 addresses.
+fn unmap_peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => SocketAddr::V6(addr),
+        },
+        addr => addr,
+    }
+}
+
+fn shut_down_error() -> io::Error {
+    io::Error::other("the listener has been shut down")
+}
+
+// A semaphore counting the connections AsyncTcpListener::serve is handling. Only the accept loop
+// waits for a permit, so there is at most one waker.
+struct ConnectionLimit {
+    state: Mutex<ConnectionLimitState>,
+}
+
+struct ConnectionLimitState {
+    available: usize,
+    waker: Option<Waker>,
+}
+
+// Returns its permit to the ConnectionLimit when dropped, including when the task handling the
+// connection panics.
+struct ConnectionPermit {
+    limit: Arc<ConnectionLimit>,
+}
+
+impl ConnectionLimit {
+    fn new(permits: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            state: Mutex::new(ConnectionLimitState {
+                available: permits,
+                waker: None,
+            }),
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> impl Future<Output = ConnectionPermit> + '_ {
+        future::poll_fn(move |cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.available == 0 {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.available -= 1;
+            Poll::Ready(ConnectionPermit {
+                limit: self.clone(),
+            })
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.limit.state.lock().unwrap();
+            state.available += 1;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
     }
 }
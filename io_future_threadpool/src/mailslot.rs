@@ -0,0 +1,121 @@
+//! Mailslots, one-way message queues that clients anywhere on the network can write to. A mailslot
+//! is created as `\\.\mailslot\name`, and clients write to `\\computer\mailslot\name`, or to
+//! `\\domain\mailslot\name` or `\\*\mailslot\name` to send to every mailslot with that name in
+//! the domain. Messages sent to more than one computer are limited to 424 bytes and can be lost.
+//!
+//! Local names are completed with the `\\.\mailslot\` prefix if they do not start with `\\`.
+
+use bindings::Windows::Win32::SystemServices::{CreateMailslotW, INVALID_HANDLE_VALUE, PWSTR};
+
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+use std::ptr;
+
+use crate::handle::AsyncOverlappedHandle;
+
+fn mailslot_path(name: &str) -> String {
+    if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\mailslot\{}", name)
+    }
+}
+
+/// The reading end of a mailslot, which only the process that created it can read from.
+pub struct AsyncMailslotServer {
+    inner: AsyncOverlappedHandle<File>,
+}
+
+impl AsyncMailslotServer {
+    /// Creates the mailslot `name` on this computer. Messages longer than `max_message_size` are
+    /// rejected; 0 allows any size.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn create(name: &str, max_message_size: u32) -> io::Result<AsyncMailslotServer> {
+        // Reads wait until a message arrives rather than failing when the mailslot is empty.
+        const MAILSLOT_WAIT_FOREVER: u32 = u32::MAX;
+
+        let mut wide: Vec<u16> = OsStr::new(&mailslot_path(name))
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+        let handle = unsafe {
+            CreateMailslotW(
+                PWSTR(wide.as_mut_ptr()),
+                max_message_size,
+                MAILSLOT_WAIT_FOREVER,
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        // Mailslot handles are always opened for overlapped I/O.
+        let file = unsafe { File::from_raw_handle(handle.0 as RawHandle) };
+        Ok(AsyncMailslotServer {
+            inner: AsyncOverlappedHandle::new(file)?,
+        })
+    }
+
+    /// Waits for a message and reads it into `buf`. Returns the length of the message.
+    ///
+    /// A message is read whole: if it does not fit in `buf`, this fails with
+    /// `ERROR_INSUFFICIENT_BUFFER` and the message stays in the mailslot.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner
+            .read_result(buf, 0)
+            .await
+            .get_number_of_bytes_transferred()
+    }
+}
+
+impl AsRawHandle for AsyncMailslotServer {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// The writing end of a mailslot. Each send is delivered as one message.
+pub struct AsyncMailslotClient {
+    inner: AsyncOverlappedHandle<File>,
+}
+
+impl AsyncMailslotClient {
+    /// Opens the mailslot `name` for writing. Opening a name that refers to every computer in a
+    /// domain succeeds whether or not any of them have created the mailslot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn open(name: &str) -> io::Result<AsyncMailslotClient> {
+        const FILE_SHARE_READ: u32 = 0x00000001;
+        const FILE_FLAG_OVERLAPPED: u32 = 0x40000000;
+
+        // The server has the mailslot open for reading, so the client has to share that.
+        let file = OpenOptions::new()
+            .write(true)
+            .share_mode(FILE_SHARE_READ)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(mailslot_path(name))?;
+        Ok(AsyncMailslotClient {
+            inner: AsyncOverlappedHandle::new(file)?,
+        })
+    }
+
+    /// Sends `buf` as one message, and returns its length.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).await
+    }
+}
+
+impl AsRawHandle for AsyncMailslotClient {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
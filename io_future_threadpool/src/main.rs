@@ -1,20 +1,17 @@
-use std::io;
-
-use futures::executor;
-use futures::executor::ThreadPool;
-use futures::task::SpawnExt;
+use futures::future;
+use futures::stream::TryStreamExt;
 
-mod iocp_threadpool;
-mod listener;
-mod stream;
+use std::io;
+use std::time::Duration;
 
-use listener::AsyncTcpListener;
-use stream::AsyncTcpStream;
+use rust_windows_io::listener::AsyncTcpListener;
+use rust_windows_io::runtime::{Handle, Runtime};
+use rust_windows_io::stream::AsyncTcpStream;
 
 const REQUEST: &str = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\n";
 
 async fn do_request() -> io::Result<()> {
-    let sock = AsyncTcpStream::connect("127.0.0.1:8080")?;
+    let sock = AsyncTcpStream::connect("127.0.0.1:8080").await?;
     sock.poll_write(REQUEST.as_ref()).await?;
     let mut response = [0; 4096];
     let _received = sock.poll_read(&mut response).await?;
@@ -23,10 +20,11 @@ async fn do_request() -> io::Result<()> {
     Ok(())
 }
 
-async fn http_client(pool: &ThreadPool) -> Result<(), Box<dyn std::error::Error>> {
+async fn http_client() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let handle = Handle::current();
     let mut running_tasks = Vec::new();
     for _i in 0..100 {
-        running_tasks.push(pool.spawn_with_handle(do_request())?);
+        running_tasks.push(handle.spawn(do_request()));
     }
 
     for subtask in running_tasks {
@@ -36,43 +34,54 @@ async fn http_client(pool: &ThreadPool) -> Result<(), Box<dyn std::error::Error>
     Ok(())
 }
 
-async fn tokio_readme_main(pool: &ThreadPool) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = AsyncTcpListener::bind("127.0.0.1:8080")?;
+async fn echo(socket: AsyncTcpStream) {
+    let mut buf = [0; 1024];
 
+    // In a loop, read data from the socket and write the data back.
     loop {
-        let socket = listener.accept().await?;
+        let n = match socket.poll_read(&mut buf).await {
+            // socket closed
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("failed to read from socket; err = {:?}", e);
+                return;
+            }
+        };
 
-        pool.spawn_ok(async move {
-            let mut buf = [0; 1024];
+        // Write the data back
+        if let Err(e) = socket.write_all(&buf[0..n]).await {
+            eprintln!("failed to write to socket; err = {:?}", e);
+            return;
+        }
+    }
+}
 
-            // In a loop, read data from the socket and write the data back.
-            loop {
-                let n = match socket.poll_read(&mut buf).await {
-                    // socket closed
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                };
+async fn tokio_readme_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let handle = Handle::current();
+    let listener = AsyncTcpListener::bind("127.0.0.1:8080")?;
 
-                // Write the data back
-                if let Err(e) = socket.write_all(&buf[0..n]).await {
-                    eprintln!("failed to write to socket; err = {:?}", e);
-                    return;
-                }
-            }
-        });
-    }
+    // The stream of connections ends when the runtime shuts down.
+    listener
+        .incoming()
+        .try_for_each(|socket| {
+            handle.spawn(echo(socket));
+            future::ok(())
+        })
+        .await?;
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let pool = ThreadPool::new().expect("Failed to build pool");
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let runtime = Runtime::new()?;
     if std::env::args().any(|a| a == "http") {
-        executor::block_on(http_client(&pool))?;
+        runtime.block_on(http_client())?;
     } else {
-        executor::block_on(tokio_readme_main(&pool))?;
+        runtime.block_on(async {
+            let server = runtime.spawn(tokio_readme_main());
+            runtime.shutdown_on_ctrl_c(Duration::from_secs(5)).await?;
+            server.await
+        })?;
     }
     Ok(())
 }
@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A snapshot of a runtime's counters, returned by [crate::runtime::Handle::metrics].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuntimeMetrics {
+    /// Number of tasks spawned over the lifetime of the runtime.
+    pub tasks_spawned: u64,
+    /// Number of spawned tasks that have not finished yet.
+    pub tasks_alive: u64,
+    /// Number of I/O operations that have been started and have not completed yet.
+    pub io_in_flight: u64,
+    /// Number of I/O operations that have completed, whether synchronously or through the threadpool.
+    pub io_completed: u64,
+}
+
+/// The live counters behind [RuntimeMetrics]. Each runtime owns one of these.
+///
+/// These are plain `AtomicU64`s so their addresses can be handed to consumers that read them
+/// directly, such as performance counters.
+#[derive(Default)]
+pub(crate) struct MetricsCounters {
+    pub(crate) tasks_spawned: AtomicU64,
+    pub(crate) tasks_alive: AtomicU64,
+    pub(crate) io_in_flight: AtomicU64,
+    pub(crate) io_completed: AtomicU64,
+}
+
+impl MetricsCounters {
+    pub(crate) fn task_spawned(&self) {
+        self.tasks_spawned.fetch_add(1, Ordering::Relaxed);
+        self.tasks_alive.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn task_finished(&self) {
+        self.tasks_alive.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn io_started(&self) {
+        self.io_in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn io_completed(&self) {
+        self.io_in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.io_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> RuntimeMetrics {
+        RuntimeMetrics {
+            tasks_spawned: self.tasks_spawned.load(Ordering::Relaxed),
+            tasks_alive: self.tasks_alive.load(Ordering::Relaxed),
+            io_in_flight: self.io_in_flight.load(Ordering::Relaxed),
+            io_completed: self.io_completed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Scheduling statistics for a single task, returned by [crate::runtime::JoinHandle::metrics].
+///
+/// A task with a large `max_poll_duration` is blocking a threadpool thread while it runs. A large
+/// scheduling delay means the task was woken but had to wait for a thread to poll it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskMetrics {
+    /// Number of times the task has been polled. A `spawn_blocking` task counts as polled once.
+    pub poll_count: u64,
+    /// Total time spent inside the task's `poll`.
+    pub total_poll_duration: Duration,
+    /// Longest single call to the task's `poll`.
+    pub max_poll_duration: Duration,
+    /// Total time between the task being woken (or spawned) and it being polled.
+    pub total_scheduling_delay: Duration,
+    /// Longest time between the task being woken (or spawned) and it being polled.
+    pub max_scheduling_delay: Duration,
+}
+
+/// The live counters behind [TaskMetrics]. Shared between a task and its JoinHandle.
+#[derive(Default)]
+pub(crate) struct TaskCounters {
+    poll_count: AtomicU64,
+    total_poll_nanos: AtomicU64,
+    max_poll_nanos: AtomicU64,
+    total_scheduling_nanos: AtomicU64,
+    max_scheduling_nanos: AtomicU64,
+    // When the task was last scheduled, if it has not been polled since.
+    scheduled_at: Mutex<Option<Instant>>,
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    duration.as_nanos().min(u64::MAX as u128) as u64
+}
+
+impl TaskCounters {
+    /// Records that a poll of the task has been queued.
+    pub(crate) fn scheduled(&self) {
+        *self.scheduled_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Records the start of a poll, returning the value to pass to [TaskCounters::poll_finished].
+    pub(crate) fn poll_started(&self) -> Instant {
+        let now = Instant::now();
+        if let Some(scheduled_at) = self.scheduled_at.lock().unwrap().take() {
+            let delay = as_nanos(now.duration_since(scheduled_at));
+            self.total_scheduling_nanos
+                .fetch_add(delay, Ordering::Relaxed);
+            self.max_scheduling_nanos
+                .fetch_max(delay, Ordering::Relaxed);
+        }
+        now
+    }
+
+    pub(crate) fn poll_finished(&self, started: Instant) {
+        let elapsed = as_nanos(started.elapsed());
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.total_poll_nanos.fetch_add(elapsed, Ordering::Relaxed);
+        self.max_poll_nanos.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> TaskMetrics {
+        TaskMetrics {
+            poll_count: self.poll_count.load(Ordering::Relaxed),
+            total_poll_duration: Duration::from_nanos(
+                self.total_poll_nanos.load(Ordering::Relaxed),
+            ),
+            max_poll_duration: Duration::from_nanos(self.max_poll_nanos.load(Ordering::Relaxed)),
+            total_scheduling_delay: Duration::from_nanos(
+                self.total_scheduling_nanos.load(Ordering::Relaxed),
+            ),
+            max_scheduling_delay: Duration::from_nanos(
+                self.max_scheduling_nanos.load(Ordering::Relaxed),
+            ),
+        }
+    }
+}
+
+/// I/O statistics for a single stream, returned by [crate::stream::AsyncTcpStream::stats].
+///
+/// Operations that complete synchronously skip the completion port entirely, because the runtime
+/// turns on `FILE_SKIP_COMPLETION_PORT_ON_SUCCESS`. A stream whose reads nearly always complete
+/// asynchronously is reading faster than data arrives.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamMetrics {
+    /// Number of receives started, including peeks and zero-byte receives.
+    pub reads: u64,
+    /// Number of sends started.
+    pub writes: u64,
+    /// Bytes received by receives that have completed.
+    pub bytes_read: u64,
+    /// Bytes sent by sends that have completed.
+    pub bytes_written: u64,
+    /// Number of operations that completed, or failed, when they were started.
+    pub completed_synchronously: u64,
+    /// Number of operations that went pending and completed through the threadpool.
+    pub completed_asynchronously: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum IoDirection {
+    Read,
+    Write,
+}
+
+/// The live counters behind [StreamMetrics].
+#[derive(Default)]
+pub(crate) struct StreamCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    completed_synchronously: AtomicU64,
+    completed_asynchronously: AtomicU64,
+}
+
+impl StreamCounters {
+    pub(crate) fn op_started(&self, direction: IoDirection, pending: bool) {
+        match direction {
+            IoDirection::Read => self.reads.fetch_add(1, Ordering::Relaxed),
+            IoDirection::Write => self.writes.fetch_add(1, Ordering::Relaxed),
+        };
+        if pending {
+            self.completed_asynchronously
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.completed_synchronously.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn op_completed(&self, direction: IoDirection, bytes: usize) {
+        let counter = match direction {
+            IoDirection::Read => &self.bytes_read,
+            IoDirection::Write => &self.bytes_written,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StreamMetrics {
+        StreamMetrics {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            completed_synchronously: self.completed_synchronously.load(Ordering::Relaxed),
+            completed_asynchronously: self.completed_asynchronously.load(Ordering::Relaxed),
+        }
+    }
+}
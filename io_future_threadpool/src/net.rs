@@ -0,0 +1,171 @@
+use bindings::{
+    Windows::Win32::IpHelper::{
+        CancelMibChangeNotify2, NotifyRouteChange2, NotifyUnicastIpAddressChange,
+    },
+    Windows::Win32::Mib::{MIB_IPFORWARD_ROW2, MIB_NOTIFICATION_TYPE, MIB_UNICASTIPADDRESS_ROW},
+    Windows::Win32::SystemServices::{HANDLE, PWSTR},
+    Windows::Win32::WinSock::GetNameInfoW,
+};
+
+use futures::stream::Stream;
+
+use std::ffi::c_void;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+use crate::runtime::Handle;
+use crate::sockaddr::RawSocketAddr;
+
+/// Performs a reverse DNS lookup of `addr`, returning its host name.
+///
+/// `GetNameInfoW` has no asynchronous form, so the lookup runs on the threadpool of the current
+/// [crate::runtime::Runtime] via [Handle::spawn_blocking]. Only the IP address of `addr` is looked
+/// up; the port is ignored. If the address has no name an error is returned rather than the
+/// numeric form of the address.
+///
+/// # Errors
+///
+/// Returns an error if called outside the context of a [crate::runtime::Runtime].
+pub async fn lookup_addr(addr: SocketAddr) -> io::Result<String> {
+    Handle::current_for_io()?
+        .spawn_blocking(move || get_name_info(&addr))
+        .await
+}
+
+fn get_name_info(addr: &SocketAddr) -> io::Result<String> {
+    const NI_MAXHOST: usize = 1025;
+    const NI_NAMEREQD: i32 = 0x04;
+
+    let addr = RawSocketAddr::new(addr);
+    let mut host = [0u16; NI_MAXHOST];
+    let rc = unsafe {
+        GetNameInfoW(
+            addr.as_ptr(),
+            addr.len(),
+            PWSTR(host.as_mut_ptr()),
+            host.len() as u32,
+            PWSTR::default(),
+            0,
+            NI_NAMEREQD,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    let len = host.iter().position(|&c| c == 0).unwrap_or(host.len());
+    Ok(String::from_utf16_lossy(&host[..len]))
+}
+
+/// A stream that yields an item whenever the network configuration changes. Returned by
+/// [watch_address_changes] and [watch_route_changes].
+///
+/// Notifications that arrive between polls are coalesced, so each item means "at least one change
+/// happened since the previous item"; re-read whatever state you depend on. The stream never ends.
+#[must_use = "streams do nothing unless polled"]
+pub struct NetworkChanges {
+    handle: HANDLE,
+    // Created with Box::into_raw and passed to the notification callback.
+    state: *mut Mutex<ChangeState>,
+}
+
+struct ChangeState {
+    changed: bool,
+    waker: Option<Waker>,
+}
+
+// The notification handle is only used with CancelMibChangeNotify2, which is free threaded.
+unsafe impl Send for NetworkChanges {}
+unsafe impl Sync for NetworkChanges {}
+
+/// Watches for IPv4 and IPv6 unicast addresses being added, removed, or changed on any interface,
+/// using `NotifyUnicastIpAddressChange`.
+pub fn watch_address_changes() -> io::Result<NetworkChanges> {
+    NetworkChanges::register(|state, handle| unsafe {
+        NotifyUnicastIpAddressChange(AF_UNSPEC, Some(address_changed), state, 0, handle).0
+    })
+}
+
+/// Watches for IPv4 and IPv6 routes being added, removed, or changed, using `NotifyRouteChange2`.
+pub fn watch_route_changes() -> io::Result<NetworkChanges> {
+    NetworkChanges::register(|state, handle| unsafe {
+        NotifyRouteChange2(AF_UNSPEC, Some(route_changed), state, 0, handle).0
+    })
+}
+
+const AF_UNSPEC: u16 = 0;
+
+extern "system" fn address_changed(
+    context: *mut c_void,
+    _row: *mut MIB_UNICASTIPADDRESS_ROW,
+    _notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    notify(context);
+}
+
+extern "system" fn route_changed(
+    context: *mut c_void,
+    _row: *mut MIB_IPFORWARD_ROW2,
+    _notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    notify(context);
+}
+
+fn notify(context: *mut c_void) {
+    // NetworkChanges::drop cancels the notification, which waits for running callbacks, before
+    // releasing the state.
+    let state = unsafe { &*(context as *const Mutex<ChangeState>) };
+    let waker = {
+        let mut state = state.lock().unwrap();
+        state.changed = true;
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl NetworkChanges {
+    fn register<F>(register: F) -> io::Result<NetworkChanges>
+    where
+        F: FnOnce(*mut c_void, *mut HANDLE) -> i32,
+    {
+        let state = Box::into_raw(Box::new(Mutex::new(ChangeState {
+            changed: false,
+            waker: None,
+        })));
+        let mut handle = HANDLE::default();
+        let rc = register(state as *mut c_void, &mut handle);
+        if rc != 0 {
+            unsafe { drop(Box::from_raw(state)) };
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        Ok(NetworkChanges { handle, state })
+    }
+}
+
+impl Stream for NetworkChanges {
+    type Item = ();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        let mut state = unsafe { &*self.state }.lock().unwrap();
+        if state.changed {
+            state.changed = false;
+            Poll::Ready(Some(()))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for NetworkChanges {
+    fn drop(&mut self) {
+        unsafe {
+            // This waits for any callback that is running to return.
+            CancelMibChangeNotify2(self.handle);
+            drop(Box::from_raw(self.state));
+        }
+    }
+}
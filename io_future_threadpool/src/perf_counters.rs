@@ -0,0 +1,186 @@
+//! Publishes a runtime's [crate::metrics::RuntimeMetrics] as Windows performance counters, so they
+//! can be watched in PerfMon or collected with `typeperf` alongside the system counters.
+//!
+//! The counters have to be registered once per machine with the manifest in this crate's directory:
+//!
+//! ```text
+//! lodctr /m:perf_counters.man <directory containing the exe>
+//! ```
+//!
+//! This module is only built with the `perf-counters` feature.
+
+use bindings::{
+    Windows::Win32::Perf::{
+        PerfCreateInstance, PerfDeleteInstance, PerfProviderHandle, PerfSetCounterRefValue,
+        PerfSetCounterSetInfo, PerfStartProvider, PerfStopProvider, PERF_COUNTERSET_INFO,
+        PERF_COUNTERSET_INSTANCE, PERF_COUNTER_INFO,
+    },
+    Windows::Win32::SystemServices::{HANDLE, PWSTR},
+};
+
+use windows::Guid;
+
+use std::ffi::{c_void, OsStr};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::runtime::Handle;
+
+// These must match perf_counters.man.
+// {600e6e91-b1f8-4642-b426-8b4c59ddb259}
+const PROVIDER_GUID: Guid = Guid::from_values(
+    0x600e6e91,
+    0xb1f8,
+    0x4642,
+    [0xb4, 0x26, 0x8b, 0x4c, 0x59, 0xdd, 0xb2, 0x59],
+);
+// {70ca2fd9-6e43-4a54-a064-13f5170d9f02}
+const COUNTERSET_GUID: Guid = Guid::from_values(
+    0x70ca2fd9,
+    0x6e43,
+    0x4a54,
+    [0xa0, 0x64, 0x13, 0xf5, 0x17, 0x0d, 0x9f, 0x02],
+);
+const COUNTER_TASKS_ALIVE: u32 = 1;
+const COUNTER_TASKS_SPAWNED: u32 = 2;
+const COUNTER_IO_IN_FLIGHT: u32 = 3;
+const COUNTER_IO_COMPLETED: u32 = 4;
+
+const PERF_COUNTERSET_MULTI_INSTANCES: u32 = 2;
+const PERF_COUNTER_LARGE_RAWCOUNT: u32 = 0x00010100;
+const PERF_COUNTER_BULK_COUNT: u32 = 0x10410500;
+const PERF_ATTRIB_BY_REFERENCE: u64 = 1;
+const PERF_DETAIL_NOVICE: u32 = 100;
+
+/// The layout PerfSetCounterSetInfo expects: the counter set followed by its counters.
+#[repr(C)]
+struct CounterSetTemplate {
+    info: PERF_COUNTERSET_INFO,
+    counters: [PERF_COUNTER_INFO; 4],
+}
+
+fn counter_info(id: u32, counter_type: u32) -> PERF_COUNTER_INFO {
+    PERF_COUNTER_INFO {
+        CounterId: id,
+        Type: counter_type,
+        Attrib: PERF_ATTRIB_BY_REFERENCE,
+        Size: mem::size_of::<u64>() as u32,
+        DetailLevel: PERF_DETAIL_NOVICE,
+        Scale: 0,
+        // By-reference counters store a pointer per counter in the instance block.
+        Offset: (id - 1) * mem::size_of::<*mut u64>() as u32,
+    }
+}
+
+fn check(rc: u32) -> io::Result<()> {
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(rc as i32))
+    }
+}
+
+/// An instance of the "Rust Windows IO Runtime" counter set that reports one runtime's metrics. The
+/// instance is removed when this is dropped.
+///
+/// The counters point directly at the runtime's metric counters, so there is nothing to update
+/// periodically.
+pub struct PerfCounters {
+    provider: PerfProviderHandle,
+    instance: *mut PERF_COUNTERSET_INSTANCE,
+    // Keeps the counters the instance points at alive.
+    _runtime: Handle,
+}
+
+// The PerfLib APIs are free threaded.
+unsafe impl Send for PerfCounters {}
+unsafe impl Sync for PerfCounters {}
+
+impl PerfCounters {
+    /// Publishes the metrics of `runtime` as a counter set instance called `instance_name`.
+    pub fn register(runtime: &Handle, instance_name: &str) -> io::Result<PerfCounters> {
+        static NEXT_INSTANCE_ID: AtomicU32 = AtomicU32::new(0);
+
+        let mut provider = PerfProviderHandle::default();
+        unsafe {
+            let mut provider_guid = PROVIDER_GUID;
+            check(PerfStartProvider(&mut provider_guid, None, &mut provider))?;
+        }
+        let mut counters = PerfCounters {
+            provider,
+            instance: ptr::null_mut(),
+            _runtime: runtime.clone(),
+        };
+
+        let mut template = CounterSetTemplate {
+            info: PERF_COUNTERSET_INFO {
+                CounterSetGuid: COUNTERSET_GUID,
+                ProviderGuid: PROVIDER_GUID,
+                NumCounters: 4,
+                InstanceType: PERF_COUNTERSET_MULTI_INSTANCES,
+            },
+            counters: [
+                counter_info(COUNTER_TASKS_ALIVE, PERF_COUNTER_LARGE_RAWCOUNT),
+                counter_info(COUNTER_TASKS_SPAWNED, PERF_COUNTER_BULK_COUNT),
+                counter_info(COUNTER_IO_IN_FLIGHT, PERF_COUNTER_LARGE_RAWCOUNT),
+                counter_info(COUNTER_IO_COMPLETED, PERF_COUNTER_BULK_COUNT),
+            ],
+        };
+        let mut name: Vec<u16> = OsStr::new(instance_name)
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+        unsafe {
+            check(PerfSetCounterSetInfo(
+                counters.provider_handle(),
+                &mut template.info,
+                mem::size_of::<CounterSetTemplate>() as u32,
+            ))?;
+
+            let mut counterset_guid = COUNTERSET_GUID;
+            counters.instance = PerfCreateInstance(
+                counters.provider,
+                &mut counterset_guid,
+                PWSTR(name.as_mut_ptr()),
+                NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed),
+            );
+            if counters.instance.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            let metrics = runtime.metrics_counters();
+            counters.set_ref(COUNTER_TASKS_ALIVE, &metrics.tasks_alive)?;
+            counters.set_ref(COUNTER_TASKS_SPAWNED, &metrics.tasks_spawned)?;
+            counters.set_ref(COUNTER_IO_IN_FLIGHT, &metrics.io_in_flight)?;
+            counters.set_ref(COUNTER_IO_COMPLETED, &metrics.io_completed)?;
+        }
+        Ok(counters)
+    }
+
+    fn provider_handle(&self) -> HANDLE {
+        HANDLE(self.provider.0)
+    }
+
+    unsafe fn set_ref(&self, counter_id: u32, value: &AtomicU64) -> io::Result<()> {
+        check(PerfSetCounterRefValue(
+            self.provider_handle(),
+            self.instance,
+            counter_id,
+            value as *const AtomicU64 as *mut c_void,
+        ))
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.instance.is_null() {
+                PerfDeleteInstance(self.provider, self.instance);
+            }
+            PerfStopProvider(self.provider);
+        }
+    }
+}
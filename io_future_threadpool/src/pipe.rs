@@ -0,0 +1,425 @@
+//! Named pipes, the usual way for processes on the same machine to talk to each other on Windows.
+//! A pipe name looks like `\\.\pipe\my-service`; the `\\.\pipe\` prefix is added to names that
+//! do not start with `\\`.
+
+use bindings::Windows::Win32::SystemServices::{
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, SetNamedPipeHandleState,
+    WaitNamedPipeW, HANDLE, INVALID_HANDLE_VALUE, PWSTR, WAIT_NAMED_PIPE_TIME_OUT_FLAGS,
+};
+
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::handle::AsyncOverlappedHandle;
+use crate::runtime::Handle;
+
+const ERROR_ACCESS_DENIED: i32 = 5;
+const ERROR_BROKEN_PIPE: i32 = 109;
+const ERROR_SEM_TIMEOUT: i32 = 121;
+const ERROR_PIPE_BUSY: i32 = 231;
+const ERROR_NO_DATA: i32 = 232;
+const ERROR_PIPE_CONNECTED: i32 = 535;
+
+const FILE_FLAG_OVERLAPPED: u32 = 0x40000000;
+const PIPE_READMODE_MESSAGE: u32 = 0x00000002;
+const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+// Keeps the server from acting as the client with the client's credentials.
+const SECURITY_IDENTIFICATION: u32 = 0x00010000;
+
+fn pipe_path(name: &str) -> String {
+    if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\pipe\{}", name)
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// How data written to a pipe is delivered to the other end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipeMode {
+    /// A stream of bytes, like a TCP connection.
+    Byte,
+    /// Each write is delivered as a separate message, like a datagram. Reads return at most one
+    /// message, see [AsyncNamedPipeClient::read_message].
+    Message,
+}
+
+// Reads from either end of a pipe, and returns the number of bytes read and whether they finish
+// a message. Byte mode pipes always finish their "message".
+async fn read_message(
+    pipe: &AsyncOverlappedHandle<File>,
+    buf: &mut [u8],
+) -> io::Result<(usize, bool)> {
+    match pipe
+        .read_result(buf, 0)
+        .await
+        .get_number_of_bytes_transferred_allow_partial()
+    {
+        Ok((read, partial)) => Ok((read, !partial)),
+        // The other end closed the pipe, which is the end of the stream.
+        Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE) => Ok((0, true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// One instance of a named pipe, created by the server with overlapped I/O on the current
+/// [crate::runtime::Runtime]. An instance serves one client at a time; use [NamedPipeListener] to
+/// serve several at once.
+pub struct AsyncNamedPipeServer {
+    inner: AsyncOverlappedHandle<File>,
+}
+
+impl AsyncNamedPipeServer {
+    /// Creates a byte mode instance of the pipe `name`, which waits for a client once
+    /// [AsyncNamedPipeServer::connect] is called. Fails if another process already created a pipe
+    /// with this name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn create(name: &str) -> io::Result<AsyncNamedPipeServer> {
+        AsyncNamedPipeServer::create_with_mode(name, PipeMode::Byte)
+    }
+
+    /// Like [AsyncNamedPipeServer::create], but the pipe can be in message mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn create_with_mode(name: &str, mode: PipeMode) -> io::Result<AsyncNamedPipeServer> {
+        AsyncNamedPipeServer::create_instance(
+            &pipe_path(name),
+            mode,
+            true,
+            PIPE_UNLIMITED_INSTANCES,
+        )
+    }
+
+    fn create_instance(
+        path: &str,
+        mode: PipeMode,
+        first: bool,
+        max_instances: u32,
+    ) -> io::Result<AsyncNamedPipeServer> {
+        const PIPE_ACCESS_DUPLEX: u32 = 0x00000003;
+        const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x00080000;
+        // PIPE_TYPE_BYTE, PIPE_READMODE_BYTE and PIPE_WAIT are all 0.
+        const PIPE_TYPE_MESSAGE: u32 = 0x00000004;
+        const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x00000008;
+        const BUFFER_SIZE: u32 = 64 * 1024;
+
+        let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+        if first {
+            // Makes sure we are not adding instances to a pipe some other process owns.
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        let mut pipe_mode = PIPE_REJECT_REMOTE_CLIENTS;
+        if mode == PipeMode::Message {
+            pipe_mode |= PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE;
+        }
+        let mut wide = to_wide(path);
+        let handle = unsafe {
+            CreateNamedPipeW(
+                PWSTR(wide.as_mut_ptr()),
+                open_mode,
+                pipe_mode,
+                max_instances,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_handle(handle.0 as RawHandle) };
+        Ok(AsyncNamedPipeServer {
+            inner: AsyncOverlappedHandle::new(file)?,
+        })
+    }
+
+    /// Waits for a client to open the pipe. A client that connected before this was called counts
+    /// too.
+    pub async fn connect(&self) -> io::Result<()> {
+        let handle = self.inner.handle();
+        let ret = self
+            .inner
+            .submit(|overlapped| unsafe {
+                if ConnectNamedPipe(handle, overlapped).as_bool() {
+                    Some(0)
+                } else {
+                    None
+                }
+            })
+            .await;
+        match ret.get_number_of_bytes_transferred() {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Disconnects the client, discarding anything it has not read yet, so the instance can be
+    /// connected to the next one.
+    pub fn disconnect(&self) -> io::Result<()> {
+        if unsafe { DisconnectNamedPipe(self.inner.handle()) }.as_bool() {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Reads into `buf`, and returns the number of bytes read, which is 0 once the client has
+    /// closed its end. In message mode, a message that does not fit is returned over several
+    /// reads.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        read_message(&self.inner, buf).await.map(|(read, _)| read)
+    }
+
+    /// Reads from a message mode pipe. Returns the number of bytes read and whether they finish
+    /// the message; if not, the rest of the message is returned by the next reads.
+    pub async fn read_message(&self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+        read_message(&self.inner, buf).await
+    }
+
+    /// Writes some of `buf`, and returns the number of bytes written. In message mode, `buf` is
+    /// one message.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf).await
+    }
+}
+
+impl AsRawHandle for AsyncNamedPipeServer {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// Accepts clients of a named pipe, each on its own instance of the pipe.
+///
+/// There is always one instance waiting for the next client: accepting a client creates the
+/// instance for the one after it, so clients never find the pipe missing between accepts.
+pub struct NamedPipeListener {
+    path: String,
+    mode: PipeMode,
+    next: AsyncNamedPipeServer,
+}
+
+impl NamedPipeListener {
+    /// Creates the first instance of the byte mode pipe `name`. Fails if another process already
+    /// created a pipe with this name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind(name: &str) -> io::Result<NamedPipeListener> {
+        NamedPipeListener::bind_with_mode(name, PipeMode::Byte)
+    }
+
+    /// Like [NamedPipeListener::bind], but the pipe can be in message mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind_with_mode(name: &str, mode: PipeMode) -> io::Result<NamedPipeListener> {
+        let path = pipe_path(name);
+        let next =
+            AsyncNamedPipeServer::create_instance(&path, mode, true, PIPE_UNLIMITED_INSTANCES)?;
+        Ok(NamedPipeListener { path, mode, next })
+    }
+
+    /// Waits for a client, and returns the instance it is connected to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn accept(&mut self) -> io::Result<AsyncNamedPipeServer> {
+        loop {
+            match self.next.connect().await {
+                Ok(()) => break,
+                // The client closed its end before we got to it.
+                Err(e) if e.raw_os_error() == Some(ERROR_NO_DATA) => self.next.disconnect()?,
+                Err(e) => return Err(e),
+            }
+        }
+        let next = AsyncNamedPipeServer::create_instance(
+            &self.path,
+            self.mode,
+            false,
+            PIPE_UNLIMITED_INSTANCES,
+        )?;
+        Ok(mem::replace(&mut self.next, next))
+    }
+}
+
+/// The client end of a named pipe, opened for overlapped I/O on the current
+/// [crate::runtime::Runtime].
+pub struct AsyncNamedPipeClient {
+    inner: AsyncOverlappedHandle<File>,
+}
+
+impl AsyncNamedPipeClient {
+    /// Connects to the pipe `name` and reads from it in byte mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect(name: &str) -> io::Result<AsyncNamedPipeClient> {
+        AsyncNamedPipeClient::connect_with_mode(name, PipeMode::Byte).await
+    }
+
+    /// Connects to the pipe `name`. [PipeMode::Message] requires the server to have created a
+    /// message mode pipe.
+    ///
+    /// If every instance of the pipe is connected to another client, this waits for the server to
+    /// create one, with `WaitNamedPipe` on the runtime's blocking pool, and tries again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect_with_mode(name: &str, mode: PipeMode) -> io::Result<AsyncNamedPipeClient> {
+        let path = pipe_path(name);
+        let file = loop {
+            let opened = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(FILE_FLAG_OVERLAPPED)
+                .security_qos_flags(SECURITY_IDENTIFICATION)
+                .open(&path);
+            match opened {
+                Ok(file) => break file,
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+                Err(e) => return Err(e),
+            }
+            let mut wide = to_wide(&path);
+            let waited = Handle::current_for_io()?
+                .spawn_blocking(move || {
+                    let waited = unsafe {
+                        WaitNamedPipeW(
+                            PWSTR(wide.as_mut_ptr()),
+                            WAIT_NAMED_PIPE_TIME_OUT_FLAGS::NMPWAIT_USE_DEFAULT_WAIT,
+                        )
+                    };
+                    if waited.as_bool() {
+                        Ok(())
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
+                })
+                .await;
+            match waited {
+                // Another client may take the instance before we open it, in which case the open
+                // fails with ERROR_PIPE_BUSY again.
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(ERROR_SEM_TIMEOUT) => {}
+                Err(e) => return Err(e),
+            }
+        };
+
+        if mode == PipeMode::Message {
+            let mut read_mode = PIPE_READMODE_MESSAGE;
+            let handle = HANDLE(file.as_raw_handle() as isize);
+            if !unsafe {
+                SetNamedPipeHandleState(handle, &mut read_mode, ptr::null_mut(), ptr::null_mut())
+            }
+            .as_bool()
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(AsyncNamedPipeClient {
+            inner: AsyncOverlappedHandle::new(file)?,
+        })
+    }
+
+    /// Reads into `buf`, and returns the number of bytes read, which is 0 once the server has
+    /// closed its end. In message mode, a message that does not fit is returned over several
+    /// reads.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        read_message(&self.inner, buf).await.map(|(read, _)| read)
+    }
+
+    /// Reads in message mode. Returns the number of bytes read and whether they finish the
+    /// message; if not, the rest of the message is returned by the next reads.
+    pub async fn read_message(&self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+        read_message(&self.inner, buf).await
+    }
+
+    /// Writes some of `buf`, and returns the number of bytes written. In message mode, `buf` is
+    /// one message.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf).await
+    }
+}
+
+impl AsRawHandle for AsyncNamedPipeClient {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// Creates the two ends of a pipe, like the anonymous pipes from `CreatePipe`, which do not support
+/// overlapped I/O. Both ends can read and write. Data written to one end is read from the other.
+///
+/// # Errors
+///
+/// Returns an error if called outside the context of a [crate::runtime::Runtime].
+pub fn pipe_pair() -> io::Result<(AsyncNamedPipeServer, AsyncNamedPipeClient)> {
+    let (server, client) = unique_pipe(true)?;
+    let client = AsyncNamedPipeClient {
+        inner: AsyncOverlappedHandle::new(client)?,
+    };
+    Ok((server, client))
+}
+
+// Creates a single instance pipe with a name no one else uses and opens its client end, which
+// only supports overlapped I/O if `overlapped_client` is set. Opening the client end connects it,
+// so there is nothing to wait for.
+pub(crate) fn unique_pipe(overlapped_client: bool) -> io::Result<(AsyncNamedPipeServer, File)> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let path = format!(
+            r"\\.\pipe\rust-windows-io-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let server = match AsyncNamedPipeServer::create_instance(&path, PipeMode::Byte, true, 1) {
+            Ok(server) => server,
+            // Some other process is using this name.
+            Err(e) if e.raw_os_error() == Some(ERROR_ACCESS_DENIED) => continue,
+            Err(e) => return Err(e),
+        };
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(true)
+            .security_qos_flags(SECURITY_IDENTIFICATION);
+        if overlapped_client {
+            options.custom_flags(FILE_FLAG_OVERLAPPED);
+        }
+        let client = options.open(&path)?;
+        return Ok((server, client));
+    }
+}
@@ -0,0 +1,339 @@
+use futures::future::{self, BoxFuture, Either, FutureExt};
+use futures::pin_mut;
+
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::runtime::Handle;
+use crate::time::Sleep;
+
+type ConnectFn<T> = Box<dyn Fn() -> BoxFuture<'static, io::Result<T>> + Send + Sync>;
+type HealthCheckFn<T> = Box<dyn Fn(&mut T) -> bool + Send + Sync>;
+
+/// A pool of reusable connections, such as [crate::stream::AsyncTcpStream]s.
+///
+/// Connections are created on demand by the `connect` function given to [Pool::builder], up to the
+/// pool's maximum size. A connection checked out with [Pool::get] goes back to the pool when the
+/// returned [Pooled] is dropped. Cloning a `Pool` gives another reference to the same pool.
+pub struct Pool<T> {
+    inner: Arc<PoolInner<T>>,
+}
+
+struct PoolInner<T> {
+    connect: ConnectFn<T>,
+    health_check: Option<HealthCheckFn<T>>,
+    max_size: usize,
+    max_idle_time: Option<Duration>,
+    checkout_timeout: Option<Duration>,
+    state: Mutex<PoolState<T>>,
+}
+
+struct PoolState<T> {
+    // Most recently returned last, so the warmest connection is reused first.
+    idle: Vec<IdleConnection<T>>,
+    // Connections that exist or are being connected, whether idle or checked out.
+    size: usize,
+    // Bumped whenever a connection is returned or a slot is freed, so waiters can tell they should
+    // try again.
+    generation: u64,
+    waiters: Vec<Waker>,
+}
+
+struct IdleConnection<T> {
+    connection: T,
+    idle_since: Instant,
+}
+
+/// Configures and creates a [Pool].
+pub struct PoolBuilder<T> {
+    connect: ConnectFn<T>,
+    health_check: Option<HealthCheckFn<T>>,
+    max_size: usize,
+    max_idle_time: Option<Duration>,
+    checkout_timeout: Option<Duration>,
+}
+
+impl<T: Send + 'static> PoolBuilder<T> {
+    /// The maximum number of connections, idle or checked out. Defaults to 10.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Idle connections older than this are closed instead of being handed out. Defaults to 90
+    /// seconds. `None` keeps idle connections forever.
+    pub fn max_idle_time(mut self, max_idle_time: Option<Duration>) -> Self {
+        self.max_idle_time = max_idle_time;
+        self
+    }
+
+    /// How long [Pool::get] waits for a connection, including the time spent connecting, before
+    /// failing with [io::ErrorKind::TimedOut]. Defaults to `None`, which waits forever.
+    pub fn checkout_timeout(mut self, checkout_timeout: Option<Duration>) -> Self {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// Sets a function that is called on an idle connection before it is handed out. If it returns
+    /// false the connection is closed and another one is tried.
+    pub fn health_check<F>(mut self, health_check: F) -> Self
+    where
+        F: Fn(&mut T) -> bool + Send + Sync + 'static,
+    {
+        self.health_check = Some(Box::new(health_check));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the maximum size is zero.
+    pub fn build(self) -> Pool<T> {
+        assert!(
+            self.max_size > 0,
+            "a pool needs room for at least one connection"
+        );
+        Pool {
+            inner: Arc::new(PoolInner {
+                connect: self.connect,
+                health_check: self.health_check,
+                max_size: self.max_size,
+                max_idle_time: self.max_idle_time,
+                checkout_timeout: self.checkout_timeout,
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    size: 0,
+                    generation: 0,
+                    waiters: Vec::new(),
+                }),
+            }),
+        }
+    }
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Starts building a pool whose connections are created by `connect`.
+    pub fn builder<F, Fut>(connect: F) -> PoolBuilder<T>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        PoolBuilder {
+            connect: Box::new(move || connect().boxed()),
+            health_check: None,
+            max_size: 10,
+            max_idle_time: Some(Duration::from_secs(90)),
+            checkout_timeout: None,
+        }
+    }
+
+    /// Checks out a connection, reusing an idle one if there is one, otherwise connecting a new one
+    /// if the pool is not full, otherwise waiting for a connection to be returned.
+    ///
+    /// # Errors
+    ///
+    /// If a checkout timeout is configured, returns an error if called outside the context of a
+    /// [crate::runtime::Runtime].
+    pub async fn get(&self) -> io::Result<Pooled<T>> {
+        let timeout = match self.inner.checkout_timeout {
+            Some(timeout) => timeout,
+            None => return self.checkout().await,
+        };
+        let delay = Sleep::new(&Handle::current_for_io()?, timeout);
+        let checkout = self.checkout();
+        pin_mut!(checkout);
+        match future::select(checkout, delay).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for a pooled connection",
+            )),
+        }
+    }
+
+    /// The number of connections, idle or checked out.
+    pub fn size(&self) -> usize {
+        self.inner.state.lock().unwrap().size
+    }
+
+    /// The number of idle connections.
+    pub fn idle(&self) -> usize {
+        self.inner.state.lock().unwrap().idle.len()
+    }
+
+    async fn checkout(&self) -> io::Result<Pooled<T>> {
+        loop {
+            let next = {
+                let mut state = self.inner.state.lock().unwrap();
+                if let Some(idle) = state.idle.pop() {
+                    Next::Idle(idle)
+                } else if state.size < self.inner.max_size {
+                    state.size += 1;
+                    Next::Connect
+                } else {
+                    Next::Wait(state.generation)
+                }
+            };
+            match next {
+                Next::Idle(mut idle) => {
+                    let expired = match self.inner.max_idle_time {
+                        Some(max) => idle.idle_since.elapsed() > max,
+                        None => false,
+                    };
+                    let healthy = !expired
+                        && match &self.inner.health_check {
+                            Some(check) => check(&mut idle.connection),
+                            None => true,
+                        };
+                    if healthy {
+                        return Ok(Pooled::new(&self.inner, idle.connection));
+                    }
+                    drop(idle);
+                    self.inner.free_slot();
+                }
+                Next::Connect => {
+                    // If this future is dropped while connecting, the guard gives the slot back.
+                    let slot = SlotGuard { pool: &self.inner };
+                    let connection = (self.inner.connect)().await?;
+                    std::mem::forget(slot);
+                    return Ok(Pooled::new(&self.inner, connection));
+                }
+                Next::Wait(generation) => {
+                    ConnectionReleased {
+                        pool: &self.inner,
+                        generation,
+                    }
+                    .await
+                }
+            }
+        }
+    }
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Pool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+enum Next<T> {
+    Idle(IdleConnection<T>),
+    Connect,
+    Wait(u64),
+}
+
+impl<T> PoolInner<T> {
+    fn release(&self, connection: T) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push(IdleConnection {
+            connection,
+            idle_since: Instant::now(),
+        });
+        Self::notify(state);
+    }
+
+    fn free_slot(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.size -= 1;
+        Self::notify(state);
+    }
+
+    fn notify(mut state: std::sync::MutexGuard<'_, PoolState<T>>) {
+        state.generation += 1;
+        // Every waiter is woken, since a waiter that was woken alone may have been dropped by a
+        // checkout timeout without taking the connection.
+        let waiters = std::mem::take(&mut state.waiters);
+        drop(state);
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+struct SlotGuard<'a, T> {
+    pool: &'a PoolInner<T>,
+}
+
+impl<T> Drop for SlotGuard<'_, T> {
+    fn drop(&mut self) {
+        self.pool.free_slot();
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct ConnectionReleased<'a, T> {
+    pool: &'a PoolInner<T>,
+    generation: u64,
+}
+
+impl<T> Future for ConnectionReleased<'_, T> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.pool.state.lock().unwrap();
+        if state.generation != self.generation {
+            Poll::Ready(())
+        } else {
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+/// A connection checked out of a [Pool]. It is returned to the pool when dropped.
+pub struct Pooled<T> {
+    connection: Option<T>,
+    pool: Arc<PoolInner<T>>,
+}
+
+impl<T> Pooled<T> {
+    fn new(pool: &Arc<PoolInner<T>>, connection: T) -> Self {
+        Pooled {
+            connection: Some(connection),
+            pool: pool.clone(),
+        }
+    }
+
+    /// Closes the connection instead of returning it to the pool. Use this when the connection is
+    /// known to be broken, for example after an IO error.
+    pub fn discard(mut self) {
+        self.connection = None;
+        self.pool.free_slot();
+    }
+
+    /// Takes the connection out of the pool for good. The pool no longer counts it against its
+    /// maximum size.
+    pub fn detach(mut self) -> T {
+        let connection = self.connection.take().unwrap();
+        self.pool.free_slot();
+        connection
+    }
+}
+
+impl<T> Deref for Pooled<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.release(connection);
+        }
+    }
+}
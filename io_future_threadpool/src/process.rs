@@ -0,0 +1,343 @@
+//! Child processes whose stdio pipes and exit are waited on by the runtime's threadpool instead of
+//! a thread per stream.
+
+use bindings::Windows::Win32::SystemServices::{
+    CloseThreadpoolWait, CreateThreadpoolWait, SetThreadpoolWait, WaitForThreadpoolWaitCallbacks,
+    HANDLE, TP_CALLBACK_INSTANCE, TP_WAIT,
+};
+
+use std::ffi::{c_void, OsStr};
+use std::future::Future;
+use std::io;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{self, ExitStatus};
+use std::ptr;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+use crate::pipe::{self, AsyncNamedPipeServer};
+use crate::runtime::Handle;
+
+/// What to connect one of the child's standard streams to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stdio {
+    /// The stream of this process.
+    Inherit,
+    /// Nothing: reads see the end of the stream and writes are discarded.
+    Null,
+    /// A pipe to this process, available from [Child].
+    Piped,
+}
+
+impl Stdio {
+    // The std equivalent, and for `Piped`, our end of the pipe.
+    fn to_std(self) -> io::Result<(process::Stdio, Option<AsyncNamedPipeServer>)> {
+        Ok(match self {
+            Stdio::Inherit => (process::Stdio::inherit(), None),
+            Stdio::Null => (process::Stdio::null(), None),
+            Stdio::Piped => {
+                // The child gets an end without overlapped I/O, which is what programs expect.
+                let (server, client) = pipe::unique_pipe(false)?;
+                (process::Stdio::from(client), Some(server))
+            }
+        })
+    }
+}
+
+/// Builds a child process, like [std::process::Command], with its pipes on the current
+/// [crate::runtime::Runtime]. All three streams are inherited unless set otherwise.
+pub struct Command {
+    inner: process::Command,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+}
+
+impl Command {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        Command {
+            inner: process::Command::new(program),
+            stdin: Stdio::Inherit,
+            stdout: Stdio::Inherit,
+            stderr: Stdio::Inherit,
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.inner.args(args);
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Command {
+        self.inner.env(key, val);
+        self
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.inner.env_remove(key);
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    pub fn stdin(&mut self, stdin: Stdio) -> &mut Command {
+        self.stdin = stdin;
+        self
+    }
+
+    pub fn stdout(&mut self, stdout: Stdio) -> &mut Command {
+        self.stdout = stdout;
+        self
+    }
+
+    pub fn stderr(&mut self, stderr: Stdio) -> &mut Command {
+        self.stderr = stderr;
+        self
+    }
+
+    /// The underlying command, for the settings that are not repeated here, such as
+    /// `creation_flags`. Its stdio settings are replaced when spawning.
+    pub fn as_std_mut(&mut self) -> &mut process::Command {
+        &mut self.inner
+    }
+
+    /// Starts the child process.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let (stdin, stdin_pipe) = self.stdin.to_std()?;
+        let (stdout, stdout_pipe) = self.stdout.to_std()?;
+        let (stderr, stderr_pipe) = self.stderr.to_std()?;
+        let spawned = self
+            .inner
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn();
+        // The command holds on to the child's ends of the pipes. Those have to be closed for our
+        // reads to see the end of the stream once the child exits.
+        self.inner
+            .stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit());
+        Ok(Child {
+            stdin: stdin_pipe.map(|inner| ChildStdin { inner }),
+            stdout: stdout_pipe.map(|inner| ChildStdout { inner }),
+            stderr: stderr_pipe.map(|inner| ChildStderr { inner }),
+            child: spawned?,
+        })
+    }
+}
+
+/// A running or exited child process started by [Command::spawn].
+///
+/// Dropping a `Child` does not kill the process.
+pub struct Child {
+    /// Writes to the child's stdin, if it was [Stdio::Piped].
+    pub stdin: Option<ChildStdin>,
+    /// Reads the child's stdout, if it was [Stdio::Piped].
+    pub stdout: Option<ChildStdout>,
+    /// Reads the child's stderr, if it was [Stdio::Piped].
+    pub stderr: Option<ChildStderr>,
+    child: process::Child,
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Terminates the process, if it is still running.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+
+    /// Returns the exit status if the process has exited, without waiting.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    /// Waits for the process to exit with a threadpool wait on the process handle.
+    ///
+    /// The child's stdin is closed first, so a child reading it to the end does not wait for us
+    /// forever. The child can still fill its stdout or stderr pipe and block writing to it, so
+    /// read those while waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+        if let Some(status) = self.child.try_wait()? {
+            return Ok(status);
+        }
+        ProcessExit::new(&Handle::current_for_io()?, &self.child)?.await;
+        self.child.wait()
+    }
+}
+
+impl AsRawHandle for Child {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.child.as_raw_handle()
+    }
+}
+
+/// The child's stdin. Dropping it closes the pipe, which the child sees as the end of its input.
+pub struct ChildStdin {
+    inner: AsyncNamedPipeServer,
+}
+
+impl ChildStdin {
+    /// Writes some of `buf`, and returns the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf).await
+    }
+}
+
+impl AsRawHandle for ChildStdin {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// The child's stdout.
+pub struct ChildStdout {
+    inner: AsyncNamedPipeServer,
+}
+
+impl ChildStdout {
+    /// Reads into `buf`, and returns the number of bytes read, which is 0 once the child and any
+    /// processes it passed its stdout to have exited.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).await
+    }
+}
+
+impl AsRawHandle for ChildStdout {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+/// The child's stderr.
+pub struct ChildStderr {
+    inner: AsyncNamedPipeServer,
+}
+
+impl ChildStderr {
+    /// Reads into `buf`, and returns the number of bytes read, which is 0 once the child and any
+    /// processes it passed its stderr to have exited.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).await
+    }
+}
+
+impl AsRawHandle for ChildStderr {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+// Completes when the process exits. The process handle must stay open until this is dropped.
+struct ProcessExit {
+    wait: *mut TP_WAIT,
+    // Created with Box::into_raw and passed to the wait callback.
+    state: *mut Mutex<ProcessExitState>,
+}
+
+struct ProcessExitState {
+    exited: bool,
+    waker: Option<Waker>,
+}
+
+// The wait is only touched through the threadpool APIs, which are free threaded.
+unsafe impl Send for ProcessExit {}
+unsafe impl Sync for ProcessExit {}
+
+extern "system" fn wait_callback(
+    _instance: *mut TP_CALLBACK_INSTANCE,
+    context: *mut c_void,
+    _wait: *mut TP_WAIT,
+    _wait_result: u32,
+) {
+    // ProcessExit::drop waits for this callback before releasing the state.
+    let state = unsafe { &*(context as *const Mutex<ProcessExitState>) };
+    let waker = {
+        let mut state = state.lock().unwrap();
+        state.exited = true;
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl ProcessExit {
+    fn new(runtime: &Handle, child: &process::Child) -> io::Result<ProcessExit> {
+        let state = Box::into_raw(Box::new(Mutex::new(ProcessExitState {
+            exited: false,
+            waker: None,
+        })));
+        unsafe {
+            let wait = CreateThreadpoolWait(
+                Some(wait_callback),
+                state as *mut c_void,
+                runtime.callback_environment(),
+            );
+            if wait.is_null() {
+                let e = io::Error::last_os_error();
+                drop(Box::from_raw(state));
+                return Err(e);
+            }
+            // A process handle is signaled once the process exits. No timeout.
+            let handle = HANDLE(child.as_raw_handle() as isize);
+            SetThreadpoolWait(wait, handle, ptr::null_mut());
+            Ok(ProcessExit { wait, state })
+        }
+    }
+}
+
+impl Future for ProcessExit {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = unsafe { &*self.state }.lock().unwrap();
+        if state.exited {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ProcessExit {
+    fn drop(&mut self) {
+        unsafe {
+            // Stop waiting and make sure the callback is not running before freeing the state.
+            SetThreadpoolWait(self.wait, HANDLE(0), ptr::null_mut());
+            WaitForThreadpoolWaitCallbacks(self.wait, true);
+            CloseThreadpoolWait(self.wait);
+            drop(Box::from_raw(self.state));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Records the traffic on a stream to a file, so what the peer actually sent can be inspected or
+//! replayed later without an external capture tool.
+//!
+//! # Format
+//!
+//! A recording starts with the 8 byte magic `RWIOREC\0` followed by a little endian `u32` version,
+//! currently 1. Each completed read or write then appends one record, with all integers little
+//! endian:
+//!
+//! | Field     | Type  | Meaning                                                      |
+//! |-----------|-------|--------------------------------------------------------------|
+//! | elapsed   | `u64` | Nanoseconds since the recording started                      |
+//! | direction | `u8`  | 0 for data received, 1 for data sent                         |
+//! | status    | `u8`  | 0 for success, 1 for an OS error, 2 for any other error      |
+//! | code      | `i32` | The OS error code if status is 1, otherwise 0                |
+//! | length    | `u32` | Number of data bytes that follow, 0 for errors and EOF       |
+//! | data      | bytes | The bytes transferred                                        |
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"RWIOREC\0";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// How a recorded operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordedError {
+    /// A Win32 or Winsock error code.
+    Os(i32),
+    /// An error that did not carry an OS error code.
+    Other,
+}
+
+/// One completed read or write.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Time since the recording started.
+    pub elapsed: Duration,
+    pub direction: Direction,
+    /// The bytes transferred. An empty successful receive is end of stream.
+    pub data: Vec<u8>,
+    pub error: Option<RecordedError>,
+}
+
+/// Wraps a stream and appends a [Record] for every completed read and write to a sink.
+///
+/// Records are written synchronously through a `BufWriter` from inside `poll_read` and
+/// `poll_write`. This is meant as a debugging aid, not for production traffic. If writing a record
+/// fails, recording stops but the stream keeps working; see [RecordingStream::recording_error].
+pub struct RecordingStream<T> {
+    inner: T,
+    recorder: Recorder,
+}
+
+struct Recorder {
+    sink: Option<BufWriter<Box<dyn Write + Send>>>,
+    started: Instant,
+    error: Option<io::Error>,
+}
+
+impl<T> RecordingStream<T> {
+    /// Records the traffic on `inner` to a newly created file at `path`.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> io::Result<Self> {
+        Self::new(inner, File::create(path)?)
+    }
+
+    /// Records the traffic on `inner` to `sink`.
+    pub fn new<W: Write + Send + 'static>(inner: T, sink: W) -> io::Result<Self> {
+        let mut sink = BufWriter::new(Box::new(sink) as Box<dyn Write + Send>);
+        sink.write_all(MAGIC)?;
+        sink.write_all(&VERSION.to_le_bytes())?;
+        Ok(RecordingStream {
+            inner,
+            recorder: Recorder {
+                sink: Some(sink),
+                started: Instant::now(),
+                error: None,
+            },
+        })
+    }
+
+    /// The error that stopped the recording, if any.
+    pub fn recording_error(&self) -> Option<&io::Error> {
+        self.recorder.error.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl Recorder {
+    fn record(&mut self, direction: Direction, result: &io::Result<usize>, buf: &[u8]) {
+        let sink = match &mut self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+        let (status, code, data): (u8, i32, &[u8]) = match result {
+            Ok(n) => (0, 0, &buf[..*n]),
+            Err(e) => match e.raw_os_error() {
+                Some(code) => (1, code, &[]),
+                None => (2, 0, &[]),
+            },
+        };
+        let elapsed = self.started.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        let direction: u8 = match direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        };
+        let mut header = Vec::with_capacity(18);
+        header.extend_from_slice(&elapsed.to_le_bytes());
+        header.push(direction);
+        header.push(status);
+        header.extend_from_slice(&code.to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let written = sink.write_all(&header).and_then(|_| sink.write_all(data));
+        if let Err(e) = written {
+            self.sink = None;
+            self.error = Some(e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = sink.flush() {
+                self.sink = None;
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordingStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        this.recorder.record(Direction::Received, &result, buf);
+        Poll::Ready(result)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RecordingStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        this.recorder.record(Direction::Sent, &result, buf);
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.recorder.flush();
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.recorder.flush();
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+/// Reads back the records written by a [RecordingStream].
+pub struct RecordingReader<R> {
+    source: R,
+}
+
+impl RecordingReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Checks the header of the recording in `source`.
+    pub fn new(mut source: R) -> io::Result<Self> {
+        let mut header = [0u8; 12];
+        source.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a stream recording",
+            ));
+        }
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported recording version {}", version),
+            ));
+        }
+        Ok(RecordingReader { source })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 18];
+        // A clean end of file between records is the end of the recording.
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.source.read(&mut header[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        let elapsed = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            _ => return Err(invalid_record()),
+        };
+        let code = i32::from_le_bytes(header[10..14].try_into().unwrap());
+        let error = match header[9] {
+            0 => None,
+            1 => Some(RecordedError::Os(code)),
+            2 => Some(RecordedError::Other),
+            _ => return Err(invalid_record()),
+        };
+        let len = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let mut data = vec![0; len as usize];
+        self.source.read_exact(&mut data)?;
+        Ok(Some(Record {
+            elapsed: Duration::from_nanos(elapsed),
+            direction,
+            data,
+            error,
+        }))
+    }
+}
+
+fn invalid_record() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt stream recording")
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = io::Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
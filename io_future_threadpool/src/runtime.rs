@@ -0,0 +1,468 @@
+use bindings::Windows::Win32::FileSystem::CancelIoEx;
+use bindings::Windows::Win32::SystemServices::{
+    CloseThreadpool, CreateThreadpool, TrySubmitThreadpoolCallback, PTP_POOL,
+    TP_CALLBACK_ENVIRON_V3, TP_CALLBACK_ENVIRON_V3_1, TP_CALLBACK_INSTANCE, TP_CALLBACK_PRIORITY,
+};
+
+use futures::channel::oneshot;
+use futures::executor;
+use futures::future::{self, BoxFuture, Either};
+use futures::task::{waker_ref, ArcWake};
+use futures::FutureExt;
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::windows::io::RawSocket;
+use std::panic::{self, catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancellation::CancellationToken;
+use crate::metrics::{MetricsCounters, RuntimeMetrics, TaskCounters, TaskMetrics};
+use crate::signal;
+use crate::time::Sleep;
+use crate::wsa_functions::WsaFunctions;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+/// Runs futures on the Windows threadpool. Each time a task is woken, a work item is submitted to the
+/// threadpool to poll it, so tasks and I/O completions share the same threads.
+///
+/// Each runtime has its own private threadpool and its own copy of any state that would otherwise be
+/// process wide, so several runtimes can coexist in one process (for example one per plugin DLL)
+/// without interfering with each other. The threadpool is released once the runtime and every
+/// [Handle] to it have been dropped.
+pub struct Runtime {
+    handle: Handle,
+}
+
+/// A cloneable reference to a [Runtime]. It can be sent to threads the runtime does not own, such as
+/// a GUI thread or a COM callback, and used to spawn work from there.
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: PTP_POOL,
+    // Objects created with this environment run their callbacks on `pool`.
+    callback_environment: TP_CALLBACK_ENVIRON_V3,
+    wsa_functions: WsaFunctions,
+    shutdown: CancellationToken,
+    connections: Mutex<Connections>,
+    metrics: MetricsCounters,
+}
+
+/// The connected sockets owned by a runtime, tracked so shutdown can wait for them to close.
+struct Connections {
+    sockets: HashSet<RawSocket>,
+    // Woken when `sockets` becomes empty.
+    drained_wakers: Vec<Waker>,
+}
+
+// The threadpool APIs are free threaded; the raw pointers in the callback environment are only read.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // If work or I/O objects are still bound to the pool, it is released after they are closed.
+        unsafe {
+            CloseThreadpool(self.pool);
+        }
+    }
+}
+
+impl Runtime {
+    pub fn new() -> io::Result<Runtime> {
+        let pool = unsafe { CreateThreadpool(ptr::null_mut()) };
+        if pool.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // This is what the inline InitializeThreadpoolEnvironment and SetThreadpoolCallbackPool
+        // functions from winbase.h do.
+        let callback_environment = TP_CALLBACK_ENVIRON_V3 {
+            Version: 3,
+            Pool: pool,
+            CleanupGroup: 0,
+            CleanupGroupCancelCallback: None,
+            RaceDll: ptr::null_mut(),
+            ActivationContext: 0,
+            FinalizationCallback: None,
+            u: TP_CALLBACK_ENVIRON_V3_1 { Flags: 0 },
+            CallbackPriority: TP_CALLBACK_PRIORITY::TP_CALLBACK_PRIORITY_NORMAL,
+            Size: mem::size_of::<TP_CALLBACK_ENVIRON_V3>() as u32,
+        };
+        Ok(Runtime {
+            handle: Handle {
+                inner: Arc::new(Inner {
+                    pool,
+                    callback_environment,
+                    wsa_functions: WsaFunctions::new(),
+                    shutdown: CancellationToken::new(),
+                    connections: Mutex::new(Connections {
+                        sockets: HashSet::new(),
+                        drained_wakers: Vec::new(),
+                    }),
+                    metrics: MetricsCounters::default(),
+                }),
+            },
+        })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(future)
+    }
+
+    pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.handle.spawn_blocking(f)
+    }
+
+    /// See [Handle::shutdown_on_ctrl_c].
+    pub async fn shutdown_on_ctrl_c(&self, grace_period: Duration) -> io::Result<()> {
+        self.handle.shutdown_on_ctrl_c(grace_period).await
+    }
+
+    /// Runs a future to completion on the current thread. [Handle::current] works inside the future.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let _guard = self.handle.enter();
+        executor::block_on(future)
+    }
+}
+
+impl Handle {
+    /// Returns the handle of the runtime that is running the current task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a task spawned on a [Runtime] or [Runtime::block_on].
+    pub fn current() -> Handle {
+        Handle::try_current().expect("must be called from the context of a Runtime")
+    }
+
+    /// Returns the handle of the runtime that is running the current task, if any.
+    pub fn try_current() -> Option<Handle> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Like [Handle::current], but for I/O functions that already return [io::Result]: outside of
+    /// a runtime this is an error rather than a panic.
+    pub(crate) fn current_for_io() -> io::Result<Handle> {
+        Handle::try_current()
+            .ok_or_else(|| io::Error::other("must be called from the context of a Runtime"))
+    }
+
+    /// Spawns a future onto the runtime. The task runs to completion even if the returned
+    /// [JoinHandle] is dropped.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let metrics = Arc::new(TaskCounters::default());
+        self.inner.metrics.task_spawned();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(
+                async move {
+                    // A panic is handed to the JoinHandle, which resumes it. The future is dropped
+                    // at the end of this statement, before the JoinHandle can see the result.
+                    let result = AssertUnwindSafe(future).catch_unwind().await;
+                    let _ = tx.send(result);
+                }
+                .boxed(),
+            )),
+            handle: self.clone(),
+            scheduled: AtomicBool::new(false),
+            metrics: metrics.clone(),
+        });
+        task.schedule();
+        JoinHandle { rx, metrics }
+    }
+
+    /// Runs a blocking function on the threadpool. Use this for work that would otherwise tie up the
+    /// thread polling a task, such as a synchronous Win32 API call.
+    pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let metrics = Arc::new(TaskCounters::default());
+        let work_metrics = metrics.clone();
+        let handle = self.clone();
+        let work: WorkItem = Box::new(move || {
+            let _guard = handle.enter();
+            let started = work_metrics.poll_started();
+            let result = catch_unwind(AssertUnwindSafe(f));
+            work_metrics.poll_finished(started);
+            // A panic is handed to the JoinHandle, which resumes it.
+            let _ = tx.send(result);
+        });
+        metrics.scheduled();
+        self.submit_work(Box::new(work));
+        JoinHandle { rx, metrics }
+    }
+
+    /// The root of this runtime's cancellation token tree. It is cancelled when the runtime starts
+    /// shutting down; listeners stop accepting at that point, and tasks can use it (or a
+    /// [CancellationToken::child_token] of it) to notice they should finish up.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.inner.shutdown.clone()
+    }
+
+    /// Waits for CTRL+C and then shuts down gracefully:
+    ///
+    /// 1. The [Handle::shutdown_token] is cancelled, which stops every listener on this runtime.
+    /// 2. Connections are given up to `grace_period` to close on their own.
+    /// 3. Any I/O still pending on the remaining connections is cancelled, so the tasks serving them
+    ///    see `ERROR_OPERATION_ABORTED` errors and can exit.
+    ///
+    /// The returned future completes after the last step. It is typically raced against, or awaited
+    /// after spawning, the server's accept loop.
+    pub async fn shutdown_on_ctrl_c(&self, grace_period: Duration) -> io::Result<()> {
+        signal::ctrl_c().await?;
+        self.inner.shutdown.cancel();
+        let drained = ConnectionsDrained { runtime: self };
+        let grace = Sleep::new(self, grace_period);
+        if let Either::Right(..) = future::select(drained, grace).await {
+            self.cancel_connection_io();
+        }
+        Ok(())
+    }
+
+    /// Registers a connected socket so that shutdown can wait for it. The socket stays registered
+    /// until the returned guard is dropped.
+    pub(crate) fn track_connection(&self, sock: RawSocket) -> ConnectionGuard {
+        self.inner.connections.lock().unwrap().sockets.insert(sock);
+        ConnectionGuard {
+            runtime: self.clone(),
+            sock,
+        }
+    }
+
+    fn cancel_connection_io(&self) {
+        let connections = self.inner.connections.lock().unwrap();
+        for sock in connections.sockets.iter() {
+            // This fails with ERROR_NOT_FOUND if there is no pending I/O, which is fine.
+            unsafe {
+                CancelIoEx(*sock, ptr::null_mut());
+            }
+        }
+    }
+
+    /// Returns a snapshot of this runtime's counters.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        self.inner.metrics.snapshot()
+    }
+
+    pub(crate) fn metrics_counters(&self) -> &MetricsCounters {
+        &self.inner.metrics
+    }
+
+    /// The callback environment to pass to `CreateThreadpool*` functions so their callbacks run on
+    /// this runtime's threadpool.
+    pub(crate) fn callback_environment(&self) -> *mut TP_CALLBACK_ENVIRON_V3 {
+        &self.inner.callback_environment as *const TP_CALLBACK_ENVIRON_V3 as *mut _
+    }
+
+    pub(crate) fn wsa_functions(&self) -> &WsaFunctions {
+        &self.inner.wsa_functions
+    }
+
+    /// Queues a closure to run on the threadpool.
+    fn submit_work(&self, work: Box<WorkItem>) {
+        let context = Box::into_raw(work);
+        unsafe {
+            if !TrySubmitThreadpoolCallback(
+                Some(work_callback),
+                context as *mut std::ffi::c_void,
+                self.callback_environment(),
+            )
+            .as_bool()
+            {
+                drop(Box::from_raw(context));
+                panic!(
+                    "failed to submit work to the threadpool: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    /// Makes this the [Handle::current] handle until the returned guard is dropped.
+    fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+        EnterGuard { previous }
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    runtime: Handle,
+    sock: RawSocket,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut connections = self.runtime.inner.connections.lock().unwrap();
+            connections.sockets.remove(&self.sock);
+            if connections.sockets.is_empty() {
+                mem::take(&mut connections.drained_wakers)
+            } else {
+                Vec::new()
+            }
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Completes when a runtime has no connections left.
+struct ConnectionsDrained<'a> {
+    runtime: &'a Handle,
+}
+
+impl Future for ConnectionsDrained<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut connections = self.runtime.inner.connections.lock().unwrap();
+        if connections.sockets.is_empty() {
+            Poll::Ready(())
+        } else {
+            connections.drained_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+struct EnterGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Resolves to the output of a task spawned with [Handle::spawn] or [Handle::spawn_blocking].
+///
+/// # Panics
+///
+/// Polling a `JoinHandle` resumes the panic of the task it refers to, if it panicked, with the
+/// task's original payload.
+pub struct JoinHandle<T> {
+    rx: oneshot::Receiver<thread::Result<T>>,
+    metrics: Arc<TaskCounters>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the scheduling statistics of the task so far. This can be used to find tasks that
+    /// block the threadpool for a long time in a single poll, or that wait a long time to be polled
+    /// after being woken.
+    pub fn metrics(&self) -> TaskMetrics {
+        self.metrics.snapshot()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.rx.poll_unpin(cx) {
+            Poll::Ready(Ok(Ok(value))) => Poll::Ready(value),
+            Poll::Ready(Ok(Err(payload))) => panic::resume_unwind(payload),
+            Poll::Ready(Err(oneshot::Canceled)) => {
+                panic!("the spawned task was dropped before it completed")
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+struct Task {
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    handle: Handle,
+    // Set while a work item to poll this task is queued, so multiple wakes only queue one poll.
+    scheduled: AtomicBool,
+    metrics: Arc<TaskCounters>,
+}
+
+impl Task {
+    fn schedule(self: &Arc<Self>) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.metrics.scheduled();
+        let task = self.clone();
+        self.handle
+            .submit_work(Box::new(Box::new(move || task.run())));
+    }
+
+    fn run(self: Arc<Self>) {
+        self.scheduled.store(false, Ordering::Release);
+        let _guard = self.handle.enter();
+        let mut future_slot = self.future.lock().unwrap();
+        if let Some(mut future) = future_slot.take() {
+            let waker = waker_ref(&self);
+            let mut cx = Context::from_waker(&waker);
+            let started = self.metrics.poll_started();
+            let result = catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx)));
+            self.metrics.poll_finished(started);
+            // If the future completes or panics it is dropped here. The spawned future's own panics
+            // are caught inside it and sent to the JoinHandle, so a panic here drops the sender,
+            // which the JoinHandle reports instead.
+            if let Ok(Poll::Pending) = result {
+                *future_slot = Some(future);
+            } else {
+                self.handle.inner.metrics.task_finished();
+            }
+        }
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.schedule();
+    }
+}
+
+type WorkItem = Box<dyn FnOnce() + Send>;
+
+extern "system" fn work_callback(
+    _instance: *mut TP_CALLBACK_INSTANCE,
+    context: *mut std::ffi::c_void,
+) {
+    // Spawned tasks and blocking functions catch their own panics and hand them to their
+    // JoinHandle, so a panic only gets here from the runtime's own bookkeeping around them. The
+    // panic hook has reported it, and unwinding into the threadpool would abort the process, so it
+    // is only dropped here.
+    let _ = catch_unwind(|| unsafe {
+        let work = Box::from_raw(context as *mut WorkItem);
+        work();
+    });
+}
@@ -0,0 +1,305 @@
+use bindings::Windows::Win32::SystemServices::{
+    GetCommState, SetCommMask, SetCommState, SetCommTimeouts, WaitCommEvent, COMMTIMEOUTS,
+    COMM_EVENT_MASK, DCB, HANDLE,
+};
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::BitOr;
+use std::os::windows::fs::OpenOptionsExt;
+
+use crate::handle::AsyncOverlappedHandle;
+
+/// A serial port (COM port) opened for overlapped I/O on the current [crate::runtime::Runtime].
+pub struct AsyncSerialPort {
+    file: AsyncOverlappedHandle<File>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF.
+    Software,
+    /// RTS/CTS.
+    Hardware,
+}
+
+/// The line settings of a serial port. The subset of `DCB` most devices need.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    /// Usually 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+/// Read and write timeouts, with the same meaning as the fields of `COMMTIMEOUTS`. All values are
+/// in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SerialTimeouts {
+    pub read_interval: u32,
+    pub read_total_multiplier: u32,
+    pub read_total_constant: u32,
+    pub write_total_multiplier: u32,
+    pub write_total_constant: u32,
+}
+
+/// A set of serial port events, used with [AsyncSerialPort::wait_for_event].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialEvents(u32);
+
+impl SerialEvents {
+    /// A character was received.
+    pub const RX_CHAR: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_RXCHAR.0);
+    /// The last character in the output buffer was sent.
+    pub const TX_EMPTY: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_TXEMPTY.0);
+    /// The CTS (clear-to-send) signal changed state.
+    pub const CTS: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_CTS.0);
+    /// The DSR (data-set-ready) signal changed state.
+    pub const DSR: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_DSR.0);
+    /// The RLSD (receive-line-signal-detect, also called carrier detect) signal changed state.
+    pub const RLSD: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_RLSD.0);
+    /// A ring indicator was detected.
+    pub const RING: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_RING.0);
+    /// A break was detected on input.
+    pub const BREAK: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_BREAK.0);
+    /// A line-status error occurred.
+    pub const ERR: SerialEvents = SerialEvents(COMM_EVENT_MASK::EV_ERR.0);
+
+    pub fn contains(self, other: SerialEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for SerialEvents {
+    type Output = SerialEvents;
+    fn bitor(self, rhs: SerialEvents) -> SerialEvents {
+        SerialEvents(self.0 | rhs.0)
+    }
+}
+
+// DCB bitfield members, see the DCB documentation for the layout.
+const DCB_BINARY: u32 = 1 << 0;
+const DCB_PARITY: u32 = 1 << 1;
+const DCB_OUTX_CTS_FLOW: u32 = 1 << 2;
+const DCB_OUTX_DSR_FLOW: u32 = 1 << 3;
+const DCB_DTR_CONTROL_SHIFT: u32 = 4;
+const DCB_OUTX: u32 = 1 << 8;
+const DCB_INX: u32 = 1 << 9;
+const DCB_RTS_CONTROL_SHIFT: u32 = 12;
+const DCB_CONTROL_MASK: u32 = 0b11;
+const DTR_CONTROL_ENABLE: u32 = 1;
+const RTS_CONTROL_ENABLE: u32 = 1;
+const RTS_CONTROL_HANDSHAKE: u32 = 2;
+
+impl AsyncSerialPort {
+    /// Opens a serial port such as `COM3`.
+    ///
+    /// The port is configured so that reads complete as soon as any bytes are available instead of
+    /// waiting for the buffer to fill. Use [AsyncSerialPort::set_timeouts] to change that.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn open(port: &str) -> io::Result<AsyncSerialPort> {
+        const FILE_FLAG_OVERLAPPED: u32 = 0x40000000;
+
+        // The device namespace prefix is required for COM10 and above.
+        let path = if port.starts_with(r"\\.\") {
+            port.to_string()
+        } else {
+            format!(r"\\.\{}", port)
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(path)?;
+        let port = AsyncSerialPort {
+            file: AsyncOverlappedHandle::new(file)?,
+        };
+        port.set_timeouts(SerialTimeouts {
+            read_interval: u32::MAX,
+            read_total_multiplier: u32::MAX,
+            read_total_constant: u32::MAX - 1,
+            write_total_multiplier: 0,
+            write_total_constant: 0,
+        })?;
+        Ok(port)
+    }
+
+    fn handle(&self) -> HANDLE {
+        self.file.handle()
+    }
+
+    fn dcb(&self) -> io::Result<DCB> {
+        let mut dcb = DCB {
+            DCBlength: std::mem::size_of::<DCB>() as u32,
+            ..Default::default()
+        };
+        if unsafe { GetCommState(self.handle(), &mut dcb) }.as_bool() {
+            Ok(dcb)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn settings(&self) -> io::Result<SerialSettings> {
+        let dcb = self.dcb()?;
+        let parity = match dcb.Parity {
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => Parity::None,
+        };
+        let stop_bits = match dcb.StopBits {
+            1 => StopBits::OnePointFive,
+            2 => StopBits::Two,
+            _ => StopBits::One,
+        };
+        let flow_control = if dcb._bitfield & DCB_OUTX_CTS_FLOW != 0 {
+            FlowControl::Hardware
+        } else if dcb._bitfield & (DCB_OUTX | DCB_INX) != 0 {
+            FlowControl::Software
+        } else {
+            FlowControl::None
+        };
+        Ok(SerialSettings {
+            baud_rate: dcb.BaudRate,
+            data_bits: dcb.ByteSize,
+            parity,
+            stop_bits,
+            flow_control,
+        })
+    }
+
+    pub fn set_settings(&self, settings: &SerialSettings) -> io::Result<()> {
+        let mut dcb = self.dcb()?;
+        dcb.BaudRate = settings.baud_rate;
+        dcb.ByteSize = settings.data_bits;
+        dcb.Parity = match settings.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        dcb.StopBits = match settings.stop_bits {
+            StopBits::One => 0,
+            StopBits::OnePointFive => 1,
+            StopBits::Two => 2,
+        };
+
+        let mut bits = dcb._bitfield
+            & !(DCB_PARITY
+                | DCB_OUTX_CTS_FLOW
+                | DCB_OUTX_DSR_FLOW
+                | DCB_OUTX
+                | DCB_INX
+                | DCB_CONTROL_MASK << DCB_DTR_CONTROL_SHIFT
+                | DCB_CONTROL_MASK << DCB_RTS_CONTROL_SHIFT);
+        // Windows does not support non-binary mode.
+        bits |= DCB_BINARY;
+        if settings.parity != Parity::None {
+            bits |= DCB_PARITY;
+        }
+        bits |= DTR_CONTROL_ENABLE << DCB_DTR_CONTROL_SHIFT;
+        match settings.flow_control {
+            FlowControl::None => bits |= RTS_CONTROL_ENABLE << DCB_RTS_CONTROL_SHIFT,
+            FlowControl::Software => {
+                bits |= DCB_OUTX | DCB_INX | RTS_CONTROL_ENABLE << DCB_RTS_CONTROL_SHIFT
+            }
+            FlowControl::Hardware => {
+                bits |= DCB_OUTX_CTS_FLOW | RTS_CONTROL_HANDSHAKE << DCB_RTS_CONTROL_SHIFT
+            }
+        }
+        dcb._bitfield = bits;
+
+        if unsafe { SetCommState(self.handle(), &mut dcb) }.as_bool() {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn set_timeouts(&self, timeouts: SerialTimeouts) -> io::Result<()> {
+        let mut timeouts = COMMTIMEOUTS {
+            ReadIntervalTimeout: timeouts.read_interval,
+            ReadTotalTimeoutMultiplier: timeouts.read_total_multiplier,
+            ReadTotalTimeoutConstant: timeouts.read_total_constant,
+            WriteTotalTimeoutMultiplier: timeouts.write_total_multiplier,
+            WriteTotalTimeoutConstant: timeouts.write_total_constant,
+        };
+        if unsafe { SetCommTimeouts(self.handle(), &mut timeouts) }.as_bool() {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut ndx = 0;
+        while ndx < buf.len() {
+            let sent = self.write(&buf[ndx..]).await?;
+            if sent == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "the serial port timed out writing",
+                ));
+            }
+            ndx += sent;
+        }
+        Ok(())
+    }
+
+    /// Waits for one of the `events` to occur, and returns the ones that did. This replaces the
+    /// port's event mask, which also completes any other pending wait with no events.
+    pub async fn wait_for_event(&self, events: SerialEvents) -> io::Result<SerialEvents> {
+        let handle = self.handle();
+        if !unsafe { SetCommMask(handle, COMM_EVENT_MASK(events.0)) }.as_bool() {
+            return Err(io::Error::last_os_error());
+        }
+        // WaitCommEvent writes the mask when the operation completes, so it must not move.
+        let mut occurred = Box::new(COMM_EVENT_MASK(0));
+        let occurred_ptr: *mut COMM_EVENT_MASK = &mut *occurred;
+        let ret = self
+            .file
+            .submit(|overlapped| unsafe {
+                if WaitCommEvent(handle, occurred_ptr, overlapped).as_bool() {
+                    Some(0)
+                } else {
+                    None
+                }
+            })
+            .await;
+        ret.get_number_of_bytes_transferred()?;
+        Ok(SerialEvents(occurred.0))
+    }
+}
@@ -0,0 +1,82 @@
+use bindings::Windows::Win32::SystemServices::{SetConsoleCtrlHandler, BOOL};
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+
+// Console control handlers are process wide, so unlike most state in this crate this is not owned by a
+// Runtime.
+struct CtrlCState {
+    // Number of CTRL+C or CTRL+BREAK events received so far.
+    events: u64,
+    wakers: Vec<Waker>,
+}
+
+static STATE: Mutex<CtrlCState> = Mutex::new(CtrlCState {
+    events: 0,
+    wakers: Vec::new(),
+});
+
+const CTRL_C_EVENT: u32 = 0;
+const CTRL_BREAK_EVENT: u32 = 1;
+
+extern "system" fn console_ctrl_handler(ctrl_type: u32) -> BOOL {
+    if ctrl_type != CTRL_C_EVENT && ctrl_type != CTRL_BREAK_EVENT {
+        // Let the next handler (ultimately the default one) deal with close, logoff, and shutdown.
+        return BOOL::from(false);
+    }
+    let wakers = {
+        let mut state = STATE.lock().unwrap();
+        state.events += 1;
+        std::mem::take(&mut state.wakers)
+    };
+    for waker in wakers {
+        waker.wake();
+    }
+    BOOL::from(true)
+}
+
+fn register_handler() -> io::Result<()> {
+    // Holds the error code if registration failed.
+    static REGISTERED: OnceLock<Option<i32>> = OnceLock::new();
+    let error = REGISTERED.get_or_init(|| unsafe {
+        if SetConsoleCtrlHandler(Some(console_ctrl_handler), true).as_bool() {
+            None
+        } else {
+            io::Error::last_os_error().raw_os_error()
+        }
+    });
+    match error {
+        Some(code) => Err(io::Error::from_raw_os_error(*code)),
+        None => Ok(()),
+    }
+}
+
+/// Completes the next time CTRL+C or CTRL+BREAK is pressed in the console.
+///
+/// Once this has been called the default behavior of terminating the process on CTRL+C is disabled.
+pub async fn ctrl_c() -> io::Result<()> {
+    register_handler()?;
+    let events = STATE.lock().unwrap().events;
+    CtrlC { events }.await;
+    Ok(())
+}
+
+struct CtrlC {
+    events: u64,
+}
+
+impl Future for CtrlC {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = STATE.lock().unwrap();
+        if state.events != self.events {
+            Poll::Ready(())
+        } else {
+            state.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use bindings::Windows::Win32::WinSock::{SOCKADDR, SOCKADDR_IN, SOCKADDR_IN6, SOCKADDR_STORAGE};
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 23;
+
+/// A `SocketAddr` in the layout Winsock expects, for passing to APIs that take a `SOCKADDR*`.
+pub(crate) struct RawSocketAddr {
+    storage: SOCKADDR_STORAGE,
+    len: i32,
+}
+
+impl RawSocketAddr {
+    pub(crate) fn new(addr: &SocketAddr) -> Self {
+        let mut storage = SOCKADDR_STORAGE::default();
+        let len = match addr {
+            SocketAddr::V4(addr) => unsafe {
+                let sin = &mut *(&mut storage as *mut SOCKADDR_STORAGE as *mut SOCKADDR_IN);
+                sin.sin_family = AF_INET;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.S_un.S_addr = u32::from_ne_bytes(addr.ip().octets());
+                mem::size_of::<SOCKADDR_IN>()
+            },
+            SocketAddr::V6(addr) => unsafe {
+                let sin6 = &mut *(&mut storage as *mut SOCKADDR_STORAGE as *mut SOCKADDR_IN6);
+                sin6.sin6_family = AF_INET6;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo().to_be();
+                sin6.sin6_addr.u.Byte = addr.ip().octets();
+                sin6.Anonymous.sin6_scope_id = addr.scope_id();
+                mem::size_of::<SOCKADDR_IN6>()
+            },
+        };
+        RawSocketAddr {
+            storage,
+            len: len as i32,
+        }
+    }
+
+    /// Space for Winsock to write an address of either family into, such as the source address
+    /// of a datagram received with `WSARecvFrom`.
+    pub(crate) fn empty() -> Self {
+        RawSocketAddr {
+            storage: SOCKADDR_STORAGE::default(),
+            len: mem::size_of::<SOCKADDR_STORAGE>() as i32,
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const SOCKADDR {
+        &self.storage as *const SOCKADDR_STORAGE as *const SOCKADDR
+    }
+
+    pub(crate) fn len(&self) -> i32 {
+        self.len
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut SOCKADDR {
+        &mut self.storage as *mut SOCKADDR_STORAGE as *mut SOCKADDR
+    }
+
+    /// Where Winsock writes the length of the address it wrote to [RawSocketAddr::as_mut_ptr].
+    pub(crate) fn len_mut_ptr(&mut self) -> *mut i32 {
+        &mut self.len
+    }
+
+    pub(crate) fn to_socket_addr(&self) -> io::Result<SocketAddr> {
+        unsafe { to_socket_addr(self.as_ptr(), self.len) }
+    }
+}
+
+/// Reads a `SocketAddr` out of a `SOCKADDR` that Winsock filled in.
+///
+/// # Safety
+///
+/// `addr` must point to at least `len` readable bytes.
+pub(crate) unsafe fn to_socket_addr(addr: *const SOCKADDR, len: i32) -> io::Result<SocketAddr> {
+    let len = len as usize;
+    if len >= mem::size_of::<u16>() {
+        match (*addr).sa_family {
+            AF_INET if len >= mem::size_of::<SOCKADDR_IN>() => {
+                let sin = &*(addr as *const SOCKADDR_IN);
+                let ip = Ipv4Addr::from(sin.sin_addr.S_un.S_addr.to_ne_bytes());
+                return Ok(SocketAddr::V4(SocketAddrV4::new(
+                    ip,
+                    u16::from_be(sin.sin_port),
+                )));
+            }
+            AF_INET6 if len >= mem::size_of::<SOCKADDR_IN6>() => {
+                let sin6 = &*(addr as *const SOCKADDR_IN6);
+                return Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.u.Byte),
+                    u16::from_be(sin6.sin6_port),
+                    u32::from_be(sin6.sin6_flowinfo),
+                    sin6.Anonymous.sin6_scope_id,
+                )));
+            }
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unsupported socket address",
+    ))
+}
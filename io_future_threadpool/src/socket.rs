@@ -0,0 +1,307 @@
+//! A TCP socket that has not connected or started listening yet, for setting options that only
+//! take effect before then.
+
+use bindings::Windows::Win32::WinSock::{bind as wsa_bind, listen as wsa_listen, WSASocketW};
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket, RawSocket};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use crate::iocp_threadpool::Tpio;
+use crate::listener::AsyncTcpListener;
+use crate::sockaddr::RawSocketAddr;
+use crate::sockopt;
+use crate::stream::AsyncTcpStream;
+
+//TODO: this is roughly based on the Socket code from std. Use that directly somehow?
+/// Creates an overlapped, non-inheritable TCP socket of the same address family as `addr`. The
+/// caller owns the returned socket.
+pub(crate) fn new_tcp_socket(addr: &SocketAddr) -> io::Result<RawSocket> {
+    const AF_INET: i32 = 2;
+    const AF_INET6: i32 = 23;
+    const SOCK_STREAM: i32 = 1;
+    const WSA_FLAG_OVERLAPPED: u32 = 1;
+    const WSA_FLAG_NO_HANDLE_INHERIT: u32 = 0x80;
+
+    let fam = match addr {
+        SocketAddr::V4(..) => AF_INET,
+        SocketAddr::V6(..) => AF_INET6,
+    };
+
+    unsafe {
+        let sock = WSASocketW(
+            fam,
+            SOCK_STREAM,
+            sockopt::IPPROTO_TCP,
+            ptr::null_mut(),
+            0,
+            WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT,
+        );
+        if sock == !0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sock as RawSocket)
+        }
+    }
+}
+
+pub(crate) fn bind<S: AsRawSocket>(sock: &S, addr: &SocketAddr) -> io::Result<()> {
+    let addr = RawSocketAddr::new(addr);
+    if unsafe { wsa_bind(sock.as_raw_socket() as usize, addr.as_ptr(), addr.len()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub(crate) fn listen<S: AsRawSocket>(sock: &S, backlog: i32) -> io::Result<()> {
+    if unsafe { wsa_listen(sock.as_raw_socket() as usize, backlog) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Turns on `SIO_LOOPBACK_FAST_PATH`, which lets loopback traffic skip most of the TCP stack. It
+/// must be set before the socket connects or listens, and only helps if both ends set it.
+pub(crate) fn enable_loopback_fast_path<S: AsRawSocket>(sock: &S) -> io::Result<()> {
+    const SIO_LOOPBACK_FAST_PATH: u32 = 0x98000010;
+    sockopt::ioctl(sock, SIO_LOOPBACK_FAST_PATH, 1u32)
+}
+
+/// A TCP socket that is configured before it becomes an [AsyncTcpStream] with
+/// [TcpSocket::connect] or an [AsyncTcpListener] with [TcpSocket::listen].
+///
+/// Some options, such as `SO_REUSEADDR`, `IPV6_V6ONLY` and the buffer sizes a connection's window
+/// scaling is based on, only take effect if they are set before the socket is bound or connected.
+pub struct TcpSocket {
+    // std's TcpStream is only used to own the socket.
+    socket: TcpStream,
+    ipv6: bool,
+    bound: bool,
+}
+
+impl TcpSocket {
+    /// Creates an IPv4 socket.
+    pub fn new_v4() -> io::Result<TcpSocket> {
+        Self::for_addr(&(Ipv4Addr::UNSPECIFIED, 0).into())
+    }
+
+    /// Creates an IPv6 socket. Turn off [TcpSocket::set_only_v6] to also use it for IPv4.
+    pub fn new_v6() -> io::Result<TcpSocket> {
+        Self::for_addr(&(Ipv6Addr::UNSPECIFIED, 0).into())
+    }
+
+    fn for_addr(addr: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = unsafe { TcpStream::from_raw_socket(new_tcp_socket(addr)?) };
+        Ok(TcpSocket {
+            socket,
+            ipv6: addr.is_ipv6(),
+            bound: false,
+        })
+    }
+
+    /// Sets `SO_REUSEADDR`, which lets the socket bind to an address that another socket is
+    /// bound to. On Windows this allows stealing a port from a listener that is still running, so
+    /// it is rarely what a server wants.
+    pub fn set_reuseaddr(&self, reuseaddr: bool) -> io::Result<()> {
+        sockopt::set(
+            &self.socket,
+            sockopt::SOL_SOCKET,
+            sockopt::SO_REUSEADDR,
+            reuseaddr as u32,
+        )
+    }
+
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        let value: u32 = sockopt::get(&self.socket, sockopt::SOL_SOCKET, sockopt::SO_REUSEADDR)?;
+        Ok(value != 0)
+    }
+
+    /// Sets `IPV6_V6ONLY` on an IPv6 socket. Windows defaults it to on; turning it off makes a
+    /// dual-stack socket that also connects to and accepts IPv4 peers, which appear as
+    /// IPv4-mapped IPv6 addresses.
+    pub fn set_only_v6(&self, only_v6: bool) -> io::Result<()> {
+        sockopt::set(
+            &self.socket,
+            sockopt::IPPROTO_IPV6,
+            sockopt::IPV6_V6ONLY,
+            only_v6 as u32,
+        )
+    }
+
+    pub fn only_v6(&self) -> io::Result<bool> {
+        let value: u32 = sockopt::get(&self.socket, sockopt::IPPROTO_IPV6, sockopt::IPV6_V6ONLY)?;
+        Ok(value != 0)
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`).
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        sockopt::set(&self.socket, sockopt::SOL_SOCKET, sockopt::SO_RCVBUF, size)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(&self.socket, sockopt::SOL_SOCKET, sockopt::SO_RCVBUF)
+    }
+
+    /// Sets the size of the socket's send buffer (`SO_SNDBUF`).
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        sockopt::set(&self.socket, sockopt::SOL_SOCKET, sockopt::SO_SNDBUF, size)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(&self.socket, sockopt::SOL_SOCKET, sockopt::SO_SNDBUF)
+    }
+
+    /// Binds the socket to `addr`, which for a connection picks its source address and port.
+    pub fn bind(&mut self, addr: SocketAddr) -> io::Result<()> {
+        bind(&self.socket, &addr)?;
+        self.bound = true;
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Connects to `addr`. If the socket has not been bound, it is bound to the unspecified address
+    /// first, since ConnectEx requires that.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect(mut self, addr: SocketAddr) -> io::Result<AsyncTcpStream> {
+        if !self.bound {
+            let unspecified = if self.ipv6 {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            };
+            self.bind(unspecified)?;
+        }
+        AsyncTcpStream::connect_bound(self.socket, addr, None).await
+    }
+
+    /// Starts listening for connections, with room for `backlog` connections that have not been
+    /// accepted yet. The socket must have been bound.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn listen(self, backlog: u32) -> io::Result<AsyncTcpListener> {
+        listen(&self.socket, backlog.min(i32::MAX as u32) as i32)?;
+        let listener = unsafe { TcpListener::from_raw_socket(self.socket.into_raw_socket()) };
+        AsyncTcpListener::from_listener(listener)
+    }
+}
+
+impl AsRawSocket for TcpSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket.as_raw_socket()
+    }
+}
+
+/// Keeps disconnected sockets so later connections can reuse them instead of creating new ones.
+///
+/// [SocketRecycler::recycle] disconnects a stream with `DisconnectEx(TF_REUSE_SOCKET)` and keeps
+/// its socket, which stays associated with the runtime's completion port. [SocketRecycler::connect]
+/// and [SocketRecycler::accept] hand such a socket to ConnectEx or AcceptEx when one of the right
+/// address family is available, skipping socket creation and completion port association. That
+/// matters for clients and servers with a lot of short-lived connections.
+///
+/// The side that closes a TCP connection first keeps its address in `TIME_WAIT` for a few minutes,
+/// and `DisconnectEx` on that side does not complete until it ends. Recycling works best on the
+/// side whose peer closes first. Cloning a `SocketRecycler` gives another reference to the same
+/// sockets.
+#[derive(Clone)]
+pub struct SocketRecycler {
+    inner: Arc<RecyclerInner>,
+}
+
+struct RecyclerInner {
+    idle: Mutex<Vec<RecycledSocket>>,
+    max_idle: usize,
+}
+
+struct RecycledSocket {
+    stream: Tpio<TcpStream>,
+    ipv6: bool,
+}
+
+impl SocketRecycler {
+    /// Creates a recycler that keeps at most `max_idle` sockets. Sockets recycled beyond that
+    /// are closed.
+    pub fn new(max_idle: usize) -> SocketRecycler {
+        SocketRecycler {
+            inner: Arc::new(RecyclerInner {
+                idle: Mutex::new(Vec::new()),
+                max_idle,
+            }),
+        }
+    }
+
+    /// The number of sockets waiting to be reused.
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Disconnects `stream` gracefully and keeps its socket for reuse. Anything the stream's
+    /// [futures::io::AsyncRead] or [futures::io::AsyncWrite] impls have in flight is cancelled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn recycle(&self, stream: AsyncTcpStream) -> io::Result<()> {
+        let ipv6 = stream.local_addr()?.is_ipv6();
+        let stream = stream.disconnect_for_reuse().await?;
+        let mut idle = self.inner.idle.lock().unwrap();
+        if idle.len() < self.inner.max_idle {
+            idle.push(RecycledSocket { stream, ipv6 });
+        }
+        Ok(())
+    }
+
+    fn take(&self, ipv6: bool) -> Option<RecycledSocket> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        let index = idle.iter().rposition(|socket| socket.ipv6 == ipv6)?;
+        Some(idle.swap_remove(index))
+    }
+
+    /// Connects to `addr`, on a recycled socket if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<AsyncTcpStream> {
+        const WSAEINVAL: i32 = 10022;
+        if let Some(socket) = self.take(addr.is_ipv6()) {
+            let stream = AsyncTcpStream::from_parts(socket.stream)?;
+            match stream.connect_ex(addr, None).await {
+                // A socket that was recycled from an accepted connection may not be reusable for
+                // ConnectEx. Fall back to a new socket.
+                Err(e) if e.raw_os_error() == Some(WSAEINVAL) => {}
+                ret => return ret,
+            }
+        }
+        let socket = if addr.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+        socket.connect(addr).await
+    }
+
+    /// Accepts a connection from `listener`, onto a recycled socket if there is one. Returns the
+    /// connection along with the address of the peer.
+    pub async fn accept(
+        &self,
+        listener: &AsyncTcpListener,
+    ) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        match self.take(listener.local_addr()?.is_ipv6()) {
+            Some(socket) => listener.accept_on(socket.stream).await,
+            None => listener.accept().await,
+        }
+    }
+}
@@ -0,0 +1,550 @@
+//! Listeners and connected stream sockets for the address families std has no types for, such as
+//! `AF_UNIX`, `AF_HYPERV` and `AF_BTH`. The public types in [crate::unix], [crate::hyperv] and
+//! [crate::bluetooth] wrap these and add the family's address type.
+
+use bindings::{
+    Windows::Win32::SystemServices::{OVERLAPPED, PSTR},
+    Windows::Win32::WinSock::{
+        bind as wsa_bind, connect as wsa_connect, shutdown, WSARecv, WSASend, WSASocketW,
+        LPFN_ACCEPTEX, LPFN_CONNECTEX, SOCKADDR, SOCKADDR_STORAGE, WSABUF,
+    },
+};
+
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::net::Shutdown;
+use std::os::windows::io::{AsRawSocket, FromRawSocket, OwnedSocket, RawHandle, RawSocket};
+use std::ptr;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use crate::iocp_threadpool;
+use crate::iocp_threadpool::start_async_io;
+use crate::iocp_threadpool::{start_op, IocpFuture, IocpResult, OverlappedOp, Tpio};
+use crate::runtime::{ConnectionGuard, Handle};
+use crate::socket;
+use crate::sockopt;
+use crate::stream::OwnedBufferOp;
+
+/// Creates an overlapped, non-inheritable stream socket of `family`.
+pub(crate) fn new_socket(family: u16, protocol: i32) -> io::Result<OwnedSocket> {
+    const SOCK_STREAM: i32 = 1;
+    const WSA_FLAG_OVERLAPPED: u32 = 1;
+    const WSA_FLAG_NO_HANDLE_INHERIT: u32 = 0x80;
+
+    unsafe {
+        let sock = WSASocketW(
+            family as i32,
+            SOCK_STREAM,
+            protocol,
+            ptr::null_mut(),
+            0,
+            WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT,
+        );
+        if sock == !0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(OwnedSocket::from_raw_socket(sock as RawSocket))
+        }
+    }
+}
+
+/// Binds `socket` to the first `len` bytes of `addr`, a `SOCKADDR_*` structure.
+pub(crate) fn bind<T>(socket: &OwnedSocket, addr: &T, len: i32) -> io::Result<()> {
+    assert!(len as usize <= mem::size_of::<T>());
+    let addr = addr as *const T as *const SOCKADDR;
+    if unsafe { wsa_bind(socket.as_raw_socket() as usize, addr, len) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// A blocking `connect` of `socket` to the first `len` bytes of `addr`, a `SOCKADDR_*` structure.
+pub(crate) fn connect<T>(socket: &OwnedSocket, addr: &T, len: i32) -> io::Result<()> {
+    assert!(len as usize <= mem::size_of::<T>());
+    let addr = addr as *const T as *const SOCKADDR;
+    if unsafe { wsa_connect(socket.as_raw_socket() as usize, addr, len) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Reads the address of `socket` with `getsockname` or `getpeername` into a `SOCKADDR_*`
+/// structure, and returns it along with the length that was written.
+pub(crate) fn socket_name<T: Copy>(
+    socket: &OwnedSocket,
+    mut addr: T,
+    name_fn: unsafe fn(usize, *mut SOCKADDR, *mut i32) -> i32,
+) -> io::Result<(T, i32)> {
+    let mut len = mem::size_of::<T>() as i32;
+    let rc = unsafe {
+        name_fn(
+            socket.as_raw_socket() as usize,
+            &mut addr as *mut T as *mut SOCKADDR,
+            &mut len,
+        )
+    };
+    if rc == 0 {
+        Ok((addr, len))
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// A socket that is listening for connections, which it accepts with `AcceptEx`.
+pub(crate) struct SocketListener {
+    tp_io: Tpio<OwnedSocket>,
+    family: u16,
+}
+
+impl SocketListener {
+    /// Starts listening on `socket`, which must already be bound, and associates it with the
+    /// runtime's completion port.
+    pub(crate) fn listen(socket: OwnedSocket, family: u16) -> io::Result<SocketListener> {
+        const SOMAXCONN: i32 = 0x7fffffff;
+
+        socket::listen(&socket, SOMAXCONN)?;
+        Ok(SocketListener {
+            tp_io: Tpio::new(socket)?,
+            family,
+        })
+    }
+
+    pub(crate) fn socket(&self) -> &OwnedSocket {
+        self.tp_io.get_ref()
+    }
+
+    /// Accepts a connection onto `socket`, a new socket of the listener's family. `addr_size` is
+    /// the size of the family's `SOCKADDR_*` structure.
+    pub(crate) async fn accept(
+        &self,
+        socket: OwnedSocket,
+        addr_size: usize,
+    ) -> io::Result<SocketStream> {
+        const SO_UPDATE_ACCEPT_CONTEXT: i32 = 0x700B;
+
+        let acceptex = Handle::current_for_io()?
+            .wsa_functions()
+            .acceptex_for_family(self.tp_io.get_ref(), self.family)?;
+        // AcceptEx needs 16 bytes more than the size of each address.
+        let base_accept = iocp_threadpool::base_socket(socket.as_raw_socket());
+        let accept = AcceptExOp::new(acceptex, socket, base_accept, addr_size + 16);
+        let (ret, _, socket) = start_op(&self.tp_io, accept).await;
+        ret?;
+        // Without this getpeername, shutdown and friends fail on the accepted socket.
+        sockopt::set(
+            &socket,
+            sockopt::SOL_SOCKET,
+            SO_UPDATE_ACCEPT_CONTEXT,
+            self.tp_io.get_ref().as_raw_socket() as usize,
+        )?;
+        SocketStream::new(socket)
+    }
+}
+
+/// A connected stream socket whose I/O completes on the runtime's threadpool.
+pub(crate) struct SocketStream {
+    // Declared first so the connection is unregistered from the runtime before the socket closes.
+    _connection: ConnectionGuard,
+    // State for the futures::io trait impls, see AsyncTcpStream. Declared before tp_io so
+    // operations still in flight are cancelled and complete before the socket closes.
+    read: Mutex<OwnedBufferOp>,
+    write: Mutex<OwnedBufferOp>,
+    tp_io: Tpio<OwnedSocket>,
+}
+
+impl SocketStream {
+    /// Associates `socket` with the runtime's completion port.
+    pub(crate) fn new(socket: OwnedSocket) -> io::Result<SocketStream> {
+        let tp_io = Tpio::new(socket)?;
+        let connection = Handle::current_for_io()?.track_connection(tp_io.socket());
+        Ok(SocketStream {
+            _connection: connection,
+            read: Mutex::new(OwnedBufferOp::default()),
+            write: Mutex::new(OwnedBufferOp::default()),
+            tp_io,
+        })
+    }
+
+    /// Connects this stream's socket, which must be bound and not yet connected, to the first
+    /// `len` bytes of `addr` with ConnectEx.
+    pub(crate) async fn connect_ex<T>(
+        self,
+        family: u16,
+        addr: &T,
+        len: i32,
+    ) -> io::Result<SocketStream> {
+        const SO_UPDATE_CONNECT_CONTEXT: i32 = 0x7010;
+
+        assert!(len as usize <= mem::size_of::<T>());
+        let connectex = Handle::current_for_io()?
+            .wsa_functions()
+            .connectex_for_family(self.tp_io.get_ref(), family)?;
+        let connect =
+            unsafe { ConnectExOp::new(connectex, addr as *const T as *const SOCKADDR, len) };
+        start_op(&self.tp_io, connect).await?;
+        // Without this the socket does not know it is connected. The option takes no value.
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            SO_UPDATE_CONNECT_CONTEXT,
+            (),
+        )?;
+        Ok(self)
+    }
+
+    pub(crate) fn socket(&self) -> &OwnedSocket {
+        self.tp_io.get_ref()
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        const SD_RECEIVE: i32 = 0;
+        const SD_SEND: i32 = 1;
+        const SD_BOTH: i32 = 2;
+
+        let how = match how {
+            Shutdown::Read => SD_RECEIVE,
+            Shutdown::Write => SD_SEND,
+            Shutdown::Both => SD_BOTH,
+        };
+        if unsafe { shutdown(self.tp_io.get_ref().as_raw_socket() as usize, how) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub(crate) async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe { self.start_recv(buf.as_mut_ptr(), buf.len()) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    pub(crate) async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let ret = unsafe { self.start_send(buf.as_ptr(), buf.len()) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    pub(crate) async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// The body of `AsyncRead::poll_read` for the wrapping type.
+    pub(crate) fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut read = self.read.lock().unwrap();
+        // The buffer is owned by `read`, which keeps it until the receive completes.
+        read.poll_read(cx, buf, |ptr, len| unsafe { self.start_recv(ptr, len) })
+    }
+
+    /// The body of `AsyncWrite::poll_write` for the wrapping type.
+    pub(crate) fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut write = self.write.lock().unwrap();
+        // The buffer is owned by `write`, which keeps it until the send completes.
+        write.poll_write(cx, buf, |ptr, len| unsafe { self.start_send(ptr, len) })
+    }
+
+    /// Starts a WSASend of `len` bytes at `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay valid until the returned future completes.
+    unsafe fn start_send(&self, buf: *const u8, len: usize) -> IocpFuture {
+        let mut wsabuf = WSABUF {
+            buf: PSTR(buf as *mut u8),
+            len: len.try_into().unwrap(),
+        };
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        start_async_io(&self.tp_io, |overlapped| {
+            let mut sent: u32 = 0;
+            let rc = WSASend(hand, &mut wsabuf, 1, &mut sent, 0, overlapped, Option::None);
+            if rc == 0 {
+                Some(sent as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Starts a WSARecv of up to `len` bytes into `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay valid until the returned future completes.
+    unsafe fn start_recv(&self, buf: *mut u8, len: usize) -> IocpFuture {
+        let mut wsabuf = WSABUF {
+            buf: PSTR(buf),
+            len: len.try_into().unwrap(),
+        };
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        start_async_io(&self.tp_io, |overlapped| {
+            let mut received: u32 = 0;
+            let mut flags: u32 = 0;
+            let rc = WSARecv(
+                hand,
+                &mut wsabuf,
+                1,
+                &mut received,
+                &mut flags,
+                overlapped,
+                Option::None,
+            );
+            if rc == 0 {
+                Some(received as usize)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Implements the parts of a public stream type that only forward to its `inner: SocketStream`
+/// field: `shutdown`, `read`, `write` and `write_all`, [AsRawSocket], `AsSocket`, and the
+/// futures::io traits for the type and for a shared reference to it. The family's module adds the
+/// connect and address methods.
+macro_rules! socket_stream_wrapper {
+    ($stream:ident) => {
+        impl $stream {
+            /// Shuts down the read half, the write half, or both halves of the connection.
+            pub fn shutdown(&self, how: ::std::net::Shutdown) -> ::std::io::Result<()> {
+                self.inner.shutdown(how)
+            }
+
+            /// Reads into `buf`, and returns the number of bytes read, which is 0 once the other
+            /// end has shut down its write half.
+            pub async fn read(&self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                self.inner.read(buf).await
+            }
+
+            /// Writes some of `buf`, and returns the number of bytes written.
+            pub async fn write(&self, buf: &[u8]) -> ::std::io::Result<usize> {
+                self.inner.write(buf).await
+            }
+
+            /// Writes all of `buf`, issuing further sends if one is only partially completed.
+            pub async fn write_all(&self, buf: &[u8]) -> ::std::io::Result<()> {
+                self.inner.write_all(buf).await
+            }
+        }
+
+        impl ::std::os::windows::io::AsRawSocket for $stream {
+            fn as_raw_socket(&self) -> ::std::os::windows::io::RawSocket {
+                ::std::os::windows::io::AsRawSocket::as_raw_socket(self.inner.socket())
+            }
+        }
+
+        impl ::std::os::windows::io::AsSocket for $stream {
+            fn as_socket(&self) -> ::std::os::windows::io::BorrowedSocket<'_> {
+                ::std::os::windows::io::AsSocket::as_socket(self.inner.socket())
+            }
+        }
+
+        impl ::futures::io::AsyncRead for &$stream {
+            fn poll_read(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+                buf: &mut [u8],
+            ) -> ::std::task::Poll<::std::io::Result<usize>> {
+                self.inner.poll_read(cx, buf)
+            }
+        }
+
+        impl ::futures::io::AsyncWrite for &$stream {
+            /// See the [AsyncWrite](futures::io::AsyncWrite) impl of
+            /// [crate::stream::AsyncTcpStream]: if this returns `Pending`, the next call must pass
+            /// the same data.
+            fn poll_write(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+                buf: &[u8],
+            ) -> ::std::task::Poll<::std::io::Result<usize>> {
+                self.inner.poll_write(cx, buf)
+            }
+
+            fn poll_flush(
+                self: ::std::pin::Pin<&mut Self>,
+                _cx: &mut ::std::task::Context<'_>,
+            ) -> ::std::task::Poll<::std::io::Result<()>> {
+                ::std::task::Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(
+                self: ::std::pin::Pin<&mut Self>,
+                _cx: &mut ::std::task::Context<'_>,
+            ) -> ::std::task::Poll<::std::io::Result<()>> {
+                ::std::task::Poll::Ready(self.shutdown(::std::net::Shutdown::Write))
+            }
+        }
+
+        impl ::futures::io::AsyncRead for $stream {
+            fn poll_read(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+                buf: &mut [u8],
+            ) -> ::std::task::Poll<::std::io::Result<usize>> {
+                ::std::pin::Pin::new(&mut &*self).poll_read(cx, buf)
+            }
+        }
+
+        impl ::futures::io::AsyncWrite for $stream {
+            fn poll_write(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+                buf: &[u8],
+            ) -> ::std::task::Poll<::std::io::Result<usize>> {
+                ::std::pin::Pin::new(&mut &*self).poll_write(cx, buf)
+            }
+
+            fn poll_flush(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+            ) -> ::std::task::Poll<::std::io::Result<()>> {
+                ::std::pin::Pin::new(&mut &*self).poll_flush(cx)
+            }
+
+            fn poll_close(
+                self: ::std::pin::Pin<&mut Self>,
+                cx: &mut ::std::task::Context<'_>,
+            ) -> ::std::task::Poll<::std::io::Result<()>> {
+                ::std::pin::Pin::new(&mut &*self).poll_close(cx)
+            }
+        }
+    };
+}
+
+pub(crate) use socket_stream_wrapper;
+
+/// An `AcceptEx` onto a socket of the listening socket's family, without receiving any data.
+/// Resolves to the number of bytes received, which is always 0, the buffer AcceptEx wrote the
+/// local and remote addresses to, and the socket that was accepted onto.
+pub(crate) struct AcceptExOp<S> {
+    acceptex: LPFN_ACCEPTEX,
+    // Owned so it stays open until the operation completes, even if the future is dropped.
+    socket: S,
+    accept: usize,
+    addrs: Vec<u8>,
+    addr_size: u32,
+}
+
+impl<S> AcceptExOp<S> {
+    /// An accept onto `socket`, whose base provider socket is `accept`. `addr_size` is the space
+    /// for each address, which is 16 bytes more than the size of the family's `SOCKADDR_*`
+    /// structure.
+    pub(crate) fn new(
+        acceptex: LPFN_ACCEPTEX,
+        socket: S,
+        accept: RawSocket,
+        addr_size: usize,
+    ) -> AcceptExOp<S> {
+        AcceptExOp {
+            acceptex,
+            socket,
+            accept: accept.try_into().unwrap(),
+            addrs: vec![0; 2 * addr_size],
+            addr_size: addr_size.try_into().unwrap(),
+        }
+    }
+}
+
+unsafe impl<S: Unpin + Send + 'static> OverlappedOp for AcceptExOp<S> {
+    type Output = (io::Result<usize>, Vec<u8>, S);
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        let mut bytes_transferred: u32 = 0;
+        let rc = (self.acceptex)(
+            handle as usize,
+            self.accept,
+            self.addrs.as_mut_ptr() as *mut c_void,
+            0,
+            self.addr_size,
+            self.addr_size,
+            &mut bytes_transferred,
+            overlapped,
+        );
+        if rc.as_bool() {
+            Some(bytes_transferred as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(self, result: IocpResult) -> Self::Output {
+        (
+            result.get_number_of_bytes_transferred(),
+            self.addrs,
+            self.socket,
+        )
+    }
+}
+
+/// A `ConnectEx` of a bound socket, without sending any data. Resolves once the connection is
+/// established.
+pub(crate) struct ConnectExOp {
+    connectex: LPFN_CONNECTEX,
+    // Boxed so the address does not move with the operation.
+    addr: Box<SOCKADDR_STORAGE>,
+    len: i32,
+}
+
+impl ConnectExOp {
+    /// A connect to the `len` bytes of the address at `addr`, which are copied.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to `len` readable bytes.
+    pub(crate) unsafe fn new(
+        connectex: LPFN_CONNECTEX,
+        addr: *const SOCKADDR,
+        len: i32,
+    ) -> ConnectExOp {
+        assert!(len as usize <= mem::size_of::<SOCKADDR_STORAGE>());
+        let mut storage: Box<SOCKADDR_STORAGE> = Box::new(mem::zeroed());
+        ptr::copy_nonoverlapping(
+            addr as *const u8,
+            &mut *storage as *mut SOCKADDR_STORAGE as *mut u8,
+            len as usize,
+        );
+        ConnectExOp {
+            connectex,
+            addr: storage,
+            len,
+        }
+    }
+}
+
+unsafe impl OverlappedOp for ConnectExOp {
+    type Output = io::Result<()>;
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        let rc = (self.connectex)(
+            handle as usize,
+            &*self.addr as *const SOCKADDR_STORAGE as *const SOCKADDR,
+            self.len,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            overlapped,
+        );
+        if rc.as_bool() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    fn complete(self, result: IocpResult) -> Self::Output {
+        result.get_number_of_bytes_transferred().map(|_| ())
+    }
+}
@@ -0,0 +1,186 @@
+use bindings::{
+    Windows::Win32::SystemServices::PSTR,
+    Windows::Win32::WinSock::{getsockopt, setsockopt, WSAIoctl},
+};
+
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::os::windows::io::AsRawSocket;
+use std::ptr;
+use std::time::Duration;
+
+pub(crate) const SOL_SOCKET: i32 = 0xffff;
+pub(crate) const IPPROTO_IP: i32 = 0;
+pub(crate) const IPPROTO_TCP: i32 = 6;
+pub(crate) const IPPROTO_UDP: i32 = 17;
+pub(crate) const IPPROTO_IPV6: i32 = 41;
+
+pub(crate) const SO_REUSEADDR: i32 = 0x0004;
+pub(crate) const SO_EXCLUSIVEADDRUSE: i32 = !SO_REUSEADDR;
+pub(crate) const SO_LINGER: i32 = 0x0080;
+pub(crate) const SO_SNDBUF: i32 = 0x1001;
+pub(crate) const SO_RCVBUF: i32 = 0x1002;
+pub(crate) const TCP_NODELAY: i32 = 1;
+pub(crate) const UDP_SEND_MSG_SIZE: i32 = 2;
+pub(crate) const IP_TOS: i32 = 3;
+pub(crate) const IP_TTL: i32 = 4;
+pub(crate) const IP_PKTINFO: i32 = 19;
+pub(crate) const IPV6_PKTINFO: i32 = 19;
+pub(crate) const IPV6_HOPLIMIT: i32 = 21;
+pub(crate) const IPV6_V6ONLY: i32 = 27;
+pub(crate) const IPV6_TCLASS: i32 = 39;
+pub(crate) const IP_ECN: i32 = 50;
+pub(crate) const IPV6_ECN: i32 = 50;
+
+/// `struct linger` from winsock2.h.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Linger {
+    pub(crate) l_onoff: u16,
+    pub(crate) l_linger: u16,
+}
+
+/// Sets `SO_LINGER`, or turns lingering off if `linger` is `None`. Only whole seconds are kept.
+pub(crate) fn set_linger<S: AsRawSocket>(sock: &S, linger: Option<Duration>) -> io::Result<()> {
+    let value = match linger {
+        Some(timeout) => Linger {
+            l_onoff: 1,
+            l_linger: timeout.as_secs().min(u16::MAX as u64) as u16,
+        },
+        None => Linger::default(),
+    };
+    set(sock, SOL_SOCKET, SO_LINGER, value)
+}
+
+pub(crate) fn linger<S: AsRawSocket>(sock: &S) -> io::Result<Option<Duration>> {
+    let value: Linger = get(sock, SOL_SOCKET, SO_LINGER)?;
+    if value.l_onoff == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_secs(value.l_linger as u64)))
+    }
+}
+
+/// Sets `IP_TOS`, the type of service byte of IPv4 packets sent from the socket.
+pub(crate) fn set_tos<S: AsRawSocket>(sock: &S, tos: u32) -> io::Result<()> {
+    set(sock, IPPROTO_IP, IP_TOS, tos)
+}
+
+pub(crate) fn tos<S: AsRawSocket>(sock: &S) -> io::Result<u32> {
+    get(sock, IPPROTO_IP, IP_TOS)
+}
+
+/// Sets `IPV6_TCLASS`, the traffic class of IPv6 packets sent from the socket.
+pub(crate) fn set_traffic_class<S: AsRawSocket>(sock: &S, tclass: u32) -> io::Result<()> {
+    set(sock, IPPROTO_IPV6, IPV6_TCLASS, tclass)
+}
+
+pub(crate) fn traffic_class<S: AsRawSocket>(sock: &S) -> io::Result<u32> {
+    get(sock, IPPROTO_IPV6, IPV6_TCLASS)
+}
+
+/// Sets a socket option whose value is a plain-old-data type, such as an `i32` or a `u32`.
+pub(crate) fn set<S, T>(sock: &S, level: i32, name: i32, value: T) -> io::Result<()>
+where
+    S: AsRawSocket,
+    T: Copy,
+{
+    let mut value = value;
+    unsafe {
+        let rc = setsockopt(
+            sock.as_raw_socket() as usize,
+            level,
+            name,
+            PSTR(&mut value as *mut T as *mut u8),
+            mem::size_of::<T>() as i32,
+        );
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Gets a socket option whose value is a plain-old-data type, such as an `i32` or a `u32`.
+pub(crate) fn get<S, T>(sock: &S, level: i32, name: i32) -> io::Result<T>
+where
+    S: AsRawSocket,
+    T: Copy + Default,
+{
+    let mut value = T::default();
+    let mut len = mem::size_of::<T>() as i32;
+    unsafe {
+        let rc = getsockopt(
+            sock.as_raw_socket() as usize,
+            level,
+            name,
+            PSTR(&mut value as *mut T as *mut u8),
+            &mut len,
+        );
+        if rc == 0 {
+            Ok(value)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Issues a socket ioctl whose input is a plain-old-data type and which returns no output, such as
+/// `SIO_KEEPALIVE_VALS`.
+pub(crate) fn ioctl<S, T>(sock: &S, code: u32, value: T) -> io::Result<()>
+where
+    S: AsRawSocket,
+    T: Copy,
+{
+    let mut value = value;
+    let mut bytes_returned: u32 = 0;
+    let rc = unsafe {
+        WSAIoctl(
+            sock.as_raw_socket() as usize,
+            code,
+            &mut value as *mut T as *mut c_void,
+            mem::size_of::<T>() as u32,
+            ptr::null_mut(),
+            0,
+            &mut bytes_returned,
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Issues a socket ioctl that takes no input and returns a plain-old-data type, such as
+/// `SIOCATMARK`.
+pub(crate) fn ioctl_get<S, T>(sock: &S, code: u32) -> io::Result<T>
+where
+    S: AsRawSocket,
+    T: Copy + Default,
+{
+    let mut value = T::default();
+    let mut bytes_returned: u32 = 0;
+    let rc = unsafe {
+        WSAIoctl(
+            sock.as_raw_socket() as usize,
+            code,
+            ptr::null_mut(),
+            0,
+            &mut value as *mut T as *mut c_void,
+            mem::size_of::<T>() as u32,
+            &mut bytes_returned,
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if rc == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -1,73 +1,1178 @@
 use bindings::{
-    Windows::Win32::SystemServices::PSTR,
-    Windows::Win32::WinSock::{WSARecv, WSASend, WSABUF},
+    Windows::Win32::FileSystem::CancelIoEx,
+    Windows::Win32::SystemServices::{HANDLE, OVERLAPPED, PSTR},
+    Windows::Win32::WinSock::{WSAIoctl, WSARecv, WSASend, WSABUF},
 };
 
+use futures::executor;
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::ready;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use std::collections::VecDeque;
 use std::convert::TryInto;
-use std::io;
-use std::net::TcpStream;
-use std::net::ToSocketAddrs;
-use std::os::windows::io::AsRawSocket;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::net::{Shutdown, TcpStream};
+use std::os::windows::io::{
+    AsRawHandle, AsRawSocket, AsSocket, BorrowedSocket, FromRawSocket, RawHandle, RawSocket,
+};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
+use crate::buf::{IoBuf, IoBufMut};
 use crate::iocp_threadpool;
-use crate::iocp_threadpool::start_async_io;
-use crate::iocp_threadpool::Tpio;
+use crate::iocp_threadpool::{start_async_io, start_async_io_with_flags};
+use crate::iocp_threadpool::{start_op, IocpFuture, IocpResult, OpFuture, OverlappedOp, Tpio};
+use crate::metrics::{IoDirection, StreamCounters, StreamMetrics};
+use crate::runtime::{ConnectionGuard, Handle};
+use crate::sockaddr::RawSocketAddr;
+use crate::socket;
+use crate::socket_stream::ConnectExOp;
+use crate::sockopt;
+use crate::time::Sleep;
+
+/// Receive out-of-band data, or on output, the data received was out-of-band.
+pub const MSG_OOB: u32 = 1;
+/// Look at incoming data without removing it from the receive queue.
+pub const MSG_PEEK: u32 = 2;
+/// On output, the buffer filled before the end of the message; the rest follows in later reads.
+pub const MSG_PARTIAL: u32 = 0x8000;
 
+/// A TCP connection whose I/O completes on the runtime's threadpool.
+///
+/// Reads and writes only need `&self`, and every operation has its own `OVERLAPPED`, so one task
+/// can read while another writes, for example by sharing the stream through an `Arc`. The
+/// [AsyncRead] and [AsyncWrite] impls are also provided for `&AsyncTcpStream` for this purpose.
+/// Each direction has a single slot for the operation those impls have in flight, so at most one
+/// task should read and one task write through them at a time.
 pub struct AsyncTcpStream {
-    stream: TcpStream,
-    tp_io: Tpio,
+    // Declared first so the connection is unregistered from the runtime before the socket closes.
+    _connection: ConnectionGuard,
+    // State for the futures::io trait impls. Their callers only lend us a buffer for the duration
+    // of one call to poll, so the overlapped operation reads into or writes from a buffer owned by
+    // the stream instead. The two directions are locked separately so a read and a write can be in
+    // flight at once. Declared before tp_io so operations still in flight are cancelled and
+    // complete before the socket is closed.
+    read: Mutex<OwnedBufferOp>,
+    write: Mutex<OwnedBufferOp>,
+    // Set by enable_stats.
+    stats: OnceLock<Arc<StreamCounters>>,
+    tp_io: Tpio<TcpStream>,
+}
+
+// An overlapped operation and the buffer it reads into or writes from. Dropping it while the
+// operation is in flight hands the buffer to the completion callback rather than waiting.
+#[derive(Default)]
+pub(crate) struct OwnedBufferOp {
+    op: Option<IocpFuture>,
+    buf: Vec<u8>,
+    // For reads, how much of `buf` has already been returned to the caller.
+    pos: usize,
+}
+
+impl Drop for OwnedBufferOp {
+    fn drop(&mut self) {
+        if let Some(op) = &mut self.op {
+            op.abandon(Box::new(mem::take(&mut self.buf)));
+        }
+    }
+}
+
+// The most the futures::io trait impls will buffer for a single operation.
+const MAX_OWNED_BUFFER: usize = 64 * 1024;
+
+impl OwnedBufferOp {
+    /// The body of [AsyncRead::poll_read] for a stream that starts its receives with
+    /// `start_recv(buf, len)`, which must be a receive into the `len` bytes at `buf`.
+    pub(crate) fn poll_read<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        start_recv: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnOnce(*mut u8, usize) -> IocpFuture,
+    {
+        let mut start_recv = Some(start_recv);
+        loop {
+            // Hand out anything left over from a previous receive first.
+            if self.op.is_none() && self.pos < self.buf.len() {
+                let available = &self.buf[self.pos..];
+                let n = available.len().min(buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                self.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            match &mut self.op {
+                Some(op) => {
+                    let result = ready!(Pin::new(op).poll(cx));
+                    self.op = None;
+                    self.pos = 0;
+                    match result.get_number_of_bytes_transferred() {
+                        Ok(0) => {
+                            self.buf.clear();
+                            return Poll::Ready(Ok(0));
+                        }
+                        Ok(n) => self.buf.truncate(n),
+                        Err(e) => {
+                            self.buf.clear();
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+                None => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    self.buf.clear();
+                    self.buf.resize(buf.len().min(MAX_OWNED_BUFFER), 0);
+                    let (ptr, len) = (self.buf.as_mut_ptr(), self.buf.len());
+                    // The loop only gets here once: the operation started here is either pending,
+                    // which returns, or complete, which returns from the arm above.
+                    let start_recv = start_recv.take().unwrap();
+                    self.op = Some(start_recv(ptr, len));
+                }
+            }
+        }
+    }
+
+    /// The body of [AsyncWrite::poll_write] for a stream that starts its sends with
+    /// `start_send(buf, len)`, which must be a send of the `len` bytes at `buf`.
+    pub(crate) fn poll_write<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        start_send: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnOnce(*const u8, usize) -> IocpFuture,
+    {
+        if self.op.is_none() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            self.buf.clear();
+            self.buf
+                .extend_from_slice(&buf[..buf.len().min(MAX_OWNED_BUFFER)]);
+            self.op = Some(start_send(self.buf.as_ptr(), self.buf.len()));
+        }
+        let result = ready!(Pin::new(self.op.as_mut().unwrap()).poll(cx));
+        self.op = None;
+        Poll::Ready(result.get_number_of_bytes_transferred())
+    }
 }
 
 impl AsyncTcpStream {
     pub(crate) fn new(stream: TcpStream) -> io::Result<AsyncTcpStream> {
-        iocp_threadpool::disable_callbacks_on_synchronous_completion(&stream)?;
-        let tp_io = iocp_threadpool::Tpio::new(&stream)?;
-        Ok(AsyncTcpStream { stream, tp_io })
+        Self::from_parts(iocp_threadpool::Tpio::new(stream)?)
+    }
+
+    /// Wraps a socket that is already associated with the completion port through `tp_io`.
+    pub(crate) fn from_parts(tp_io: Tpio<TcpStream>) -> io::Result<AsyncTcpStream> {
+        let connection = Handle::current_for_io()?.track_connection(tp_io.socket());
+        Ok(AsyncTcpStream {
+            _connection: connection,
+            tp_io,
+            read: Mutex::new(OwnedBufferOp::default()),
+            write: Mutex::new(OwnedBufferOp::default()),
+            stats: OnceLock::new(),
+        })
+    }
+
+    /// Wraps a connected [TcpStream], for example one configured with socket2 before it was
+    /// connected. The socket must have been created for overlapped I/O, which sockets created by
+    /// std and by `WSASocketW` with `WSA_FLAG_OVERLAPPED` are. It is associated with the runtime's
+    /// completion port; use [AsyncTcpStream::into_std] to get the blocking stream back.
+    ///
+    /// A stream that has already been wrapped once cannot be wrapped again, including one returned
+    /// by `into_std`, because Windows refuses to associate a handle with a completion port twice.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn from_std(stream: TcpStream) -> io::Result<AsyncTcpStream> {
+        Self::new(stream)
+    }
+
+    /// Opens a connection to `addr`. If it resolves to several addresses they are raced Happy
+    /// Eyeballs style, alternating between IPv6 and IPv4 with a 250ms head start for each attempt,
+    /// and the first connection established wins.
+    ///
+    /// The connect itself is an overlapped `ConnectEx` completed on the threadpool, so it does not
+    /// block the calling thread. Resolving a host name through [ToSocketAddrs] still does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<AsyncTcpStream> {
+        ConnectOptions::default().connect(addr).await
+    }
+
+    /// Opens a connection to `addr`, failing with [io::ErrorKind::TimedOut] if it has not been
+    /// established within `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect_timeout(
+        addr: &SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<AsyncTcpStream> {
+        ConnectOptions::new().timeout(timeout).connect(addr).await
+    }
+
+    /// Connects to `addr` with the local address or outgoing interface chosen by `options`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect_with<A: ToSocketAddrs>(
+        addr: A,
+        options: &ConnectOptions,
+    ) -> io::Result<AsyncTcpStream> {
+        options.connect(addr).await
+    }
+
+    /// The address of the remote end of the connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        // Accepted sockets have SO_UPDATE_ACCEPT_CONTEXT set, so getpeername works on them too.
+        self.tp_io.get_ref().peer_addr()
+    }
+
+    /// The local address the connection is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tp_io.get_ref().local_addr()
+    }
+
+    /// Shuts down the read half, the write half, or both halves of the connection. Shutting down
+    /// the write half sends a FIN once the data already queued has been sent, while reads keep
+    /// working until the peer closes its side.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tp_io.get_ref().shutdown(how)
+    }
+
+    /// Closes the connection gracefully.
+    ///
+    /// A write started through [AsyncWrite] is allowed to finish, and its error, if any, is
+    /// returned. A read started through [AsyncRead] is cancelled. Once no overlapped operations
+    /// are outstanding the write half is shut down and the socket is closed, so the peer sees a
+    /// clean end of stream instead of a reset.
+    pub async fn close(mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        if let Some(op) = self.write.get_mut().unwrap().op.take() {
+            result = op.await.get_number_of_bytes_transferred().map(|_| ());
+        }
+        if let Some(op) = self.read.get_mut().unwrap().op.take() {
+            unsafe {
+                CancelIoEx(self.tp_io.socket(), ptr::null_mut());
+            }
+            let _ = op.await;
+        }
+        result?;
+        self.tp_io.get_ref().shutdown(Shutdown::Write)
+    }
+
+    /// Converts this stream back into a blocking [TcpStream], for handing the connection to code
+    /// that expects blocking I/O. Returned along with it are the bytes this stream has already
+    /// received but not yet returned through [AsyncRead], which come before anything read from the
+    /// blocking stream.
+    ///
+    /// A write started through [AsyncWrite] is allowed to finish. A read started through
+    /// [AsyncRead] is cancelled, and whatever it received before the cancellation took effect is
+    /// included in the returned bytes.
+    ///
+    /// The socket stays associated with the runtime's completion port, which Windows offers no
+    /// supported way to undo. Blocking I/O on the returned stream works normally, but it must not
+    /// be used for overlapped I/O with a different completion port.
+    pub async fn into_std(mut self) -> io::Result<(TcpStream, Vec<u8>)> {
+        if let Some(op) = self.write.get_mut().unwrap().op.take() {
+            // The result is the caller's to observe through poll_write, which it no longer can.
+            let _ = op.await;
+        }
+        let read = self.read.get_mut().unwrap();
+        if let Some(op) = read.op.take() {
+            // This fails with ERROR_NOT_FOUND if the read already completed, which is fine.
+            unsafe {
+                CancelIoEx(self.tp_io.socket(), ptr::null_mut());
+            }
+            read.pos = 0;
+            match op.await.get_number_of_bytes_transferred() {
+                Ok(n) => read.buf.truncate(n),
+                // Cancelled, or failed in a way the blocking stream reports again.
+                Err(_) => read.buf.clear(),
+            }
+        }
+        let unread = read.buf[read.pos..].to_vec();
+        let AsyncTcpStream {
+            _connection, tp_io, ..
+        } = self;
+        // No operations are in flight, so the TP_IO can be closed while the socket stays open.
+        let stream = tp_io.into_inner();
+        drop(_connection);
+        Ok((stream, unread))
+    }
+
+    /// Converts this into a stream with blocking `std::io::Read` and `Write` implementations, for
+    /// synchronous code that wants to use a connection set up here. Unlike
+    /// [AsyncTcpStream::into_std], the socket keeps using overlapped I/O on the runtime's
+    /// threadpool, and the calling thread waits for each operation to complete.
+    ///
+    /// Data the [AsyncRead] impl has already received but not returned, and a receive it still has
+    /// in flight, are read first, so nothing is lost or reordered by the conversion.
+    pub fn into_blocking(self) -> BlockingTcpStream {
+        BlockingTcpStream { stream: self }
+    }
+}
+
+/// Controls where an outgoing connection made by [AsyncTcpStream::connect_with] originates.
+///
+/// Multi-homed hosts and VPN split-tunnel setups often need a connection to leave through a
+/// particular interface, which the routing table alone would not pick.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    local_addr: Option<SocketAddr>,
+    interface_index: Option<u32>,
+    timeout: Option<Duration>,
+    loopback_fast_path: bool,
+}
+
+impl ConnectOptions {
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    /// Binds the socket to `addr` before connecting. Use port 0 to pick only the source address.
+    ///
+    /// Only destinations of the same address family as `addr` are tried.
+    pub fn local_addr(mut self, addr: SocketAddr) -> ConnectOptions {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Sends the connection's packets out of the interface with this index, using
+    /// `IP_UNICAST_IF` or `IPV6_UNICAST_IF`. The index is the one reported by `GetAdaptersAddresses`
+    /// or `netsh interface ip show interfaces`.
+    pub fn interface_index(mut self, index: u32) -> ConnectOptions {
+        self.interface_index = Some(index);
+        self
+    }
+
+    /// Gives up on each address after `timeout`, cancelling the pending connect and failing with
+    /// [io::ErrorKind::TimedOut].
+    pub fn timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Turns on `SIO_LOOPBACK_FAST_PATH` before connecting, which speeds up connections to
+    /// localhost if the listening socket has it turned on too; see
+    /// [crate::listener::ListenerBuilder::loopback_fast_path]. It is ignored for other
+    /// destinations.
+    pub fn loopback_fast_path(mut self, enabled: bool) -> ConnectOptions {
+        self.loopback_fast_path = enabled;
+        self
+    }
+
+    // Happy Eyeballs (RFC 8305): the addresses are tried alternating between IPv6 and IPv4, starting
+    // with the family of the first address. A new attempt starts whenever the previous one fails or
+    // has been outstanding for CONNECTION_ATTEMPT_DELAY, so an unreachable address or family costs
+    // at most that delay. The first connection to succeed is returned and the rest are dropped,
+    // which closes their sockets and cancels their ConnectEx.
+    async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<AsyncTcpStream> {
+        const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+        let runtime = Handle::current_for_io()?;
+        let addrs: Vec<SocketAddr> = addr
+            .to_socket_addrs()?
+            .filter(|addr| match &self.local_addr {
+                Some(local) => local.is_ipv4() == addr.is_ipv4(),
+                None => true,
+            })
+            .collect();
+        let mut addrs = interleave_families(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if attempts.is_empty() {
+                match addrs.next() {
+                    Some(addr) => attempts.push(self.connect_one(addr)),
+                    None => break,
+                }
+            }
+            let finished = if addrs.len() == 0 {
+                attempts.next().await
+            } else {
+                let delay = Sleep::new(&runtime, CONNECTION_ATTEMPT_DELAY);
+                match future::select(attempts.next(), delay).await {
+                    Either::Left((finished, _)) => finished,
+                    Either::Right(_) => None,
+                }
+            };
+            match finished {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(e)) => last_err = Some(e),
+                // The delay elapsed.
+                None => {}
+            }
+            if let Some(addr) = addrs.next() {
+                attempts.push(self.connect_one(addr));
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address usable with the local address",
+            )
+        }))
+    }
+
+    async fn connect_one(&self, addr: SocketAddr) -> io::Result<AsyncTcpStream> {
+        const IP_UNICAST_IF: i32 = 31;
+        const IPV6_UNICAST_IF: i32 = 31;
+
+        let stream = unsafe { TcpStream::from_raw_socket(socket::new_tcp_socket(&addr)?) };
+        if self.loopback_fast_path {
+            socket::enable_loopback_fast_path(&stream)?;
+        }
+
+        if let Some(index) = self.interface_index {
+            match addr {
+                // IPv4 wants the index in network byte order, IPv6 in host byte order.
+                SocketAddr::V4(..) => {
+                    sockopt::set(&stream, sockopt::IPPROTO_IP, IP_UNICAST_IF, index.to_be())?
+                }
+                SocketAddr::V6(..) => {
+                    sockopt::set(&stream, sockopt::IPPROTO_IPV6, IPV6_UNICAST_IF, index)?
+                }
+            }
+        }
+        // ConnectEx only works on a bound socket.
+        let local = match self.local_addr {
+            Some(local) => local,
+            None => match addr {
+                SocketAddr::V4(..) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(..) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            },
+        };
+        socket::bind(&stream, &local)?;
+        AsyncTcpStream::connect_bound(stream, addr, self.timeout).await
+    }
+}
+
+impl AsyncTcpStream {
+    /// Connects `stream`, which must already be bound, to `addr` with ConnectEx. Gives up after
+    /// `timeout` if there is one.
+    pub(crate) async fn connect_bound(
+        stream: TcpStream,
+        addr: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<AsyncTcpStream> {
+        AsyncTcpStream::new(stream)?.connect_ex(addr, timeout).await
+    }
+
+    /// Connects this stream's socket, which must be bound and not yet connected, with ConnectEx.
+    pub(crate) async fn connect_ex(
+        self,
+        addr: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<AsyncTcpStream> {
+        const SO_UPDATE_CONNECT_CONTEXT: i32 = 0x7010;
+
+        let stream = self;
+        let runtime = Handle::current_for_io()?;
+        let connectex = runtime
+            .wsa_functions()
+            .connectex(stream.tp_io.get_ref(), &addr)?;
+        let remote = RawSocketAddr::new(&addr);
+        let connect = start_op(&stream.tp_io, unsafe {
+            ConnectExOp::new(connectex, remote.as_ptr(), remote.len())
+        });
+        let (ret, timed_out) = match timeout {
+            None => (connect.await, false),
+            Some(timeout) => match future::select(connect, Sleep::new(&runtime, timeout)).await {
+                Either::Left((ret, _)) => (ret, false),
+                Either::Right((_, connect)) => {
+                    unsafe {
+                        CancelIoEx(stream.tp_io.socket(), ptr::null_mut());
+                    }
+                    (connect.await, true)
+                }
+            },
+        };
+        match ret {
+            // The connect may have completed before it could be cancelled.
+            Ok(_) => {}
+            Err(_) if timed_out => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Without this the socket does not know it is connected, and shutdown, getpeername and
+        // friends fail. The option takes no value.
+        sockopt::set(
+            stream.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            SO_UPDATE_CONNECT_CONTEXT,
+            (),
+        )?;
+        Ok(stream)
+    }
+
+    /// Disconnects with `DisconnectEx(TF_REUSE_SOCKET)`, after which the socket can be connected
+    /// or accepted on again without creating a new one. The socket stays associated with the
+    /// completion port through the returned [Tpio].
+    pub(crate) async fn disconnect_for_reuse(mut self) -> io::Result<Tpio<TcpStream>> {
+        const TF_REUSE_SOCKET: u32 = 2;
+
+        // Operations started by the futures::io impls must finish before the socket is reused.
+        let read = self.read.get_mut().unwrap().op.take();
+        let write = self.write.get_mut().unwrap().op.take();
+        if read.is_some() || write.is_some() {
+            unsafe {
+                CancelIoEx(self.tp_io.socket(), ptr::null_mut());
+            }
+        }
+        for op in read.into_iter().chain(write) {
+            let _ = op.await;
+        }
+
+        let disconnectex = Handle::current_for_io()?
+            .wsa_functions()
+            .disconnectex(self.tp_io.get_ref())?;
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let disconnect = start_async_io(&self.tp_io, |overlapped| unsafe {
+            if disconnectex(hand, overlapped, TF_REUSE_SOCKET, 0).as_bool() {
+                Some(0)
+            } else {
+                None
+            }
+        });
+        disconnect.await.get_number_of_bytes_transferred()?;
+        let AsyncTcpStream { tp_io, .. } = self;
+        Ok(tp_io)
+    }
+}
+
+// Reorders `addrs` to alternate between address families, keeping the relative order within each
+// family and starting with the family of the first address.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+impl AsyncTcpStream {
+    /// Disables or enables Nagle's algorithm. With `nodelay` set, small writes are sent
+    /// immediately instead of being coalesced while an earlier segment is unacknowledged.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::IPPROTO_TCP,
+            sockopt::TCP_NODELAY,
+            nodelay as i32,
+        )
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        // Some versions of Windows only write a single byte, so start from zero.
+        let nodelay: i32 = sockopt::get(
+            self.tp_io.get_ref(),
+            sockopt::IPPROTO_TCP,
+            sockopt::TCP_NODELAY,
+        )?;
+        Ok(nodelay != 0)
+    }
+
+    /// Sets the time-to-live of IPv4 packets sent from this socket.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::IPPROTO_IP,
+            sockopt::IP_TTL,
+            ttl,
+        )
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::get(self.tp_io.get_ref(), sockopt::IPPROTO_IP, sockopt::IP_TTL)
+    }
+
+    /// Sets `SO_LINGER`, which controls what closing the socket does with data that has not been
+    /// sent yet. `None`, the default, closes immediately and sends the data gracefully in the
+    /// background. `Some(timeout)` waits up to `timeout`, in whole seconds, for it to be sent, and
+    /// `Some(Duration::ZERO)` discards it and resets the connection.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        sockopt::set_linger(self.tp_io.get_ref(), linger)
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        sockopt::linger(self.tp_io.get_ref())
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`).
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_RCVBUF,
+            size,
+        )
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(
+            self.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_RCVBUF,
+        )
+    }
+
+    /// Sets the size of the socket's send buffer (`SO_SNDBUF`). Zero makes sends complete only
+    /// once the data has been acknowledged, skipping the copy into a Winsock buffer.
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_SNDBUF,
+            size,
+        )
     }
 
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<AsyncTcpStream> {
-        Ok(Self::new(TcpStream::connect(addr)?)?)
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        sockopt::get(
+            self.tp_io.get_ref(),
+            sockopt::SOL_SOCKET,
+            sockopt::SO_SNDBUF,
+        )
+    }
+}
+
+/// TCP keepalive settings, applied with [AsyncTcpStream::set_keepalive] or to every accepted
+/// connection with [crate::listener::AsyncTcpListener::set_keepalive].
+///
+/// Keepalives stop NATs and firewalls from silently dropping the state of long-lived idle
+/// connections, and let a dead peer be detected without sending application data.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpKeepalive {
+    enabled: bool,
+    time: Duration,
+    interval: Duration,
+}
+
+impl Default for TcpKeepalive {
+    /// Enabled, with the Windows defaults of two hours idle before the first probe and one second
+    /// between probes.
+    fn default() -> TcpKeepalive {
+        TcpKeepalive {
+            enabled: true,
+            time: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TcpKeepalive {
+    pub fn new() -> TcpKeepalive {
+        TcpKeepalive::default()
+    }
+
+    /// Turns keepalives on or off.
+    pub fn enabled(mut self, enabled: bool) -> TcpKeepalive {
+        self.enabled = enabled;
+        self
+    }
+
+    /// How long the connection must be idle before the first probe is sent. Millisecond
+    /// resolution.
+    pub fn time(mut self, time: Duration) -> TcpKeepalive {
+        self.time = time;
+        self
+    }
+
+    /// How long to wait between probes that go unanswered. Millisecond resolution.
+    pub fn interval(mut self, interval: Duration) -> TcpKeepalive {
+        self.interval = interval;
+        self
+    }
+
+    pub(crate) fn apply<S: AsRawSocket>(&self, sock: &S) -> io::Result<()> {
+        const SIO_KEEPALIVE_VALS: u32 = 0x98000004;
+
+        // struct tcp_keepalive from mstcpip.h.
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        struct KeepaliveVals {
+            onoff: u32,
+            keepalivetime: u32,
+            keepaliveinterval: u32,
+        }
+
+        let millis = |d: Duration| d.as_millis().min(u32::MAX as u128) as u32;
+        let vals = KeepaliveVals {
+            onoff: self.enabled as u32,
+            keepalivetime: millis(self.time),
+            keepaliveinterval: millis(self.interval),
+        };
+        sockopt::ioctl(sock, SIO_KEEPALIVE_VALS, vals)
+    }
+}
+
+impl AsyncTcpStream {
+    /// Configures TCP keepalives for this connection.
+    pub fn set_keepalive(&self, keepalive: &TcpKeepalive) -> io::Result<()> {
+        keepalive.apply(self.tp_io.get_ref())
+    }
+}
+
+// Differentiated services. These are a thin wrapper over `setsockopt`.
+//
+// NOTE: by default Windows ignores `IP_TOS` and `IPV6_TCLASS` set by applications; the call succeeds
+// but the DSCP bits on the wire are unchanged unless the `DisableUserTOSSetting` registry value is
+// cleared or a Group Policy QoS rule applies. Microsoft documents the qWAVE APIs
+// (`QOSAddSocketToFlow` and `QOSSetFlow` with `QOSSetOutgoingDSCPValue`) as the supported way for an
+// application to mark its own traffic, which requires administrator rights for most DSCP values.
+impl AsyncTcpStream {
+    /// Sets the `IP_TOS` field for IPv4 packets sent from this socket. See the note above about when
+    /// Windows actually honors this.
+    pub fn set_tos(&self, tos: u32) -> io::Result<()> {
+        sockopt::set_tos(self.tp_io.get_ref(), tos)
+    }
+
+    pub fn tos(&self) -> io::Result<u32> {
+        sockopt::tos(self.tp_io.get_ref())
+    }
+
+    /// Sets the `IPV6_TCLASS` field for IPv6 packets sent from this socket. This is the IPv6
+    /// equivalent of [AsyncTcpStream::set_tos].
+    pub fn set_traffic_class(&self, tclass: u32) -> io::Result<()> {
+        sockopt::set_traffic_class(self.tp_io.get_ref(), tclass)
+    }
+
+    pub fn traffic_class(&self) -> io::Result<u32> {
+        sockopt::traffic_class(self.tp_io.get_ref())
     }
 }
 
 //these are similar to futures::{AsyncRead, AsyncWrite}
 impl AsyncTcpStream {
     pub async fn poll_write(&self, buf: &[u8]) -> io::Result<usize> {
-        let hand: usize = self.stream.as_raw_socket().try_into().unwrap();
+        let ret = unsafe { self.start_send(buf.as_ptr(), buf.len()) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
 
-        let ret = start_async_io(&self.tp_io, |overlapped| unsafe {
+    pub async fn poll_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe { self.start_recv(buf.as_mut_ptr(), buf.len()) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    /// Waits until data can be read from the socket, or the connection is closed or fails, without
+    /// setting aside a buffer for the data. This is an overlapped zero-byte WSARecv, so a server with
+    /// many mostly-idle connections can park on this and only allocate a buffer once a read will
+    /// actually return something. After it completes, a read returns data immediately, or returns
+    /// 0 if the peer closed the connection.
+    pub async fn readable(&self) -> io::Result<()> {
+        let ret = unsafe { self.start_recv(ptr::null_mut(), 0) }.await;
+        ret.get_number_of_bytes_transferred().map(|_| ())
+    }
+
+    /// Sends `len` bytes of `file` starting at `offset` with `TransmitFile`, which reads the file
+    /// and sends it from the kernel without copying it through user mode buffers. Returns the
+    /// number of bytes sent, which is less than `len` only if the file ends first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn send_file(&self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        // TransmitFile sends at most 2^31 - 2 bytes per call.
+        const MAX_TRANSMIT: u64 = 0x7fff_fffe;
+
+        let transmitfile = Handle::current_for_io()?
+            .wsa_functions()
+            .transmitfile(self.tp_io.get_ref())?;
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let file_handle = HANDLE(file.as_raw_handle() as isize);
+        // A synchronous completion does not report how much was sent, so never ask for more than
+        // the file holds.
+        let len = len.min(file.metadata()?.len().saturating_sub(offset));
+        let mut sent = 0;
+        while sent < len {
+            let chunk = (len - sent).min(MAX_TRANSMIT) as u32;
+            let position = offset + sent;
+            let op = start_async_io(&self.tp_io, |overlapped| unsafe {
+                (*overlapped).Anonymous.Anonymous.Offset = position as u32;
+                (*overlapped).Anonymous.Anonymous.OffsetHigh = (position >> 32) as u32;
+                let rc = transmitfile(hand, file_handle, chunk, 0, overlapped, ptr::null_mut(), 0);
+                if rc.as_bool() {
+                    Some(chunk as usize)
+                } else {
+                    None
+                }
+            });
+            let n = self
+                .counted(op, IoDirection::Write)
+                .await
+                .get_number_of_bytes_transferred()? as u64;
+            if n == 0 {
+                break;
+            }
+            sent += n;
+        }
+        Ok(sent)
+    }
+
+    /// Receives data into `buf` without removing it from the socket's receive queue, so the next
+    /// read returns the same bytes again. Like any read, this waits until data is available.
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Winsock captures the WSABUF before WSARecv returns, so it does not have to live across
+        // the await.
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_recv_wsabufs(&mut wsabuf, 1, MSG_PEEK) }
+        };
+        let ret = op.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    /// Sends `buf` as urgent (out-of-band) data. TCP only really supports a single urgent byte; for
+    /// longer buffers, only the last byte is marked urgent.
+    pub async fn send_oob(&self, buf: &[u8]) -> io::Result<usize> {
+        let op = {
             let mut wsabuf = WSABUF {
                 buf: PSTR(buf.as_ptr() as *mut u8),
                 len: buf.len().try_into().unwrap(),
             };
+            unsafe { self.start_send_wsabufs(&mut wsabuf, 1, MSG_OOB) }
+        };
+        op.await.get_number_of_bytes_transferred()
+    }
+
+    /// Receives urgent (out-of-band) data sent by the peer with `send_oob`. Fails with
+    /// `WSAEINVAL` if there is none waiting, or if `SO_OOBINLINE` puts it in the normal stream.
+    pub async fn recv_oob(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_recv_wsabufs(&mut wsabuf, 1, MSG_OOB) }
+        };
+        op.await.get_number_of_bytes_transferred()
+    }
+
+    /// Whether all urgent data has been read. When this returns false, there is urgent data
+    /// waiting for [AsyncTcpStream::recv_oob].
+    pub fn at_mark(&self) -> io::Result<bool> {
+        const SIOCATMARK: u32 = 0x40047307;
+        sockopt::ioctl_get::<_, u32>(self.tp_io.get_ref(), SIOCATMARK).map(|at_mark| at_mark != 0)
+    }
+
+    /// Reads into `buf`, passing `flags` such as [MSG_PEEK] or [MSG_OOB] to WSARecv, and returns the
+    /// number of bytes read along with the flags WSARecv reported. [MSG_PARTIAL] is set if a
+    /// message-oriented provider truncated the data to fit `buf`.
+    pub async fn read_with_flags(&self, buf: &mut [u8], flags: u32) -> io::Result<(usize, u32)> {
+        let mut out_flags = flags;
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_recv_wsabufs_with_flags(&mut wsabuf, 1, &mut out_flags) }
+        };
+        let pending = op.is_pending();
+        let result = op.await;
+        let (received, partial) = result.get_number_of_bytes_transferred_allow_partial()?;
+        if pending {
+            // WSARecv only writes the flags when it completes synchronously. For a completion
+            // through the port they come from WSAGetOverlappedResult, and failing that, all that
+            // is known is whether the data was truncated, and that out-of-band data was asked for.
+            out_flags = result.flags().unwrap_or(flags & MSG_OOB);
+        }
+        if partial {
+            out_flags |= MSG_PARTIAL;
+        }
+        Ok((received, out_flags))
+    }
+
+    /// Reads into `buf`, which is handed back with the result. Unlike [AsyncTcpStream::poll_read],
+    /// the buffer stays valid even if this future is dropped before the read completes; see
+    /// [crate::buf]. Data is written from the start of the buffer, up to its total capacity.
+    pub async fn read_owned<B: IoBufMut>(&self, buf: B) -> (io::Result<usize>, B) {
+        let op = start_op(&self.tp_io, RecvOp::new(buf, 0));
+        self.counted_op(op, IoDirection::Read).await
+    }
+
+    /// Sends the initialized part of `buf`, which is handed back with the result. The buffer stays
+    /// valid even if this future is dropped before the send completes; see [crate::buf].
+    pub async fn write_owned<B: IoBuf>(&self, buf: B) -> (io::Result<usize>, B) {
+        let op = start_op(&self.tp_io, SendOp::new(buf, 0));
+        self.counted_op(op, IoDirection::Write).await
+    }
+
+    /// Sends the data from several buffers with a single WSASend, without copying them into one.
+    pub async fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        // IoSlice is guaranteed to have the same layout as WSABUF on Windows. Winsock does not
+        // write through the buffers of a send.
+        let count = bufs.len().try_into().unwrap();
+        let ret = unsafe { self.start_send_wsabufs(bufs.as_ptr() as *mut WSABUF, count, 0) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    /// Receives into several buffers with a single WSARecv, filling each before the next.
+    pub async fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        // IoSliceMut is guaranteed to have the same layout as WSABUF on Windows.
+        let count = bufs.len().try_into().unwrap();
+        let ret =
+            unsafe { self.start_recv_wsabufs(bufs.as_mut_ptr() as *mut WSABUF, count, 0) }.await;
+        ret.get_number_of_bytes_transferred()
+    }
+
+    /// Sends all of `buf`.
+    ///
+    /// Rather than handing Winsock the whole buffer at once, this keeps about the socket's ideal
+    /// send backlog (see [AsyncTcpStream::ideal_send_backlog]) in flight, split across two sends so
+    /// the next one is already queued when the first completes. If the backlog cannot be queried
+    /// the buffer is sent with a single WSASend.
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        // Don't let a tiny backlog turn into a flood of small sends.
+        const MIN_SEND: usize = 4 * 1024;
+
+        let chunk = match self.ideal_send_backlog() {
+            Ok(isb) if isb > 0 => (isb / 2).max(MIN_SEND),
+            _ => buf.len().max(1),
+        };
+        let mut in_flight: VecDeque<(IocpFuture, usize)> = VecDeque::with_capacity(2);
+        let mut ndx = 0;
+        while ndx < buf.len() || !in_flight.is_empty() {
+            while ndx < buf.len() && in_flight.len() < 2 {
+                let len = chunk.min(buf.len() - ndx);
+                let op = unsafe { self.start_send(buf[ndx..].as_ptr(), len) };
+                in_flight.push_back((op, len));
+                ndx += len;
+            }
+            let (op, len) = in_flight.pop_front().unwrap();
+            // An overlapped send on a stream socket only completes short if the connection failed,
+            // and the sends queued behind it can't be taken back, so treat that as fatal.
+            if op.await.get_number_of_bytes_transferred()? != len {
+                return Err(io::Error::other("disconnected"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The ideal send backlog (ISB): how many bytes should be outstanding in sends to keep the
+    /// connection's pipe full, based on its current congestion window and round trip time.
+    pub fn ideal_send_backlog(&self) -> io::Result<usize> {
+        const SIO_IDEAL_SEND_BACKLOG_QUERY: u32 = 0x4004747b;
+        sockopt::ioctl_get::<_, u32>(self.tp_io.get_ref(), SIO_IDEAL_SEND_BACKLOG_QUERY)
+            .map(|isb| isb as usize)
+    }
+
+    /// Completes when the ideal send backlog changes, after which
+    /// [AsyncTcpStream::ideal_send_backlog] returns the new value. Applications that manage their
+    /// own sends can use this to resize them as the connection speeds up or slows down.
+    pub async fn ideal_send_backlog_changed(&self) -> io::Result<()> {
+        const SIO_IDEAL_SEND_BACKLOG_CHANGE: u32 = 0x2000747a;
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let op = start_async_io(&self.tp_io, |overlapped| unsafe {
+            let mut bytes_returned: u32 = 0;
+            let rc = WSAIoctl(
+                hand,
+                SIO_IDEAL_SEND_BACKLOG_CHANGE,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                0,
+                &mut bytes_returned,
+                overlapped,
+                None,
+            );
+            if rc == 0 {
+                Some(0)
+            } else {
+                None
+            }
+        });
+        op.await.get_number_of_bytes_transferred().map(|_| ())
+    }
+
+    /// Sends all the data in `bufs`, issuing as many vectored sends as it takes. The slices are
+    /// advanced past the data that was sent, so their contents are unspecified afterwards.
+    pub async fn write_all_vectored(&self, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+        // Skip leading empty slices so an all-empty input does not look like a disconnect.
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            let sent = self.write_vectored(bufs).await?;
+            if sent == 0 {
+                return Err(io::Error::other("disconnected"));
+            }
+            IoSlice::advance_slices(&mut bufs, sent);
+        }
+        Ok(())
+    }
+
+    /// Reads exactly enough data to fill `buf`. Fails with [io::ErrorKind::UnexpectedEof] if the
+    /// peer closes the connection first, in which case the contents of `buf` are unspecified.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut ndx = 0;
+        while ndx < buf.len() {
+            let received = self.poll_read(&mut buf[ndx..]).await?;
+            if received == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            ndx += received;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncTcpStream {
+    /// Starts a WSASend of `len` bytes at `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay valid until the returned future completes.
+    unsafe fn start_send(&self, buf: *const u8, len: usize) -> IocpFuture {
+        let mut wsabuf = WSABUF {
+            buf: PSTR(buf as *mut u8),
+            len: len.try_into().unwrap(),
+        };
+        self.start_send_wsabufs(&mut wsabuf, 1, 0)
+    }
+
+    /// Starts counting this stream's sends and receives, for [AsyncTcpStream::stats]. Operations
+    /// that are already in flight are not counted.
+    pub fn enable_stats(&self) {
+        self.stats.get_or_init(Default::default);
+    }
+
+    /// The stream's I/O statistics since [AsyncTcpStream::enable_stats] was called, or `None` if
+    /// it has not been. Sends, receives and `send_file` are counted, including those made through
+    /// the [AsyncRead] and [AsyncWrite] impls.
+    pub fn stats(&self) -> Option<StreamMetrics> {
+        self.stats.get().map(|counters| counters.snapshot())
+    }
+
+    fn counted(&self, op: IocpFuture, direction: IoDirection) -> IocpFuture {
+        match self.stats.get() {
+            Some(counters) => op.record_stats(counters, direction),
+            None => op,
+        }
+    }
+
+    fn counted_op<O: OverlappedOp>(&self, op: OpFuture<O>, direction: IoDirection) -> OpFuture<O> {
+        match self.stats.get() {
+            Some(counters) => op.record_stats(counters, direction),
+            None => op,
+        }
+    }
+
+    /// Starts a WSASend gathering from `count` buffers described by `bufs`, with send flags such as
+    /// `MSG_OOB`.
+    ///
+    /// # Safety
+    ///
+    /// The memory the buffers point to must stay valid until the returned future completes. The
+    /// `WSABUF` array itself is captured by Winsock before WSASend returns.
+    unsafe fn start_send_wsabufs(&self, bufs: *mut WSABUF, count: u32, flags: u32) -> IocpFuture {
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let op = start_async_io(&self.tp_io, |overlapped| {
             let mut sent: u32 = 0;
-            let rc = WSASend(hand, &mut wsabuf, 1, &mut sent, 0, overlapped, Option::None);
+            let rc = WSASend(
+                hand,
+                bufs,
+                count,
+                &mut sent,
+                flags,
+                overlapped,
+                Option::None,
+            );
             if rc == 0 {
                 Some(sent as usize)
             } else {
                 None
             }
-        })
-        .await;
-        ret.get_number_of_bytes_transferred()
+        });
+        self.counted(op, IoDirection::Write)
     }
 
-    pub async fn poll_read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let hand: usize = self.stream.as_raw_socket().try_into().unwrap();
+    /// Starts a WSARecv of up to `len` bytes into `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay valid until the returned future completes.
+    unsafe fn start_recv(&self, buf: *mut u8, len: usize) -> IocpFuture {
+        let mut wsabuf = WSABUF {
+            buf: PSTR(buf),
+            len: len.try_into().unwrap(),
+        };
+        self.start_recv_wsabufs(&mut wsabuf, 1, 0)
+    }
 
-        let ret = start_async_io(&self.tp_io, |overlapped| unsafe {
-            let mut wsabuf = WSABUF {
-                buf: PSTR(buf.as_ptr() as *mut u8),
-                len: buf.len().try_into().unwrap(),
-            };
+    /// Starts a WSARecv scattering into `count` buffers described by `bufs`. `flags` are `MSG_*`
+    /// flags such as `MSG_PEEK`.
+    ///
+    /// # Safety
+    ///
+    /// The memory the buffers point to must stay valid until the returned future completes. The
+    /// `WSABUF` array itself is captured by Winsock before WSARecv returns.
+    unsafe fn start_recv_wsabufs(&self, bufs: *mut WSABUF, count: u32, flags: u32) -> IocpFuture {
+        let mut flags = flags;
+        self.start_wsarecv(bufs, count, &mut flags, false)
+    }
+
+    /// Like [AsyncTcpStream::start_recv_wsabufs], but WSARecv's output flags are written to
+    /// `flags` if it completes synchronously, and are in the result's [IocpResult::flags] if it
+    /// completes through the port.
+    unsafe fn start_recv_wsabufs_with_flags(
+        &self,
+        bufs: *mut WSABUF,
+        count: u32,
+        flags: &mut u32,
+    ) -> IocpFuture {
+        self.start_wsarecv(bufs, count, flags, true)
+    }
+
+    // Starts the WSARecv for start_recv_wsabufs and start_recv_wsabufs_with_flags, fetching the
+    // flags of a completion only if `want_flags` is set, since that costs a syscall.
+    unsafe fn start_wsarecv(
+        &self,
+        bufs: *mut WSABUF,
+        count: u32,
+        flags: &mut u32,
+        want_flags: bool,
+    ) -> IocpFuture {
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let recv = |overlapped| {
             let mut received: u32 = 0;
-            let mut flags: u32 = 0;
             let rc = WSARecv(
                 hand,
-                &mut wsabuf,
-                1,
+                bufs,
+                count,
                 &mut received,
-                &mut flags,
+                flags,
                 overlapped,
                 Option::None,
             );
@@ -76,20 +1181,289 @@ impl AsyncTcpStream {
             } else {
                 None
             }
-        })
-        .await;
-        ret.get_number_of_bytes_transferred()
+        };
+        let op = if want_flags {
+            start_async_io_with_flags(&self.tp_io, recv)
+        } else {
+            start_async_io(&self.tp_io, recv)
+        };
+        self.counted(op, IoDirection::Read)
     }
+}
 
-    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
-        let mut ndx = 0;
-        while ndx < buf.len() {
-            let sent = self.poll_write(&buf[ndx..]).await?;
-            if sent == 0 {
-                return Err(io::Error::new(io::ErrorKind::Other, "disconnected"));
-            }
-            ndx += sent;
+impl AsRawSocket for AsyncTcpStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.tp_io.get_ref().as_raw_socket()
+    }
+}
+
+impl AsSocket for AsyncTcpStream {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.tp_io.get_ref().as_socket()
+    }
+}
+
+impl AsyncRead for &AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this: &AsyncTcpStream = *self;
+        let mut read = this.read.lock().unwrap();
+        // The buffer is owned by `read`, which keeps it until the receive completes.
+        read.poll_read(cx, buf, |ptr, len| unsafe { this.start_recv(ptr, len) })
+    }
+}
+
+impl AsyncWrite for &AsyncTcpStream {
+    /// Copies as much of `buf` as fits in the stream's send buffer and completes once the send
+    /// completes. If this returns `Pending`, the next call must pass the same data; the send that
+    /// is already in flight is completed before any new data is looked at.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this: &AsyncTcpStream = *self;
+        let mut write = this.write.lock().unwrap();
+        // The buffer is owned by `write`, which keeps it until the send completes.
+        write.poll_write(cx, buf, |ptr, len| unsafe { this.start_send(ptr, len) })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // poll_write does not complete until the data has been handed to Winsock, so there is
+        // nothing to flush.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.tp_io.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_close(cx)
+    }
+}
+
+/// An [AsyncTcpStream] whose reads and writes block the calling thread until their overlapped
+/// operation completes. Created with [AsyncTcpStream::into_blocking].
+///
+/// The completions are still delivered on the runtime's threadpool, so this should not be used
+/// from a task running on that threadpool: every blocked task takes a thread away from the pool.
+pub struct BlockingTcpStream {
+    stream: AsyncTcpStream,
+}
+
+impl BlockingTcpStream {
+    pub fn get_ref(&self) -> &AsyncTcpStream {
+        &self.stream
+    }
+
+    pub fn into_async(self) -> AsyncTcpStream {
+        self.stream
+    }
+}
+
+impl BlockingTcpStream {
+    // Whether the AsyncRead impl holds received data that has not been returned yet, or has a
+    // receive in flight. Reads go through it until it is drained.
+    fn async_read_pending(&self) -> bool {
+        let read = self.stream.read.lock().unwrap();
+        read.op.is_some() || read.pos < read.buf.len()
+    }
+}
+
+impl io::Read for &BlockingTcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.async_read_pending() {
+            return executor::block_on(AsyncReadExt::read(&mut &self.stream, buf));
+        }
+        executor::block_on(self.stream.poll_read(buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        if self.async_read_pending() {
+            // The AsyncRead impl reads into one buffer at a time.
+            let buf = bufs
+                .iter_mut()
+                .find(|b| !b.is_empty())
+                .map_or(&mut [][..], |b| &mut **b);
+            return self.read(buf);
         }
+        executor::block_on(self.stream.read_vectored(bufs))
+    }
+}
+
+impl io::Write for &BlockingTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        executor::block_on(self.stream.poll_write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        executor::block_on(self.stream.write_vectored(bufs))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        executor::block_on(self.stream.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes have been handed to Winsock by the time they return.
         Ok(())
     }
 }
+
+impl io::Read for BlockingTcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        (&*self).read_vectored(bufs)
+    }
+}
+
+impl io::Write for BlockingTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        (&*self).write_vectored(bufs)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        (&*self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl AsRawSocket for BlockingTcpStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.stream.as_raw_socket()
+    }
+}
+
+/// A `WSARecv` into an owned buffer, with `MSG_*` flags such as [MSG_PEEK]. Resolves to the number
+/// of bytes received, which is 0 once the other end has shut down its write half, and the buffer.
+pub struct RecvOp<B> {
+    buf: B,
+    flags: u32,
+}
+
+impl<B: IoBufMut> RecvOp<B> {
+    /// A receive into `buf`, filling it up to its total capacity.
+    pub fn new(buf: B, flags: u32) -> RecvOp<B> {
+        RecvOp { buf, flags }
+    }
+}
+
+unsafe impl<B: IoBufMut> OverlappedOp for RecvOp<B> {
+    type Output = (io::Result<usize>, B);
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        // Winsock captures the WSABUF before WSARecv returns. The output flags of a receive that
+        // goes pending are reported with the completion, so they are not written here later.
+        let mut wsabuf = WSABUF {
+            buf: PSTR(self.buf.stable_mut_ptr()),
+            len: self.buf.bytes_total().try_into().unwrap(),
+        };
+        let mut flags = self.flags;
+        let mut received: u32 = 0;
+        let rc = WSARecv(
+            handle as usize,
+            &mut wsabuf,
+            1,
+            &mut received,
+            &mut flags,
+            overlapped,
+            Option::None,
+        );
+        if rc == 0 {
+            Some(received as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(mut self, result: IocpResult) -> Self::Output {
+        let ret = result.get_number_of_bytes_transferred();
+        if let Ok(n) = ret {
+            unsafe { self.buf.set_init(n) };
+        }
+        (ret, self.buf)
+    }
+}
+
+/// A `WSASend` of the initialized part of an owned buffer, with `MSG_*` flags such as [MSG_OOB].
+/// Resolves to the number of bytes sent and the buffer.
+pub struct SendOp<B> {
+    buf: B,
+    flags: u32,
+}
+
+impl<B: IoBuf> SendOp<B> {
+    /// A send of `buf`.
+    pub fn new(buf: B, flags: u32) -> SendOp<B> {
+        SendOp { buf, flags }
+    }
+}
+
+unsafe impl<B: IoBuf> OverlappedOp for SendOp<B> {
+    type Output = (io::Result<usize>, B);
+
+    unsafe fn submit(&mut self, handle: RawHandle, overlapped: *mut OVERLAPPED) -> Option<usize> {
+        // Winsock does not write through the buffers of a send.
+        let mut wsabuf = WSABUF {
+            buf: PSTR(self.buf.stable_ptr() as *mut u8),
+            len: self.buf.bytes_init().try_into().unwrap(),
+        };
+        let mut sent: u32 = 0;
+        let rc = WSASend(
+            handle as usize,
+            &mut wsabuf,
+            1,
+            &mut sent,
+            self.flags,
+            overlapped,
+            Option::None,
+        );
+        if rc == 0 {
+            Some(sent as usize)
+        } else {
+            None
+        }
+    }
+
+    fn complete(self, result: IocpResult) -> Self::Output {
+        (result.get_number_of_bytes_transferred(), self.buf)
+    }
+}
@@ -0,0 +1,159 @@
+use futures::future::{self, Either, FutureExt};
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::cancellation::CancellationToken;
+use crate::runtime::{Handle, JoinHandle};
+
+/// Runs `f` with a [Scope] that tasks can be spawned on, and does not complete until every task
+/// spawned on the scope has finished.
+///
+/// If the future returned by `scope` is dropped before it completes, the scope's tasks are
+/// cancelled: each one is dropped the next time it would be polled. They are also cancelled when
+/// the runtime starts shutting down.
+///
+/// Scoped tasks run in parallel on the threadpool, so like [Handle::spawn] they must be `'static`.
+/// They cannot borrow from the caller, because nothing stops the `scope` future itself from being
+/// leaked with `mem::forget` while the tasks still run. Share data with them through an `Arc`.
+///
+/// # Panics
+///
+/// Panics if called outside the context of a [crate::runtime::Runtime], and after all tasks have
+/// finished if any of them panicked.
+pub async fn scope<F, Fut, R>(f: F) -> R
+where
+    F: FnOnce(Scope) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let scope = Scope {
+        inner: Arc::new(ScopeInner {
+            runtime: Handle::current(),
+            token: Handle::current().shutdown_token().child_token(),
+            state: Mutex::new(ScopeState {
+                active: 0,
+                panicked: false,
+                waker: None,
+            }),
+        }),
+    };
+    let mut cancel_on_drop = CancelOnDrop(Some(scope.inner.token.clone()));
+    let result = f(scope.clone()).await;
+    AllTasksFinished { scope: &scope }.await;
+    cancel_on_drop.disarm();
+    if scope.inner.state.lock().unwrap().panicked {
+        panic!("a scoped task panicked");
+    }
+    result
+}
+
+/// A handle for spawning tasks that are joined before [scope] returns.
+#[derive(Clone)]
+pub struct Scope {
+    inner: Arc<ScopeInner>,
+}
+
+struct ScopeInner {
+    runtime: Handle,
+    token: CancellationToken,
+    state: Mutex<ScopeState>,
+}
+
+struct ScopeState {
+    active: usize,
+    panicked: bool,
+    waker: Option<Waker>,
+}
+
+impl Scope {
+    /// Spawns a task that [scope] waits for. The task's output is `None` if it was cancelled.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.inner.state.lock().unwrap().active += 1;
+        let guard = TaskGuard {
+            scope: self.inner.clone(),
+        };
+        let token = self.inner.token.clone();
+        self.inner.runtime.spawn(async move {
+            let cancellable = future::select(future.boxed(), token.cancelled());
+            match AssertUnwindSafe(cancellable).catch_unwind().await {
+                Ok(Either::Left((output, _))) => Some(output),
+                Ok(Either::Right(_)) => None,
+                Err(payload) => {
+                    guard.scope.state.lock().unwrap().panicked = true;
+                    // Let the JoinHandle see the panic too.
+                    panic::resume_unwind(payload)
+                }
+            }
+        })
+    }
+
+    /// A token that is cancelled when the scope's tasks are cancelled, for passing to work that
+    /// should stop at the same time.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.inner.token
+    }
+}
+
+// Counts a task as finished when it is dropped, which happens whether it completed, panicked, or
+// was cancelled.
+struct TaskGuard {
+    scope: Arc<ScopeInner>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.scope.state.lock().unwrap();
+            state.active -= 1;
+            if state.active == 0 {
+                state.waker.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+struct CancelOnDrop(Option<CancellationToken>);
+
+impl CancelOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = &self.0 {
+            token.cancel();
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+struct AllTasksFinished<'a> {
+    scope: &'a Scope,
+}
+
+impl Future for AllTasksFinished<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.scope.inner.state.lock().unwrap();
+        if state.active == 0 {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
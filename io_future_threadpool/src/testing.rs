@@ -0,0 +1,276 @@
+//! Helpers for testing code built on this crate.
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::runtime::Handle;
+use crate::time::Sleep;
+
+/// Controls which faults a [FaultyStream] injects. Probabilities are per read or write call and
+/// range from 0 (never) to 1 (always). The default policy injects nothing.
+#[derive(Clone, Debug)]
+pub struct FaultPolicy {
+    /// Seeds the random number generator. The same seed and the same sequence of calls produce the
+    /// same faults.
+    pub seed: u64,
+    /// Chance of waiting before a read or write is passed to the wrapped stream.
+    pub delay_probability: f64,
+    /// Delays are chosen uniformly between zero and this.
+    pub max_delay: Duration,
+    /// Chance of truncating the buffer passed to the wrapped stream, so it reads or writes fewer
+    /// bytes than asked for.
+    pub short_io_probability: f64,
+    /// Chance of failing a call with [io::ErrorKind::WouldBlock] without touching the wrapped
+    /// stream. The stream keeps working afterwards.
+    pub would_block_probability: f64,
+    /// Chance of failing a call with `error_kind`. After this the stream fails every call.
+    pub error_probability: f64,
+    /// Fail with `error_kind` once this many bytes have been read and written in total. The call
+    /// that reaches the limit is truncated so the limit is hit exactly.
+    pub fail_after_bytes: Option<u64>,
+    /// The kind of error returned for injected failures.
+    pub error_kind: io::ErrorKind,
+}
+
+impl Default for FaultPolicy {
+    fn default() -> FaultPolicy {
+        FaultPolicy {
+            seed: 0,
+            delay_probability: 0.0,
+            max_delay: Duration::from_millis(10),
+            short_io_probability: 0.0,
+            would_block_probability: 0.0,
+            error_probability: 0.0,
+            fail_after_bytes: None,
+            error_kind: io::ErrorKind::ConnectionReset,
+        }
+    }
+}
+
+/// Wraps a stream and injects delays, short reads and writes, spurious `WouldBlock` errors, and
+/// mid-stream failures according to a seeded [FaultPolicy], so error handling can be tested
+/// deterministically.
+///
+/// The faults for a call are decided when it is first polled and kept until it completes, so a
+/// call that returns `Pending` is not rolled again when it is polled a second time.
+pub struct FaultyStream<T> {
+    inner: T,
+    faults: Faults,
+}
+
+struct Faults {
+    policy: FaultPolicy,
+    runtime: Handle,
+    rng: SplitMix64,
+    transferred: u64,
+    failed: bool,
+    read: Direction,
+    write: Direction,
+}
+
+#[derive(Default)]
+struct Direction {
+    plan: Option<Plan>,
+    delay: Option<Sleep>,
+}
+
+#[derive(Clone, Copy)]
+enum Plan {
+    WouldBlock,
+    Fail,
+    Transfer(usize),
+}
+
+impl<T> FaultyStream<T> {
+    /// # Panics
+    ///
+    /// Panics if called outside the context of a [crate::runtime::Runtime].
+    pub fn new(inner: T, policy: FaultPolicy) -> Self {
+        FaultyStream {
+            inner,
+            faults: Faults {
+                rng: SplitMix64(policy.seed),
+                policy,
+                runtime: Handle::current(),
+                transferred: 0,
+                failed: false,
+                read: Direction::default(),
+                write: Direction::default(),
+            },
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl FaultPolicy {
+    fn plan(&self, rng: &mut SplitMix64, transferred: u64, len: usize) -> Plan {
+        let remaining = self
+            .fail_after_bytes
+            .map(|limit| limit.saturating_sub(transferred));
+        if remaining == Some(0) {
+            return Plan::Fail;
+        }
+        if rng.chance(self.would_block_probability) {
+            return Plan::WouldBlock;
+        }
+        if rng.chance(self.error_probability) {
+            return Plan::Fail;
+        }
+        let mut len = len;
+        if len > 1 && rng.chance(self.short_io_probability) {
+            len = 1 + (rng.next() % (len as u64 - 1)) as usize;
+        }
+        if let Some(remaining) = remaining {
+            len = len.min(remaining.min(usize::MAX as u64) as usize);
+        }
+        Plan::Transfer(len)
+    }
+}
+
+impl Faults {
+    fn injected_error(&self) -> io::Error {
+        io::Error::new(self.policy.error_kind, "injected fault")
+    }
+
+    /// Applies the faults for one read or write of `len` bytes. `io` performs the call on the
+    /// wrapped stream with a possibly shortened length.
+    fn poll_io<F>(
+        &mut self,
+        write: bool,
+        cx: &mut Context<'_>,
+        len: usize,
+        io: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnOnce(&mut Context<'_>, usize) -> Poll<io::Result<usize>>,
+    {
+        if self.failed {
+            return Poll::Ready(Err(self.injected_error()));
+        }
+        if len == 0 {
+            return io(cx, 0);
+        }
+        let direction = if write {
+            &mut self.write
+        } else {
+            &mut self.read
+        };
+        let plan = match direction.plan {
+            Some(plan) => plan,
+            None => {
+                if self.rng.chance(self.policy.delay_probability) {
+                    let delay = self.policy.max_delay.mul_f64(self.rng.next_f64());
+                    direction.delay = Some(Sleep::new(&self.runtime, delay));
+                }
+                let plan = self.policy.plan(&mut self.rng, self.transferred, len);
+                direction.plan = Some(plan);
+                plan
+            }
+        };
+        if let Some(delay) = &mut direction.delay {
+            ready!(Pin::new(delay).poll(cx));
+            direction.delay = None;
+        }
+        let n = match plan {
+            Plan::WouldBlock => {
+                direction.plan = None;
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "injected spurious WouldBlock",
+                )));
+            }
+            Plan::Fail => {
+                direction.plan = None;
+                self.failed = true;
+                return Poll::Ready(Err(self.injected_error()));
+            }
+            // The caller may pass a smaller buffer when polling again.
+            Plan::Transfer(n) => n.min(len),
+        };
+        let result = ready!(io(cx, n));
+        direction.plan = None;
+        if let Ok(transferred) = &result {
+            self.transferred += *transferred as u64;
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FaultyStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let inner = &mut this.inner;
+        this.faults.poll_io(false, cx, buf.len(), |cx, n| {
+            Pin::new(inner).poll_read(cx, &mut buf[..n])
+        })
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FaultyStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let inner = &mut this.inner;
+        this.faults.poll_io(true, cx, buf.len(), |cx, n| {
+            Pin::new(inner).poll_write(cx, &buf[..n])
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.faults.failed {
+            return Poll::Ready(Err(this.faults.injected_error()));
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// A small, fast, seedable generator. Statistical quality is more than enough for picking faults.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
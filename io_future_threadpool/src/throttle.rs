@@ -0,0 +1,187 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::runtime::Handle;
+use crate::time::Sleep;
+
+/// Wraps a stream and limits how many bytes per second can be read from and written to it.
+///
+/// Each direction has its own token bucket that holds up to a tenth of a second of traffic. Reads
+/// and writes are truncated to the bytes available in the bucket, and when the bucket is empty the
+/// stream waits on a threadpool timer until it has refilled. This is meant for background transfers
+/// that must not saturate the link; it does not account for protocol overhead.
+pub struct ThrottledStream<T> {
+    inner: T,
+    runtime: Handle,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+impl<T> ThrottledStream<T> {
+    /// Wraps `inner` with no limits set. Use [ThrottledStream::set_read_limit] and
+    /// [ThrottledStream::set_write_limit] to configure it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside the context of a [crate::runtime::Runtime].
+    pub fn new(inner: T) -> Self {
+        ThrottledStream {
+            inner,
+            runtime: Handle::current(),
+            read: None,
+            write: None,
+        }
+    }
+
+    /// Limits reads to `bytes_per_second`, or removes the limit if `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    pub fn set_read_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.read = bytes_per_second.map(TokenBucket::new);
+    }
+
+    /// Limits writes to `bytes_per_second`, or removes the limit if `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    pub fn set_write_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.write = bytes_per_second.map(TokenBucket::new);
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+struct TokenBucket {
+    bytes_per_second: u64,
+    capacity: u64,
+    available: u64,
+    last_refill: Instant,
+    // Carries over the fraction of a byte earned since the last refill.
+    remainder_nanos: u128,
+    delay: Option<Sleep>,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> TokenBucket {
+        assert!(
+            bytes_per_second > 0,
+            "a rate limit must be at least one byte per second"
+        );
+        let capacity = (bytes_per_second / 10).max(1);
+        TokenBucket {
+            bytes_per_second,
+            capacity,
+            available: capacity,
+            last_refill: Instant::now(),
+            remainder_nanos: 0,
+            delay: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.last_refill).as_nanos()
+            * self.bytes_per_second as u128
+            + self.remainder_nanos;
+        self.last_refill = now;
+        let bytes = earned / 1_000_000_000;
+        self.remainder_nanos = earned % 1_000_000_000;
+        self.available = (self.available as u128 + bytes).min(self.capacity as u128) as u64;
+        if self.available == self.capacity {
+            self.remainder_nanos = 0;
+        }
+    }
+
+    /// Waits until at least some of `wanted` bytes may be transferred, and returns how many.
+    fn poll_acquire(
+        &mut self,
+        runtime: &Handle,
+        cx: &mut Context<'_>,
+        wanted: usize,
+    ) -> Poll<usize> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            self.refill();
+            if self.available > 0 {
+                return Poll::Ready((self.available.min(wanted as u64)) as usize);
+            }
+            // Sleep until the smaller of the request and the bucket capacity has been earned, so a
+            // slow limit does not turn into a stream of one byte transfers.
+            let target = self.capacity.min(wanted as u64) as u128;
+            let nanos = (target * 1_000_000_000 - self.remainder_nanos)
+                .div_ceil(self.bytes_per_second as u128);
+            let wait = Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+            self.delay = Some(Sleep::new(runtime, wait));
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.available = self.available.saturating_sub(bytes as u64);
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ThrottledStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let bucket = match &mut this.read {
+            Some(bucket) if !buf.is_empty() => bucket,
+            _ => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        let allowed = ready!(bucket.poll_acquire(&this.runtime, cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let bucket = match &mut this.write {
+            Some(bucket) if !buf.is_empty() => bucket,
+            _ => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        let allowed = ready!(bucket.poll_acquire(&this.runtime, cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
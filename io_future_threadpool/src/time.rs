@@ -0,0 +1,118 @@
+use bindings::{
+    Windows::Win32::SystemServices::{
+        CloseThreadpoolTimer, CreateThreadpoolTimer, SetThreadpoolTimer,
+        WaitForThreadpoolTimerCallbacks, TP_CALLBACK_INSTANCE, TP_TIMER,
+    },
+    Windows::Win32::WindowsProgramming::FILETIME,
+};
+
+use std::ffi::c_void;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::runtime::Handle;
+
+/// Returns a future that completes after `duration` has elapsed. The timer is a threadpool timer on
+/// the current [crate::runtime::Runtime].
+///
+/// # Panics
+///
+/// Panics if called outside the context of a [crate::runtime::Runtime].
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::new(&Handle::current(), duration)
+}
+
+/// Future returned by [sleep].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sleep {
+    timer: *mut TP_TIMER,
+    // Created with Box::into_raw and passed to the timer callback.
+    state: *mut Mutex<SleepState>,
+}
+
+struct SleepState {
+    elapsed: bool,
+    waker: Option<Waker>,
+}
+
+// The timer is only touched through the threadpool APIs, which are free threaded.
+unsafe impl Send for Sleep {}
+unsafe impl Sync for Sleep {}
+
+extern "system" fn timer_callback(
+    _instance: *mut TP_CALLBACK_INSTANCE,
+    context: *mut c_void,
+    _timer: *mut TP_TIMER,
+) {
+    // Sleep::drop waits for this callback before releasing the state.
+    let state = unsafe { &*(context as *const Mutex<SleepState>) };
+    let waker = {
+        let mut state = state.lock().unwrap();
+        state.elapsed = true;
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl Sleep {
+    pub(crate) fn new(runtime: &Handle, duration: Duration) -> Sleep {
+        let state = Box::into_raw(Box::new(Mutex::new(SleepState {
+            elapsed: false,
+            waker: None,
+        })));
+        unsafe {
+            let timer = CreateThreadpoolTimer(
+                Some(timer_callback),
+                state as *mut c_void,
+                runtime.callback_environment(),
+            );
+            if timer.is_null() {
+                drop(Box::from_raw(state));
+                panic!(
+                    "failed to create threadpool timer: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            // A negative due time is relative, in 100 nanosecond units.
+            let due = -((duration.as_nanos() / 100).min(i64::MAX as u128) as i64);
+            let mut due_time = FILETIME {
+                dwLowDateTime: due as u32,
+                dwHighDateTime: (due >> 32) as u32,
+            };
+            SetThreadpoolTimer(timer, &mut due_time, 0, 0);
+            Sleep { timer, state }
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = unsafe { &*self.state }.lock().unwrap();
+        if state.elapsed {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        unsafe {
+            // Stop the timer and make sure the callback is not running before freeing the state.
+            SetThreadpoolTimer(self.timer, ptr::null_mut(), 0, 0);
+            WaitForThreadpoolTimerCallbacks(self.timer, true);
+            CloseThreadpoolTimer(self.timer);
+            drop(Box::from_raw(self.state));
+        }
+    }
+}
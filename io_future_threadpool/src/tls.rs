@@ -0,0 +1,849 @@
+//! TLS over [AsyncTcpStream], using the Windows SChannel security package.
+//!
+//! SChannel works on buffers rather than sockets: the handshake functions consume the bytes
+//! received from the peer and produce the bytes to send back, and records are encrypted and
+//! decrypted in place. [AsyncTlsStream] moves those bytes over the TCP stream with overlapped I/O,
+//! so neither the handshake nor the record layer ever blocks a threadpool thread on the network.
+//!
+//! Clients validate the server's certificate chain and name against the Windows certificate store,
+//! as SChannel does by default. Servers present a [Certificate] found in a system store. Only the
+//! protocols SChannel enables for `SCHANNEL_CRED` by default are offered, and renegotiation is not
+//! supported.
+
+use bindings::{
+    Windows::Win32::Security::{
+        AcceptSecurityContext, AcquireCredentialsHandleW, ApplyControlToken, CertCloseStore,
+        CertFindCertificateInStore, CertFreeCertificateContext, CertOpenStore, DecryptMessage,
+        DeleteSecurityContext, EncryptMessage, FreeContextBuffer, FreeCredentialsHandle,
+        InitializeSecurityContextW, QueryContextAttributesW, SecBuffer, SecBufferDesc, SecHandle,
+        SecPkgContext_StreamSizes, ACCEPT_SECURITY_CONTEXT_CONTEXT_REQ, CERT_CONTEXT,
+        CERT_FIND_FLAGS, CERT_OPEN_STORE_FLAGS, CERT_QUERY_ENCODING_TYPE, CRYPTOAPI_BLOB,
+        SCHANNEL_CRED, SCHANNEL_CRED_FLAGS, SECPKG_ATTR, SECPKG_CRED,
+    },
+    Windows::Win32::SystemServices::PSTR,
+};
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::ready;
+
+use std::ffi::{c_void, OsStr};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::pin::Pin;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::stream::AsyncTcpStream;
+
+const UNISP_NAME: &str = "Microsoft Unified Security Protocol Provider";
+
+const SEC_E_OK: i32 = 0;
+const SEC_I_CONTINUE_NEEDED: i32 = 0x00090312;
+const SEC_I_CONTEXT_EXPIRED: i32 = 0x00090317;
+const SEC_I_INCOMPLETE_CREDENTIALS: i32 = 0x00090320;
+const SEC_I_RENEGOTIATE: i32 = 0x00090321;
+const SEC_E_INCOMPLETE_MESSAGE: i32 = 0x80090318u32 as i32;
+
+const SECBUFFER_VERSION: u32 = 0;
+const SECBUFFER_EMPTY: u32 = 0;
+const SECBUFFER_DATA: u32 = 1;
+const SECBUFFER_TOKEN: u32 = 2;
+const SECBUFFER_EXTRA: u32 = 5;
+const SECBUFFER_STREAM_TRAILER: u32 = 6;
+const SECBUFFER_STREAM_HEADER: u32 = 7;
+
+const ISC_REQ_FLAGS: u32 = 0x4 // ISC_REQ_REPLAY_DETECT
+    | 0x8 // ISC_REQ_SEQUENCE_DETECT
+    | 0x10 // ISC_REQ_CONFIDENTIALITY
+    | 0x100 // ISC_REQ_ALLOCATE_MEMORY
+    | 0x4000 // ISC_REQ_EXTENDED_ERROR
+    | 0x8000; // ISC_REQ_STREAM
+const ASC_REQ_FLAGS: u32 = 0x4 // ASC_REQ_REPLAY_DETECT
+    | 0x8 // ASC_REQ_SEQUENCE_DETECT
+    | 0x10 // ASC_REQ_CONFIDENTIALITY
+    | 0x100 // ASC_REQ_ALLOCATE_MEMORY
+    | 0x8000 // ASC_REQ_EXTENDED_ERROR
+    | 0x10000; // ASC_REQ_STREAM
+
+// How much to read from the socket at a time. A TLS record is at most about 16 KiB.
+const READ_SIZE: usize = 16 * 1024 + 512;
+
+fn sspi_error(status: i32) -> io::Error {
+    // SSPI statuses are HRESULTs, which FormatMessage knows how to describe.
+    io::Error::from_raw_os_error(status)
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// Where a system certificate store lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    CurrentUser,
+    LocalMachine,
+}
+
+/// A certificate with a private key, for a server to present.
+pub struct Certificate {
+    context: *mut CERT_CONTEXT,
+}
+
+// A CERT_CONTEXT is immutable and reference counted, and CryptoAPI is thread safe.
+unsafe impl Send for Certificate {}
+unsafe impl Sync for Certificate {}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        unsafe {
+            CertFreeCertificateContext(self.context);
+        }
+    }
+}
+
+impl Certificate {
+    /// Finds the first certificate whose subject contains `subject` in the system store named
+    /// `store`, such as `"My"`.
+    pub fn find_by_subject(
+        location: StoreLocation,
+        store: &str,
+        subject: &str,
+    ) -> io::Result<Certificate> {
+        let subject = to_wide(subject);
+        Self::find(
+            location,
+            store,
+            CERT_FIND_FLAGS::CERT_FIND_SUBJECT_STR_W,
+            subject.as_ptr() as *const c_void,
+        )
+    }
+
+    /// Finds the certificate with the SHA-1 `thumbprint` in the system store named `store`.
+    pub fn find_by_thumbprint(
+        location: StoreLocation,
+        store: &str,
+        thumbprint: &[u8],
+    ) -> io::Result<Certificate> {
+        let blob = CRYPTOAPI_BLOB {
+            cbData: thumbprint.len() as u32,
+            pbData: thumbprint.as_ptr() as *mut u8,
+        };
+        Self::find(
+            location,
+            store,
+            CERT_FIND_FLAGS::CERT_FIND_HASH,
+            &blob as *const CRYPTOAPI_BLOB as *const c_void,
+        )
+    }
+
+    fn find(
+        location: StoreLocation,
+        store: &str,
+        find_type: CERT_FIND_FLAGS,
+        find_para: *const c_void,
+    ) -> io::Result<Certificate> {
+        const CERT_STORE_PROV_SYSTEM_W: usize = 10;
+        const CERT_SYSTEM_STORE_CURRENT_USER: u32 = 1 << 16;
+        const CERT_SYSTEM_STORE_LOCAL_MACHINE: u32 = 2 << 16;
+
+        let location = match location {
+            StoreLocation::CurrentUser => CERT_SYSTEM_STORE_CURRENT_USER,
+            StoreLocation::LocalMachine => CERT_SYSTEM_STORE_LOCAL_MACHINE,
+        };
+        let flags = location
+            | CERT_OPEN_STORE_FLAGS::CERT_STORE_OPEN_EXISTING_FLAG.0
+            | CERT_OPEN_STORE_FLAGS::CERT_STORE_READONLY_FLAG.0;
+        let encoding = CERT_QUERY_ENCODING_TYPE::X509_ASN_ENCODING.0
+            | CERT_QUERY_ENCODING_TYPE::PKCS_7_ASN_ENCODING.0;
+        let store = to_wide(store);
+        unsafe {
+            let hstore = CertOpenStore(
+                PSTR(CERT_STORE_PROV_SYSTEM_W as *mut u8),
+                CERT_QUERY_ENCODING_TYPE(0),
+                0,
+                CERT_OPEN_STORE_FLAGS(flags),
+                store.as_ptr() as *const c_void,
+            );
+            if hstore.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let context = CertFindCertificateInStore(
+                hstore,
+                encoding,
+                0,
+                find_type,
+                find_para,
+                ptr::null_mut(),
+            );
+            let err = io::Error::last_os_error();
+            // The certificate holds its own reference to the store.
+            CertCloseStore(hstore, 0);
+            if context.is_null() {
+                Err(err)
+            } else {
+                Ok(Certificate { context })
+            }
+        }
+    }
+}
+
+/// An SChannel credentials handle.
+struct Credentials {
+    handle: SecHandle,
+}
+
+// Credentials handles may be used from any thread, and by many contexts at once.
+unsafe impl Send for Credentials {}
+unsafe impl Sync for Credentials {}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        unsafe {
+            FreeCredentialsHandle(&mut self.handle);
+        }
+    }
+}
+
+impl Credentials {
+    fn acquire(
+        usage: SECPKG_CRED,
+        flags: u32,
+        certificate: Option<&Certificate>,
+    ) -> io::Result<Credentials> {
+        const SCHANNEL_CRED_VERSION: u32 = 4;
+        let mut cert_context = certificate.map_or(ptr::null_mut(), |c| c.context);
+        let mut cred = SCHANNEL_CRED {
+            dwVersion: SCHANNEL_CRED_VERSION,
+            dwFlags: SCHANNEL_CRED_FLAGS(flags),
+            ..Default::default()
+        };
+        if certificate.is_some() {
+            cred.cCreds = 1;
+            cred.paCred = &mut cert_context;
+        }
+        let mut handle = SecHandle::default();
+        let status = unsafe {
+            AcquireCredentialsHandleW(
+                None,
+                UNISP_NAME,
+                usage,
+                ptr::null_mut(),
+                &mut cred as *mut SCHANNEL_CRED as *mut c_void,
+                None,
+                ptr::null_mut(),
+                &mut handle,
+                ptr::null_mut(),
+            )
+        };
+        if status == SEC_E_OK {
+            Ok(Credentials { handle })
+        } else {
+            Err(sspi_error(status))
+        }
+    }
+}
+
+/// Starts TLS client connections.
+///
+/// Creating a connector acquires an SChannel credentials handle, which is shared by all the
+/// connections it makes. Clone it rather than creating one per connection.
+#[derive(Clone)]
+pub struct TlsConnector {
+    credentials: Arc<Credentials>,
+}
+
+impl TlsConnector {
+    /// A connector that validates the server's certificate and name.
+    pub fn new() -> io::Result<TlsConnector> {
+        TlsConnector::builder().build()
+    }
+
+    pub fn builder() -> TlsConnectorBuilder {
+        TlsConnectorBuilder::default()
+    }
+
+    /// Performs the client side of the handshake over `stream`. `domain` is sent as the server
+    /// name indication and is the name the server's certificate must match.
+    pub async fn connect(
+        &self,
+        domain: &str,
+        stream: AsyncTcpStream,
+    ) -> io::Result<AsyncTlsStream> {
+        let mut tls = AsyncTlsStream::new(stream, self.credentials.clone(), Some(to_wide(domain)));
+        tls.client_handshake().await?;
+        Ok(tls)
+    }
+}
+
+/// Configures a [TlsConnector].
+#[derive(Clone, Debug, Default)]
+pub struct TlsConnectorBuilder {
+    accept_invalid_certs: bool,
+    accept_invalid_hostnames: bool,
+}
+
+impl TlsConnectorBuilder {
+    /// Skips validating the server's certificate entirely. This makes the connection open to
+    /// interception, and is only for testing against servers with self-signed certificates.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> TlsConnectorBuilder {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Validates the server's certificate chain, but not that it was issued for the domain
+    /// passed to [TlsConnector::connect].
+    pub fn danger_accept_invalid_hostnames(mut self, accept: bool) -> TlsConnectorBuilder {
+        self.accept_invalid_hostnames = accept;
+        self
+    }
+
+    pub fn build(self) -> io::Result<TlsConnector> {
+        const SCH_CRED_NO_SERVERNAME_CHECK: u32 = 0x4;
+        const SCH_CRED_MANUAL_CRED_VALIDATION: u32 = 0x8;
+        const SCH_CRED_NO_DEFAULT_CREDS: u32 = 0x10;
+        const SCH_CRED_AUTO_CRED_VALIDATION: u32 = 0x20;
+        const SCH_USE_STRONG_CRYPTO: u32 = 0x00400000;
+
+        let mut flags = SCH_CRED_NO_DEFAULT_CREDS | SCH_USE_STRONG_CRYPTO;
+        if self.accept_invalid_certs {
+            flags |= SCH_CRED_MANUAL_CRED_VALIDATION | SCH_CRED_NO_SERVERNAME_CHECK;
+        } else {
+            flags |= SCH_CRED_AUTO_CRED_VALIDATION;
+            if self.accept_invalid_hostnames {
+                flags |= SCH_CRED_NO_SERVERNAME_CHECK;
+            }
+        }
+        let credentials = Credentials::acquire(SECPKG_CRED::SECPKG_CRED_OUTBOUND, flags, None)?;
+        Ok(TlsConnector {
+            credentials: Arc::new(credentials),
+        })
+    }
+}
+
+/// Accepts TLS server connections, presenting a [Certificate].
+///
+/// Like [TlsConnector], an acceptor holds a credentials handle that is shared by its connections.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    credentials: Arc<Credentials>,
+}
+
+impl TlsAcceptor {
+    /// The certificate must have a private key the process can use.
+    pub fn new(certificate: &Certificate) -> io::Result<TlsAcceptor> {
+        const SCH_USE_STRONG_CRYPTO: u32 = 0x00400000;
+        let credentials = Credentials::acquire(
+            SECPKG_CRED::SECPKG_CRED_INBOUND,
+            SCH_USE_STRONG_CRYPTO,
+            Some(certificate),
+        )?;
+        Ok(TlsAcceptor {
+            credentials: Arc::new(credentials),
+        })
+    }
+
+    /// Performs the server side of the handshake over `stream`.
+    pub async fn accept(&self, stream: AsyncTcpStream) -> io::Result<AsyncTlsStream> {
+        let mut tls = AsyncTlsStream::new(stream, self.credentials.clone(), None);
+        tls.server_handshake().await?;
+        Ok(tls)
+    }
+}
+
+/// A TLS connection over an [AsyncTcpStream], created by [TlsConnector::connect] or
+/// [TlsAcceptor::accept].
+///
+/// Data is read and written through the [AsyncRead] and [AsyncWrite] implementations. Closing the
+/// stream sends a close_notify alert before shutting down the TCP connection's write half.
+pub struct AsyncTlsStream {
+    stream: AsyncTcpStream,
+    // The context is deleted before the credentials it was created from are released.
+    context: SecurityContext,
+    credentials: Arc<Credentials>,
+    // The server's name for a client, None for a server.
+    target_name: Option<Vec<u16>>,
+    sizes: SecPkgContext_StreamSizes,
+    // Bytes received from the peer that have not been decrypted yet.
+    incoming: Vec<u8>,
+    // Decrypted data that has not been returned by poll_read yet.
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    read_eof: bool,
+    // An encrypted record being sent, how much of it has been sent, and how many bytes of the
+    // caller's data it holds.
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    outgoing_plaintext: usize,
+    close_notify_queued: bool,
+}
+
+struct SecurityContext {
+    handle: SecHandle,
+    valid: bool,
+}
+
+impl Drop for SecurityContext {
+    fn drop(&mut self) {
+        if self.valid {
+            unsafe {
+                DeleteSecurityContext(&mut self.handle);
+            }
+        }
+    }
+}
+
+// The context is only used through &mut AsyncTlsStream.
+unsafe impl Send for AsyncTlsStream {}
+unsafe impl Sync for AsyncTlsStream {}
+
+/// Output token buffers allocated by SSPI, freed on drop.
+struct OutputToken(SecBuffer);
+
+impl OutputToken {
+    fn new() -> OutputToken {
+        OutputToken(SecBuffer {
+            cbBuffer: 0,
+            BufferType: SECBUFFER_TOKEN,
+            pvBuffer: ptr::null_mut(),
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.0.pvBuffer.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.0.pvBuffer as *const u8, self.0.cbBuffer as usize) }
+        }
+    }
+}
+
+impl Drop for OutputToken {
+    fn drop(&mut self) {
+        if !self.0.pvBuffer.is_null() {
+            unsafe {
+                FreeContextBuffer(self.0.pvBuffer);
+            }
+        }
+    }
+}
+
+fn buffer_desc(buffers: &mut [SecBuffer]) -> SecBufferDesc {
+    SecBufferDesc {
+        ulVersion: SECBUFFER_VERSION,
+        cBuffers: buffers.len() as u32,
+        pBuffers: buffers.as_mut_ptr(),
+    }
+}
+
+impl AsyncTlsStream {
+    fn new(
+        stream: AsyncTcpStream,
+        credentials: Arc<Credentials>,
+        target_name: Option<Vec<u16>>,
+    ) -> AsyncTlsStream {
+        AsyncTlsStream {
+            stream,
+            context: SecurityContext {
+                handle: SecHandle::default(),
+                valid: false,
+            },
+            credentials,
+            target_name,
+            sizes: SecPkgContext_StreamSizes::default(),
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+            read_eof: false,
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+            outgoing_plaintext: 0,
+            close_notify_queued: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &AsyncTcpStream {
+        &self.stream
+    }
+
+    /// Reads more of the handshake from the peer into `incoming`.
+    async fn read_handshake(&mut self) -> io::Result<()> {
+        let start = self.incoming.len();
+        self.incoming.resize(start + READ_SIZE, 0);
+        let n = (&self.stream).read(&mut self.incoming[start..]).await;
+        self.incoming.truncate(start + *n.as_ref().unwrap_or(&0));
+        match n? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during the TLS handshake",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the handshake input that SChannel consumed, keeping the `extra` bytes at the end.
+    fn keep_extra(&mut self, extra: &SecBuffer) {
+        if extra.BufferType == SECBUFFER_EXTRA {
+            let consumed = self.incoming.len() - extra.cbBuffer as usize;
+            self.incoming.drain(..consumed);
+        } else {
+            self.incoming.clear();
+        }
+    }
+
+    /// Runs one step of the handshake over the bytes in `incoming`, returning the status and the
+    /// token to send to the peer.
+    fn handshake_step(&mut self) -> (i32, OutputToken) {
+        let mut input = [
+            SecBuffer {
+                cbBuffer: self.incoming.len() as u32,
+                BufferType: SECBUFFER_TOKEN,
+                pvBuffer: self.incoming.as_mut_ptr() as *mut c_void,
+            },
+            SecBuffer {
+                cbBuffer: 0,
+                BufferType: SECBUFFER_EMPTY,
+                pvBuffer: ptr::null_mut(),
+            },
+        ];
+        let mut input_desc = buffer_desc(&mut input);
+        // There is no input on a client's first step, nor when generating close_notify.
+        let input_desc = if self.incoming.is_empty() {
+            ptr::null_mut()
+        } else {
+            &mut input_desc as *mut SecBufferDesc
+        };
+        let mut output = OutputToken::new();
+        let mut output_desc = buffer_desc(slice::from_mut(&mut output.0));
+        let mut attributes: u32 = 0;
+        // SSPI takes the credentials handle by pointer but does not change it.
+        let mut credentials = self.credentials.handle;
+        let context = if self.context.valid {
+            &mut self.context.handle as *mut SecHandle
+        } else {
+            ptr::null_mut()
+        };
+        let status = unsafe {
+            match &mut self.target_name {
+                Some(target_name) => InitializeSecurityContextW(
+                    &mut credentials,
+                    context,
+                    target_name.as_mut_ptr(),
+                    ISC_REQ_FLAGS,
+                    0,
+                    0,
+                    input_desc,
+                    0,
+                    &mut self.context.handle,
+                    &mut output_desc,
+                    &mut attributes,
+                    ptr::null_mut(),
+                ),
+                None => AcceptSecurityContext(
+                    &mut credentials,
+                    context,
+                    input_desc,
+                    ACCEPT_SECURITY_CONTEXT_CONTEXT_REQ(ASC_REQ_FLAGS),
+                    0,
+                    &mut self.context.handle,
+                    &mut output_desc,
+                    &mut attributes,
+                    ptr::null_mut(),
+                ),
+            }
+        };
+        // The first call creates the context only when it succeeds or asks to continue. A first
+        // call that fails leaves no context behind, so there is nothing to delete.
+        if !self.context.valid && (status == SEC_E_OK || status == SEC_I_CONTINUE_NEEDED) {
+            self.context.valid = true;
+        }
+        if status == SEC_E_OK || status == SEC_I_CONTINUE_NEEDED {
+            self.keep_extra(&input[1]);
+        }
+        (status, output)
+    }
+
+    async fn client_handshake(&mut self) -> io::Result<()> {
+        self.handshake().await
+    }
+
+    async fn server_handshake(&mut self) -> io::Result<()> {
+        // The client speaks first.
+        self.read_handshake().await?;
+        self.handshake().await
+    }
+
+    async fn handshake(&mut self) -> io::Result<()> {
+        loop {
+            let (status, output) = self.handshake_step();
+            // On failure, the output can hold an alert telling the peer why.
+            if !output.as_slice().is_empty() {
+                let sent = self.stream.write_all(output.as_slice()).await;
+                if status == SEC_E_OK || status == SEC_I_CONTINUE_NEEDED {
+                    sent?;
+                }
+            }
+            match status {
+                SEC_E_OK => break,
+                SEC_I_CONTINUE_NEEDED => {
+                    if self.incoming.is_empty() {
+                        self.read_handshake().await?;
+                    }
+                }
+                SEC_E_INCOMPLETE_MESSAGE => self.read_handshake().await?,
+                // The server asked for a client certificate. Carry on without one.
+                SEC_I_INCOMPLETE_CREDENTIALS => {}
+                _ => return Err(sspi_error(status)),
+            }
+        }
+
+        let status = unsafe {
+            QueryContextAttributesW(
+                &mut self.context.handle,
+                SECPKG_ATTR::SECPKG_ATTR_STREAM_SIZES,
+                &mut self.sizes as *mut SecPkgContext_StreamSizes as *mut c_void,
+            )
+        };
+        if status == SEC_E_OK {
+            Ok(())
+        } else {
+            Err(sspi_error(status))
+        }
+    }
+
+    /// Decrypts a record from `incoming` into `plaintext`. Returns false if `incoming` does not
+    /// hold a whole record yet.
+    fn decrypt(&mut self) -> io::Result<bool> {
+        if self.incoming.is_empty() {
+            return Ok(false);
+        }
+        let empty = SecBuffer {
+            cbBuffer: 0,
+            BufferType: SECBUFFER_EMPTY,
+            pvBuffer: ptr::null_mut(),
+        };
+        let mut buffers = [
+            SecBuffer {
+                cbBuffer: self.incoming.len() as u32,
+                BufferType: SECBUFFER_DATA,
+                pvBuffer: self.incoming.as_mut_ptr() as *mut c_void,
+            },
+            empty,
+            empty,
+            empty,
+        ];
+        let mut desc = buffer_desc(&mut buffers);
+        let status =
+            unsafe { DecryptMessage(&mut self.context.handle, &mut desc, 0, ptr::null_mut()) };
+        match status {
+            SEC_E_OK => {}
+            SEC_E_INCOMPLETE_MESSAGE => return Ok(false),
+            SEC_I_CONTEXT_EXPIRED => {
+                // The peer sent close_notify.
+                self.read_eof = true;
+                self.incoming.clear();
+                return Ok(true);
+            }
+            SEC_I_RENEGOTIATE => {
+                return Err(io::Error::other("TLS renegotiation is not supported"));
+            }
+            _ => return Err(sspi_error(status)),
+        }
+        // The data was decrypted in place, so copy it out before trimming incoming.
+        self.plaintext.clear();
+        self.plaintext_pos = 0;
+        let mut extra = 0;
+        for buffer in &buffers {
+            match buffer.BufferType {
+                SECBUFFER_DATA => self.plaintext.extend_from_slice(unsafe {
+                    slice::from_raw_parts(buffer.pvBuffer as *const u8, buffer.cbBuffer as usize)
+                }),
+                SECBUFFER_EXTRA => extra = buffer.cbBuffer as usize,
+                _ => {}
+            }
+        }
+        let consumed = self.incoming.len() - extra;
+        self.incoming.drain(..consumed);
+        Ok(true)
+    }
+
+    /// Encrypts `data`, which must be no longer than the maximum message size, into `outgoing`.
+    fn encrypt(&mut self, data: &[u8]) -> io::Result<()> {
+        let header = self.sizes.cbHeader as usize;
+        let trailer = self.sizes.cbTrailer as usize;
+        self.outgoing.clear();
+        self.outgoing.resize(header + data.len() + trailer, 0);
+        self.outgoing[header..header + data.len()].copy_from_slice(data);
+        let base = self.outgoing.as_mut_ptr();
+        let mut buffers = [
+            SecBuffer {
+                cbBuffer: header as u32,
+                BufferType: SECBUFFER_STREAM_HEADER,
+                pvBuffer: base as *mut c_void,
+            },
+            SecBuffer {
+                cbBuffer: data.len() as u32,
+                BufferType: SECBUFFER_DATA,
+                pvBuffer: unsafe { base.add(header) } as *mut c_void,
+            },
+            SecBuffer {
+                cbBuffer: trailer as u32,
+                BufferType: SECBUFFER_STREAM_TRAILER,
+                pvBuffer: unsafe { base.add(header + data.len()) } as *mut c_void,
+            },
+            SecBuffer {
+                cbBuffer: 0,
+                BufferType: SECBUFFER_EMPTY,
+                pvBuffer: ptr::null_mut(),
+            },
+        ];
+        let mut desc = buffer_desc(&mut buffers);
+        let status = unsafe { EncryptMessage(&mut self.context.handle, 0, &mut desc, 0) };
+        if status != SEC_E_OK {
+            self.outgoing.clear();
+            return Err(sspi_error(status));
+        }
+        // The trailer can come out shorter than its maximum size.
+        let len = (buffers[0].cbBuffer + buffers[1].cbBuffer + buffers[2].cbBuffer) as usize;
+        self.outgoing.truncate(len);
+        self.outgoing_pos = 0;
+        Ok(())
+    }
+
+    /// Puts a close_notify alert in `outgoing`.
+    fn queue_close_notify(&mut self) -> io::Result<()> {
+        const SCHANNEL_SHUTDOWN: u32 = 1;
+        let mut shutdown = SCHANNEL_SHUTDOWN;
+        let mut control = [SecBuffer {
+            cbBuffer: mem::size_of::<u32>() as u32,
+            BufferType: SECBUFFER_TOKEN,
+            pvBuffer: &mut shutdown as *mut u32 as *mut c_void,
+        }];
+        let mut control_desc = buffer_desc(&mut control);
+        let status = unsafe { ApplyControlToken(&mut self.context.handle, &mut control_desc) };
+        if status != SEC_E_OK {
+            return Err(sspi_error(status));
+        }
+        // With the shutdown applied, the next handshake step produces the alert.
+        self.incoming.clear();
+        let (status, output) = self.handshake_step();
+        if status != SEC_E_OK && status != SEC_I_CONTEXT_EXPIRED {
+            return Err(sspi_error(status));
+        }
+        self.outgoing.clear();
+        self.outgoing.extend_from_slice(output.as_slice());
+        self.outgoing_pos = 0;
+        self.outgoing_plaintext = 0;
+        Ok(())
+    }
+
+    /// Sends the rest of `outgoing`.
+    fn poll_send_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            let n = ready!(
+                Pin::new(&mut &self.stream).poll_write(cx, &self.outgoing[self.outgoing_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += n;
+        }
+        self.outgoing.clear();
+        self.outgoing_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for AsyncTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let n = available.len().min(buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                this.plaintext_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.read_eof {
+                return Poll::Ready(Ok(0));
+            }
+            if this.decrypt()? {
+                continue;
+            }
+
+            let start = this.incoming.len();
+            this.incoming.resize(start + READ_SIZE, 0);
+            let read = Pin::new(&mut &this.stream).poll_read(cx, &mut this.incoming[start..]);
+            let n = match read {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => {
+                    this.incoming.truncate(start);
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => {
+                    this.incoming.truncate(start);
+                    return Poll::Pending;
+                }
+            };
+            this.incoming.truncate(start + n);
+            if n == 0 {
+                if this.incoming.is_empty() {
+                    // The peer closed the connection without close_notify. Like most TLS
+                    // libraries, treat that as the end of the stream.
+                    this.read_eof = true;
+                } else {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a TLS record",
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTlsStream {
+    /// Encrypts up to one record's worth of `buf` and completes once the record has been sent.
+    /// As with [AsyncTcpStream], if this returns `Pending` the next call must pass the same data.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.outgoing.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let len = buf.len().min(this.sizes.cbMaximumMessage as usize);
+            this.encrypt(&buf[..len])?;
+            this.outgoing_plaintext = len;
+        }
+        ready!(this.poll_send_outgoing(cx))?;
+        Poll::Ready(Ok(mem::take(&mut this.outgoing_plaintext)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_outgoing(cx))?;
+        Pin::new(&mut &this.stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_outgoing(cx))?;
+        if !this.close_notify_queued {
+            this.close_notify_queued = true;
+            this.queue_close_notify()?;
+            ready!(this.poll_send_outgoing(cx))?;
+        }
+        Pin::new(&mut &this.stream).poll_close(cx)
+    }
+}
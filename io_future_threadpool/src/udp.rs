@@ -0,0 +1,819 @@
+//! UDP sockets whose sends and receives complete on the runtime's threadpool.
+
+use bindings::{
+    Windows::Win32::SystemServices::PSTR,
+    Windows::Win32::WinSock::{WSARecvFrom, WSASendTo, IN6_PKTINFO, IN_PKTINFO, WSABUF, WSAMSG},
+};
+
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+use std::ptr;
+
+use crate::iocp_threadpool::start_async_io;
+use crate::iocp_threadpool::{IocpFuture, IocpResult, Tpio};
+use crate::runtime::{ConnectionGuard, Handle};
+use crate::sockaddr::{self, RawSocketAddr};
+use crate::sockopt;
+
+const WSAEMSGSIZE: i32 = 10040;
+
+/// Where a datagram received with [AsyncUdpSocket::recv_msg] was delivered, or which local address
+/// and interface [AsyncUdpSocket::send_msg] sends from. This is the `IP_PKTINFO` or
+/// `IPV6_PKTINFO` control data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// The local address the datagram was sent to, or the source address to send from.
+    pub addr: IpAddr,
+    /// The index of the interface the datagram arrived on, or the interface to send from. When
+    /// sending, 0 lets the routing table pick.
+    pub interface_index: u32,
+}
+
+/// The Explicit Congestion Notification codepoint in the IP header of a datagram, from RFC 3168.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ecn {
+    /// Not ECN-capable transport.
+    NotEct,
+    /// ECN-capable transport, ECT(1).
+    Ect1,
+    /// ECN-capable transport, ECT(0).
+    Ect0,
+    /// Congestion experienced.
+    Ce,
+}
+
+impl Ecn {
+    fn from_codepoint(codepoint: i32) -> Ecn {
+        match codepoint & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    fn codepoint(self) -> i32 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+/// The control data of a datagram received with [AsyncUdpSocket::recv_msg].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecvMeta {
+    /// Where the datagram was delivered, if [AsyncUdpSocket::set_packet_info] is turned on.
+    pub packet_info: Option<PacketInfo>,
+    /// The ECN codepoint the datagram arrived with, if [AsyncUdpSocket::set_recv_ecn] is turned
+    /// on.
+    pub ecn: Option<Ecn>,
+}
+
+/// Per-datagram options for [AsyncUdpSocket::send_msg_with_options], sent as `WSASendMsg` control
+/// data. Versions of Windows that do not understand a piece of control data fail the send.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SendOptions {
+    source: Option<PacketInfo>,
+    ttl: Option<u32>,
+    ecn: Option<Ecn>,
+    segment_size: Option<u32>,
+}
+
+impl SendOptions {
+    pub fn new() -> SendOptions {
+        SendOptions::default()
+    }
+
+    /// Sends from this local address and interface, as [AsyncUdpSocket::send_msg] does.
+    pub fn source(mut self, source: PacketInfo) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Sets the TTL, or the hop limit for IPv6, of this datagram instead of the socket's. Sending
+    /// fails with `InvalidInput` if it is above 255.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Marks the datagram with an ECN codepoint.
+    pub fn ecn(mut self, ecn: Ecn) -> Self {
+        self.ecn = Some(ecn);
+        self
+    }
+
+    /// Splits the buffer into datagrams of this size, as [AsyncUdpSocket::send_segmented] does.
+    pub fn segment_size(mut self, segment_size: u32) -> Self {
+        self.segment_size = Some(segment_size);
+        self
+    }
+}
+
+/// A UDP socket whose I/O completes on the runtime's threadpool.
+///
+/// Sends and receives only need `&self`, and every operation has its own `OVERLAPPED`, so several
+/// tasks can send and receive at once, for example by sharing the socket through an `Arc`.
+pub struct AsyncUdpSocket {
+    // Declared first so the socket is unregistered from the runtime before it closes.
+    _connection: ConnectionGuard,
+    tp_io: Tpio<UdpSocket>,
+}
+
+impl AsyncUdpSocket {
+    /// Creates a socket bound to `addr`. Bind to port 0 to have the system pick a port, and find
+    /// out which with [AsyncUdpSocket::local_addr].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<AsyncUdpSocket> {
+        Self::from_std(UdpSocket::bind(addr)?)
+    }
+
+    /// Wraps a bound [UdpSocket], for example one configured with socket2. The socket must have
+    /// been created for overlapped I/O, which sockets created by std and by `WSASocketW` with
+    /// `WSA_FLAG_OVERLAPPED` are. It is associated with the runtime's completion port, so it cannot
+    /// already be associated with another one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn from_std(socket: UdpSocket) -> io::Result<AsyncUdpSocket> {
+        set_udp_connreset(&socket, false)?;
+        let tp_io = Tpio::new(socket)?;
+        let connection = Handle::current_for_io()?.track_connection(tp_io.socket());
+        Ok(AsyncUdpSocket {
+            _connection: connection,
+            tp_io,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tp_io.get_ref().local_addr()
+    }
+
+    /// Sets `SO_BROADCAST`, which [AsyncUdpSocket::send_to] needs to send to a broadcast address
+    /// such as `255.255.255.255` or a subnet's broadcast address. Without it those sends fail with
+    /// `WSAEACCES`.
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.tp_io.get_ref().set_broadcast(broadcast)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.tp_io.get_ref().broadcast()
+    }
+
+    /// Sets the `IP_TOS` field for IPv4 datagrams sent from this socket. Like
+    /// [crate::stream::AsyncTcpStream::set_tos], Windows ignores it unless the system is configured
+    /// to honor application-set DSCP values.
+    pub fn set_tos(&self, tos: u32) -> io::Result<()> {
+        sockopt::set_tos(self.tp_io.get_ref(), tos)
+    }
+
+    pub fn tos(&self) -> io::Result<u32> {
+        sockopt::tos(self.tp_io.get_ref())
+    }
+
+    /// Sets the `IPV6_TCLASS` field for IPv6 datagrams sent from this socket, the IPv6 equivalent
+    /// of [AsyncUdpSocket::set_tos].
+    pub fn set_traffic_class(&self, tclass: u32) -> io::Result<()> {
+        sockopt::set_traffic_class(self.tp_io.get_ref(), tclass)
+    }
+
+    pub fn traffic_class(&self) -> io::Result<u32> {
+        sockopt::traffic_class(self.tp_io.get_ref())
+    }
+
+    /// Sets whether an ICMP port unreachable message, which a host sends back when a datagram
+    /// arrives at a port nothing is listening on, makes the next receive fail with
+    /// `WSAECONNRESET`. This is `SIO_UDP_CONNRESET`. On by default in Windows but turned off by
+    /// [AsyncUdpSocket::bind] and [AsyncUdpSocket::from_std], because the reset usually comes from
+    /// an unrelated peer and would otherwise end a [AsyncUdpSocket::recv_from] loop serving
+    /// everyone else.
+    pub fn set_report_connection_resets(&self, report: bool) -> io::Result<()> {
+        set_udp_connreset(self.tp_io.get_ref(), report)
+    }
+
+    /// Sets the address [AsyncUdpSocket::send] sends to, and only receives datagrams from that
+    /// address. If `addr` resolves to several addresses, the first one that works is used. Nothing
+    /// is sent to the peer, so this completes immediately.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.tp_io.get_ref().connect(addr)
+    }
+
+    /// The address the socket is connected to with [AsyncUdpSocket::connect].
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tp_io.get_ref().peer_addr()
+    }
+
+    /// Sends `buf` as a single datagram to the address the socket is connected to, and returns the
+    /// number of bytes sent.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_ptr() as *mut u8),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_send_to(&mut wsabuf, None) }
+        };
+        op.await.get_number_of_bytes_transferred()
+    }
+
+    /// Receives a single datagram from the address the socket is connected to into `buf`, and
+    /// returns its length. Like [AsyncUdpSocket::recv_from], a datagram that does not fit fails
+    /// with `WSAEMSGSIZE`.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_recv_from(&mut wsabuf, None) }
+        };
+        datagram_len(op.await)
+    }
+
+    /// Sends `buf` as a single datagram to `target`, and returns the number of bytes sent. If
+    /// `target` resolves to several addresses, the first one is used.
+    pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], target: A) -> io::Result<usize> {
+        let target = match target.to_socket_addrs()?.next() {
+            Some(addr) => RawSocketAddr::new(&addr),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no addresses to send data to",
+                ))
+            }
+        };
+        // Winsock captures the WSABUF before WSASendTo returns, so it does not have to live across
+        // the await.
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_ptr() as *mut u8),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_send_to(&mut wsabuf, Some(&target)) }
+        };
+        op.await.get_number_of_bytes_transferred()
+    }
+
+    /// Receives a single datagram into `buf`, and returns its length and the address it came from.
+    ///
+    /// Like [UdpSocket::recv_from], if the datagram does not fit in `buf` the rest of it is
+    /// discarded and this fails with `WSAEMSGSIZE`.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // WSARecvFrom writes the source address when the datagram arrives, so unlike the WSABUF,
+        // `from` has to outlive the operation.
+        let mut from = RawSocketAddr::empty();
+        let op = {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            unsafe { self.start_recv_from(&mut wsabuf, Some(&mut from)) }
+        };
+        let received = datagram_len(op.await)?;
+        Ok((received, from.to_socket_addr()?))
+    }
+
+    /// Turns on `IP_PKTINFO` or `IPV6_PKTINFO`, which makes [AsyncUdpSocket::recv_msg] report the
+    /// local address each datagram was sent to. A dual-stack socket gets both.
+    pub fn set_packet_info(&self, enabled: bool) -> io::Result<()> {
+        self.set_ip_option(sockopt::IP_PKTINFO, sockopt::IPV6_PKTINFO, enabled)
+    }
+
+    /// Turns on `IP_RECVECN` or `IPV6_RECVECN`, which makes [AsyncUdpSocket::recv_msg] report the
+    /// ECN codepoint of each datagram. A dual-stack socket gets both.
+    pub fn set_recv_ecn(&self, enabled: bool) -> io::Result<()> {
+        self.set_ip_option(sockopt::IP_ECN, sockopt::IPV6_ECN, enabled)
+    }
+
+    // Sets an IPv4 option on an IPv4 socket, an IPv6 option on an IPv6 socket, and both on a
+    // dual-stack socket.
+    fn set_ip_option(&self, v4_name: i32, v6_name: i32, enabled: bool) -> io::Result<()> {
+        if self.local_addr()?.is_ipv6() {
+            sockopt::set(
+                self.tp_io.get_ref(),
+                sockopt::IPPROTO_IPV6,
+                v6_name,
+                enabled as u32,
+            )?;
+            let only_v6: u32 = sockopt::get(
+                self.tp_io.get_ref(),
+                sockopt::IPPROTO_IPV6,
+                sockopt::IPV6_V6ONLY,
+            )?;
+            if only_v6 != 0 {
+                return Ok(());
+            }
+        }
+        sockopt::set(
+            self.tp_io.get_ref(),
+            sockopt::IPPROTO_IP,
+            v4_name,
+            enabled as u32,
+        )
+    }
+
+    /// Receives a single datagram into `buf` with `WSARecvMsg`, and returns its length, the
+    /// address it came from, and its control data. The control data is only filled in for the
+    /// options that have been turned on, such as [AsyncUdpSocket::set_packet_info]. A server with
+    /// several addresses can reply from the address the client used by passing the packet info to
+    /// [AsyncUdpSocket::send_msg].
+    ///
+    /// Like [AsyncUdpSocket::recv_from], a datagram that does not fit fails with `WSAEMSGSIZE`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn recv_msg(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, RecvMeta)> {
+        let wsarecvmsg = Handle::current_for_io()?
+            .wsa_functions()
+            .wsarecvmsg(self.tp_io.get_ref())?;
+        let mut state = MsgState::new(buf.as_mut_ptr(), buf.len());
+        let op = {
+            let msg = state.prepare(mem::size_of::<ControlBuffer>());
+            let hand: usize = self.tp_io.socket().try_into().unwrap();
+            start_async_io(&self.tp_io, |overlapped| unsafe {
+                let mut received: u32 = 0;
+                let rc = wsarecvmsg(hand, msg, &mut received, overlapped, ptr::null_mut());
+                if rc == 0 {
+                    Some(received as usize)
+                } else {
+                    None
+                }
+            })
+        };
+        let received = datagram_len(op.await)?;
+        // WSARecvMsg updates the lengths in the WSAMSG to what it wrote.
+        let from = unsafe { sockaddr::to_socket_addr(state.addr.as_ptr(), state.msg.namelen)? };
+        let control_len = (state.msg.Control.len as usize).min(mem::size_of::<ControlBuffer>());
+        Ok((received, from, state.control.recv_meta(control_len)))
+    }
+
+    /// Sends `buf` as a single datagram to `target` with `WSASendMsg`, and returns the number of
+    /// bytes sent. If `source` is given, the datagram is sent from that local address and
+    /// interface, typically the [PacketInfo] of the datagram being replied to. The source is
+    /// given at the level of the IP version the datagram is sent with, so on a dual-stack socket
+    /// an IPv4 or IPv4-mapped source is used as is for an IPv4-mapped target, and mapped to IPv6
+    /// for an IPv6 target.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn send_msg(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        source: Option<PacketInfo>,
+    ) -> io::Result<usize> {
+        let mut options = SendOptions::new();
+        options.source = source;
+        self.send_msg_with_options(buf, target, &options).await
+    }
+
+    /// Sends `buf` to `target` as a series of datagrams of `segment_size` bytes each, the last of
+    /// which may be shorter, using UDP segmentation offload (`UDP_SEND_MSG_SIZE`). The network
+    /// adapter, or the stack if the adapter cannot, splits the buffer into datagrams, so sending
+    /// all of them takes a single operation and a single completion. Returns the number of bytes
+    /// sent.
+    ///
+    /// This needs Windows 10 version 2004 or later; older versions fail the send.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn send_segmented(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        segment_size: u32,
+    ) -> io::Result<usize> {
+        let options = SendOptions::new().segment_size(segment_size);
+        self.send_msg_with_options(buf, target, &options).await
+    }
+
+    /// Sends `buf` to `target` with `WSASendMsg` and the per-datagram `options`, and returns the
+    /// number of bytes sent. This is how a protocol doing its own congestion control, such as QUIC,
+    /// marks its datagrams with [Ecn::Ect0].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime], or with
+    /// `InvalidInput` if the TTL is above 255 or an IPv6 source is given for an IPv4 target.
+    pub async fn send_msg_with_options(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        options: &SendOptions,
+    ) -> io::Result<usize> {
+        let wsasendmsg = Handle::current_for_io()?
+            .wsa_functions()
+            .wsasendmsg(self.tp_io.get_ref())?;
+        let mut state = MsgState::new(buf.as_ptr() as *mut u8, buf.len());
+        state.addr = RawSocketAddr::new(&target);
+        // The control data has to be at the level of the IP version the datagram is sent with,
+        // which for an IPv4-mapped target on a dual-stack socket is IPv4.
+        let target_v4 = match target.ip() {
+            IpAddr::V4(..) => true,
+            IpAddr::V6(addr) => addr.to_ipv4_mapped().is_some(),
+        };
+        let mut control_len = 0;
+        if let Some(ttl) = options.ttl {
+            let ttl: u8 = ttl.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "TTL must be at most 255")
+            })?;
+            let ttl = i32::from(ttl);
+            control_len = if target_v4 {
+                state
+                    .control
+                    .push(control_len, sockopt::IPPROTO_IP, sockopt::IP_TTL, ttl)
+            } else {
+                state.control.push(
+                    control_len,
+                    sockopt::IPPROTO_IPV6,
+                    sockopt::IPV6_HOPLIMIT,
+                    ttl,
+                )
+            };
+        }
+        if let Some(ecn) = options.ecn {
+            control_len = if target_v4 {
+                state.control.push(
+                    control_len,
+                    sockopt::IPPROTO_IP,
+                    sockopt::IP_ECN,
+                    ecn.codepoint(),
+                )
+            } else {
+                state.control.push(
+                    control_len,
+                    sockopt::IPPROTO_IPV6,
+                    sockopt::IPV6_ECN,
+                    ecn.codepoint(),
+                )
+            };
+        }
+        if let Some(source) = options.source {
+            control_len = state
+                .control
+                .push_packet_info(control_len, &source, target_v4)?;
+        }
+        if let Some(segment_size) = options.segment_size {
+            control_len = state.control.push(
+                control_len,
+                sockopt::IPPROTO_UDP,
+                sockopt::UDP_SEND_MSG_SIZE,
+                segment_size,
+            );
+        }
+        let op = {
+            let msg = state.prepare(control_len);
+            let hand: usize = self.tp_io.socket().try_into().unwrap();
+            start_async_io(&self.tp_io, |overlapped| unsafe {
+                let mut sent: u32 = 0;
+                let rc = wsasendmsg(hand, msg, 0, &mut sent, overlapped, ptr::null_mut());
+                if rc == 0 {
+                    Some(sent as usize)
+                } else {
+                    None
+                }
+            })
+        };
+        op.await.get_number_of_bytes_transferred()
+    }
+
+    /// Receives up to `bufs.len()` datagrams, one into each buffer, with a WSARecvFrom outstanding
+    /// on every buffer at once. Waits until the receive into `bufs[0]` has finished, then cancels
+    /// the rest and returns every datagram that arrived before they were cancelled, as the index of
+    /// the buffer it is in, its length and its source address, in buffer order.
+    ///
+    /// A receive that failed, such as with `WSAEMSGSIZE` for a datagram that did not fit its
+    /// buffer, or that was cancelled, has no entry, and does not affect the other buffers. If no
+    /// buffer received a datagram, the error of the receive into `bufs[0]` is returned.
+    pub async fn recv_many(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+        // Like recv_from, the addresses have to outlive the operations. The Vec is not resized
+        // once the operations have started, so the addresses do not move.
+        let mut froms: Vec<RawSocketAddr> = bufs.iter().map(|_| RawSocketAddr::empty()).collect();
+        let mut ops = Vec::with_capacity(bufs.len());
+        for (buf, from) in bufs.iter_mut().zip(froms.iter_mut()) {
+            let mut wsabuf = WSABUF {
+                buf: PSTR(buf.as_mut_ptr()),
+                len: buf.len().try_into().unwrap(),
+            };
+            ops.push(unsafe { self.start_recv_from(&mut wsabuf, Some(from)) });
+        }
+        let mut ops = ops.into_iter();
+        let first = match ops.next() {
+            Some(op) => datagram_len(op.await),
+            None => return Ok(Vec::new()),
+        };
+        // Only the remaining operations are cancelled, not other receives on the socket.
+        let rest: Vec<IocpFuture> = ops.collect();
+        for op in &rest {
+            op.cancel();
+        }
+        // Every operation is awaited before anything is returned, so none is still writing into
+        // `bufs` or `froms`, and a datagram a later buffer received is never lost.
+        let mut results = vec![first];
+        for op in rest {
+            results.push(datagram_len(op.await));
+        }
+        let mut received = Vec::new();
+        let mut first_err = None;
+        for (i, (result, from)) in results.into_iter().zip(&froms).enumerate() {
+            match result.and_then(|n| Ok((n, from.to_socket_addr()?))) {
+                Ok((n, from)) => received.push((i, n, from)),
+                Err(e) if i == 0 => first_err = Some(e),
+                Err(_) => {}
+            }
+        }
+        match first_err {
+            Some(e) if received.is_empty() => Err(e),
+            _ => Ok(received),
+        }
+    }
+
+    /// Starts a WSASendTo of the buffer described by `wsabuf` to `target`, or to the connected
+    /// peer if `target` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// The memory the buffer points to and `target` must stay valid until the returned future
+    /// completes.
+    unsafe fn start_send_to(
+        &self,
+        wsabuf: &mut WSABUF,
+        target: Option<&RawSocketAddr>,
+    ) -> IocpFuture {
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let (to, to_len) = match target {
+            Some(target) => (target.as_ptr(), target.len()),
+            None => (ptr::null(), 0),
+        };
+        start_async_io(&self.tp_io, |overlapped| {
+            let mut sent: u32 = 0;
+            let rc = WSASendTo(
+                hand,
+                wsabuf,
+                1,
+                &mut sent,
+                0,
+                to,
+                to_len,
+                overlapped,
+                Option::None,
+            );
+            if rc == 0 {
+                Some(sent as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Starts a WSARecvFrom into the buffer described by `wsabuf`, with the source address written
+    /// to `from` if it is not `None`.
+    ///
+    /// # Safety
+    ///
+    /// The memory the buffer points to and `from` must stay valid until the returned future
+    /// completes.
+    unsafe fn start_recv_from(
+        &self,
+        wsabuf: &mut WSABUF,
+        from: Option<&mut RawSocketAddr>,
+    ) -> IocpFuture {
+        let hand: usize = self.tp_io.socket().try_into().unwrap();
+        let (from, from_len) = match from {
+            Some(from) => (from.as_mut_ptr(), from.len_mut_ptr()),
+            None => (ptr::null_mut(), ptr::null_mut()),
+        };
+        start_async_io(&self.tp_io, |overlapped| {
+            let mut received: u32 = 0;
+            let mut flags: u32 = 0;
+            let rc = WSARecvFrom(
+                hand,
+                wsabuf,
+                1,
+                &mut received,
+                &mut flags,
+                from,
+                from_len,
+                overlapped,
+                Option::None,
+            );
+            if rc == 0 {
+                Some(received as usize)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// The WSAMSG for WSARecvMsg or WSASendMsg and everything it points to other than the data buffer.
+// Winsock writes the lengths and the address back into it when a receive completes, so it has to
+// outlive the operation, and it is boxed so that the pointers between its fields stay valid.
+struct MsgState {
+    msg: WSAMSG,
+    wsabuf: WSABUF,
+    addr: RawSocketAddr,
+    control: ControlBuffer,
+}
+
+// The pointers in the WSAMSG only refer to the rest of the MsgState and to the caller's buffer.
+unsafe impl Send for MsgState {}
+
+impl MsgState {
+    fn new(buf: *mut u8, len: usize) -> Box<MsgState> {
+        Box::new(MsgState {
+            msg: WSAMSG::default(),
+            wsabuf: WSABUF {
+                buf: PSTR(buf),
+                len: len.try_into().unwrap(),
+            },
+            addr: RawSocketAddr::empty(),
+            control: ControlBuffer([0; CONTROL_BUFFER_LEN]),
+        })
+    }
+
+    // Points the WSAMSG at the other fields, with `control_len` bytes of control data.
+    fn prepare(&mut self, control_len: usize) -> *mut WSAMSG {
+        self.msg.name = self.addr.as_mut_ptr();
+        self.msg.namelen = self.addr.len();
+        self.msg.lpBuffers = &mut self.wsabuf;
+        self.msg.dwBufferCount = 1;
+        self.msg.Control = WSABUF {
+            buf: PSTR(if control_len == 0 {
+                ptr::null_mut()
+            } else {
+                self.control.0.as_mut_ptr()
+            }),
+            len: control_len as u32,
+        };
+        &mut self.msg
+    }
+}
+
+const CONTROL_BUFFER_LEN: usize = 128;
+
+// Room for the control data of an IPV6_PKTINFO, an IP_PKTINFO and the ECN codepoint of each,
+// which a dual-stack socket can receive together. Aligned for WSACMSGHDR.
+#[repr(C, align(8))]
+struct ControlBuffer([u8; CONTROL_BUFFER_LEN]);
+
+// `WSACMSGHDR` from ws2def.h, which heads each piece of control data.
+#[repr(C)]
+struct CmsgHdr {
+    cmsg_len: usize,
+    cmsg_level: i32,
+    cmsg_type: i32,
+}
+
+// WSA_CMSGHDR_ALIGN and WSA_CMSGDATA_ALIGN, which both align to a pointer.
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::align_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+impl ControlBuffer {
+    // Parses the control data in the first `len` bytes.
+    fn recv_meta(&self, len: usize) -> RecvMeta {
+        let header_len = cmsg_align(mem::size_of::<CmsgHdr>());
+        let mut meta = RecvMeta::default();
+        let mut offset = 0;
+        while offset + header_len <= len {
+            let header =
+                unsafe { ptr::read_unaligned(self.0[offset..].as_ptr() as *const CmsgHdr) };
+            if header.cmsg_len < header_len || offset + header.cmsg_len > len {
+                break;
+            }
+            let data = self.0[offset + header_len..].as_ptr();
+            let data_len = header.cmsg_len - header_len;
+            match (header.cmsg_level, header.cmsg_type) {
+                (sockopt::IPPROTO_IP, sockopt::IP_PKTINFO)
+                    if data_len >= mem::size_of::<IN_PKTINFO>() =>
+                unsafe {
+                    let info = ptr::read_unaligned(data as *const IN_PKTINFO);
+                    meta.packet_info = Some(PacketInfo {
+                        addr: Ipv4Addr::from(info.ipi_addr.S_un.S_addr.to_ne_bytes()).into(),
+                        interface_index: info.ipi_ifindex,
+                    });
+                },
+                (sockopt::IPPROTO_IPV6, sockopt::IPV6_PKTINFO)
+                    if data_len >= mem::size_of::<IN6_PKTINFO>() =>
+                unsafe {
+                    let info = ptr::read_unaligned(data as *const IN6_PKTINFO);
+                    meta.packet_info = Some(PacketInfo {
+                        addr: Ipv6Addr::from(info.ipi6_addr.u.Byte).into(),
+                        interface_index: info.ipi6_ifindex,
+                    });
+                },
+                (sockopt::IPPROTO_IP, sockopt::IP_ECN)
+                | (sockopt::IPPROTO_IPV6, sockopt::IPV6_ECN)
+                    if data_len >= mem::size_of::<i32>() =>
+                {
+                    let codepoint = unsafe { ptr::read_unaligned(data as *const i32) };
+                    meta.ecn = Some(Ecn::from_codepoint(codepoint));
+                }
+                _ => {}
+            }
+            offset += cmsg_align(header.cmsg_len);
+        }
+        meta
+    }
+
+    // Appends a piece of control data at `offset`, and returns the offset after it.
+    fn push<T: Copy>(&mut self, offset: usize, level: i32, kind: i32, data: T) -> usize {
+        let header_len = cmsg_align(mem::size_of::<CmsgHdr>());
+        let end = offset + header_len + cmsg_align(mem::size_of::<T>());
+        assert!(end <= CONTROL_BUFFER_LEN, "control data does not fit");
+        let header = CmsgHdr {
+            cmsg_len: header_len + mem::size_of::<T>(),
+            cmsg_level: level,
+            cmsg_type: kind,
+        };
+        unsafe {
+            let start = self.0[offset..].as_mut_ptr();
+            ptr::write_unaligned(start as *mut CmsgHdr, header);
+            ptr::write_unaligned(start.add(header_len) as *mut T, data);
+        }
+        end
+    }
+
+    // Appends `info` as IP_PKTINFO for a datagram sent over IPv4, which includes one sent to an
+    // IPv4-mapped address from a dual-stack socket, or as IPV6_PKTINFO otherwise.
+    fn push_packet_info(
+        &mut self,
+        offset: usize,
+        info: &PacketInfo,
+        v4: bool,
+    ) -> io::Result<usize> {
+        if v4 {
+            let addr = match info.addr {
+                IpAddr::V4(addr) => addr,
+                IpAddr::V6(addr) => addr.to_ipv4_mapped().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "an IPv4 datagram cannot be sent from an IPv6 address",
+                    )
+                })?,
+            };
+            let mut pktinfo: IN_PKTINFO = unsafe { mem::zeroed() };
+            pktinfo.ipi_addr.S_un.S_addr = u32::from_ne_bytes(addr.octets());
+            pktinfo.ipi_ifindex = info.interface_index;
+            Ok(self.push(offset, sockopt::IPPROTO_IP, sockopt::IP_PKTINFO, pktinfo))
+        } else {
+            let addr = match info.addr {
+                IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+                IpAddr::V6(addr) => addr,
+            };
+            let mut pktinfo: IN6_PKTINFO = unsafe { mem::zeroed() };
+            pktinfo.ipi6_addr.u.Byte = addr.octets();
+            pktinfo.ipi6_ifindex = info.interface_index;
+            Ok(self.push(
+                offset,
+                sockopt::IPPROTO_IPV6,
+                sockopt::IPV6_PKTINFO,
+                pktinfo,
+            ))
+        }
+    }
+}
+
+// The length of a received datagram. The completion reports a datagram that did not fit as
+// ERROR_MORE_DATA; report it the way a synchronous WSARecvFrom does.
+fn datagram_len(result: IocpResult) -> io::Result<usize> {
+    match result.get_number_of_bytes_transferred_allow_partial()? {
+        (n, false) => Ok(n),
+        (_, true) => Err(io::Error::from_raw_os_error(WSAEMSGSIZE)),
+    }
+}
+
+fn set_udp_connreset(socket: &UdpSocket, report: bool) -> io::Result<()> {
+    const SIO_UDP_CONNRESET: u32 = 0x9800000C;
+    sockopt::ioctl(socket, SIO_UDP_CONNRESET, report as i32)
+}
+
+impl AsRawSocket for AsyncUdpSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.tp_io.get_ref().as_raw_socket()
+    }
+}
+
+impl AsSocket for AsyncUdpSocket {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.tp_io.get_ref().as_socket()
+    }
+}
@@ -0,0 +1,207 @@
+//! Unix domain stream sockets (`AF_UNIX`), for local IPC that does not go through TCP loopback.
+//! Windows supports them from Windows 10 version 1803.
+
+use bindings::Windows::Win32::WinSock::{getpeername, getsockname};
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+use std::path::{Path, PathBuf};
+
+use crate::runtime::Handle;
+use crate::socket_stream::{self, SocketListener, SocketStream};
+
+const AF_UNIX: u16 = 1;
+
+/// `SOCKADDR_UN` from afunix.h.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrUn {
+    sun_family: u16,
+    sun_path: [u8; 108],
+}
+
+impl SockaddrUn {
+    fn empty() -> SockaddrUn {
+        SockaddrUn {
+            sun_family: AF_UNIX,
+            sun_path: [0; 108],
+        }
+    }
+
+    // Returns the address and the length to pass along with it.
+    fn from_path(path: &Path) -> io::Result<(SockaddrUn, i32)> {
+        let bytes = match path.to_str() {
+            Some(path) => path.as_bytes(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unix socket paths must be valid unicode",
+                ))
+            }
+        };
+        let mut addr = SockaddrUn::empty();
+        // The path has to leave room for its terminating NUL.
+        if bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unix socket paths must be shorter than 108 bytes and cannot contain NUL",
+            ));
+        }
+        addr.sun_path[..bytes.len()].copy_from_slice(bytes);
+        let len = mem::size_of::<u16>() + bytes.len() + 1;
+        Ok((addr, len as i32))
+    }
+
+    fn socket_addr(&self, len: i32) -> SocketAddr {
+        let len = (len as usize)
+            .saturating_sub(mem::size_of::<u16>())
+            .min(self.sun_path.len());
+        let path = &self.sun_path[..len];
+        let path = match path.iter().position(|&b| b == 0) {
+            Some(nul) => &path[..nul],
+            None => path,
+        };
+        SocketAddr {
+            path: match std::str::from_utf8(path) {
+                Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The address of a unix domain socket: a path, or nothing for a socket that was not bound,
+/// such as the client end of a connection.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SocketAddr {
+    path: Option<PathBuf>,
+}
+
+impl SocketAddr {
+    /// The path the socket is bound to, if it is bound to one.
+    pub fn as_pathname(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn is_unnamed(&self) -> bool {
+        self.path.is_none()
+    }
+}
+
+impl fmt::Debug for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{:?} (pathname)", path),
+            None => f.write_str("(unnamed)"),
+        }
+    }
+}
+
+/// A unix domain socket listening for connections, which it accepts with an overlapped `AcceptEx`
+/// completed on the runtime's threadpool.
+///
+/// Windows does not remove the socket's file when the listener is closed, so a server that binds
+/// to the same path each time it starts should delete the file first.
+pub struct AsyncUnixListener {
+    inner: SocketListener,
+}
+
+impl AsyncUnixListener {
+    /// Creates a socket bound to `path` and starts listening on it. The file must not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<AsyncUnixListener> {
+        let socket = socket_stream::new_socket(AF_UNIX, 0)?;
+        let (addr, len) = SockaddrUn::from_path(path.as_ref())?;
+        socket_stream::bind(&socket, &addr, len)?;
+        Ok(AsyncUnixListener {
+            inner: SocketListener::listen(socket, AF_UNIX)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let (addr, len) =
+            socket_stream::socket_name(self.inner.socket(), SockaddrUn::empty(), getsockname)?;
+        Ok(addr.socket_addr(len))
+    }
+
+    /// Accepts a connection, and returns it along with the address of the client, which is usually
+    /// unnamed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn accept(&self) -> io::Result<(AsyncUnixStream, SocketAddr)> {
+        let socket = socket_stream::new_socket(AF_UNIX, 0)?;
+        let inner = self
+            .inner
+            .accept(socket, mem::size_of::<SockaddrUn>())
+            .await?;
+        let stream = AsyncUnixStream { inner };
+        let peer_addr = stream.peer_addr()?;
+        Ok((stream, peer_addr))
+    }
+}
+
+impl AsRawSocket for AsyncUnixListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.socket().as_raw_socket()
+    }
+}
+
+impl AsSocket for AsyncUnixListener {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.socket().as_socket()
+    }
+}
+
+/// A unix domain stream socket connection whose I/O completes on the runtime's threadpool.
+///
+/// Like [crate::stream::AsyncTcpStream], reads and writes only need `&self`, and the
+/// [AsyncRead](futures::io::AsyncRead) and [AsyncWrite](futures::io::AsyncWrite) impls are also
+/// provided for `&AsyncUnixStream`, with at most one task reading and one task writing through them
+/// at a time.
+pub struct AsyncUnixStream {
+    inner: SocketStream,
+}
+
+impl AsyncUnixStream {
+    /// Connects to the listener bound to `path`.
+    ///
+    /// `ConnectEx` does not support unix domain sockets, so the connect is a blocking `connect` on
+    /// the runtime's blocking pool. It completes as soon as the listener's backlog has room.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if called outside the context of a [crate::runtime::Runtime].
+    pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<AsyncUnixStream> {
+        let (addr, len) = SockaddrUn::from_path(path.as_ref())?;
+        let socket = socket_stream::new_socket(AF_UNIX, 0)?;
+        let socket = Handle::current_for_io()?
+            .spawn_blocking(move || socket_stream::connect(&socket, &addr, len).map(|_| socket))
+            .await?;
+        Ok(AsyncUnixStream {
+            inner: SocketStream::new(socket)?,
+        })
+    }
+
+    /// The address of the other end of the connection, which for the client end of an accepted
+    /// connection is usually unnamed.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let (addr, len) =
+            socket_stream::socket_name(self.inner.socket(), SockaddrUn::empty(), getpeername)?;
+        Ok(addr.socket_addr(len))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let (addr, len) =
+            socket_stream::socket_name(self.inner.socket(), SockaddrUn::empty(), getsockname)?;
+        Ok(addr.socket_addr(len))
+    }
+}
+
+socket_stream::socket_stream_wrapper!(AsyncUnixStream);
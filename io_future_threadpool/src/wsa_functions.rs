@@ -0,0 +1,269 @@
+use bindings::Windows::Win32::WinSock::{
+    WSAIoctl, LPFN_ACCEPTEX, LPFN_CONNECTEX, LPFN_DISCONNECTEX, LPFN_GETACCEPTEXSOCKADDRS,
+    LPFN_TRANSMITFILE, LPFN_WSARECVMSG, LPFN_WSASENDMSG,
+};
+
+use windows::Guid;
+
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::windows::io::AsRawSocket;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// The WinSock extension functions, which have to be loaded at runtime with
+/// `SIO_GET_EXTENSION_FUNCTION_POINTER`. Each [crate::runtime::Runtime] owns one of these, so the
+/// cached pointers are not shared between runtimes.
+pub(crate) struct WsaFunctions {
+    acceptex: WsaFunctionCache,
+    connectex: WsaFunctionCache,
+    disconnectex: WsaFunctionCache,
+    get_acceptex_sockaddrs: WsaFunctionCache,
+    transmitfile: WsaFunctionCache,
+    wsarecvmsg: WsaFunctionCache,
+    wsasendmsg: WsaFunctionCache,
+}
+
+impl WsaFunctions {
+    pub(crate) fn new() -> WsaFunctions {
+        WsaFunctions {
+            // WSAID_ACCEPTEX
+            acceptex: WsaFunctionCache::new(Guid::from_values(
+                0xb5367df1,
+                0xcbac,
+                0x11cf,
+                [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+            )),
+            // WSAID_CONNECTEX
+            connectex: WsaFunctionCache::new(Guid::from_values(
+                0x25a207b9,
+                0xddf3,
+                0x4660,
+                [0x8e, 0xe9, 0x76, 0xe5, 0x8c, 0x74, 0x06, 0x3e],
+            )),
+            // WSAID_DISCONNECTEX
+            disconnectex: WsaFunctionCache::new(Guid::from_values(
+                0x7fda2e11,
+                0x8630,
+                0x436f,
+                [0xa0, 0x31, 0xf5, 0x36, 0xa6, 0xee, 0xc1, 0x57],
+            )),
+            // WSAID_GETACCEPTEXSOCKADDRS
+            get_acceptex_sockaddrs: WsaFunctionCache::new(Guid::from_values(
+                0xb5367df2,
+                0xcbac,
+                0x11cf,
+                [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+            )),
+            // WSAID_TRANSMITFILE
+            transmitfile: WsaFunctionCache::new(Guid::from_values(
+                0xb5367df0,
+                0xcbac,
+                0x11cf,
+                [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+            )),
+            // WSAID_WSARECVMSG
+            wsarecvmsg: WsaFunctionCache::new(Guid::from_values(
+                0xf689d7c8,
+                0x6f1f,
+                0x436b,
+                [0x8a, 0x53, 0xe5, 0x4f, 0xe3, 0x51, 0xc3, 0x22],
+            )),
+            // WSAID_WSASENDMSG
+            wsasendmsg: WsaFunctionCache::new(Guid::from_values(
+                0xa441e712,
+                0x754f,
+                0x43ca,
+                [0x84, 0xa7, 0x0d, 0xee, 0x44, 0xcf, 0x60, 0x6d],
+            )),
+        }
+    }
+
+    pub(crate) fn acceptex(&self, listener: &TcpListener) -> io::Result<LPFN_ACCEPTEX> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_ACCEPTEX>(
+                self.acceptex.get_ptr(listener, listener.local_addr()?)?,
+            ))
+        }
+    }
+
+    /// Loads AcceptEx for a listener of one of the families in [crate::socket_stream], such as
+    /// `AF_UNIX`.
+    pub(crate) fn acceptex_for_family<S: AsRawSocket>(
+        &self,
+        listener: &S,
+        family: u16,
+    ) -> io::Result<LPFN_ACCEPTEX> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_ACCEPTEX>(
+                self.acceptex
+                    .get_ptr_in(listener, self.acceptex.family_ptr(family)?)?,
+            ))
+        }
+    }
+
+    /// Loads ConnectEx for a socket of one of the families in [crate::socket_stream].
+    pub(crate) fn connectex_for_family<S: AsRawSocket>(
+        &self,
+        socket: &S,
+        family: u16,
+    ) -> io::Result<LPFN_CONNECTEX> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_CONNECTEX>(
+                self.connectex
+                    .get_ptr_in(socket, self.connectex.family_ptr(family)?)?,
+            ))
+        }
+    }
+
+    /// Loads ConnectEx for a socket that will connect to `addr`. The socket does not have to be
+    /// bound yet.
+    pub(crate) fn connectex(
+        &self,
+        socket: &TcpStream,
+        addr: &SocketAddr,
+    ) -> io::Result<LPFN_CONNECTEX> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_CONNECTEX>(
+                self.connectex.get_ptr(socket, *addr)?,
+            ))
+        }
+    }
+
+    pub(crate) fn disconnectex(&self, socket: &TcpStream) -> io::Result<LPFN_DISCONNECTEX> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_DISCONNECTEX>(
+                self.disconnectex.get_ptr(socket, socket.local_addr()?)?,
+            ))
+        }
+    }
+
+    pub(crate) fn transmitfile(&self, socket: &TcpStream) -> io::Result<LPFN_TRANSMITFILE> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_TRANSMITFILE>(
+                self.transmitfile.get_ptr(socket, socket.local_addr()?)?,
+            ))
+        }
+    }
+
+    pub(crate) fn wsarecvmsg(&self, socket: &UdpSocket) -> io::Result<LPFN_WSARECVMSG> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_WSARECVMSG>(
+                self.wsarecvmsg.get_ptr(socket, socket.local_addr()?)?,
+            ))
+        }
+    }
+
+    pub(crate) fn wsasendmsg(&self, socket: &UdpSocket) -> io::Result<LPFN_WSASENDMSG> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_WSASENDMSG>(
+                self.wsasendmsg.get_ptr(socket, socket.local_addr()?)?,
+            ))
+        }
+    }
+
+    pub(crate) fn get_acceptex_sockaddrs(
+        &self,
+        listener: &TcpListener,
+    ) -> io::Result<LPFN_GETACCEPTEXSOCKADDRS> {
+        unsafe {
+            Ok(mem::transmute::<*mut c_void, LPFN_GETACCEPTEXSOCKADDRS>(
+                self.get_acceptex_sockaddrs
+                    .get_ptr(listener, listener.local_addr()?)?,
+            ))
+        }
+    }
+}
+
+struct WsaFunctionCache {
+    guid: Guid,
+    //We don't need any ordering guarantees when loading or storing to these.
+    //It's ok if we do the IOCTL multiple times; it should gives us the same pointer each time.
+    ipv4_ptr: AtomicPtr<c_void>,
+    ipv6_ptr: AtomicPtr<c_void>,
+    unix_ptr: AtomicPtr<c_void>,
+    hyperv_ptr: AtomicPtr<c_void>,
+    bth_ptr: AtomicPtr<c_void>,
+}
+
+impl WsaFunctionCache {
+    fn new(guid: Guid) -> WsaFunctionCache {
+        WsaFunctionCache {
+            guid,
+            ipv4_ptr: AtomicPtr::new(ptr::null_mut()),
+            ipv6_ptr: AtomicPtr::new(ptr::null_mut()),
+            unix_ptr: AtomicPtr::new(ptr::null_mut()),
+            hyperv_ptr: AtomicPtr::new(ptr::null_mut()),
+            bth_ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // The cache for a family other than AF_INET and AF_INET6.
+    fn family_ptr(&self, family: u16) -> io::Result<&AtomicPtr<c_void>> {
+        const AF_UNIX: u16 = 1;
+        const AF_BTH: u16 = 32;
+        const AF_HYPERV: u16 = 34;
+
+        match family {
+            AF_UNIX => Ok(&self.unix_ptr),
+            AF_BTH => Ok(&self.bth_ptr),
+            AF_HYPERV => Ok(&self.hyperv_ptr),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("no extension function cache for address family {}", family),
+            )),
+        }
+    }
+
+    // The pointer can differ between address families, so `addr` is any address of the family
+    // `socket` was created for.
+    fn get_ptr<S: AsRawSocket>(&self, socket: &S, addr: SocketAddr) -> io::Result<*mut c_void> {
+        let atomic_ptr = match addr {
+            SocketAddr::V4(..) => &self.ipv4_ptr,
+            SocketAddr::V6(..) => &self.ipv6_ptr,
+        };
+        self.get_ptr_in(socket, atomic_ptr)
+    }
+
+    // Loads the pointer for `socket` and caches it in `atomic_ptr`, which is the cache for the
+    // socket's address family.
+    fn get_ptr_in<S: AsRawSocket>(
+        &self,
+        socket: &S,
+        atomic_ptr: &AtomicPtr<c_void>,
+    ) -> io::Result<*mut c_void> {
+        {
+            let ret = atomic_ptr.load(Ordering::Relaxed);
+            if !ret.is_null() {
+                return Ok(ret);
+            }
+        }
+
+        const SIO_GET_EXTENSION_FUNCTION_POINTER: u32 = 0xC8000006;
+        let mut guid = self.guid;
+        let mut fnptr: *mut c_void = ptr::null_mut();
+        let mut bytes_returned: u32 = 0;
+        let rc: i32;
+        unsafe {
+            rc = WSAIoctl(
+                socket.as_raw_socket() as usize,
+                SIO_GET_EXTENSION_FUNCTION_POINTER,
+                &mut guid as *mut Guid as *mut c_void,
+                std::mem::size_of::<Guid>() as u32,
+                &mut fnptr as *mut *mut c_void as *mut c_void,
+                std::mem::size_of::<*mut c_void>() as u32,
+                &mut bytes_returned,
+                ptr::null_mut(),
+                None,
+            );
+        }
+        if rc == 0 {
+            atomic_ptr.store(fnptr, Ordering::Relaxed);
+            Ok(fnptr)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}